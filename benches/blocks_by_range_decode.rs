@@ -0,0 +1,111 @@
+// Measures the decode throughput of `BlocksByRange` responses through the outbound codec, which
+// is the hot path of range sync.
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use lighthouse_network::rpc::codec::base::{BaseInboundCodec, BaseOutboundCodec};
+use lighthouse_network::rpc::codec::ssz_snappy::{SSZSnappyInboundCodec, SSZSnappyOutboundCodec};
+use lighthouse_network::rpc::methods::{RPCCodedResponse, RPCResponse};
+use lighthouse_network::rpc::protocol::{Encoding, ProtocolId, SupportedProtocol};
+use std::sync::Arc;
+use tokio_util::codec::{Decoder, Encoder};
+use types::{
+    BeaconBlock, BeaconBlockMerge, ChainSpec, EthSpec, ForkContext, Hash256, MainnetEthSpec,
+    Signature, SignedBeaconBlock, VariableList,
+};
+
+// The same as `max_rpc_size` of the RPC handler.
+const MAX_RPC_SIZE: usize = 10 * 1_048_576; // 10M
+
+// The number of blocks in a response, i.e. a batch of `EPOCHS_PER_BATCH` epochs.
+const BLOCKS_PER_RESPONSE: usize = 64;
+
+// The sizes of the execution payload transactions of each block, to cover light and heavy blocks.
+const TRANSACTION_BYTES_PER_BLOCK: [usize; 3] = [1_024, 128 * 1_024, 1_048_576];
+
+fn fork_context(spec: &ChainSpec) -> Arc<ForkContext> {
+    let bellatrix_slot = spec
+        .bellatrix_fork_epoch
+        .expect("bellatrix is scheduled on mainnet")
+        .start_slot(MainnetEthSpec::slots_per_epoch());
+    Arc::new(ForkContext::new::<MainnetEthSpec>(
+        bellatrix_slot,
+        Hash256::zero(),
+        spec,
+    ))
+}
+
+fn protocol_id() -> ProtocolId {
+    ProtocolId::new(SupportedProtocol::BlocksByRangeV2, Encoding::SSZSnappy)
+}
+
+// A block whose execution payload carries transactions of the given total size.
+fn block(spec: &ChainSpec, transaction_bytes: usize) -> SignedBeaconBlock<MainnetEthSpec> {
+    // Split into transactions of 1K, as real payloads carry many small transactions.
+    let transaction = VariableList::new(vec![0xab; 1_024]).expect("within the limit");
+    let transactions =
+        VariableList::new(vec![transaction; transaction_bytes / 1_024]).expect("within the limit");
+
+    let mut block = BeaconBlockMerge::<MainnetEthSpec>::empty(spec);
+    block.body.execution_payload.execution_payload.transactions = transactions;
+    SignedBeaconBlock::from_block(BeaconBlock::Merge(block), Signature::empty())
+}
+
+// Encodes a `BlocksByRange` response as the peer sends it.
+fn encoded_response(spec: &ChainSpec, transaction_bytes: usize) -> BytesMut {
+    let block = Arc::new(block(spec, transaction_bytes));
+    let mut codec = BaseInboundCodec::new(SSZSnappyInboundCodec::<MainnetEthSpec>::new(
+        protocol_id(),
+        MAX_RPC_SIZE,
+        fork_context(spec),
+    ));
+
+    let mut bytes = BytesMut::new();
+    for _ in 0..BLOCKS_PER_RESPONSE {
+        codec
+            .encode(
+                RPCCodedResponse::Success(RPCResponse::BlocksByRange(block.clone())),
+                &mut bytes,
+            )
+            .expect("encodes the response");
+    }
+    bytes
+}
+
+fn decode_blocks_by_range(c: &mut Criterion) {
+    let spec = ChainSpec::mainnet();
+    let mut group = c.benchmark_group("blocks_by_range_decode");
+
+    for transaction_bytes in TRANSACTION_BYTES_PER_BLOCK {
+        let encoded = encoded_response(&spec, transaction_bytes);
+        group.throughput(Throughput::Bytes(encoded.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{}KiB_per_block", transaction_bytes / 1_024)),
+            &encoded,
+            |b, encoded| {
+                b.iter(|| {
+                    let mut codec =
+                        BaseOutboundCodec::new(SSZSnappyOutboundCodec::<MainnetEthSpec>::new(
+                            protocol_id(),
+                            MAX_RPC_SIZE,
+                            fork_context(&spec),
+                        ));
+                    let mut bytes = encoded.clone();
+                    let mut blocks = 0;
+                    while let Some(response) = codec.decode(&mut bytes).expect("decodes") {
+                        assert!(matches!(
+                            response,
+                            RPCCodedResponse::Success(RPCResponse::BlocksByRange(_))
+                        ));
+                        blocks += 1;
+                    }
+                    assert_eq!(blocks, BLOCKS_PER_RESPONSE);
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, decode_blocks_by_range);
+criterion_main!(benches);
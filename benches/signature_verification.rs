@@ -0,0 +1,64 @@
+// Compares verifying the signatures of a batch of blocks on a single thread with verifying them in
+// parallel, as range sync does with its signature verification pool.
+use bls::{verify_signature_sets, Keypair, PublicKey, Signature, SignatureSet};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rayon::prelude::*;
+use std::borrow::Cow;
+use types::Hash256;
+
+// Roughly the signature sets of a batch of 64 blocks: the proposal, randao reveal and a dozen of
+// attestations per block.
+const SIGNATURE_SETS: usize = 64 * 16;
+
+// The thread counts of the parallel verification.
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+
+fn signatures() -> Vec<(PublicKey, Signature, Hash256)> {
+    (0..SIGNATURE_SETS)
+        .map(|i| {
+            let keypair = Keypair::random();
+            let message = Hash256::from_low_u64_be(i as u64);
+            let signature = keypair.sk.sign(message);
+            (keypair.pk, signature, message)
+        })
+        .collect()
+}
+
+fn verify_signatures(c: &mut Criterion) {
+    let signatures = signatures();
+    let sets = signatures
+        .iter()
+        .map(|(pk, signature, message)| {
+            SignatureSet::single_pubkey(signature, Cow::Borrowed(pk), *message)
+        })
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("signature_verification");
+
+    group.bench_function("sequential", |b| {
+        b.iter(|| assert!(verify_signature_sets(sets.iter())))
+    });
+
+    for threads in THREADS {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .expect("should build the thread pool");
+        // Split the sets into a chunk per thread, as lighthouse's `BlockSignatureVerifier` does.
+        let chunk_size = std::cmp::max(1, sets.len() / threads);
+        group.bench_with_input(BenchmarkId::new("parallel", threads), &sets, |b, sets| {
+            b.iter(|| {
+                pool.install(|| {
+                    assert!(sets
+                        .par_chunks(chunk_size)
+                        .all(|chunk| verify_signature_sets(chunk.iter())))
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, verify_signatures);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+// Measures the heap allocations of the event queues (`SmallVec`s) in `PeerManager` and the RPC
+// `Handler` at several inline capacities, for bursts of events such as a heartbeat queuing a STATUS
+// and a PING to each peer. The allocations per burst are printed, and the time is measured by
+// Criterion.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use smallvec::{Array, SmallVec};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Counts the allocations, including the reallocations of a growing vector.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// Roughly the size of a `PeerManagerEvent`, which mostly carries a `PeerId`.
+type Event = [u8; 96];
+
+// The numbers of events queued at once, from a quiet node to a heartbeat over 100 peers.
+const BURSTS: [usize; 4] = [2, 8, 32, 200];
+
+// Queues the events and drains them in order, as the `poll`s do.
+fn queue_and_drain<A: Array<Item = Event>>(burst: usize) {
+    let mut events: SmallVec<A> = SmallVec::new();
+    for i in 0..burst {
+        events.push([i as u8; 96]);
+    }
+    while !events.is_empty() {
+        std::hint::black_box(events.remove(0));
+    }
+}
+
+fn allocations_per_burst<A: Array<Item = Event>>(burst: usize) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    queue_and_drain::<A>(burst);
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn bench_capacity<A: Array<Item = Event>>(c: &mut Criterion, capacity: usize) {
+    let mut group = c.benchmark_group(format!("smallvec_inline_capacity/{capacity}"));
+    for burst in BURSTS {
+        println!(
+            "inline capacity: {capacity}, burst: {burst}, allocations: {}",
+            allocations_per_burst::<A>(burst)
+        );
+        group.bench_with_input(BenchmarkId::new("burst", burst), &burst, |b, &burst| {
+            b.iter(|| queue_and_drain::<A>(burst))
+        });
+    }
+    group.finish();
+}
+
+fn smallvec_inline_capacity(c: &mut Criterion) {
+    // The current capacities of the RPC handler queues and the PeerManager events.
+    bench_capacity::<[Event; 4]>(c, 4);
+    bench_capacity::<[Event; 10]>(c, 10);
+    bench_capacity::<[Event; 16]>(c, 16);
+    bench_capacity::<[Event; 32]>(c, 32);
+}
+
+criterion_group!(benches, smallvec_inline_capacity);
+criterion_main!(benches);
@@ -0,0 +1,145 @@
+use crate::network::NetworkMessage;
+use crate::sync::SyncSender;
+use crate::PeerDB;
+use parking_lot::RwLock;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, info};
+
+mod node;
+
+// The state shared by the requests.
+struct Context {
+    peer_db: Arc<RwLock<PeerDB>>,
+    sync_sender: SyncSender,
+    network_sender: Sender<NetworkMessage>,
+}
+
+// A JSON response with its HTTP status line, e.g. `200 OK`.
+struct Response {
+    status: &'static str,
+    body: serde_json::Value,
+}
+
+impl Response {
+    fn ok(body: serde_json::Value) -> Self {
+        Response {
+            status: "200 OK",
+            body,
+        }
+    }
+
+    fn error(status: &'static str, message: String) -> Self {
+        Response {
+            status,
+            body: serde_json::json!({ "message": message }),
+        }
+    }
+}
+
+// Serves a tiny subset of the beacon node API on `localhost:port`, for introspecting a running
+// node.
+// Ref: https://ethereum.github.io/beacon-APIs/#/Node
+pub(crate) fn spawn(
+    runtime: Arc<Runtime>,
+    port: u16,
+    peer_db: Arc<RwLock<PeerDB>>,
+    sync_sender: SyncSender,
+    network_sender: Sender<NetworkMessage>,
+) -> Result<(), String> {
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let listener = runtime
+        .block_on(TcpListener::bind(address))
+        .map_err(|e| format!("Failed to bind the HTTP API server to {address}. {e}"))?;
+    info!("Serving the HTTP API on http://{address}");
+
+    let context = Arc::new(Context {
+        peer_db,
+        sync_sender,
+        network_sender,
+    });
+
+    runtime.spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _remote)) => {
+                    let context = context.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, &context).await {
+                            debug!("Failed to serve an HTTP API request. {}", e);
+                        }
+                    });
+                }
+                Err(e) => debug!("Failed to accept an HTTP API connection. {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn serve(mut stream: TcpStream, context: &Context) -> std::io::Result<()> {
+    // Only the request line is parsed, as none of the endpoints take a body.
+    let request_line = crate::http::read_request_line(&mut stream).await?;
+    let mut request_line = request_line.split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let response = route(method, path, context).await;
+
+    crate::http::respond(
+        &mut stream,
+        response.status,
+        "application/json",
+        response.body.to_string().as_bytes(),
+    )
+    .await
+}
+
+async fn route(method: &str, path: &str, context: &Context) -> Response {
+    if let Some(peer_id) = path
+        .strip_prefix("/node/peers/")
+        .and_then(|path| path.strip_suffix("/ping"))
+    {
+        return ping(method, peer_id, context);
+    }
+
+    let result = match (method, path) {
+        ("GET", "/node/peers") => Ok(node::peers(&context.peer_db)),
+        ("GET", "/node/syncing") => node::syncing(&context.sync_sender).await,
+        ("GET", "/node/identity") => node::identity(&context.network_sender).await,
+        (_, "/node/peers" | "/node/syncing" | "/node/identity") => {
+            return Response::error("405 Method Not Allowed", format!("{method} {path}"));
+        }
+        _ => return Response::error("404 Not Found", format!("{method} {path}")),
+    };
+
+    match result {
+        Ok(body) => Response::ok(body),
+        // The sync manager or the Network is too busy or has stopped.
+        Err(e) => Response::error("503 Service Unavailable", e),
+    }
+}
+
+// POST /node/peers/{peer_id}/ping
+fn ping(method: &str, peer_id: &str, context: &Context) -> Response {
+    if method != "POST" {
+        return Response::error(
+            "405 Method Not Allowed",
+            format!("{method} /node/peers/{peer_id}/ping"),
+        );
+    }
+    let peer_id = match peer_id.parse::<libp2p::PeerId>() {
+        Ok(peer_id) => peer_id,
+        Err(e) => return Response::error("400 Bad Request", format!("Invalid peer id. {e}")),
+    };
+
+    match node::ping(&context.network_sender, peer_id) {
+        Ok(body) => Response::ok(body),
+        // The Network is too busy or has stopped.
+        Err(e) => Response::error("503 Service Unavailable", e),
+    }
+}
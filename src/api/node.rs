@@ -0,0 +1,71 @@
+use crate::network::NetworkMessage;
+use crate::peer_db::ConnectionStatus;
+use crate::sync::{SyncOperation, SyncSender};
+use crate::PeerDB;
+use libp2p::PeerId;
+use parking_lot::RwLock;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+// GET /node/peers
+// The connected peers with their sync status.
+pub(super) fn peers(peer_db: &RwLock<PeerDB>) -> serde_json::Value {
+    let peers = peer_db
+        .read()
+        .snapshot()
+        .into_iter()
+        .filter(|peer| matches!(peer.connection_status, ConnectionStatus::Connected))
+        .map(|peer| {
+            serde_json::json!({
+                "peer_id": peer.peer_id.to_string(),
+                "sync_status": format!("{:?}", peer.sync_status),
+                "score": peer.score,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let count = peers.len();
+    serde_json::json!({ "data": peers, "meta": { "count": count } })
+}
+
+// GET /node/syncing
+// The target of the chain being synced, from the sync manager.
+pub(super) async fn syncing(sync_sender: &SyncSender) -> Result<serde_json::Value, String> {
+    let (reply, response) = oneshot::channel();
+    sync_sender.send(SyncOperation::QuerySyncing(reply))?;
+    let syncing = response
+        .await
+        .map_err(|_| "The sync manager dropped the query".to_string())?;
+
+    Ok(serde_json::json!({ "data": syncing }))
+}
+
+// GET /node/identity
+// The local PeerId and ENR, from the Network as the ENR is updated while running.
+pub(super) async fn identity(
+    network_sender: &Sender<NetworkMessage>,
+) -> Result<serde_json::Value, String> {
+    let (reply, response) = oneshot::channel();
+    network_sender
+        .try_send(NetworkMessage::QueryIdentity(reply))
+        .map_err(|e| format!("Failed to send NetworkMessage: {e}"))?;
+    let identity = response
+        .await
+        .map_err(|_| "The Network dropped the query".to_string())?;
+
+    Ok(serde_json::json!({ "data": identity }))
+}
+
+// POST /node/peers/{peer_id}/ping
+// Sends a Ping to the peer, to measure the latency to it. The round-trip time is logged and
+// recorded in the `ping_round_trip_time_seconds` metric once the Pong arrives.
+pub(super) fn ping(
+    network_sender: &Sender<NetworkMessage>,
+    peer_id: PeerId,
+) -> Result<serde_json::Value, String> {
+    network_sender
+        .try_send(NetworkMessage::PingPeer(peer_id))
+        .map_err(|e| format!("Failed to send NetworkMessage: {e}"))?;
+
+    Ok(serde_json::json!({ "data": { "peer_id": peer_id.to_string() } }))
+}
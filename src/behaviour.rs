@@ -18,20 +18,23 @@ pub enum RequestId<AppReqId> {
 
 /// Events `BehaviourComposer` emits.
 #[derive(Debug)]
-pub(crate) enum BehaviourComposerEvent {
+pub(crate) enum BehaviourComposerEvent<AppReqId> {
     Discovery(DiscoveryEvent),
     PeerManager(PeerManagerEvent),
-    Rpc(RpcEvent),
+    Rpc(RpcEvent<RequestId<AppReqId>>),
+    AutoNat(libp2p::autonat::Event),
 }
 
 /// The core behaviour that combines the sub-behaviours.
 #[derive(NetworkBehaviour)]
-#[behaviour(to_swarm = "BehaviourComposerEvent")]
+#[behaviour(to_swarm = "BehaviourComposerEvent<AppReqId>")]
 pub(crate) struct BehaviourComposer<AppReqId: ReqId> {
     /* Sub-Behaviours */
     pub(crate) discovery: crate::discovery::behaviour::Behaviour,
     pub(crate) peer_manager: crate::peer_manager::PeerManager,
     pub(crate) rpc: crate::rpc::behaviour::Behaviour<RequestId<AppReqId>>,
+    // Determines whether we are publicly reachable.
+    pub(crate) autonat: libp2p::autonat::Behaviour,
 }
 
 impl<AppReqId: ReqId> BehaviourComposer<AppReqId> {
@@ -39,29 +42,49 @@ impl<AppReqId: ReqId> BehaviourComposer<AppReqId> {
         discovery: crate::discovery::behaviour::Behaviour,
         peer_manager: crate::peer_manager::PeerManager,
         rpc: crate::rpc::behaviour::Behaviour<RequestId<AppReqId>>,
+        autonat: libp2p::autonat::Behaviour,
     ) -> Self {
         Self {
             discovery,
             peer_manager,
             rpc,
+            autonat,
         }
     }
+
+    /// The names of the sub-behaviours holding events that haven't been polled out yet.
+    pub(crate) fn behaviours_with_queued_events(&self) -> Vec<&'static str> {
+        let mut behaviours = vec![];
+        if self.peer_manager.has_queued_events() {
+            behaviours.push("peer_manager");
+        }
+        if self.rpc.has_queued_events() {
+            behaviours.push("rpc");
+        }
+        behaviours
+    }
 }
 
-impl From<DiscoveryEvent> for BehaviourComposerEvent {
+impl<AppReqId> From<DiscoveryEvent> for BehaviourComposerEvent<AppReqId> {
     fn from(event: DiscoveryEvent) -> Self {
         BehaviourComposerEvent::Discovery(event)
     }
 }
 
-impl From<PeerManagerEvent> for BehaviourComposerEvent {
+impl<AppReqId> From<PeerManagerEvent> for BehaviourComposerEvent<AppReqId> {
     fn from(event: PeerManagerEvent) -> Self {
         BehaviourComposerEvent::PeerManager(event)
     }
 }
 
-impl From<RpcEvent> for BehaviourComposerEvent {
-    fn from(event: RpcEvent) -> Self {
+impl<AppReqId> From<RpcEvent<RequestId<AppReqId>>> for BehaviourComposerEvent<AppReqId> {
+    fn from(event: RpcEvent<RequestId<AppReqId>>) -> Self {
         BehaviourComposerEvent::Rpc(event)
     }
 }
+
+impl<AppReqId> From<libp2p::autonat::Event> for BehaviourComposerEvent<AppReqId> {
+    fn from(event: libp2p::autonat::Event) -> Self {
+        BehaviourComposerEvent::AutoNat(event)
+    }
+}
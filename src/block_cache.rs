@@ -0,0 +1,83 @@
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use types::{Hash256, MainnetEthSpec, SignedBeaconBlock};
+
+/// A bounded in-memory buffer of recent blocks keyed by block root.
+///
+/// Peers mostly ask for recently produced blocks (e.g. parent lookups via `BlocksByRoot`), so this
+/// is consulted before the store to avoid disk reads for the same few blocks. Only sync inserts
+/// the blocks it imports, so that serving old blocks (e.g. a BlocksByRange request) doesn't evict
+/// the recent ones.
+pub(crate) struct BlockCache {
+    blocks: LruCache<Hash256, Arc<SignedBeaconBlock<MainnetEthSpec>>>,
+}
+
+impl BlockCache {
+    pub(crate) fn new(size: usize) -> Result<Self, String> {
+        let size = NonZeroUsize::new(size)
+            .ok_or_else(|| "The size of the block cache must be greater than 0".to_string())?;
+        Ok(BlockCache {
+            blocks: LruCache::new(size),
+        })
+    }
+
+    pub(crate) fn insert(&mut self, root: Hash256, block: Arc<SignedBeaconBlock<MainnetEthSpec>>) {
+        self.blocks.put(root, block);
+    }
+
+    // Returns the block if it's cached. A miss is left to the caller to look up in the store,
+    // without holding the lock of the cache.
+    pub(crate) fn get(&mut self, root: &Hash256) -> Option<Arc<SignedBeaconBlock<MainnetEthSpec>>> {
+        self.blocks.get(root).cloned()
+    }
+}
+
+// An empty, unsigned phase0 block at `slot`, for the tests across the crate.
+#[cfg(test)]
+pub(crate) fn test_block(slot: u64) -> Arc<SignedBeaconBlock<MainnetEthSpec>> {
+    let mut block = types::BeaconBlock::<MainnetEthSpec>::empty(&types::ChainSpec::mainnet());
+    *block.slot_mut() = types::Slot::new(slot);
+    Arc::new(SignedBeaconBlock::from_block(
+        block,
+        types::Signature::empty(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_zero_size() {
+        assert!(BlockCache::new(0).is_err());
+    }
+
+    #[test]
+    fn serves_imported_block() {
+        let mut cache = BlockCache::new(2).unwrap();
+        let imported = test_block(1);
+        let root = imported.canonical_root();
+        cache.insert(root, imported.clone());
+
+        let served = cache.get(&root).expect("cached block");
+        assert_eq!(served.canonical_root(), root);
+        // A block that hasn't been imported is left to the store.
+        assert!(cache.get(&test_block(2).canonical_root()).is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_block() {
+        let mut cache = BlockCache::new(2).unwrap();
+        let (first, second, third) = (test_block(1), test_block(2), test_block(3));
+        cache.insert(first.canonical_root(), first.clone());
+        cache.insert(second.canonical_root(), second.clone());
+        // Serving the first block makes the second one the least recently used.
+        assert!(cache.get(&first.canonical_root()).is_some());
+        cache.insert(third.canonical_root(), third.clone());
+
+        assert!(cache.get(&first.canonical_root()).is_some());
+        assert!(cache.get(&second.canonical_root()).is_none());
+        assert!(cache.get(&third.canonical_root()).is_some());
+    }
+}
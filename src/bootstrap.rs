@@ -1,49 +1,107 @@
-use crate::network::ReqId;
-use crate::{BehaviourComposer, CombinedKey, NetworkConfig, PeerDB, TARGET_PEERS_COUNT};
+use crate::config::RuntimeConfig;
+use crate::network::{NetworkError, ReqId};
+use crate::peer_manager::{ConnectionLimits, EpochTiming, IpLimits};
+use crate::{BehaviourComposer, CombinedKey, NetworkConfig, PeerDB};
 use beacon_chain::BeaconChainTypes;
 use discv5::Enr;
 use libp2p::core::muxing::StreamMuxerBox;
 use libp2p::identity::Keypair;
-use libp2p::{noise, yamux, PeerId, Transport};
+use libp2p::{noise, yamux, PeerId, Transport, TransportError};
 use parking_lot::RwLock;
-use std::process::exit;
+use slot_clock::SlotClock;
+use std::io;
+use std::net::{IpAddr, TcpListener, UdpSocket};
+use std::path::PathBuf;
 use std::sync::Arc;
-use tracing::error;
-use types::{ForkContext, MainnetEthSpec};
+use std::time::Duration;
+use types::{EthSpec, ForkContext, MainnetEthSpec};
 
 pub(crate) async fn build_network_transport(
     key_pair: Keypair,
-) -> libp2p::core::transport::Boxed<(PeerId, StreamMuxerBox)> {
+) -> Result<libp2p::core::transport::Boxed<(PeerId, StreamMuxerBox)>, NetworkError> {
     let tcp = libp2p::tcp::tokio::Transport::new(libp2p::tcp::Config::default().nodelay(true));
-    let transport = libp2p::dns::tokio::Transport::system(tcp).unwrap_or_else(|e| {
-        error!("Failed to configure DNS: {}", e);
-        exit(1);
-    });
+    let transport =
+        libp2p::dns::tokio::Transport::system(tcp).map_err(|e| NetworkError::Dns(e.to_string()))?;
 
     // Ref: Why are we using Noise?
     // https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#why-are-we-using-noise
-    let noise_config = noise::Config::new(&key_pair).expect("noise config");
+    let noise_config =
+        noise::Config::new(&key_pair).map_err(|e| NetworkError::Transport(e.to_string()))?;
     let yamux_config = yamux::Config::default();
 
-    transport
+    Ok(transport
         .upgrade(libp2p::core::upgrade::Version::V1)
         .authenticate(noise_config)
         .multiplex(yamux_config)
         .timeout(std::time::Duration::from_secs(20))
-        .boxed()
+        .boxed())
+}
+
+// Determines whether a transport error was caused by the security (Noise) or muxer (yamux)
+// negotiation, as those errors are otherwise buried in the boxed transport error and make interop
+// problems hard to diagnose.
+pub(crate) fn upgrade_failure(error: &TransportError<io::Error>) -> Option<&'static str> {
+    let io_error = match error {
+        TransportError::Other(io_error) => io_error,
+        TransportError::MultiaddrNotSupported(_) => return None,
+    };
+
+    // NOTE: `io::Error::source()` skips the wrapped error, so start from `get_ref()`.
+    let mut source: Option<&(dyn std::error::Error + 'static)> = io_error
+        .get_ref()
+        .map(|e| e as &(dyn std::error::Error + 'static));
+    while let Some(e) = source {
+        if e.is::<libp2p::core::upgrade::NegotiationError>() {
+            return Some(
+                "security/muxer negotiation failed (the peer may not support Noise/yamux)",
+            );
+        }
+        if e.is::<noise::Error>() {
+            return Some("Noise security handshake failed");
+        }
+        source = e.source();
+    }
+
+    None
 }
 
 pub(crate) async fn build_network_behaviour<T: BeaconChainTypes, AppReqId: ReqId>(
+    local_peer_id: PeerId,
     enr: Enr,
     enr_key: CombinedKey,
     network_config: NetworkConfig,
     peer_db: Arc<RwLock<PeerDB>>,
+    peer_db_path: PathBuf,
     lh_beacon_chain: Arc<beacon_chain::BeaconChain<T>>,
-) -> BehaviourComposer<AppReqId> {
-    let mut discovery =
-        crate::discovery::behaviour::Behaviour::new(enr, enr_key, &network_config.boot_enr).await;
+    runtime_config: &RuntimeConfig,
+) -> Result<BehaviourComposer<AppReqId>, NetworkError> {
+    // Make sure both servers can bind their ports before starting them, as the errors from
+    // discv5 and libp2p are opaque.
+    check_listen_ports(
+        network_config.listen_address,
+        network_config.libp2p_port,
+        network_config.discovery_port,
+    )
+    .map_err(NetworkError::ListenPort)?;
+
+    let peer_filter = Arc::new(network_config.peer_filter);
+
+    let mut discovery = crate::discovery::behaviour::Behaviour::new(
+        enr,
+        enr_key,
+        &network_config.boot_enr,
+        runtime_config.max_concurrent_discovery_queries,
+        peer_db.clone(),
+        runtime_config.min_score_to_dial,
+        peer_filter.clone(),
+        runtime_config.peer_memory_soft_limit,
+        network_config.listen_address,
+        network_config.discovery_port,
+    )
+    .await
+    .map_err(NetworkError::Discovery)?;
     // start searching for peers
-    discovery.discover_peers();
+    discovery.discover_peers_with_jitter(runtime_config.max_discovery_startup_jitter);
 
     let fork_context = Arc::new(ForkContext::new::<MainnetEthSpec>(
         lh_beacon_chain.slot().expect("slot"),
@@ -51,9 +109,104 @@ pub(crate) async fn build_network_behaviour<T: BeaconChainTypes, AppReqId: ReqId
         &lh_beacon_chain.spec,
     ));
 
-    BehaviourComposer::new(
+    let epoch_timing = if runtime_config.status_on_epoch_boundary {
+        let slots_per_epoch = MainnetEthSpec::slots_per_epoch();
+        lh_beacon_chain
+            .slot_clock
+            .duration_to_next_epoch(slots_per_epoch)
+            .map(|duration_to_next_epoch| EpochTiming {
+                duration_to_next_epoch,
+                epoch_duration: Duration::from_secs(
+                    lh_beacon_chain.spec.seconds_per_slot * slots_per_epoch,
+                ),
+            })
+    } else {
+        None
+    };
+
+    Ok(BehaviourComposer::new(
         discovery,
-        crate::peer_manager::PeerManager::new(TARGET_PEERS_COUNT, peer_db),
-        crate::rpc::behaviour::Behaviour::new(fork_context),
-    )
+        crate::peer_manager::PeerManager::new(
+            runtime_config.target_peers,
+            runtime_config.min_score_to_dial,
+            peer_db,
+            epoch_timing,
+            IpLimits {
+                max_peers_per_ip: runtime_config.max_peers_per_ip,
+                max_peers_per_subnet: runtime_config.max_peers_per_subnet,
+            },
+            ConnectionLimits {
+                max_peers: runtime_config.max_peers(),
+                max_inbound_peers: runtime_config.max_peers(),
+                max_outbound_peers: runtime_config.target_peers,
+            },
+            peer_filter,
+            Some(peer_db_path),
+        ),
+        crate::rpc::behaviour::Behaviour::new(
+            fork_context,
+            runtime_config.max_buffered_response_bytes,
+            runtime_config.rpc_config(),
+        ),
+        // Once AutoNAT confirms our external address, the discovery behaviour updates the local
+        // ENR with it.
+        libp2p::autonat::Behaviour::new(local_peer_id, libp2p::autonat::Config::default()),
+    ))
+}
+
+// Checks that the TCP port for libp2p and the UDP port for discv5 are bindable.
+fn check_listen_ports(address: IpAddr, tcp_port: u16, udp_port: u16) -> Result<(), String> {
+    TcpListener::bind((address, tcp_port)).map_err(|e| {
+        format!(
+            "Failed to bind TCP port {} on {} for libp2p. Is another process using the port? error: {}",
+            tcp_port, address, e
+        )
+    })?;
+
+    UdpSocket::bind((address, udp_port)).map_err(|e| {
+        format!(
+            "Failed to bind UDP port {} on {} for discv5. Is another process using the port? error: {}",
+            udp_port, address, e
+        )
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::core::upgrade::{NegotiationError, UpgradeError};
+
+    fn transport_error<E>(error: E) -> TransportError<io::Error>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        TransportError::Other(io::Error::other(error))
+    }
+
+    #[test]
+    fn detects_muxer_negotiation_failure() {
+        // The peer supports none of the muxers we offer.
+        let error = transport_error(UpgradeError::<io::Error>::Select(NegotiationError::Failed));
+        assert_eq!(
+            upgrade_failure(&error),
+            Some("security/muxer negotiation failed (the peer may not support Noise/yamux)")
+        );
+    }
+
+    #[test]
+    fn detects_noise_handshake_failure() {
+        let error = transport_error(noise::Error::BadSignature);
+        assert_eq!(
+            upgrade_failure(&error),
+            Some("Noise security handshake failed")
+        );
+    }
+
+    #[test]
+    fn ignores_other_transport_errors() {
+        let error = TransportError::Other(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert_eq!(upgrade_failure(&error), None);
+    }
 }
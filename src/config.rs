@@ -1,7 +1,16 @@
+use crate::peer_manager::PeerFilter;
+use crate::rpc::RpcConfig;
 use discv5::Enr;
+use libp2p::PeerId;
+use sensitive_url::SensitiveUrl;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
 use std::fs::File;
 use std::io::{BufReader, Read};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 use tracing::info;
 use types::Config;
 
@@ -11,10 +20,21 @@ pub(crate) struct NetworkConfig {
     pub(crate) config: Config,
     pub(crate) genesis_state_bytes: Vec<u8>,
     pub(crate) boot_enr: Vec<Enr>,
+    pub(crate) peer_filter: PeerFilter,
+    // The address both libp2p and discv5 listen on.
+    pub(crate) listen_address: IpAddr,
+    // The TCP port libp2p listens on.
+    pub(crate) libp2p_port: u16,
+    // The UDP port discv5 listens on.
+    pub(crate) discovery_port: u16,
 }
 
 impl NetworkConfig {
-    pub(crate) fn new() -> Result<Self, String> {
+    pub(crate) fn new(
+        listen_address: IpAddr,
+        libp2p_port: u16,
+        discovery_port: u16,
+    ) -> Result<Self, String> {
         let network_config_dir = env!("CARGO_MANIFEST_DIR")
             .parse::<PathBuf>()
             .map_err(|e| format!("should parse manifest dir as path: {}", e))?
@@ -24,6 +44,14 @@ impl NetworkConfig {
             config: load_config(&network_config_dir)?,
             genesis_state_bytes: load_genesis_state(&network_config_dir)?,
             boot_enr: load_boot_enr(&network_config_dir)?,
+            peer_filter: PeerFilter {
+                allowlist: load_peer_list(&network_config_dir, "peer_allowlist.yaml")?,
+                denylist: load_peer_list(&network_config_dir, "peer_denylist.yaml")?
+                    .unwrap_or_default(),
+            },
+            listen_address,
+            libp2p_port,
+            discovery_port,
         })
     }
 
@@ -40,6 +68,308 @@ impl NetworkConfig {
     // }
 }
 
+// The tunables of the node, so that it can be tuned without rebuilding. Each is taken from, in
+// order of precedence, the environment variable `RAY_<KEY>` (e.g. `RAY_TARGET_PEERS`), the runtime
+// config file, and the default in `main.rs`.
+#[derive(Debug, Clone)]
+pub(crate) struct RuntimeConfig {
+    // Target number of peers to connect to.
+    pub(crate) target_peers: usize,
+    // Number of recent blocks kept in memory for serving `BlocksByRoot`.
+    pub(crate) block_cache_size: usize,
+    // The maximum number of discovery queries running at the same time.
+    pub(crate) max_concurrent_discovery_queries: usize,
+    // The first discovery query is delayed randomly up to this.
+    pub(crate) max_discovery_startup_jitter: Duration,
+    // Discovered peers with a score below this are not dialed.
+    pub(crate) min_score_to_dial: f64,
+    // Whether to STATUS all peers shortly after each epoch boundary.
+    pub(crate) status_on_epoch_boundary: bool,
+    // The maximum number of finalized chains tracked by sync.
+    pub(crate) max_finalized_chains: usize,
+    // The number of threads verifying the signatures of the blocks downloaded by sync.
+    pub(crate) signature_verification_threads: usize,
+    // The maximum number of requests sync sends to a peer per second.
+    pub(crate) max_outbound_requests_per_peer: usize,
+    // The maximum number of requests sync awaits responses to, across all the peers.
+    pub(crate) max_outbound_requests_in_flight: usize,
+    // The maximum number of low-priority operations queued for the sync manager.
+    pub(crate) sync_channel_capacity: usize,
+    // The maximum number of messages queued for the Network service.
+    pub(crate) network_channel_capacity: usize,
+    // The maximum bytes of responses buffered for sending to a peer.
+    pub(crate) max_buffered_response_bytes: usize,
+    // The soft limit of the estimated memory used by the cached ENRs and the PeerDB together.
+    pub(crate) peer_memory_soft_limit: usize,
+    // The maximum number of peers connected from the same IP address.
+    pub(crate) max_peers_per_ip: usize,
+    // The maximum number of peers connected from the same /24 subnet.
+    pub(crate) max_peers_per_subnet: usize,
+    // Connections with no active protocols are closed after this period.
+    pub(crate) idle_connection_timeout: Duration,
+    // The clock disagreement tolerated when checking the head slot of a peer.
+    pub(crate) max_clock_disparity: Duration,
+    // The time to wait for a whole RPC request, and to send ours.
+    pub(crate) rpc_request_timeout: Duration,
+    // The time to wait for the next bytes while reading an RPC request.
+    pub(crate) rpc_read_timeout: Duration,
+}
+
+// The keys of the tunables in the runtime config file. The environment variables are the
+// uppercased keys prefixed with `RAY_`.
+const RUNTIME_CONFIG_KEYS: [&str; 20] = [
+    "target_peers",
+    "block_cache_size",
+    "max_concurrent_discovery_queries",
+    "max_discovery_startup_jitter_millis",
+    "min_score_to_dial",
+    "status_on_epoch_boundary",
+    "max_finalized_chains",
+    "signature_verification_threads",
+    "max_outbound_requests_per_peer",
+    "max_outbound_requests_in_flight",
+    "sync_channel_capacity",
+    "network_channel_capacity",
+    "max_buffered_response_bytes",
+    "peer_memory_soft_limit",
+    "max_peers_per_ip",
+    "max_peers_per_subnet",
+    "idle_connection_timeout_secs",
+    "max_clock_disparity_millis",
+    "rpc_request_timeout_secs",
+    "rpc_read_timeout_secs",
+];
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            target_peers: crate::TARGET_PEERS_COUNT,
+            block_cache_size: crate::BLOCK_CACHE_SIZE,
+            max_concurrent_discovery_queries: crate::MAX_CONCURRENT_DISCOVERY_QUERIES,
+            max_discovery_startup_jitter: crate::MAX_DISCOVERY_STARTUP_JITTER,
+            min_score_to_dial: crate::MIN_SCORE_TO_DIAL,
+            status_on_epoch_boundary: crate::STATUS_ON_EPOCH_BOUNDARY,
+            max_finalized_chains: crate::MAX_FINALIZED_CHAINS,
+            signature_verification_threads: crate::SIGNATURE_VERIFICATION_THREADS,
+            max_outbound_requests_per_peer: crate::MAX_OUTBOUND_REQUESTS_PER_PEER,
+            max_outbound_requests_in_flight: crate::MAX_OUTBOUND_REQUESTS_IN_FLIGHT,
+            sync_channel_capacity: crate::SYNC_CHANNEL_CAPACITY,
+            network_channel_capacity: crate::NETWORK_CHANNEL_CAPACITY,
+            max_buffered_response_bytes: crate::MAX_BUFFERED_RESPONSE_BYTES,
+            peer_memory_soft_limit: crate::PEER_MEMORY_SOFT_LIMIT,
+            max_peers_per_ip: crate::MAX_PEERS_PER_IP,
+            max_peers_per_subnet: crate::MAX_PEERS_PER_SUBNET,
+            idle_connection_timeout: crate::IDLE_CONNECTION_TIMEOUT,
+            max_clock_disparity: crate::MAX_CLOCK_DISPARITY,
+            rpc_request_timeout: crate::RPC_REQUEST_TIMEOUT,
+            rpc_read_timeout: crate::RPC_READ_TIMEOUT,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    // Loads the tunables, overriding the defaults with the config file at `path` if it exists, and
+    // then with the environment variables looked up by `env`.
+    pub(crate) fn load(path: &Path, env: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut config = RuntimeConfig::default();
+
+        if path.exists() {
+            info!("Loading runtime config from {}", path.display());
+            let values: HashMap<String, serde_yaml::Value> = File::open(path)
+                .map_err(|e| format!("Unable to open {}: {:?}", path.display(), e))
+                .and_then(|file| {
+                    serde_yaml::from_reader(file)
+                        .map_err(|e| format!("Unable to parse {}: {:?}", path.display(), e))
+                })?;
+            for (key, value) in values {
+                let value = match value {
+                    serde_yaml::Value::Bool(b) => b.to_string(),
+                    serde_yaml::Value::Number(n) => n.to_string(),
+                    serde_yaml::Value::String(s) => s,
+                    other => return Err(format!("Invalid value of {}: {:?}", key, other)),
+                };
+                config
+                    .set(&key, &value)
+                    .map_err(|e| format!("{} in {}", e, path.display()))?;
+            }
+        }
+
+        for key in RUNTIME_CONFIG_KEYS {
+            let var = format!("RAY_{}", key.to_uppercase());
+            if let Some(value) = env(&var) {
+                config
+                    .set(key, &value)
+                    .map_err(|e| format!("{} in {}", e, var))?;
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Rejects the values the node can't run with, e.g. a cache or a limit of zero.
+    fn validate(&self) -> Result<(), String> {
+        let counts = [
+            ("target_peers", self.target_peers),
+            ("block_cache_size", self.block_cache_size),
+            (
+                "max_concurrent_discovery_queries",
+                self.max_concurrent_discovery_queries,
+            ),
+            ("max_finalized_chains", self.max_finalized_chains),
+            (
+                "signature_verification_threads",
+                self.signature_verification_threads,
+            ),
+            (
+                "max_outbound_requests_per_peer",
+                self.max_outbound_requests_per_peer,
+            ),
+            (
+                "max_outbound_requests_in_flight",
+                self.max_outbound_requests_in_flight,
+            ),
+            ("sync_channel_capacity", self.sync_channel_capacity),
+            ("network_channel_capacity", self.network_channel_capacity),
+            (
+                "max_buffered_response_bytes",
+                self.max_buffered_response_bytes,
+            ),
+            ("peer_memory_soft_limit", self.peer_memory_soft_limit),
+            ("max_peers_per_ip", self.max_peers_per_ip),
+            ("max_peers_per_subnet", self.max_peers_per_subnet),
+        ];
+        let durations = [
+            ("idle_connection_timeout_secs", self.idle_connection_timeout),
+            ("rpc_request_timeout_secs", self.rpc_request_timeout),
+            ("rpc_read_timeout_secs", self.rpc_read_timeout),
+        ];
+
+        for (key, count) in counts {
+            if count == 0 {
+                return Err(format!("Invalid value of {}: 0. It must be positive.", key));
+            }
+        }
+        for (key, duration) in durations {
+            if duration.is_zero() {
+                return Err(format!("Invalid value of {}: 0. It must be positive.", key));
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "target_peers" => self.target_peers = parse(key, value)?,
+            "block_cache_size" => self.block_cache_size = parse(key, value)?,
+            "max_concurrent_discovery_queries" => {
+                self.max_concurrent_discovery_queries = parse(key, value)?
+            }
+            "max_discovery_startup_jitter_millis" => {
+                self.max_discovery_startup_jitter = Duration::from_millis(parse(key, value)?)
+            }
+            "min_score_to_dial" => self.min_score_to_dial = parse(key, value)?,
+            "status_on_epoch_boundary" => self.status_on_epoch_boundary = parse(key, value)?,
+            "max_finalized_chains" => self.max_finalized_chains = parse(key, value)?,
+            "signature_verification_threads" => {
+                self.signature_verification_threads = parse(key, value)?
+            }
+            "max_outbound_requests_per_peer" => {
+                self.max_outbound_requests_per_peer = parse(key, value)?
+            }
+            "max_outbound_requests_in_flight" => {
+                self.max_outbound_requests_in_flight = parse(key, value)?
+            }
+            "sync_channel_capacity" => self.sync_channel_capacity = parse(key, value)?,
+            "network_channel_capacity" => self.network_channel_capacity = parse(key, value)?,
+            "max_buffered_response_bytes" => self.max_buffered_response_bytes = parse(key, value)?,
+            "peer_memory_soft_limit" => self.peer_memory_soft_limit = parse(key, value)?,
+            "max_peers_per_ip" => self.max_peers_per_ip = parse(key, value)?,
+            "max_peers_per_subnet" => self.max_peers_per_subnet = parse(key, value)?,
+            "idle_connection_timeout_secs" => {
+                self.idle_connection_timeout = Duration::from_secs(parse(key, value)?)
+            }
+            "max_clock_disparity_millis" => {
+                self.max_clock_disparity = Duration::from_millis(parse(key, value)?)
+            }
+            "rpc_request_timeout_secs" => {
+                self.rpc_request_timeout = Duration::from_secs(parse(key, value)?)
+            }
+            "rpc_read_timeout_secs" => {
+                self.rpc_read_timeout = Duration::from_secs(parse(key, value)?)
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown runtime config: {}. Supported: {:?}",
+                    key, RUNTIME_CONFIG_KEYS
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // The maximum number of peers connected. Inbound connections beyond this are denied.
+    pub(crate) fn max_peers(&self) -> usize {
+        self.target_peers * 11 / 10
+    }
+
+    pub(crate) fn rpc_config(&self) -> RpcConfig {
+        RpcConfig {
+            request_timeout: self.rpc_request_timeout,
+            read_timeout: self.rpc_read_timeout,
+        }
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("Invalid value of {}: {}. {}", key, value, e))
+}
+
+// The checkpoint sync endpoint used for a network, unless `--checkpoint-sync-url` is given.
+// https://eth-clients.github.io/checkpoint-sync-endpoints/
+pub(crate) fn default_checkpoint_sync_url(network: &str) -> Option<&'static str> {
+    match network {
+        "mainnet" => Some("https://mainnet.checkpoint.sigp.io"),
+        "prater" => Some("http://unstable.prater.beacon-api.nimbus.team"),
+        "holesky" => Some("https://checkpoint-sync.holesky.ethpandaops.io"),
+        "sepolia" => Some("https://checkpoint-sync.sepolia.ethpandaops.io"),
+        _ => None,
+    }
+}
+
+// The time to wait for the eth1 endpoint to accept a connection at startup.
+const ETH1_ENDPOINT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Parses the eth1 endpoint and checks that it accepts connections, so that a typo is reported at
+// startup rather than as failing deposit updates later on.
+pub(crate) fn eth1_endpoint(endpoint: &str) -> Result<SensitiveUrl, String> {
+    let url = SensitiveUrl::parse(endpoint)
+        .map_err(|e| format!("Unable to parse {}: {:?}", endpoint, e))?;
+
+    let host = url
+        .full
+        .host_str()
+        .ok_or_else(|| format!("No host in {}", url))?;
+    let port = url
+        .full
+        .port_or_known_default()
+        .ok_or_else(|| format!("No port in {}", url))?;
+    let address = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Unable to resolve {}: {}", url, e))?
+        .next()
+        .ok_or_else(|| format!("Unable to resolve {}", url))?;
+    TcpStream::connect_timeout(&address, ETH1_ENDPOINT_CONNECT_TIMEOUT)
+        .map_err(|e| format!("Unable to connect to {}: {}", url, e))?;
+
+    Ok(url)
+}
+
 fn load_config(network_config_dir: &Path) -> Result<Config, String> {
     let path = network_config_dir.join("config.yaml");
     info!("Loading network config from {}", path.display());
@@ -75,3 +405,64 @@ fn load_boot_enr(network_config_dir: &Path) -> Result<Vec<Enr>, String> {
             serde_yaml::from_reader(file).map_err(|e| format!("Unable to parse boot enr: {}", e))
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_overrides_the_file_which_overrides_the_defaults() {
+        let path =
+            std::env::temp_dir().join(format!("ray-test-runtime-config-{}.yaml", PeerId::random()));
+        std::fs::write(&path, "block_cache_size: 8\nmax_peers_per_ip: 3\n").unwrap();
+        let env = HashMap::from([("RAY_MAX_PEERS_PER_IP".to_string(), "4".to_string())]);
+
+        let config = RuntimeConfig::load(&path, |var| env.get(var).cloned());
+        std::fs::remove_file(&path).unwrap();
+
+        let config = config.expect("runtime config");
+        assert_eq!(config.target_peers, crate::TARGET_PEERS_COUNT);
+        assert_eq!(config.block_cache_size, 8);
+        assert_eq!(config.max_peers_per_ip, 4);
+    }
+
+    #[test]
+    fn zero_values_are_rejected_on_load() {
+        let path =
+            std::env::temp_dir().join(format!("ray-test-runtime-config-{}.yaml", PeerId::random()));
+        std::fs::write(&path, "max_outbound_requests_in_flight: 0\n").unwrap();
+
+        let config = RuntimeConfig::load(&path, |_| None);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(config.is_err());
+
+        for var in [
+            "RAY_MAX_BUFFERED_RESPONSE_BYTES",
+            "RAY_PEER_MEMORY_SOFT_LIMIT",
+        ] {
+            let config = RuntimeConfig::load(&path, |v| (v == var).then(|| "0".to_string()));
+            assert!(config.is_err(), "{var}");
+        }
+    }
+
+    #[test]
+    fn the_last_value_set_wins() {
+        let mut config = RuntimeConfig::default();
+        config.set("block_cache_size", "8").unwrap();
+        config.set("block_cache_size", "16").unwrap();
+        config.set("rpc_read_timeout_secs", "3").unwrap();
+
+        assert_eq!(config.block_cache_size, 16);
+        assert_eq!(config.rpc_read_timeout, Duration::from_secs(3));
+        assert!(config.validate().is_ok());
+
+        // An invalid or unknown value leaves the config as is.
+        assert!(config.set("block_cache_size", "-1").is_err());
+        assert!(config.set("unknown_key", "1").is_err());
+        assert_eq!(config.block_cache_size, 16);
+
+        config.set("block_cache_size", "0").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
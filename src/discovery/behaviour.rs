@@ -1,30 +1,45 @@
-use crate::discovery::enr::Eth2Enr;
+use crate::discovery::enr::{Eth2Enr, ETH2_ENR_KEY};
 use crate::discovery::DiscoveryEvent;
+use crate::peer_manager::PeerFilter;
 use crate::types::Enr;
+use crate::PeerDB;
 use discv5::enr::{CombinedKey, NodeId};
 use discv5::{ConfigBuilder, Discv5, ListenConfig, QueryError};
 use futures::stream::FuturesUnordered;
 use futures::{Future, FutureExt, StreamExt};
 use libp2p::core::Endpoint;
+use libp2p::multiaddr::Protocol;
 use libp2p::swarm::dummy::ConnectionHandler as DummyConnectionHandler;
 use libp2p::swarm::{
-    ConnectionDenied, ConnectionId, DialError, DialFailure, FromSwarm, NetworkBehaviour, THandler,
-    THandlerInEvent, THandlerOutEvent, ToSwarm,
+    ConnectionDenied, ConnectionId, DialError, DialFailure, ExternalAddrConfirmed, FromSwarm,
+    NetworkBehaviour, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
 };
 use libp2p::{Multiaddr, PeerId};
 use lru::LruCache;
-use std::net::Ipv4Addr;
+use parking_lot::RwLock;
+use rand::Rng;
+use ssz::Encode;
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroUsize;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Receiver;
 use tracing::{debug, error, info, trace, warn};
+use types::{EnrForkId, SubnetId};
 
 // The number of closest peers to search for when doing a regular peer search.
 // We could reduce this constant to speed up queries however at the cost of security. It will
 // make it easier to peers to eclipse this node. Kademlia suggests a value of 16.
 const FIND_NODE_QUERY_CLOSEST_PEERS: usize = 16;
 
+// A summary of the discovery queries is logged every this number of queries.
+const QUERY_SUMMARY_INTERVAL: usize = 10;
+
+// After this number of consecutive discovery queries yielding no results, the routing table is
+// considered depleted and is re-seeded from the boot ENRs.
+const MAX_CONSECUTIVE_EMPTY_QUERIES: usize = 3;
+
 // ////////////////////////////////////////////////////////
 // Internal message of Discovery module
 // ////////////////////////////////////////////////////////
@@ -32,6 +47,25 @@ const FIND_NODE_QUERY_CLOSEST_PEERS: usize = 16;
 // The result of a query.
 struct QueryResult {
     result: Result<Vec<Enr>, discv5::QueryError>,
+    // When the query started.
+    started: Instant,
+}
+
+// The result of a query for peers on specific attestation subnets.
+struct SubnetQueryResult {
+    subnets: Vec<SubnetId>,
+    result: Result<Vec<Enr>, discv5::QueryError>,
+    // When the query started.
+    started: Instant,
+}
+
+// Running totals of the discovery queries, for the periodic summary.
+#[derive(Default)]
+struct QueryStats {
+    queries: usize,
+    duration: Duration,
+    found_peers: usize,
+    usable_peers: usize,
 }
 
 // ////////////////////////////////////////////////////////
@@ -43,8 +77,32 @@ pub(crate) struct Behaviour {
     event_stream: Receiver<discv5::Event>,
     // Active discovery queries.
     active_queries: FuturesUnordered<std::pin::Pin<Box<dyn Future<Output = QueryResult> + Send>>>,
+    // Active discovery queries for peers on specific attestation subnets.
+    subnet_queries:
+        FuturesUnordered<std::pin::Pin<Box<dyn Future<Output = SubnetQueryResult> + Send>>>,
     // A collection of seen live ENRs for quick lookup and to map peer-id's to ENRs.
     cached_enrs: LruCache<PeerId, Enr>,
+    // The maximum number of discovery queries running at the same time.
+    max_concurrent_queries: usize,
+    peer_db: Arc<RwLock<PeerDB>>,
+    // Discovered peers whose score is below this are neither cached nor dialed.
+    min_score_to_dial: f64,
+    // Discovered peers rejected by this are neither cached nor dialed.
+    peer_filter: Arc<PeerFilter>,
+    query_stats: QueryStats,
+    // The boot ENRs, kept to re-seed the routing table.
+    boot_enr: Vec<Enr>,
+    // The number of consecutive discovery queries that yielded no results.
+    consecutive_empty_queries: usize,
+    // The soft limit of the estimated memory used by `cached_enrs` and the PeerDB together. Once
+    // exceeded, they are pruned.
+    peer_memory_soft_limit: usize,
+    // Fires when to start the first query, which is delayed by a random jitter so that nodes
+    // started together don't query in a burst. No query is started until then.
+    startup_delay: Option<std::pin::Pin<Box<tokio::time::Sleep>>>,
+    // Whether AutoNAT has found us publicly reachable. Only then is a confirmed external address
+    // advertised in the local ENR.
+    publicly_reachable: bool,
 }
 
 impl Behaviour {
@@ -52,14 +110,21 @@ impl Behaviour {
         local_enr: Enr,
         local_enr_key: CombinedKey,
         boot_enr: &Vec<Enr>,
-    ) -> Self {
-        let config =
-            ConfigBuilder::new(ListenConfig::default().with_ipv4(Ipv4Addr::UNSPECIFIED, 9000))
-                // For ease to observe the `discv5::Event::SocketUpdated` event, set a short duration here.
-                .ping_interval(Duration::from_secs(10))
-                .build();
+        max_concurrent_queries: usize,
+        peer_db: Arc<RwLock<PeerDB>>,
+        min_score_to_dial: f64,
+        peer_filter: Arc<PeerFilter>,
+        peer_memory_soft_limit: usize,
+        listen_address: IpAddr,
+        listen_port: u16,
+    ) -> Result<Self, String> {
+        let config = ConfigBuilder::new(ListenConfig::from_ip(listen_address, listen_port))
+            // For ease to observe the `discv5::Event::SocketUpdated` event, set a short duration here.
+            .ping_interval(Duration::from_secs(10))
+            .build();
         // construct the discv5 server
-        let mut discv5 = Discv5::new(local_enr, local_enr_key, config).unwrap();
+        let mut discv5 = Discv5::new(local_enr, local_enr_key, config)
+            .map_err(|e| format!("Failed to create the discv5 server: {}", e))?;
 
         for enr in boot_enr {
             info!("Boot ENR: {}", enr);
@@ -69,30 +134,128 @@ impl Behaviour {
         }
 
         // start the discv5 server
-        // TODO: error handling
         // SEE https://github.com/sigp/lighthouse/blob/73ec29c267f057e70e89856403060c4c35b5c0c8/beacon_node/eth2_libp2p/src/discovery/mod.rs#L235-L238
-        discv5.start().await.unwrap();
+        discv5
+            .start()
+            .await
+            .map_err(|e| format!("Failed to start the discv5 server: {:?}", e))?;
         info!(
             "Started Discovery v5 server. local_enr: {}",
             discv5.local_enr()
         );
 
-        // TODO: error handling
-        let event_stream = discv5.event_stream().await.unwrap();
+        let event_stream = discv5
+            .event_stream()
+            .await
+            .map_err(|e| format!("Failed to obtain the discv5 event stream: {:?}", e))?;
 
-        Behaviour {
+        Ok(Behaviour {
             discv5,
             event_stream,
             active_queries: FuturesUnordered::new(),
+            subnet_queries: FuturesUnordered::new(),
             cached_enrs: LruCache::new(NonZeroUsize::new(50).expect("non zero usize")),
+            max_concurrent_queries,
+            peer_db,
+            min_score_to_dial,
+            peer_filter,
+            query_stats: QueryStats::default(),
+            boot_enr: boot_enr.clone(),
+            consecutive_empty_queries: 0,
+            peer_memory_soft_limit,
+            startup_delay: None,
+            publicly_reachable: false,
+        })
+    }
+
+    // Re-adds the boot ENRs to the routing table, which may have been evicted as unresponsive.
+    fn reseed_from_boot_enr(&mut self) {
+        info!(
+            "Re-seeding the routing table from {} boot ENRs.",
+            self.boot_enr.len()
+        );
+        for enr in &self.boot_enr {
+            if let Err(e) = self.discv5.add_enr(enr.clone()) {
+                warn!("Failed to add Boot ENR: {:?}", e);
+            }
         }
     }
 
+    // A discovery query has yielded no results. After consecutive ones, the routing table is
+    // considered depleted, so it's re-seeded from the boot ENRs and a new query is started.
+    // Returns whether the routing table has been re-seeded.
+    fn on_empty_query(&mut self) -> bool {
+        self.consecutive_empty_queries += 1;
+        if self.consecutive_empty_queries < MAX_CONSECUTIVE_EMPTY_QUERIES {
+            return false;
+        }
+
+        warn!(
+            "{} consecutive discovery queries yielded no results.",
+            self.consecutive_empty_queries
+        );
+        self.consecutive_empty_queries = 0;
+        self.reseed_from_boot_enr();
+        self.discover_peers();
+        true
+    }
+
+    // Records the reachability found by AutoNAT.
+    pub(crate) fn set_publicly_reachable(&mut self, publicly_reachable: bool) {
+        self.publicly_reachable = publicly_reachable;
+    }
+
     pub(crate) fn has_active_queries(&self) -> bool {
-        !self.active_queries.is_empty()
+        !self.active_queries.is_empty() || !self.subnet_queries.is_empty()
+    }
+
+    // Updates the `eth2` field of the local ENR, e.g. on a new fork. The sequence number is bumped,
+    // so that peers replace our record with the new one.
+    pub(crate) fn update_eth2_enr(&mut self, enr_fork_id: EnrForkId) {
+        if let Err(e) = self
+            .discv5
+            .enr_insert(ETH2_ENR_KEY, &enr_fork_id.as_ssz_bytes())
+        {
+            error!("Failed to update the eth2 field of the local ENR. {:?}", e);
+            return;
+        }
+        info!(
+            "Updated the eth2 field of the local ENR. local_enr: {}",
+            self.discv5.local_enr()
+        );
+    }
+
+    pub(crate) fn local_enr(&self) -> Enr {
+        self.discv5.local_enr()
+    }
+
+    // The number of queries running, including the subnet ones.
+    fn running_queries(&self) -> usize {
+        self.active_queries.len() + self.subnet_queries.len()
+    }
+
+    // Starts the first query after a random delay of up to `max_jitter`.
+    pub(crate) fn discover_peers_with_jitter(&mut self, max_jitter: Duration) {
+        let jitter =
+            Duration::from_millis(rand::thread_rng().gen_range(0..=max_jitter.as_millis() as u64));
+        info!("Starting discovery in {:?}.", jitter);
+        self.startup_delay = Some(Box::pin(tokio::time::sleep(jitter)));
     }
 
     pub(crate) fn discover_peers(&mut self) {
+        if self.startup_delay.is_some() {
+            trace!("Skipped starting a discovery query as discovery hasn't started yet.");
+            return;
+        }
+
+        if self.running_queries() >= self.max_concurrent_queries {
+            debug!(
+                "Skipped starting a discovery query as the number of active queries reached the limit: {}",
+                self.max_concurrent_queries
+            );
+            return;
+        }
+
         let target_node = NodeId::random();
         let local_enr_fork_id = match self.discv5.local_enr().eth2() {
             Ok(enr_fork_id) => enr_fork_id,
@@ -104,10 +267,10 @@ impl Behaviour {
 
         // predicate for finding nodes with a matching fork and valid tcp port
         let predicate = move |enr: &Enr| {
-            enr.eth2().map(|enr_fork_id| enr_fork_id.fork_digest)
-                == Ok(local_enr_fork_id.fork_digest)
+            has_fork_digest(enr, local_enr_fork_id.fork_digest)
                 && (enr.tcp4().is_some() || enr.tcp6().is_some())
         };
+        let started = Instant::now();
         let query_future = self
             .discv5
             .find_node_predicate(
@@ -115,7 +278,7 @@ impl Behaviour {
                 Box::new(predicate),
                 FIND_NODE_QUERY_CLOSEST_PEERS,
             )
-            .map(|result: Result<Vec<Enr>, QueryError>| QueryResult { result });
+            .map(move |result: Result<Vec<Enr>, QueryError>| QueryResult { result, started });
 
         info!(
             "Active query for discovery: target_node(random) -> {}",
@@ -124,6 +287,197 @@ impl Behaviour {
         self.active_queries.push(Box::pin(query_future));
     }
 
+    // Searches for peers subscribed to any of the attestation subnets.
+    // Ref: https://github.com/sigp/lighthouse/blob/stable/beacon_node/lighthouse_network/src/discovery/mod.rs
+    pub(crate) fn discover_subnet_peers(&mut self, subnets: Vec<SubnetId>) {
+        if subnets.is_empty() {
+            return;
+        }
+
+        if self.running_queries() >= self.max_concurrent_queries {
+            debug!(
+                "Skipped starting a subnet discovery query as the number of active queries reached the limit: {}",
+                self.max_concurrent_queries
+            );
+            return;
+        }
+
+        let target_node = NodeId::random();
+        let local_enr_fork_id = match self.discv5.local_enr().eth2() {
+            Ok(enr_fork_id) => enr_fork_id,
+            Err(e) => {
+                error!("Local ENR has no EnrForkId: {e}");
+                return;
+            }
+        };
+
+        // predicate for finding nodes with a matching fork, valid tcp port and any of the subnets
+        let subnets_to_match = subnets.clone();
+        let predicate = move |enr: &Enr| {
+            has_fork_digest(enr, local_enr_fork_id.fork_digest)
+                && (enr.tcp4().is_some() || enr.tcp6().is_some())
+                && enr.attestation_bitfield().map_or(false, |attnets| {
+                    subnets_to_match
+                        .iter()
+                        .any(|subnet| attnets.get(**subnet as usize).unwrap_or(false))
+                })
+        };
+        let started = Instant::now();
+        let query_future = self
+            .discv5
+            .find_node_predicate(
+                target_node,
+                Box::new(predicate),
+                FIND_NODE_QUERY_CLOSEST_PEERS,
+            )
+            .map(
+                move |result: Result<Vec<Enr>, QueryError>| SubnetQueryResult {
+                    subnets,
+                    result,
+                    started,
+                },
+            );
+
+        info!(
+            "Active subnet query for discovery: target_node(random) -> {}",
+            target_node
+        );
+        self.subnet_queries.push(Box::pin(query_future));
+    }
+
+    // Filters out the discovered peers that aren't worth dialing, and caches the ENRs of the rest.
+    fn usable_peers(&mut self, enrs: Vec<Enr>) -> Vec<PeerId> {
+        // The query predicate has filtered the results by the fork digest at the time the query
+        // started, but our fork digest may have changed since then, e.g. at a fork boundary.
+        let local_fork_digest = match self.discv5.local_enr().eth2() {
+            Ok(enr_fork_id) => enr_fork_id.fork_digest,
+            Err(e) => {
+                error!("Local ENR has no EnrForkId: {e}");
+                return vec![];
+            }
+        };
+
+        let mut peers = vec![];
+        for enr in enrs {
+            let peer_id = crate::identity::enr_to_peer_id(&enr);
+
+            if !has_fork_digest(&enr, local_fork_digest) {
+                debug!("[{peer_id}] Skipped a discovered peer on another fork.");
+                continue;
+            }
+
+            if let Err(e) = self.peer_filter.check(&peer_id) {
+                debug!("[{peer_id}] Skipped a discovered peer. {e}");
+                continue;
+            }
+
+            // Skip the known-bad peers
+            if let Some(score) = self.peer_db.read().score(&peer_id) {
+                if score < self.min_score_to_dial {
+                    debug!("[{peer_id}] Skipped a discovered peer due to its low score: {score}");
+                    continue;
+                }
+            }
+
+            // Skip the peers we can't dial. They are kept in the discv5 routing table though, as
+            // they are still useful for routing.
+            if crate::identity::enr_to_multiaddrs(&enr).is_empty() {
+                debug!(
+                    "[{peer_id}] Skipped a discovered peer as its ENR has no reachable address."
+                );
+                continue;
+            }
+
+            // Cache the found ENR
+            self.cached_enrs.put(peer_id, enr);
+            peers.push(peer_id);
+        }
+        self.enforce_peer_memory_limit();
+
+        peers
+    }
+
+    // The approximate memory used by an entry of `cached_enrs`.
+    fn cached_enr_size(enr: &Enr) -> usize {
+        std::mem::size_of::<PeerId>() + enr.size()
+    }
+
+    // Prunes the cached ENRs and the PeerDB if their estimated memory exceeds the soft limit. The
+    // oldest ENRs are evicted first as they are cheap to discover again, then the disconnected
+    // peers.
+    fn enforce_peer_memory_limit(&mut self) {
+        let mut enrs_memory = self
+            .cached_enrs
+            .iter()
+            .map(|(_peer_id, enr)| Self::cached_enr_size(enr))
+            .sum::<usize>();
+        let mut peer_db_memory = self.peer_db.read().estimated_memory();
+
+        if enrs_memory + peer_db_memory > self.peer_memory_soft_limit {
+            let before = enrs_memory + peer_db_memory;
+            let mut evicted_enrs = 0;
+            while enrs_memory + peer_db_memory > self.peer_memory_soft_limit {
+                let Some((_peer_id, enr)) = self.cached_enrs.pop_lru() else {
+                    break;
+                };
+                enrs_memory -= Self::cached_enr_size(&enr);
+                evicted_enrs += 1;
+            }
+
+            let mut pruned_peers = 0;
+            if enrs_memory + peer_db_memory > self.peer_memory_soft_limit {
+                let mut peer_db = self.peer_db.write();
+                pruned_peers = peer_db.prune_disconnected_to_size(
+                    self.peer_memory_soft_limit.saturating_sub(enrs_memory),
+                );
+                peer_db_memory = peer_db.estimated_memory();
+            }
+
+            warn!(
+                "The estimated peer memory exceeded the soft limit: {} bytes. before: {before} bytes, after: {} bytes, evicted_enrs: {evicted_enrs}, pruned_peers: {pruned_peers}",
+                self.peer_memory_soft_limit,
+                enrs_memory + peer_db_memory
+            );
+        }
+
+        crate::metrics::set_gauge(
+            &crate::metrics::PEER_MEMORY_BYTES,
+            (enrs_memory + peer_db_memory) as i64,
+        );
+    }
+
+    // Records the latency and the yield of a completed query.
+    fn record_query(&mut self, duration: Duration, found_peers: usize, usable_peers: usize) {
+        crate::metrics::observe(
+            &crate::metrics::DISCOVERY_QUERY_DURATION,
+            duration.as_secs_f64(),
+        );
+        crate::metrics::observe(
+            &crate::metrics::DISCOVERY_QUERY_FOUND_PEERS,
+            found_peers as f64,
+        );
+        crate::metrics::observe(
+            &crate::metrics::DISCOVERY_QUERY_USABLE_PEERS,
+            usable_peers as f64,
+        );
+
+        let stats = &mut self.query_stats;
+        stats.queries += 1;
+        stats.duration += duration;
+        stats.found_peers += found_peers;
+        stats.usable_peers += usable_peers;
+
+        if stats.queries % QUERY_SUMMARY_INTERVAL == 0 {
+            info!(
+                "Discovery summary: queries: {}, average duration: {:?}, found peers: {}, usable peers: {}",
+                stats.queries,
+                stats.duration / stats.queries as u32,
+                stats.found_peers,
+                stats.usable_peers
+            );
+        }
+    }
+
     fn on_dial_failure(&self, peer_id: Option<PeerId>, dial_error: &DialError) {
         if let Some(peer_id) = peer_id {
             match dial_error {
@@ -148,6 +502,48 @@ impl Behaviour {
             }
         }
     }
+
+    // Updates the IP and TCP port of the local ENR with our confirmed external address, so that
+    // peers can reach us (e.g. behind NAT). The address isn't advertised unless we're publicly
+    // reachable, as peers couldn't dial it anyway.
+    fn on_external_addr_confirmed(&self, addr: &Multiaddr) {
+        if !self.publicly_reachable {
+            debug!(
+                "Ignored the confirmed external address as we aren't publicly reachable: {addr}"
+            );
+            return;
+        }
+
+        let mut ip = None;
+        let mut tcp = None;
+        for protocol in addr.iter() {
+            match protocol {
+                Protocol::Ip4(ip4) => ip = Some(IpAddr::V4(ip4)),
+                Protocol::Ip6(ip6) => ip = Some(IpAddr::V6(ip6)),
+                Protocol::Tcp(port) => tcp = Some(port),
+                _ => {}
+            }
+        }
+
+        match (ip, tcp) {
+            (Some(ip), Some(port)) => {
+                if self
+                    .discv5
+                    .update_local_enr_socket(SocketAddr::new(ip, port), true)
+                {
+                    info!(
+                        "Updated the local ENR with the confirmed external address: {addr}. local_enr: {}",
+                        self.discv5.local_enr()
+                    );
+                }
+            }
+            _ => {
+                debug!(
+                    "Ignored the confirmed external address as it has no IP and TCP port: {addr}"
+                );
+            }
+        }
+    }
 }
 
 // ************************************************
@@ -229,6 +625,9 @@ impl NetworkBehaviour for Behaviour {
             }) => {
                 self.on_dial_failure(peer_id, error);
             }
+            FromSwarm::ExternalAddrConfirmed(ExternalAddrConfirmed { addr }) => {
+                self.on_external_addr_confirmed(addr);
+            }
             FromSwarm::ConnectionEstablished(_)
             | FromSwarm::ConnectionClosed(_)
             | FromSwarm::AddressChange(_)
@@ -239,8 +638,7 @@ impl NetworkBehaviour for Behaviour {
             | FromSwarm::ListenerError(_)
             | FromSwarm::ListenerClosed(_)
             | FromSwarm::NewExternalAddrCandidate(_)
-            | FromSwarm::ExternalAddrExpired(_)
-            | FromSwarm::ExternalAddrConfirmed(_) => {
+            | FromSwarm::ExternalAddrExpired(_) => {
                 // Ignore events not relevant to discovery
             }
             _ => todo!(),
@@ -264,35 +662,81 @@ impl NetworkBehaviour for Behaviour {
         trace!("poll");
 
         debug!("Active discovery query: {}", self.active_queries.len());
+        crate::metrics::set_gauge(
+            &crate::metrics::DISCOVERY_ACTIVE_QUERIES,
+            self.running_queries() as i64,
+        );
+
+        if let Some(startup_delay) = self.startup_delay.as_mut() {
+            if startup_delay.as_mut().poll(cx).is_ready() {
+                self.startup_delay = None;
+                self.discover_peers();
+            }
+        }
 
-        if let Poll::Ready(Some(query_result)) = self.active_queries.poll_next_unpin(cx) {
+        // The queries yielding nothing to report don't return, so that the subnet queries and the
+        // discv5 events are polled as well.
+        while let Poll::Ready(Some(query_result)) = self.active_queries.poll_next_unpin(cx) {
             trace!("poll -> self.active_queries");
-            return match query_result.result {
+            let duration = query_result.started.elapsed();
+            match query_result.result {
                 Ok(enrs) if enrs.is_empty() => {
-                    info!("Discovery query yielded no results.");
-                    Poll::Pending
+                    info!(
+                        "Discovery query yielded no results. duration: {:?}",
+                        duration
+                    );
+                    self.record_query(duration, 0, 0);
+                    // A new query started on re-seeding is driven by this loop.
+                    self.on_empty_query();
                 }
                 Ok(enrs) => {
-                    info!("Discovery query completed. found peers: {:?}", enrs);
-                    // NOTE: Ideally we need to filter out peers from the result.
-                    // https://github.com/sigp/lighthouse/blob/9c5a8ab7f2098d1ffc567af27f385c55f471cb9c/beacon_node/eth2_libp2p/src/peer_manager/mod.rs#L256
-                    let peers = enrs
-                        .iter()
-                        .map(crate::identity::enr_to_peer_id)
-                        .collect::<Vec<_>>();
+                    self.consecutive_empty_queries = 0;
+                    info!(
+                        "Discovery query completed. duration: {:?}, found peers: {:?}",
+                        duration, enrs
+                    );
+                    let found_peers = enrs.len();
+                    let peers = self.usable_peers(enrs);
+                    self.record_query(duration, found_peers, peers.len());
 
-                    // Cache the found ENRs
-                    for (p, e) in peers.iter().zip(enrs.iter()) {
-                        self.cached_enrs.put(*p, e.clone());
-                    }
-
-                    Poll::Ready(ToSwarm::GenerateEvent(DiscoveryEvent::FoundPeers(peers)))
+                    return Poll::Ready(ToSwarm::GenerateEvent(DiscoveryEvent::FoundPeers(peers)));
                 }
                 Err(query_error) => {
                     error!("Discovery query failed: {}", query_error);
-                    Poll::Pending
+                    self.record_query(duration, 0, 0);
+                }
+            }
+        }
+
+        while let Poll::Ready(Some(query_result)) = self.subnet_queries.poll_next_unpin(cx) {
+            trace!("poll -> self.subnet_queries");
+            let duration = query_result.started.elapsed();
+            match query_result.result {
+                Ok(enrs) => {
+                    info!(
+                        "Subnet discovery query completed. subnets: {:?}, duration: {:?}, found peers: {}",
+                        query_result.subnets,
+                        duration,
+                        enrs.len()
+                    );
+                    let found_peers = enrs.len();
+                    let peers = self.usable_peers(enrs);
+                    self.record_query(duration, found_peers, peers.len());
+
+                    if !peers.is_empty() {
+                        return Poll::Ready(ToSwarm::GenerateEvent(DiscoveryEvent::FoundPeers(
+                            peers,
+                        )));
+                    }
                 }
-            };
+                Err(query_error) => {
+                    error!(
+                        "Subnet discovery query failed. subnets: {:?}, error: {}",
+                        query_result.subnets, query_error
+                    );
+                    self.record_query(duration, 0, 0);
+                }
+            }
         }
 
         while let Poll::Ready(Some(event)) = self.event_stream.poll_recv(cx) {
@@ -310,3 +754,229 @@ impl NetworkBehaviour for Behaviour {
         Poll::Pending
     }
 }
+
+// Returns true if the ENR advertises the fork digest, i.e. the peer is on the same fork as us.
+fn has_fork_digest(enr: &Enr, fork_digest: [u8; 4]) -> bool {
+    enr.eth2().map(|enr_fork_id| enr_fork_id.fork_digest) == Ok(fork_digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use types::Epoch;
+
+    const FORK_DIGEST: [u8; 4] = [1, 2, 3, 4];
+
+    fn enr_fork_id(fork_digest: [u8; 4]) -> EnrForkId {
+        EnrForkId {
+            fork_digest,
+            next_fork_version: [0; 4],
+            next_fork_epoch: Epoch::max_value(),
+        }
+    }
+
+    // The ENR of a remote peer on the fork, reachable over TCP.
+    fn peer_enr(fork_digest: [u8; 4]) -> Enr {
+        let enr_key = CombinedKey::generate_secp256k1();
+        Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .tcp4(9000)
+            .add_value(ETH2_ENR_KEY, &enr_fork_id(fork_digest).as_ssz_bytes())
+            .build(&enr_key)
+            .unwrap()
+    }
+
+    // A discovery behaviour listening on an ephemeral local port, with no boot ENRs.
+    async fn behaviour() -> Behaviour {
+        behaviour_with_boot_enr(&Vec::new()).await
+    }
+
+    async fn behaviour_with_boot_enr(boot_enr: &Vec<Enr>) -> Behaviour {
+        let enr_key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder()
+            .add_value(ETH2_ENR_KEY, &enr_fork_id(FORK_DIGEST).as_ssz_bytes())
+            .build(&enr_key)
+            .unwrap();
+        Behaviour::new(
+            enr,
+            enr_key,
+            boot_enr,
+            2,
+            Arc::new(RwLock::new(PeerDB::new())),
+            -20.0,
+            Arc::new(PeerFilter::default()),
+            usize::MAX,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            0,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn discovery_queries_are_capped() {
+        let mut behaviour = behaviour().await;
+
+        for _ in 0..5 {
+            behaviour.discover_peers();
+        }
+        assert_eq!(behaviour.running_queries(), 2);
+
+        behaviour.discover_subnet_peers(vec![SubnetId::new(0)]);
+        assert_eq!(behaviour.running_queries(), 2);
+    }
+
+    #[tokio::test]
+    async fn confirmed_external_address_updates_enr() {
+        let mut behaviour = behaviour().await;
+        behaviour.set_publicly_reachable(true);
+
+        let addr: Multiaddr = "/ip4/203.0.113.1/tcp/9000".parse().unwrap();
+        behaviour.on_external_addr_confirmed(&addr);
+
+        let enr = behaviour.local_enr();
+        assert_eq!(enr.ip4(), Some(Ipv4Addr::new(203, 0, 113, 1)));
+        assert_eq!(enr.tcp4(), Some(9000));
+    }
+
+    #[tokio::test]
+    async fn confirmed_external_address_is_ignored_unless_publicly_reachable() {
+        let behaviour = behaviour().await;
+
+        let addr: Multiaddr = "/ip4/203.0.113.1/tcp/9000".parse().unwrap();
+        behaviour.on_external_addr_confirmed(&addr);
+
+        assert_eq!(behaviour.local_enr().tcp4(), None);
+    }
+
+    #[tokio::test]
+    async fn confirmed_external_address_without_tcp_is_ignored() {
+        let mut behaviour = behaviour().await;
+        behaviour.set_publicly_reachable(true);
+
+        let addr: Multiaddr = "/ip4/203.0.113.1/udp/9000".parse().unwrap();
+        behaviour.on_external_addr_confirmed(&addr);
+
+        assert_eq!(behaviour.local_enr().tcp4(), None);
+    }
+
+    #[tokio::test]
+    async fn low_scored_peers_are_not_dialed() {
+        let mut behaviour = behaviour().await;
+        let (bad, good) = (peer_enr(FORK_DIGEST), peer_enr(FORK_DIGEST));
+        let bad_peer = crate::identity::enr_to_peer_id(&bad);
+        {
+            let mut peer_db = behaviour.peer_db.write();
+            peer_db.add_peer(bad_peer, "/ip4/127.0.0.1/tcp/9000".parse().unwrap(), true);
+            peer_db.add_score(&bad_peer, -50.0);
+        }
+
+        let peers = behaviour.usable_peers(vec![bad, good.clone()]);
+
+        assert_eq!(peers, vec![crate::identity::enr_to_peer_id(&good)]);
+        assert!(!behaviour.cached_enrs.contains(&bad_peer));
+    }
+
+    #[tokio::test]
+    async fn peers_without_a_reachable_address_are_not_dialed() {
+        let mut behaviour = behaviour().await;
+        // An ENR without the IP address and the TCP port.
+        let unreachable = Enr::builder()
+            .add_value(ETH2_ENR_KEY, &enr_fork_id(FORK_DIGEST).as_ssz_bytes())
+            .build(&CombinedKey::generate_secp256k1())
+            .unwrap();
+        let unreachable_peer = crate::identity::enr_to_peer_id(&unreachable);
+        let reachable = peer_enr(FORK_DIGEST);
+
+        let peers = behaviour.usable_peers(vec![unreachable, reachable.clone()]);
+
+        assert_eq!(peers, vec![crate::identity::enr_to_peer_id(&reachable)]);
+        assert!(!behaviour.cached_enrs.contains(&unreachable_peer));
+    }
+
+    #[tokio::test]
+    async fn consecutive_empty_results_re_seed_from_the_boot_enrs() {
+        let boot_enr = Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(9000)
+            .build(&CombinedKey::generate_secp256k1())
+            .unwrap();
+        let mut behaviour = behaviour_with_boot_enr(&vec![boot_enr.clone()]).await;
+        // The boot node has been evicted as unresponsive.
+        behaviour.discv5.remove_node(&boot_enr.node_id());
+
+        for _ in 0..MAX_CONSECUTIVE_EMPTY_QUERIES - 1 {
+            assert!(!behaviour.on_empty_query());
+        }
+        assert!(!behaviour
+            .discv5
+            .table_entries_id()
+            .contains(&boot_enr.node_id()));
+
+        assert!(behaviour.on_empty_query());
+        assert!(behaviour
+            .discv5
+            .table_entries_id()
+            .contains(&boot_enr.node_id()));
+        assert_eq!(behaviour.running_queries(), 1);
+
+        // The count starts over.
+        assert!(!behaviour.on_empty_query());
+    }
+
+    #[tokio::test]
+    async fn exceeding_the_peer_memory_soft_limit_prunes_enrs_and_disconnected_peers() {
+        let mut behaviour = behaviour().await;
+        for _ in 0..3 {
+            behaviour
+                .cached_enrs
+                .put(PeerId::random(), peer_enr(FORK_DIGEST));
+        }
+        let (connected, disconnected) = (PeerId::random(), PeerId::random());
+        {
+            let mut peer_db = behaviour.peer_db.write();
+            for peer_id in [connected, disconnected] {
+                peer_db.add_peer(peer_id, "/ip4/127.0.0.1/tcp/9000".parse().unwrap(), true);
+            }
+            peer_db.update_connection_status(
+                &disconnected,
+                crate::peer_db::ConnectionStatus::Disconnected {
+                    since: Instant::now(),
+                },
+            );
+        }
+
+        // Within the limit.
+        behaviour.enforce_peer_memory_limit();
+        assert_eq!(behaviour.cached_enrs.len(), 3);
+        assert_eq!(behaviour.peer_db.read().peer_count(), 2);
+
+        behaviour.peer_memory_soft_limit = 0;
+        behaviour.enforce_peer_memory_limit();
+
+        assert!(behaviour.cached_enrs.is_empty());
+        // The connected peer is kept.
+        assert_eq!(behaviour.peer_db.read().peer_count(), 1);
+        assert!(behaviour.peer_db.read().is_connected(&connected));
+    }
+
+    #[tokio::test]
+    async fn peers_on_another_fork_are_not_dialed() {
+        let mut behaviour = behaviour().await;
+        let (matching, other_fork) = (peer_enr(FORK_DIGEST), peer_enr([5, 6, 7, 8]));
+        // An ENR without the eth2 field.
+        let no_fork = Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .tcp4(9000)
+            .build(&CombinedKey::generate_secp256k1())
+            .unwrap();
+
+        let peers = behaviour.usable_peers(vec![other_fork.clone(), matching.clone(), no_fork]);
+
+        assert_eq!(peers, vec![crate::identity::enr_to_peer_id(&matching)]);
+        assert!(!behaviour
+            .cached_enrs
+            .contains(&crate::identity::enr_to_peer_id(&other_fork)));
+    }
+}
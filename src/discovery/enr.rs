@@ -1,12 +1,52 @@
 use crate::types::Enr;
 use libp2p::bytes::Bytes;
+use lighthouse_network::types::{EnrAttestationBitfield, EnrSyncCommitteeBitfield};
 use ssz::Decode;
-use types::EnrForkId;
+use types::{EnrForkId, MainnetEthSpec};
 
-const ETH2_ENR_KEY: &str = "eth2";
+pub(crate) const ETH2_ENR_KEY: &str = "eth2";
+pub(crate) const ATTESTATION_BITFIELD_ENR_KEY: &str = "attnets";
+pub(crate) const SYNC_COMMITTEE_BITFIELD_ENR_KEY: &str = "syncnets";
+
+// Returns the attestation subnets bitfield of the local node, which is advertised via the ENR.
+// A supernode subscribes to all the subnets.
+pub(crate) fn attnets_bitfield(
+    subscribe_all_subnets: bool,
+) -> EnrAttestationBitfield<MainnetEthSpec> {
+    let mut attnets = EnrAttestationBitfield::<MainnetEthSpec>::new();
+    if subscribe_all_subnets {
+        for subnet in 0..attnets.len() {
+            attnets
+                .set(subnet, true)
+                .expect("subnet should be within the bitfield length");
+        }
+    }
+    attnets
+}
+
+// Returns the sync committee subnets bitfield of the local node, which is advertised via the ENR.
+pub(crate) fn syncnets_bitfield(
+    subscribe_all_subnets: bool,
+) -> EnrSyncCommitteeBitfield<MainnetEthSpec> {
+    let mut syncnets = EnrSyncCommitteeBitfield::<MainnetEthSpec>::new();
+    if subscribe_all_subnets {
+        for subnet in 0..syncnets.len() {
+            syncnets
+                .set(subnet, true)
+                .expect("subnet should be within the bitfield length");
+        }
+    }
+    syncnets
+}
 
 pub(crate) trait Eth2Enr {
     fn eth2(&self) -> Result<EnrForkId, String>;
+
+    // The attestation subnets the node is subscribed to.
+    fn attestation_bitfield(&self) -> Result<EnrAttestationBitfield<MainnetEthSpec>, String>;
+
+    // The sync committee subnets the node is subscribed to.
+    fn sync_committee_bitfield(&self) -> Result<EnrSyncCommitteeBitfield<MainnetEthSpec>, String>;
 }
 
 impl Eth2Enr for Enr {
@@ -19,4 +59,24 @@ impl Eth2Enr for Enr {
         EnrForkId::from_ssz_bytes(&eth2_bytes)
             .map_err(|e| format!("Could not decode EnrForkId: {e:?}"))
     }
+
+    fn attestation_bitfield(&self) -> Result<EnrAttestationBitfield<MainnetEthSpec>, String> {
+        let bitfield_bytes: Bytes = self
+            .get_decodable(ATTESTATION_BITFIELD_ENR_KEY)
+            .ok_or("ENR has no attnets field")?
+            .map_err(|e| format!("Failed to decode attnets field: {}", e))?;
+
+        EnrAttestationBitfield::<MainnetEthSpec>::from_ssz_bytes(&bitfield_bytes)
+            .map_err(|e| format!("Could not decode the attestation bitfield: {e:?}"))
+    }
+
+    fn sync_committee_bitfield(&self) -> Result<EnrSyncCommitteeBitfield<MainnetEthSpec>, String> {
+        let bitfield_bytes: Bytes = self
+            .get_decodable(SYNC_COMMITTEE_BITFIELD_ENR_KEY)
+            .ok_or("ENR has no syncnets field")?
+            .map_err(|e| format!("Failed to decode syncnets field: {}", e))?;
+
+        EnrSyncCommitteeBitfield::<MainnetEthSpec>::from_ssz_bytes(&bitfield_bytes)
+            .map_err(|e| format!("Could not decode the sync committee bitfield: {e:?}"))
+    }
 }
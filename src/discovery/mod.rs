@@ -1,5 +1,5 @@
 pub(crate) mod behaviour;
-mod enr;
+pub(crate) mod enr;
 
 use libp2p::PeerId;
 
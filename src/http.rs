@@ -0,0 +1,41 @@
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// The time to wait for the request, so that an idle connection doesn't hold a task forever.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Reads the request line, e.g. `GET /metrics HTTP/1.1`. The headers and the body are ignored, as
+// none of the servers need them.
+pub(crate) async fn read_request_line(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut request = [0u8; 1024];
+    let len = tokio::time::timeout(READ_TIMEOUT, stream.read(&mut request))
+        .await
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Timed out reading the request",
+            )
+        })??;
+    Ok(String::from_utf8_lossy(&request[..len])
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+// Writes the response and closes the connection.
+pub(crate) async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    let header = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,90 @@
+use crate::metrics::{Encoder, TextEncoder};
+use crate::peer_db::SyncStatus;
+use crate::PeerDB;
+use parking_lot::RwLock;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tracing::{debug, info};
+
+// The label values of the peers by sync status.
+const SYNC_STATUSES: [(&str, SyncStatus); 5] = [
+    ("synced", SyncStatus::Synced),
+    ("advanced", SyncStatus::Advanced),
+    ("behind", SyncStatus::Behind),
+    ("irrelevant", SyncStatus::IrrelevantPeer),
+    ("unknown", SyncStatus::Unknown),
+];
+
+// Serves the metrics in the Prometheus text format on `localhost:port/metrics`.
+// Ref: https://github.com/ethereum/beacon-metrics
+pub(crate) fn spawn(
+    runtime: Arc<Runtime>,
+    port: u16,
+    peer_db: Arc<RwLock<PeerDB>>,
+) -> Result<(), String> {
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let listener = runtime
+        .block_on(TcpListener::bind(address))
+        .map_err(|e| format!("Failed to bind the metrics server to {address}. {e}"))?;
+    info!("Serving metrics on http://{address}/metrics");
+
+    runtime.spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _remote)) => {
+                    let peer_db = peer_db.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, &peer_db).await {
+                            debug!("Failed to serve metrics. {}", e);
+                        }
+                    });
+                }
+                Err(e) => debug!("Failed to accept a metrics connection. {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn serve(mut stream: TcpStream, peer_db: &RwLock<PeerDB>) -> std::io::Result<()> {
+    // Only the path of the request line is parsed, as the metrics are the only thing served.
+    let request_line = crate::http::read_request_line(&mut stream).await?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    if path != "/metrics" {
+        return crate::http::respond(&mut stream, "404 Not Found", "text/plain", b"Not Found")
+            .await;
+    }
+
+    update_peer_metrics(peer_db);
+
+    let mut body = vec![];
+    if let Err(e) = TextEncoder::new().encode(&crate::metrics::gather(), &mut body) {
+        debug!("Failed to encode the metrics. {}", e);
+    }
+    crate::http::respond(
+        &mut stream,
+        "200 OK",
+        TextEncoder::new().format_type(),
+        &body,
+    )
+    .await
+}
+
+// The peer gauges are computed from the PeerDB on each scrape rather than tracked on each change.
+fn update_peer_metrics(peer_db: &RwLock<PeerDB>) {
+    let peer_db = peer_db.read();
+    crate::metrics::set_gauge(
+        &crate::metrics::CONNECTED_PEERS,
+        peer_db.active_peer_count() as i64,
+    );
+    for (label, sync_status) in SYNC_STATUSES.iter() {
+        crate::metrics::set_gauge_vec(
+            &crate::metrics::PEERS_BY_SYNC_STATUS,
+            &[*label],
+            peer_db.peers_by_sync_status(sync_status).len() as i64,
+        );
+    }
+}
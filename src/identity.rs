@@ -1,8 +1,129 @@
-use discv5::enr::CombinedPublicKey;
+use discv5::enr::{CombinedKey, CombinedPublicKey};
 use discv5::Enr;
 use libp2p::multiaddr::Protocol;
 use libp2p::{Multiaddr, PeerId};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
 use tiny_keccak::{Hasher, Keccak};
+use tracing::{info, warn};
+
+// The file name of the persisted ENR key in the data directory.
+const ENR_KEY_FILE: &str = "enr.key";
+
+// Loads the ENR key from the data directory, or generates a new one and persists it so that the
+// node keeps the same identity (and the same ENR) across restarts.
+//
+// A corrupt key file is an error, unless `regenerate_corrupt_key` is set, in which case the file is
+// moved aside and a new key (i.e. a new identity) is generated.
+// SEE: https://github.com/sigp/lighthouse/blob/4af6fcfafd2c29bca82474ee378cda9ac254783a/beacon_node/eth2_libp2p/src/config.rs
+pub(crate) fn load_or_generate_enr_key(
+    data_dir: &Path,
+    regenerate_corrupt_key: bool,
+) -> Result<CombinedKey, String> {
+    let path = data_dir.join(ENR_KEY_FILE);
+
+    if path.exists() {
+        info!("Loading ENR key from {}", path.display());
+        let mut key_bytes = std::fs::read(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        match decode_enr_key(&mut key_bytes) {
+            Ok(enr_key) => return Ok(enr_key),
+            Err(e) if !regenerate_corrupt_key => {
+                return Err(format!(
+                    "Failed to decode ENR key {}: {}. Remove the file or pass --regenerate-corrupt-key to generate a new identity.",
+                    path.display(),
+                    e
+                ));
+            }
+            Err(e) => {
+                let corrupt_path = path.with_extension("key.corrupt");
+                std::fs::rename(&path, &corrupt_path).map_err(|e| {
+                    format!(
+                        "Failed to move the corrupt ENR key to {}: {}",
+                        corrupt_path.display(),
+                        e
+                    )
+                })?;
+                warn!(
+                    "!!! The ENR key {} is corrupt ({}), so a new one is generated. The node identity (PeerId and ENR) has CHANGED. The corrupt key is kept at {}.",
+                    path.display(),
+                    e,
+                    corrupt_path.display()
+                );
+            }
+        }
+    }
+
+    info!("Generating a new ENR key to {}", path.display());
+    let enr_key = CombinedKey::generate_secp256k1();
+    std::fs::create_dir_all(data_dir)
+        .map_err(|e| format!("Failed to create {}: {}", data_dir.display(), e))?;
+    write_secret(&path, &enr_key.encode())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    Ok(enr_key)
+}
+
+// Writes the secret key to a file readable by the owner only.
+fn write_secret(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(bytes)
+}
+
+// Decodes the persisted ENR key, checking that it's also usable as the libp2p key.
+fn decode_enr_key(key_bytes: &mut [u8]) -> Result<CombinedKey, String> {
+    let enr_key = CombinedKey::secp256k1_from_bytes(key_bytes)
+        .map_err(|e| format!("invalid secp256k1 key: {:?}", e))?;
+    to_libp2p_keypair(&enr_key)?;
+    Ok(enr_key)
+}
+
+// Converts the ENR key to the libp2p key, so that the PeerId matches the ENR.
+pub(crate) fn to_libp2p_keypair(
+    enr_key: &CombinedKey,
+) -> Result<libp2p::identity::Keypair, String> {
+    match enr_key {
+        CombinedKey::Secp256k1(key) => {
+            let mut key_bytes = key.to_bytes();
+            let secret_key = libp2p::identity::secp256k1::SecretKey::try_from_bytes(&mut key_bytes)
+                .map_err(|e| format!("invalid secp256k1 key for libp2p: {}", e))?;
+            let keypair: libp2p::identity::secp256k1::Keypair = secret_key.into();
+            Ok(keypair.into())
+        }
+        CombinedKey::Ed25519(key) => {
+            let mut key_bytes = key.to_bytes();
+            let secret_key = libp2p::identity::ed25519::SecretKey::try_from_bytes(&mut key_bytes)
+                .map_err(|e| format!("invalid ed25519 key for libp2p: {}", e))?;
+            let keypair: libp2p::identity::ed25519::Keypair = secret_key.into();
+            Ok(keypair.into())
+        }
+    }
+}
+
+// Returns the libp2p (transport) key. It's derived from the ENR key, unless `rotate` is set, in
+// which case a fresh key is generated so that the node presents a stable ENR while rotating its
+// session identity.
+//
+// NOTE: Peers derive the PeerId to dial from our ENR (see `enr_to_peer_id`), and we look up the ENR
+// of a peer by the NodeId derived from its PeerId (see `peer_id_to_node_id`). With a rotated
+// transport key, our PeerId no longer matches our ENR, so peers can't dial us via discovery, and
+// only outbound connections are made.
+pub(crate) fn transport_keypair(
+    enr_key: &CombinedKey,
+    rotate: bool,
+) -> Result<libp2p::identity::Keypair, String> {
+    if rotate {
+        info!("Generated an ephemeral transport key, which differs from the ENR key.");
+        return Ok(libp2p::identity::Keypair::generate_secp256k1());
+    }
+    to_libp2p_keypair(enr_key)
+}
 
 // SEE: https://github.com/sigp/lighthouse/blob/4af6fcfafd2c29bca82474ee378cda9ac254783a/beacon_node/eth2_libp2p/src/discovery/enr_ext.rs#L200
 pub(crate) fn enr_to_peer_id(enr: &Enr) -> PeerId {
@@ -14,7 +135,14 @@ pub(crate) fn enr_to_peer_id(enr: &Enr) -> PeerId {
             let public_key: libp2p::identity::PublicKey = libp2p_pk.into();
             PeerId::from(public_key)
         }
-        CombinedPublicKey::Ed25519(_) => unreachable!(), // not implemented as the ENR key is generated with secp256k1
+        // Our ENR key is generated with secp256k1, but some bootnodes use ed25519.
+        CombinedPublicKey::Ed25519(pk) => {
+            let pk_bytes = pk.to_bytes();
+            let libp2p_pk = libp2p::identity::ed25519::PublicKey::try_from_bytes(&pk_bytes)
+                .expect("valid public key");
+            let public_key: libp2p::identity::PublicKey = libp2p_pk.into();
+            PeerId::from(public_key)
+        }
     }
 }
 
@@ -74,3 +202,59 @@ pub(crate) fn enr_to_multiaddrs(enr: &Enr) -> Vec<Multiaddr> {
     }
     multiaddrs
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotated_transport_key_differs_from_the_enr_key() {
+        let enr_key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder().build(&enr_key).expect("enr");
+
+        let derived = transport_keypair(&enr_key, false).expect("derived key");
+        assert_eq!(derived.public().to_peer_id(), enr_to_peer_id(&enr));
+
+        let rotated = transport_keypair(&enr_key, true).expect("rotated key");
+        assert_ne!(rotated.public().to_peer_id(), enr_to_peer_id(&enr));
+    }
+
+    #[test]
+    fn corrupt_enr_key_is_rejected_or_regenerated() {
+        let data_dir = std::env::temp_dir().join(format!("ray-test-identity-{}", PeerId::random()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let path = data_dir.join(ENR_KEY_FILE);
+        // A zero scalar isn't a valid secp256k1 key.
+        std::fs::write(&path, [0u8; 32]).unwrap();
+        assert!(decode_enr_key(&mut [0u8; 32]).is_err());
+        assert!(decode_enr_key(&mut [1u8; 3]).is_err());
+
+        // Without the flag the corrupt key is an error, and the file is left as is.
+        assert!(load_or_generate_enr_key(&data_dir, false).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0u8; 32]);
+
+        // With the flag a new key is generated, and the corrupt one is kept aside.
+        let enr_key = load_or_generate_enr_key(&data_dir, true).expect("regenerated key");
+        assert!(path.with_extension("key.corrupt").exists());
+        let reloaded = load_or_generate_enr_key(&data_dir, false).expect("reloaded key");
+        assert_eq!(reloaded.encode(), enr_key.encode());
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn generated_enr_key_is_readable_by_the_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let data_dir = std::env::temp_dir().join(format!("ray-test-identity-{}", PeerId::random()));
+        load_or_generate_enr_key(&data_dir, false).expect("generated key");
+
+        let mode = std::fs::metadata(data_dir.join(ENR_KEY_FILE))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
@@ -1,8 +1,13 @@
+mod api;
 mod behaviour;
+mod block_cache;
 mod bootstrap;
 mod config;
 mod discovery;
+mod http;
+mod http_metrics;
 mod identity;
+mod metrics;
 mod network;
 mod peer_db;
 mod peer_manager;
@@ -12,52 +17,240 @@ mod sync;
 mod types;
 
 use crate::behaviour::{BehaviourComposer, BehaviourComposerEvent};
+use crate::block_cache::BlockCache;
 use crate::bootstrap::{build_network_behaviour, build_network_transport};
-use crate::config::NetworkConfig;
+use crate::config::{NetworkConfig, RuntimeConfig};
 use crate::network::Network;
 use crate::peer_db::PeerDB;
-use ::types::MainnetEthSpec;
+use ::types::{EnrForkId, Epoch, MainnetEthSpec, Slot};
+use clap::Parser;
 use client::config::{ClientGenesis, Config};
 use client::ClientBuilder;
 use discv5::enr::CombinedKey;
 use discv5::Enr;
 use environment::{EnvironmentBuilder, LoggerConfig};
-use eth2_network_config::Eth2NetworkConfig;
-use parking_lot::RwLock;
+use eth2_network_config::{Eth2NetworkConfig, HARDCODED_NET_NAMES};
+use parking_lot::{Mutex, RwLock};
+use sensitive_url::SensitiveUrl;
 use ssz::Encode;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
-use tracing::info;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+// The defaults of the tunables in `RuntimeConfig`.
 
 // Target number of peers to connect to.
 const TARGET_PEERS_COUNT: usize = 50;
 
+// Number of recent blocks kept in memory for serving `BlocksByRoot`.
+const BLOCK_CACHE_SIZE: usize = 64;
+
+// The maximum number of discovery queries running at the same time.
+const MAX_CONCURRENT_DISCOVERY_QUERIES: usize = 2;
+// The first discovery query is delayed randomly up to this, so that nodes started together (e.g.
+// on a testnet spin-up) don't query in a burst. Zero starts discovery immediately.
+const MAX_DISCOVERY_STARTUP_JITTER: Duration = Duration::from_secs(3);
+
+// Discovered peers with a score below this are not dialed.
+const MIN_SCORE_TO_DIAL: f64 = -20.0;
+
+// Whether to STATUS all peers shortly after each epoch boundary, in addition to the regular
+// re-status of each peer.
+const STATUS_ON_EPOCH_BOUNDARY: bool = false;
+
+// The maximum number of finalized chains tracked by sync, to bound memory under many distinct
+// chains claimed by peers.
+const MAX_FINALIZED_CHAINS: usize = 8;
+
+// The number of threads verifying the signatures of the blocks downloaded by sync. The blocks are
+// still imported in order.
+const SIGNATURE_VERIFICATION_THREADS: usize = 4;
+
+// The maximum number of requests sync sends to a peer per second.
+const MAX_OUTBOUND_REQUESTS_PER_PEER: usize = 2;
+// The maximum number of requests sync awaits responses to, across all the peers.
+const MAX_OUTBOUND_REQUESTS_IN_FLIGHT: usize = 32;
+
+// The maximum number of low-priority operations queued for the sync manager. See `sync::SyncSender` for what
+// happens once it's full.
+const SYNC_CHANNEL_CAPACITY: usize = 4096;
+// The maximum number of messages queued for the Network service.
+const NETWORK_CHANNEL_CAPACITY: usize = 256;
+
+// The time to wait for a whole RPC request once a protocol has been established before the
+// substream is terminated, and to send ours.
+const RPC_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+// The time to wait for the next bytes while reading an RPC request.
+const RPC_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The maximum bytes of responses buffered for sending to a peer.
+const MAX_BUFFERED_RESPONSE_BYTES: usize = 50 * 1_048_576; // 50M
+
+// The soft limit of the estimated memory used by the cached ENRs and the PeerDB together, beyond
+// which they are pruned.
+const PEER_MEMORY_SOFT_LIMIT: usize = 4 * 1_048_576; // 4M
+
+// The maximum number of peers connected from the same IP address.
+const MAX_PEERS_PER_IP: usize = 2;
+// The maximum number of peers connected from the same /24 subnet.
+const MAX_PEERS_PER_SUBNET: usize = 5;
+
+// Connections with no active protocols are closed after this period.
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The clock disagreement tolerated when checking the head slot of a peer.
+const MAX_CLOCK_DISPARITY: Duration = Duration::from_millis(500);
+
+// The file the known peers are persisted to, in the data directory.
+const PEER_DB_FILE: &str = "peers.json";
+// The optional file the tunables are loaded from, in the data directory.
+const RUNTIME_CONFIG_FILE: &str = "runtime_config.yaml";
+
+// The network to run on, unless `--network` is given.
+const DEFAULT_NETWORK: &str = "prater";
+
+// The TCP port libp2p listens on, unless `--port` is given.
+const DEFAULT_LIBP2P_PORT: u16 = 9000;
+// The UDP port discv5 listens on, unless `--discovery-port` is given.
+const DEFAULT_DISCOVERY_PORT: u16 = 9000;
+// The TCP port the metrics are served on with `--metrics`, unless `--metrics-port` is given.
+const DEFAULT_METRICS_PORT: u16 = 5054;
+// The TCP port the HTTP API is served on with `--http`, unless `--http-port` is given.
+const DEFAULT_HTTP_PORT: u16 = 5052;
+
+// The command-line options.
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    /// The network to run on, which has to be one built into lighthouse.
+    #[arg(long, value_parser = parse_network)]
+    network: Option<String>,
+    /// The beacon node to fetch the checkpoint state from, on the first start. Defaults to a
+    /// public endpoint of the network.
+    #[arg(long)]
+    checkpoint_sync_url: Option<String>,
+    /// The address to listen on, either IPv4 or IPv6, so that several nodes can run on one host.
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    listen_address: IpAddr,
+    /// The TCP port libp2p listens on.
+    #[arg(long, default_value_t = DEFAULT_LIBP2P_PORT)]
+    port: u16,
+    /// The UDP port discv5 listens on.
+    #[arg(long, default_value_t = DEFAULT_DISCOVERY_PORT)]
+    discovery_port: u16,
+    /// The endpoint of an execution node serving the eth1 deposit data. Without it, the dummy
+    /// eth1 backend is used, which is enough for a consensus-only observer.
+    #[arg(long)]
+    eth1_endpoint: Option<String>,
+    /// Stops syncing at this epoch rather than following the head, for reproducible sync
+    /// benchmarks.
+    #[arg(long)]
+    target_epoch: Option<u64>,
+    /// Prints the local ENR and exits, without running the node. This is handy for setting up
+    /// bootnodes of a private network.
+    #[arg(long)]
+    dump_enr: bool,
+    /// Subscribes to all the attestation subnets, as a supernode does.
+    #[arg(long)]
+    subscribe_all_subnets: bool,
+    /// Uses a fresh libp2p (transport) key on each start, instead of the one derived from the
+    /// persistent ENR key, so the node presents a stable ENR while rotating its session identity.
+    #[arg(long)]
+    rotate_transport_key: bool,
+    /// Replaces a corrupt persisted ENR key with a new one, which changes the node identity.
+    #[arg(long)]
+    regenerate_corrupt_key: bool,
+    /// Serves the metrics.
+    #[arg(long)]
+    metrics: bool,
+    /// The TCP port the metrics are served on.
+    #[arg(long, default_value_t = DEFAULT_METRICS_PORT)]
+    metrics_port: u16,
+    /// Serves the HTTP API.
+    #[arg(long)]
+    http: bool,
+    /// The TCP port the HTTP API is served on.
+    #[arg(long, default_value_t = DEFAULT_HTTP_PORT)]
+    http_port: u16,
+}
+
+// Accepts only the networks built into lighthouse.
+fn parse_network(name: &str) -> Result<String, String> {
+    if HARDCODED_NET_NAMES.contains(&name) {
+        Ok(name.to_string())
+    } else {
+        Err(format!(
+            "Unknown network: {}. Supported networks: {:?}",
+            name, HARDCODED_NET_NAMES
+        ))
+    }
+}
+
 fn main() {
     tracing_subscriber::fmt::init();
     info!("Starting Ray v{}", env!("CARGO_PKG_VERSION"));
 
-    // Keys
-    info!("Generating keys...");
-    let enr_key = CombinedKey::generate_secp256k1();
-    let key_pair: libp2p::identity::Keypair = {
-        match enr_key {
-            CombinedKey::Secp256k1(ref key) => {
-                let mut key_bytes = key.to_bytes();
-                let secret_key =
-                    libp2p::identity::secp256k1::SecretKey::try_from_bytes(&mut key_bytes)
-                        .expect("valid secp256k1 key");
-
-                let kp: libp2p::identity::secp256k1::Keypair = secret_key.into();
-                kp.into()
-            }
-            CombinedKey::Ed25519(_) => unreachable!(), // not implemented as the ENR key is generated with secp256k1
-        }
+    let Cli {
+        network,
+        checkpoint_sync_url,
+        listen_address,
+        port: libp2p_port,
+        discovery_port,
+        eth1_endpoint,
+        target_epoch,
+        dump_enr,
+        subscribe_all_subnets,
+        rotate_transport_key,
+        regenerate_corrupt_key,
+        metrics,
+        metrics_port,
+        http,
+        http_port,
+    } = Cli::parse();
+    let eth1_endpoint = eth1_endpoint.map(|endpoint| {
+        crate::config::eth1_endpoint(&endpoint).unwrap_or_else(|e| {
+            error!("Invalid eth1 endpoint. {}", e);
+            std::process::exit(1);
+        })
+    });
+    let target_epoch = target_epoch.map(Epoch::new);
+
+    let data_dir = {
+        let mut data_dir = home::home_dir().expect("home dir");
+        data_dir.push(".ray");
+        data_dir
     };
-    info!("Generated ENR keys.");
+
+    // RuntimeConfig
+    let runtime_config = RuntimeConfig::load(&data_dir.join(RUNTIME_CONFIG_FILE), |var| {
+        std::env::var(var).ok()
+    })
+    .unwrap_or_else(|e| {
+        error!("Failed to load the runtime config. {}", e);
+        std::process::exit(1);
+    });
+    info!("Loaded RuntimeConfig. {:?}", runtime_config);
+
+    // Keys
+    info!("Loading keys...");
+    let enr_key = identity::load_or_generate_enr_key(&data_dir, regenerate_corrupt_key)
+        .unwrap_or_else(|e| {
+            error!("Failed to load the ENR key. {}", e);
+            std::process::exit(1);
+        });
+    let key_pair =
+        identity::transport_keypair(&enr_key, rotate_transport_key).unwrap_or_else(|e| {
+            error!("Failed to derive the transport key from the ENR key. {}", e);
+            std::process::exit(1);
+        });
+    info!("Loaded ENR keys.");
 
     // NetworkConfig
     // Ref: https://github.com/sigp/lighthouse/blob/b6493d5e2400234ce7148e3a400d6663c3f0af89/common/clap_utils/src/lib.rs#L20
     info!("Loading NetworkConfig...");
-    let network_config = NetworkConfig::new().expect("should load network config");
+    let mut network_config = NetworkConfig::new(listen_address, libp2p_port, discovery_port)
+        .expect("should load network config");
     info!("Loaded NetworkConfig.");
 
     // tokio Runtime
@@ -72,14 +265,67 @@ fn main() {
     info!("Built tokio runtime.");
 
     // PeerDB
-    let peer_db = Arc::new(RwLock::new(PeerDB::new()));
+    let peer_db_path = data_dir.join(PEER_DB_FILE);
+    let peer_db = Arc::new(RwLock::new(
+        PeerDB::load_from_path(&peer_db_path).unwrap_or_else(|e| {
+            warn!(
+                "Failed to load the PeerDB, starting with no known peers. {}",
+                e
+            );
+            PeerDB::new()
+        }),
+    ));
 
     // Eth2NetworkConfig
     info!("Initializing Eth2NetworkConfig...");
-    let eth2_network_config = Eth2NetworkConfig::constant("prater")
+    let network_name = network.as_deref().unwrap_or(DEFAULT_NETWORK);
+    let eth2_network_config = Eth2NetworkConfig::constant(network_name)
         .expect("Initiating the network config never fail")
-        .expect("wrong network name");
-    info!(network = "prater", "Initialized Eth2NetworkConfig.");
+        .unwrap_or_else(|| {
+            error!(
+                "Unknown network: {}. Supported networks: {:?}",
+                network_name, HARDCODED_NET_NAMES
+            );
+            std::process::exit(1);
+        });
+    // The boot ENRs in `network_config` are for the default network, so use the built-in ones of
+    // the network selected explicitly.
+    if network.is_some() {
+        if let Some(boot_enr) = eth2_network_config.boot_enr.clone() {
+            network_config.boot_enr = boot_enr;
+        }
+    }
+
+    // Only the key and the fork digest are needed for the ENR, so it's dumped before the
+    // BeaconChain is built.
+    if dump_enr {
+        let enr_fork_id = enr_fork_id_from_config(&eth2_network_config).unwrap_or_else(|e| {
+            error!("Failed to compute the fork digest. {}", e);
+            std::process::exit(1);
+        });
+        println!(
+            "{}",
+            build_local_enr(&enr_key, &enr_fork_id, subscribe_all_subnets)
+        );
+        return;
+    }
+
+    let checkpoint_sync_url = checkpoint_sync_url
+        .as_deref()
+        .or_else(|| crate::config::default_checkpoint_sync_url(network_name))
+        .unwrap_or_else(|| {
+            error!(
+                "No default checkpoint sync endpoint for {}. Specify one with --checkpoint-sync-url.",
+                network_name
+            );
+            std::process::exit(1);
+        })
+        .parse::<SensitiveUrl>()
+        .unwrap_or_else(|e| {
+            error!("Invalid checkpoint sync url. {:?}", e);
+            std::process::exit(1);
+        });
+    info!(network = network_name, "Initialized Eth2NetworkConfig.");
 
     // Environment
     info!("Building Environment...");
@@ -98,14 +344,17 @@ fn main() {
     info!("Building BeaconChain...");
     let lh_beacon_chain = runtime.block_on(async {
         let client_config = {
-            let mut data_dir = home::home_dir().expect("home dir");
-            data_dir.push(".ray");
+            let data_dir = data_dir.clone();
             info!(data_dir = ?data_dir.display(), "Building the core configuration of a beacon node.");
             let mut client_config = Config::default();
             client_config.set_data_dir(data_dir);
             client_config
         };
 
+        // Resume from the stored chain if the database has been created by a previous run, so that
+        // sync starts from the persisted head instead of re-downloading the imported blocks.
+        let resume_from_store = client_config.get_db_path().exists();
+
         let db_path = client_config.create_db_path().expect("db_path");
         let freezer_db_path = client_config
             .create_freezer_db_path()
@@ -113,6 +362,7 @@ fn main() {
         let blobs_db_path = client_config.create_blobs_db_path().expect("blob_db_path");
 
         let runtime_context = environment.core_context();
+        let mut eth1_config = client_config.eth1.clone();
 
         let client_builder = ClientBuilder::new(MainnetEthSpec)
             .chain_spec(runtime_context.eth2_config.spec.clone())
@@ -126,21 +376,32 @@ fn main() {
             )
             .expect("disk_store")
             .beacon_chain_builder(
-                // Ethereum Beacon Chain checkpoint sync endpoints
-                // https://eth-clients.github.io/checkpoint-sync-endpoints/
-                ClientGenesis::CheckpointSyncUrl {
-                    url: "http://unstable.prater.beacon-api.nimbus.team"
-                        .parse()
-                        .expect("checkpoint sync url should be parsed correctly."),
+                if resume_from_store {
+                    info!("Resuming the BeaconChain from the store.");
+                    ClientGenesis::FromStore
+                } else {
+                    info!("Checkpoint syncing from {}", checkpoint_sync_url);
+                    ClientGenesis::CheckpointSyncUrl {
+                        url: checkpoint_sync_url,
+                    }
                 },
                 client_config,
             )
             .await
             .expect("beacon_chain_builder")
             .system_time_slot_clock()
-            .expect("")
-            .dummy_eth1_backend()
-            .expect("")
+            .expect("");
+
+        let client_builder = if let Some(endpoint) = eth1_endpoint {
+            info!("Using the eth1 backend at {}", endpoint);
+            eth1_config.endpoint = eth1::Eth1Endpoint::NoAuth(endpoint);
+            client_builder.caching_eth1_backend(eth1_config)
+        } else {
+            client_builder.dummy_eth1_backend()
+        }
+        .expect("eth1 backend");
+
+        let client_builder = client_builder
             .build_beacon_chain()
             .expect("build_beacon_chain");
 
@@ -148,7 +409,25 @@ fn main() {
     });
     info!("Built BeaconChain.");
 
-    let (network_sender, network_receiver) = tokio::sync::mpsc::unbounded_channel();
+    // construct a local ENR
+    // The `eth2` field is updated by the Network on a new fork.
+    let enr = build_local_enr(
+        &enr_key,
+        &lh_beacon_chain.enr_fork_id(),
+        subscribe_all_subnets,
+    );
+    info!("Local ENR: {}", enr);
+
+    let (network_sender, network_receiver) =
+        tokio::sync::mpsc::channel(runtime_config.network_channel_capacity);
+
+    // The recent blocks, filled by sync on import and consulted by the Network when serving them.
+    let block_cache = Arc::new(Mutex::new(
+        BlockCache::new(runtime_config.block_cache_size).unwrap_or_else(|e| {
+            error!("Invalid block_cache_size. {}", e);
+            std::process::exit(1);
+        }),
+    ));
 
     // SyncManager
     info!("Building SyncManager...");
@@ -156,38 +435,197 @@ fn main() {
         runtime.clone(),
         peer_db.clone(),
         lh_beacon_chain.clone(),
-        network_sender,
+        network_sender.clone(),
+        block_cache.clone(),
+        key_pair.public().to_peer_id(),
+        target_epoch,
+        &runtime_config,
     );
     info!("Built and spawned SyncManager.");
 
-    // construct a local ENR
-    // TODO: update local ENR on a new fork
-    // https://github.com/sigp/lighthouse/blob/878027654f0ebc498168c7d9f0646fc1d7f5d710/beacon_node/network/src/service.rs#L483
-    let enr_fork_id = lh_beacon_chain.enr_fork_id();
-    let enr = Enr::builder()
-        .add_value("eth2", &enr_fork_id.as_ssz_bytes())
-        .build(&enr_key)
-        .unwrap();
-    info!("Local ENR: {}", enr);
-
     // Network
     info!("Building Network...");
-    let network = runtime.block_on(Network::new(
-        network_receiver,
-        lh_beacon_chain,
-        sync_sender,
-        key_pair,
-        enr,
-        enr_key,
-        network_config,
-        peer_db,
-        runtime.clone(),
-    ));
+    let network = runtime
+        .block_on(Network::new(
+            network_receiver,
+            lh_beacon_chain,
+            sync_sender.clone(),
+            key_pair,
+            enr,
+            enr_key,
+            network_config,
+            peer_db.clone(),
+            peer_db_path.clone(),
+            block_cache,
+            runtime.clone(),
+            runtime_config,
+        ))
+        .unwrap_or_else(|e| {
+            error!("Failed to start the network. {}", e);
+            std::process::exit(1);
+        });
     runtime.block_on(network.spawn(runtime.clone()));
     info!("Built and spawned Network");
 
+    if metrics {
+        crate::http_metrics::spawn(runtime.clone(), metrics_port, peer_db.clone()).unwrap_or_else(
+            |e| {
+                error!("Failed to start the metrics server. {}", e);
+                std::process::exit(1);
+            },
+        );
+    }
+
+    if http {
+        crate::api::spawn(
+            runtime.clone(),
+            http_port,
+            peer_db.clone(),
+            sync_sender.clone(),
+            network_sender,
+        )
+        .unwrap_or_else(|e| {
+            error!("Failed to start the HTTP API server. {}", e);
+            std::process::exit(1);
+        });
+    }
+
+    crate::signal::dump_peers_on_sigusr1(runtime.clone(), peer_db.clone());
+    crate::signal::dump_sync_state_on_sigusr2(runtime.clone(), sync_sender);
+
     // block until shutdown requested
     let message = crate::signal::block_until_shutdown_requested(runtime);
 
     info!("Shutting down: {:?}", message.0);
+
+    if let Err(e) = peer_db.read().save_to_path(&peer_db_path) {
+        error!("Failed to save the PeerDB. {}", e);
+    }
+}
+
+fn build_local_enr(
+    enr_key: &CombinedKey,
+    enr_fork_id: &EnrForkId,
+    subscribe_all_subnets: bool,
+) -> Enr {
+    let attnets = crate::discovery::enr::attnets_bitfield(subscribe_all_subnets);
+    let syncnets = crate::discovery::enr::syncnets_bitfield(subscribe_all_subnets);
+    Enr::builder()
+        .add_value(
+            crate::discovery::enr::ETH2_ENR_KEY,
+            &enr_fork_id.as_ssz_bytes(),
+        )
+        .add_value(
+            crate::discovery::enr::ATTESTATION_BITFIELD_ENR_KEY,
+            &attnets.as_ssz_bytes(),
+        )
+        .add_value(
+            crate::discovery::enr::SYNC_COMMITTEE_BITFIELD_ENR_KEY,
+            &syncnets.as_ssz_bytes(),
+        )
+        .build(enr_key)
+        .unwrap()
+}
+
+// Computes the `eth2` ENR field from the network config, without the BeaconChain.
+fn enr_fork_id_from_config(network_config: &Eth2NetworkConfig) -> Result<EnrForkId, String> {
+    let spec = network_config.chain_spec::<MainnetEthSpec>()?;
+    let genesis_validators_root = network_config
+        .genesis_validators_root::<MainnetEthSpec>()?
+        .ok_or_else(|| "The genesis validators root of the network is unknown".to_string())?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| format!("The system time is before the unix epoch. {}", e))?;
+    let genesis_time = genesis_time(
+        network_config.genesis_state_bytes.as_deref(),
+        spec.min_genesis_time,
+        spec.genesis_delay,
+    );
+    let slot = now.as_secs().saturating_sub(genesis_time) / spec.seconds_per_slot;
+
+    Ok(spec.enr_fork_id::<MainnetEthSpec>(Slot::new(slot), genesis_validators_root))
+}
+
+// The genesis time of the network, read from the genesis state if it's built in. `genesis_time` is
+// the first field of the state, so the state isn't decoded as a whole.
+//
+// The genesis state of some networks is downloaded rather than built in, in which case the genesis
+// time is taken as `MIN_GENESIS_TIME + GENESIS_DELAY`, which is the genesis of the testnets whose
+// deposits were made in time (e.g. Prater). On a network whose genesis differs from that (e.g.
+// mainnet, whose genesis is 23 seconds after `MIN_GENESIS_TIME`), the fork digest is off around a
+// fork boundary.
+fn genesis_time(
+    genesis_state_bytes: Option<&[u8]>,
+    min_genesis_time: u64,
+    genesis_delay: u64,
+) -> u64 {
+    genesis_state_bytes
+        .and_then(|bytes| bytes.get(..8))
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u64::from_le_bytes)
+        .unwrap_or(min_genesis_time + genesis_delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::enr::Eth2Enr;
+
+    fn enr_fork_id() -> EnrForkId {
+        EnrForkId {
+            fork_digest: [1, 2, 3, 4],
+            next_fork_version: [0, 0, 0, 0],
+            next_fork_epoch: Epoch::max_value(),
+        }
+    }
+
+    #[test]
+    fn all_attestation_subnets_are_advertised_when_subscribing_all_subnets() {
+        let enr = build_local_enr(&CombinedKey::generate_secp256k1(), &enr_fork_id(), true);
+
+        let attnets = enr.attestation_bitfield().expect("attnets");
+        assert!(attnets.iter().all(|subscribed| subscribed));
+        assert_eq!(enr.eth2().expect("eth2"), enr_fork_id());
+    }
+
+    #[test]
+    fn no_attestation_subnets_are_advertised_by_default() {
+        let enr = build_local_enr(&CombinedKey::generate_secp256k1(), &enr_fork_id(), false);
+
+        let attnets = enr.attestation_bitfield().expect("attnets");
+        assert!(attnets.is_zero());
+    }
+
+    #[test]
+    fn genesis_time_is_read_from_the_genesis_state_or_estimated() {
+        let mut genesis_state = 1_616_508_000u64.to_le_bytes().to_vec();
+        genesis_state.extend_from_slice(&[0; 32]);
+        assert_eq!(
+            genesis_time(Some(&genesis_state), 1_614_588_812, 1_919_188),
+            1_616_508_000
+        );
+
+        // Prater, whose genesis state isn't built in.
+        assert_eq!(genesis_time(None, 1_614_588_812, 1_919_188), 1_616_508_000);
+    }
+
+    #[test]
+    fn cli_falls_back_to_the_defaults_without_flags() {
+        let cli = Cli::try_parse_from(["ray"]).expect("parse");
+        assert!(cli.network.is_none());
+        assert!(cli.checkpoint_sync_url.is_none());
+        assert_eq!(cli.port, DEFAULT_LIBP2P_PORT);
+        assert_eq!(cli.discovery_port, DEFAULT_DISCOVERY_PORT);
+        assert!(!cli.metrics);
+    }
+
+    #[test]
+    fn cli_accepts_only_the_built_in_networks() {
+        let cli =
+            Cli::try_parse_from(["ray", "--network", "mainnet", "--port", "9100"]).expect("parse");
+        assert_eq!(cli.network.as_deref(), Some("mainnet"));
+        assert_eq!(cli.port, 9100);
+
+        assert!(Cli::try_parse_from(["ray", "--network", "unknown"]).is_err());
+    }
 }
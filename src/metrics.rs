@@ -0,0 +1,78 @@
+use lazy_static::lazy_static;
+pub(crate) use lighthouse_metrics::*;
+
+lazy_static! {
+    // Discovery
+    pub(crate) static ref DISCOVERY_QUERY_DURATION: Result<Histogram> = try_create_histogram(
+        "discovery_query_duration_seconds",
+        "The time taken by a discovery query"
+    );
+    pub(crate) static ref DISCOVERY_QUERY_FOUND_PEERS: Result<Histogram> = try_create_histogram(
+        "discovery_query_found_peers",
+        "The number of peers found by a discovery query"
+    );
+    pub(crate) static ref DISCOVERY_QUERY_USABLE_PEERS: Result<Histogram> = try_create_histogram(
+        "discovery_query_usable_peers",
+        "The number of peers found by a discovery query that are worth dialing"
+    );
+    pub(crate) static ref DISCOVERY_ACTIVE_QUERIES: Result<IntGauge> = try_create_int_gauge(
+        "discovery_active_queries",
+        "The number of discovery queries running, including the subnet ones"
+    );
+
+    pub(crate) static ref PEER_MEMORY_BYTES: Result<IntGauge> = try_create_int_gauge(
+        "peer_memory_bytes",
+        "The estimated memory used by the cached ENRs and the PeerDB"
+    );
+
+    // AutoNAT
+    pub(crate) static ref NAT_REACHABILITY: Result<IntGauge> = try_create_int_gauge(
+        "nat_reachability",
+        "The reachability found by AutoNAT: 1 if public, 0 if private and -1 if unknown"
+    );
+
+    // RPC
+    pub(crate) static ref PING_ROUND_TRIP_TIME: Result<Histogram> = try_create_histogram(
+        "ping_round_trip_time_seconds",
+        "The round-trip time of a Ping to a peer"
+    );
+
+    // PeerManager
+    pub(crate) static ref CONNECTED_PEERS: Result<IntGauge> = try_create_int_gauge(
+        "connected_peers",
+        "The number of connected peers"
+    );
+    pub(crate) static ref PEERS_BY_SYNC_STATUS: Result<IntGaugeVec> = try_create_int_gauge_vec(
+        "peers_by_sync_status",
+        "The number of connected peers, by sync status",
+        &["sync_status"]
+    );
+    pub(crate) static ref DIAL_FAILURES: Result<IntCounter> = try_create_int_counter(
+        "dial_failures_total",
+        "The number of failed dials to peers"
+    );
+    pub(crate) static ref GOODBYES_SENT: Result<IntCounterVec> = try_create_int_counter_vec(
+        "goodbyes_sent_total",
+        "The number of Goodbyes sent to peers, by reason",
+        &["reason"]
+    );
+
+    // Sync
+    pub(crate) static ref SYNC_QUEUE_DEPTH: Result<IntGauge> = try_create_int_gauge(
+        "sync_queue_depth",
+        "The number of operations queued for the sync manager"
+    );
+    pub(crate) static ref SYNC_OPERATIONS_DROPPED: Result<IntCounterVec> = try_create_int_counter_vec(
+        "sync_operations_dropped_total",
+        "The number of operations dropped as the sync channel was full, by operation",
+        &["operation"]
+    );
+    pub(crate) static ref NETWORK_QUEUE_DEPTH: Result<IntGauge> = try_create_int_gauge(
+        "network_queue_depth",
+        "The number of messages queued for the Network service"
+    );
+    pub(crate) static ref SYNCING_CHAIN_TARGET_SLOT: Result<IntGauge> = try_create_int_gauge(
+        "syncing_chain_target_slot",
+        "The target head slot of the chain being synced, or 0 when not syncing"
+    );
+}
@@ -1,27 +1,96 @@
 use crate::behaviour::RequestId;
+use crate::block_cache::BlockCache;
+use crate::bootstrap::upgrade_failure;
+use crate::config::RuntimeConfig;
+use crate::discovery::enr::Eth2Enr;
 use crate::discovery::DiscoveryEvent;
 use crate::peer_manager::PeerManagerEvent;
+use crate::rpc::handler::SubstreamId;
 use crate::rpc::status::status_message;
-use crate::rpc::RpcEvent;
-use crate::sync::{SyncOperation, SyncRequestId};
+use crate::rpc::{RpcEvent, META_DATA_SEQ_NUMBER};
+use crate::sync::{SyncOperation, SyncRequestId, SyncSender};
 use crate::{
     build_network_behaviour, build_network_transport, BehaviourComposer, BehaviourComposerEvent,
     NetworkConfig, PeerDB,
 };
-use beacon_chain::BeaconChainTypes;
+use beacon_chain::{BeaconChainTypes, WhenSlotSkipped};
 use discv5::enr::CombinedKey;
 use discv5::Enr;
 use futures::StreamExt;
 use libp2p::identity::Keypair;
-use libp2p::swarm::SwarmEvent;
-use libp2p::{PeerId, Swarm, SwarmBuilder};
-use parking_lot::RwLock;
+use libp2p::swarm::{ConnectionId, DialError, ListenError, SwarmEvent};
+use libp2p::{Multiaddr, PeerId, Swarm, SwarmBuilder};
+use lighthouse_network::rpc::methods::RPCResponseErrorCode;
+use lighthouse_network::types::{EnrAttestationBitfield, EnrSyncCommitteeBitfield};
+use parking_lot::{Mutex, RwLock};
+use slot_clock::SlotClock;
+use std::collections::HashMap;
 use std::future::Future;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{Receiver, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::Sleep;
 use tracing::{debug, error, info, trace, warn};
+use types::{Hash256, MainnetEthSpec, SignedBeaconBlock, Slot};
+
+// If no swarm event is seen for this long while a sub-behaviour holds queued events, the swarm
+// is suspected to be wedged, i.e. some `poll` returned `Poll::Pending` without waking the task.
+const SWARM_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The backoff before re-listening after our listener has closed. It doubles on each failure.
+const LISTEN_RETRY_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const LISTEN_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// The maximum number of blocks served for a BlocksByRange request. A larger count is clamped.
+// Ref: https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#configuration
+const MAX_REQUEST_BLOCKS: u64 = 1024;
+
+// The sub-behaviours suspected to have wedged the swarm, i.e. the ones holding queued events while
+// no swarm event has been seen for `SWARM_STALL_TIMEOUT`.
+fn suspected_stuck_behaviours(
+    since_last_swarm_event: Duration,
+    behaviours_with_queued_events: Vec<&'static str>,
+) -> Vec<&'static str> {
+    if since_last_swarm_event < SWARM_STALL_TIMEOUT {
+        return vec![];
+    }
+    behaviours_with_queued_events
+}
+
+// Re-listening after our listener has closed, with a backoff doubling on each failure.
+struct ListenRetry {
+    // Fires when to re-listen, if scheduled.
+    delay: Option<Pin<Box<Sleep>>>,
+    // The backoff of the next re-listening.
+    backoff: Duration,
+}
+
+impl ListenRetry {
+    fn new() -> Self {
+        ListenRetry {
+            delay: None,
+            backoff: LISTEN_RETRY_INITIAL_BACKOFF,
+        }
+    }
+
+    // Schedules re-listening after the backoff, which is returned, and doubles the backoff in case
+    // it fails again.
+    fn schedule(&mut self) -> Duration {
+        let backoff = self.backoff;
+        self.delay = Some(Box::pin(tokio::time::sleep(backoff)));
+        self.backoff = (backoff * 2).min(LISTEN_RETRY_MAX_BACKOFF);
+        backoff
+    }
+
+    // The listener has been confirmed.
+    fn reset(&mut self) {
+        self.backoff = LISTEN_RETRY_INITIAL_BACKOFF;
+    }
+}
 
 /// The executor for libp2p
 struct Executor(Weak<Runtime>);
@@ -37,70 +106,193 @@ impl libp2p::swarm::Executor for Executor {
     }
 }
 
+fn swarm_config(
+    runtime: &Arc<Runtime>,
+    idle_connection_timeout: Duration,
+) -> libp2p::swarm::Config {
+    libp2p::swarm::Config::with_executor(Executor(Arc::downgrade(runtime)))
+        // Connections on which no handler keeps alive are closed after this period. The timeout
+        // only starts once every handler has released the keep-alive, e.g. the RPC handler keeps
+        // the connection alive until it's deactivated, so a connection to a peer isn't closed for
+        // being idle while the RPC is active on it.
+        .with_idle_connection_timeout(idle_connection_timeout)
+}
+
+/// The errors that prevent the network from starting.
+#[derive(Debug)]
+pub(crate) enum NetworkError {
+    /// The DNS resolver couldn't be configured.
+    Dns(String),
+    /// The transport (e.g. Noise) couldn't be configured.
+    Transport(String),
+    /// The discv5 server couldn't be started.
+    Discovery(String),
+    /// The ports to listen on aren't available.
+    ListenPort(String),
+}
+
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkError::Dns(e) => write!(f, "Failed to configure DNS: {e}"),
+            NetworkError::Transport(e) => write!(f, "Failed to configure the transport: {e}"),
+            NetworkError::Discovery(e) => write!(f, "Failed to start discovery: {e}"),
+            NetworkError::ListenPort(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+// Identifies an inbound substream, on which responses are sent.
+type InboundSubstreamKey = (PeerId, ConnectionId, SubstreamId);
+
+// The progress of serving an inbound BlocksByRange request.
+#[derive(Clone, Copy)]
+struct BlocksByRangeServing {
+    // The slot to look up the next block from.
+    next_slot: Slot,
+    // The slot after the last one to serve.
+    end_slot: Slot,
+}
+
+impl BlocksByRangeServing {
+    // Looks up the next block to serve, skipping the slots without a block. Only a single block is
+    // looked up at a time, so that memory stays bounded regardless of the range size. Returns
+    // `None` once the range is exhausted.
+    fn next_block<F>(
+        &mut self,
+        mut lookup: F,
+    ) -> Result<Option<Arc<SignedBeaconBlock<MainnetEthSpec>>>, String>
+    where
+        F: FnMut(Slot) -> Result<Option<Arc<SignedBeaconBlock<MainnetEthSpec>>>, String>,
+    {
+        while self.next_slot < self.end_slot {
+            let slot = self.next_slot;
+            self.next_slot += 1;
+            match lookup(slot) {
+                Ok(Some(block)) => return Ok(Some(block)),
+                // The slot has been skipped.
+                Ok(None) => {}
+                Err(e) => {
+                    return Err(format!(
+                        "Failed to look up a block to serve. slot: {slot}, error: {e}"
+                    ))
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+// The blocks looked up on a blocking thread, to be sent on the inbound substream.
+enum BlockLookup {
+    // The next block of a BlocksByRange request, with the progress after the lookup.
+    ByRange {
+        key: InboundSubstreamKey,
+        serving: BlocksByRangeServing,
+        result: Result<Option<Arc<SignedBeaconBlock<MainnetEthSpec>>>, String>,
+    },
+    // The blocks of a BlocksByRoot request that we have.
+    ByRoot {
+        key: InboundSubstreamKey,
+        blocks: Vec<Arc<SignedBeaconBlock<MainnetEthSpec>>>,
+    },
+}
+
 pub trait ReqId: Send + 'static + std::fmt::Debug + Copy + Clone {}
 impl<T> ReqId for T where T: Send + 'static + std::fmt::Debug + Copy + Clone {}
 
 pub(crate) struct Network<T: BeaconChainTypes> {
     swarm: Swarm<BehaviourComposer<ApplicationRequestId>>,
-    network_receiver: UnboundedReceiver<NetworkMessage>,
+    network_receiver: Receiver<NetworkMessage>,
     lh_beacon_chain: Arc<beacon_chain::BeaconChain<T>>,
-    sync_sender: UnboundedSender<SyncOperation>,
+    sync_sender: SyncSender,
+    // Recently imported blocks, consulted before the store.
+    block_cache: Arc<Mutex<BlockCache>>,
+    // The inbound BlocksByRange requests being served.
+    blocks_by_range_serving: HashMap<InboundSubstreamKey, BlocksByRangeServing>,
+    // The blocks to serve are looked up on blocking threads, so that the store reads don't hold
+    // up the swarm. The lookups are bounded by the inbound requests being served.
+    block_lookup_sender: UnboundedSender<BlockLookup>,
+    block_lookup_receiver: UnboundedReceiver<BlockLookup>,
+    // When the Pings awaiting a Pong were sent, to measure the round-trip time.
+    pings_sent: HashMap<PeerId, Instant>,
+    // Re-listening, if we have no listener.
+    listen_retry: ListenRetry,
+    // The address libp2p listens on.
+    listen_multiaddr: Multiaddr,
+    // The clock disagreement tolerated when checking the head slot of a peer.
+    max_clock_disparity: Duration,
+    // Fires at the next fork, to advertise its fork digest.
+    next_fork_update: Option<Pin<Box<Sleep>>>,
 }
 
 impl<T> Network<T>
 where
-    T: BeaconChainTypes,
+    T: BeaconChainTypes<EthSpec = MainnetEthSpec>,
 {
     #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new(
-        network_receiver: UnboundedReceiver<NetworkMessage>,
+        network_receiver: Receiver<NetworkMessage>,
         lh_beacon_chain: Arc<beacon_chain::BeaconChain<T>>,
-        sync_sender: UnboundedSender<SyncOperation>,
+        sync_sender: SyncSender,
         key_pair: Keypair,
         enr: Enr,
         enr_key: CombinedKey,
         network_config: NetworkConfig,
         peer_db: Arc<RwLock<PeerDB>>,
+        peer_db_path: PathBuf,
+        block_cache: Arc<Mutex<BlockCache>>,
         runtime: Arc<Runtime>,
-    ) -> Self {
-        let transport = build_network_transport(key_pair.clone()).await;
+        runtime_config: RuntimeConfig,
+    ) -> Result<Self, NetworkError> {
+        let transport = build_network_transport(key_pair.clone()).await?;
+        let listen_multiaddr =
+            listen_multiaddr(network_config.listen_address, network_config.libp2p_port);
         let behaviour = build_network_behaviour(
+            key_pair.public().to_peer_id(),
             enr,
             enr_key,
             network_config,
             peer_db,
+            peer_db_path,
             lh_beacon_chain.clone(),
+            &runtime_config,
         )
-        .await;
+        .await?;
         let swarm = SwarmBuilder::with_existing_identity(key_pair)
             .with_tokio()
             .with_other_transport(|_| transport)
             .expect("infallible")
             .with_behaviour(|_| behaviour)
             .expect("infallible")
-            .with_swarm_config(|_| {
-                libp2p::swarm::Config::with_executor(Executor(Arc::downgrade(&runtime)))
-            })
+            .with_swarm_config(|_| swarm_config(&runtime, runtime_config.idle_connection_timeout))
             .build();
 
-        Network {
+        let next_fork_update = next_fork_delay(&lh_beacon_chain);
+        let (block_lookup_sender, block_lookup_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        Ok(Network {
             swarm,
             network_receiver,
             lh_beacon_chain,
             sync_sender,
-        }
+            block_cache,
+            blocks_by_range_serving: HashMap::new(),
+            block_lookup_sender,
+            block_lookup_receiver,
+            pings_sent: HashMap::new(),
+            listen_retry: ListenRetry::new(),
+            listen_multiaddr,
+            max_clock_disparity: runtime_config.max_clock_disparity,
+            next_fork_update,
+        })
     }
 
     async fn start(&mut self) {
-        let listen_multiaddr = {
-            let mut multiaddr =
-                libp2p::core::multiaddr::Multiaddr::from(std::net::Ipv4Addr::new(0, 0, 0, 0));
-            multiaddr.push(libp2p::core::multiaddr::Protocol::Tcp(9000));
-            multiaddr
-        };
-
         self.swarm
-            .listen_on(listen_multiaddr)
+            .listen_on(self.listen_multiaddr.clone())
             .expect("Swarm starts listening");
 
         loop {
@@ -115,21 +307,40 @@ where
         self.start().await;
 
         let fut = async move {
+            let mut last_swarm_event = tokio::time::Instant::now();
+            // NOTE: Each tick also wakes the task, so the swarm gets polled again even if a
+            // sub-behaviour has failed to register a waker.
+            let mut watchdog = tokio::time::interval(SWARM_STALL_TIMEOUT);
+
             loop {
                 tokio::select! {
                     // SEE:
                     // https://github.com/sigp/lighthouse/blob/9667dc2f0379272fe0f36a2ec015c5a560bca652/beacon_node/network/src/service.rs#L309
                     // https://github.com/sigp/lighthouse/blob/0aee7ec873bcc7206b9acf2741f46c209b510c57/beacon_node/eth2_libp2p/src/service.rs#L305
                     event = self.swarm.select_next_some() => {
+                        last_swarm_event = tokio::time::Instant::now();
                         match event {
                             SwarmEvent::Behaviour(behaviour_event) => self.handle_behaviour_event(behaviour_event),
                             SwarmEvent::ConnectionEstablished { peer_id, .. } => info!("SwarmEvent::ConnectionEstablished. peer_id: {}", peer_id),
+                            SwarmEvent::ConnectionClosed { peer_id, connection_id, num_established, .. } => self.on_connection_closed(peer_id, connection_id, num_established),
+                            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => self.on_outgoing_connection_error(peer_id, &error),
+                            SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => self.on_incoming_connection_error(&send_back_addr, &error),
+                            SwarmEvent::NewListenAddr { address, .. } => {
+                                info!("Listening on {}", address);
+                                self.listen_retry.reset();
+                            }
+                            SwarmEvent::ListenerClosed { addresses, reason, .. } => self.on_listener_closed(&addresses, reason),
+                            SwarmEvent::ListenerError { error, .. } => error!("A listener has encountered an error: {}", error),
                             ev => {
                                 debug!("SwarmEvent: {:?}", ev);
                             }
                         }
                     }
                     Some(message) = self.network_receiver.recv() => self.on_network_message(message),
+                    Some(lookup) = self.block_lookup_receiver.recv() => self.on_block_lookup(lookup),
+                    _ = watchdog.tick() => self.check_swarm_liveness(last_swarm_event),
+                    _ = wait_for_delay(&mut self.listen_retry.delay) => self.retry_listen(),
+                    _ = wait_for_delay(&mut self.next_fork_update) => self.on_fork_boundary(),
                 }
             }
         };
@@ -137,7 +348,107 @@ where
         runtime.spawn(fut);
     }
 
-    fn handle_behaviour_event(&mut self, event: BehaviourComposerEvent) {
+    fn on_listener_closed(&mut self, addresses: &[Multiaddr], reason: Result<(), std::io::Error>) {
+        error!(
+            "A listener has closed. addresses: {:?}, reason: {:?}",
+            addresses, reason
+        );
+
+        if self.swarm.listeners().next().is_none() {
+            self.schedule_listen_retry();
+        }
+    }
+
+    // Schedules re-listening, since no inbound connection is accepted without a listener.
+    fn schedule_listen_retry(&mut self) {
+        let backoff = self.listen_retry.schedule();
+        error!("No listener is running. Re-listening in {:?}.", backoff);
+    }
+
+    // Advertises the fork digest of the new fork, so that the peers on it keep finding us.
+    // Ref: https://github.com/sigp/lighthouse/blob/878027654f0ebc498168c7d9f0646fc1d7f5d710/beacon_node/network/src/service.rs#L483
+    fn on_fork_boundary(&mut self) {
+        let enr_fork_id = self.lh_beacon_chain.enr_fork_id();
+        info!(
+            "Crossed a fork boundary. fork_digest: {}",
+            hex::encode(enr_fork_id.fork_digest)
+        );
+        self.swarm
+            .behaviour_mut()
+            .discovery
+            .update_eth2_enr(enr_fork_id);
+        self.next_fork_update = next_fork_delay(&self.lh_beacon_chain);
+    }
+
+    fn retry_listen(&mut self) {
+        self.listen_retry.delay = None;
+
+        // The backoff is reset once a `NewListenAddr` event confirms the listener.
+        if let Err(e) = self.swarm.listen_on(self.listen_multiaddr.clone()) {
+            error!("Failed to re-listen: {}", e);
+            self.schedule_listen_retry();
+        }
+    }
+
+    fn on_connection_closed(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        num_established: u32,
+    ) {
+        // Stop serving the requests received on the connection.
+        self.blocks_by_range_serving
+            .retain(|(p, c, _), _| *p != peer_id || *c != connection_id);
+
+        if num_established == 0 {
+            self.pings_sent.remove(&peer_id);
+            self.sync_sender
+                .send(SyncOperation::PeerDisconnected(peer_id))
+                .unwrap_or_else(|e| {
+                    error!("Failed to send message to the sync manager: {}", e);
+                });
+        }
+    }
+
+    fn check_swarm_liveness(&self, last_swarm_event: tokio::time::Instant) {
+        let behaviours = suspected_stuck_behaviours(
+            last_swarm_event.elapsed(),
+            self.swarm.behaviour().behaviours_with_queued_events(),
+        );
+        if !behaviours.is_empty() {
+            warn!(
+                "No swarm event for {:?} despite queued events. The swarm may be wedged. suspected behaviours: {:?}",
+                last_swarm_event.elapsed(),
+                behaviours
+            );
+        }
+    }
+
+    fn on_outgoing_connection_error(&self, peer_id: Option<PeerId>, error: &DialError) {
+        if let DialError::Transport(errors) = error {
+            for (address, transport_error) in errors {
+                if let Some(failure) = upgrade_failure(transport_error) {
+                    warn!("[{peer_id:?}] Outgoing connection failed: {failure}. address: {address}, error: {transport_error:?}");
+                    return;
+                }
+            }
+        }
+
+        debug!("[{peer_id:?}] Outgoing connection failed. error: {error}");
+    }
+
+    fn on_incoming_connection_error(&self, send_back_addr: &Multiaddr, error: &ListenError) {
+        if let ListenError::Transport(transport_error) = error {
+            if let Some(failure) = upgrade_failure(transport_error) {
+                warn!("Incoming connection failed: {failure}. address: {send_back_addr}, error: {transport_error:?}");
+                return;
+            }
+        }
+
+        debug!("Incoming connection failed. address: {send_back_addr}, error: {error}");
+    }
+
+    fn handle_behaviour_event(&mut self, event: BehaviourComposerEvent<ApplicationRequestId>) {
         match event {
             BehaviourComposerEvent::Discovery(discovery_event) => {
                 self.handle_discovery_event(discovery_event)
@@ -146,6 +457,46 @@ where
                 self.handle_peer_manager_event(peer_manager_event)
             }
             BehaviourComposerEvent::Rpc(rpc_event) => self.handle_rpc_event(rpc_event),
+            BehaviourComposerEvent::AutoNat(autonat_event) => {
+                self.handle_autonat_event(autonat_event)
+            }
+        }
+    }
+
+    // /////////////////////////////////////////////////////////////////////////////////////////////
+    // AutoNAT
+    // /////////////////////////////////////////////////////////////////////////////////////////////
+    fn handle_autonat_event(&mut self, event: libp2p::autonat::Event) {
+        match event {
+            libp2p::autonat::Event::StatusChanged { old, new } => {
+                let reachability = match &new {
+                    libp2p::autonat::NatStatus::Public(address) => {
+                        info!("AutoNAT: We are publicly reachable at {address}. Inbound connections are expected. previous: {old:?}");
+                        1
+                    }
+                    libp2p::autonat::NatStatus::Private => {
+                        warn!("AutoNAT: We are not publicly reachable, so we don't expect inbound connections. previous: {old:?}");
+                        0
+                    }
+                    libp2p::autonat::NatStatus::Unknown => {
+                        info!("AutoNAT: Reachability is unknown. previous: {old:?}");
+                        -1
+                    }
+                };
+                crate::metrics::set_gauge(&crate::metrics::NAT_REACHABILITY, reachability);
+                // AutoNAT confirms the public address right after this event, which the discovery
+                // advertises in the local ENR only if we are publicly reachable.
+                self.swarm
+                    .behaviour_mut()
+                    .discovery
+                    .set_publicly_reachable(new.is_public());
+            }
+            libp2p::autonat::Event::InboundProbe(probe) => {
+                debug!("AutoNAT: Inbound probe. {probe:?}");
+            }
+            libp2p::autonat::Event::OutboundProbe(probe) => {
+                debug!("AutoNAT: Outbound probe. {probe:?}");
+            }
         }
     }
 
@@ -163,7 +514,15 @@ where
                 }
 
                 for peer in peer_ids {
-                    self.swarm.behaviour_mut().peer_manager.dial_peer(peer);
+                    let peer_manager = &mut self.swarm.behaviour_mut().peer_manager;
+                    if peer_manager.is_connected_or_dialing(&peer) {
+                        trace!(
+                            "[{}] Skipped dialing the discovered peer as it's connected or being dialed.",
+                            peer
+                        );
+                        continue;
+                    }
+                    peer_manager.dial_peer(peer);
                 }
             }
         };
@@ -175,7 +534,12 @@ where
     fn handle_peer_manager_event(&mut self, event: PeerManagerEvent) {
         match event {
             PeerManagerEvent::PeerConnectedIncoming(peer_id) => {
-                warn!("PeerManagerEvent::PeerConnectedIncoming, but no implementation for the event for now. peer_id: {}", peer_id);
+                // The peer dialed us, so it sends Status first. `PeerManager` sends ours if it
+                // doesn't shortly, and disconnects the peer if the handshake doesn't complete.
+                debug!(
+                    "[{}] A peer has dialed us. Waiting for its Status.",
+                    peer_id
+                );
             }
             PeerManagerEvent::PeerConnectedOutgoing(peer_id) => {
                 // Spec: The dialing client MUST send a Status request upon connection.
@@ -190,6 +554,15 @@ where
                 let behaviour = self.swarm.behaviour_mut();
                 if !behaviour.discovery.has_active_queries() {
                     behaviour.discovery.discover_peers();
+                    // Also look for peers on the subnets we are subscribed to but have no peer
+                    // on.
+                    match behaviour.discovery.local_enr().attestation_bitfield() {
+                        Ok(attnets) => {
+                            let subnets = behaviour.peer_manager.subnets_without_peers(&attnets);
+                            behaviour.discovery.discover_subnet_peers(subnets);
+                        }
+                        Err(e) => warn!("Failed to read the attnets of the local ENR: {e}"),
+                    }
                 }
             }
             PeerManagerEvent::SendStatus(peer_id) => {
@@ -206,13 +579,30 @@ where
                     goodbye_reason,
                 );
             }
+            PeerManagerEvent::Ping(peer_id) => self.ping_peer(peer_id),
+            PeerManagerEvent::MetaData(peer_id) => {
+                let behaviour = self.swarm.behaviour_mut();
+                if !behaviour.peer_manager.supports_protocol(
+                    &peer_id,
+                    &lighthouse_network::rpc::protocol::Protocol::MetaData,
+                ) {
+                    debug!(
+                        "[{}] Skipped sending a MetaData request as the peer doesn't support it.",
+                        peer_id
+                    );
+                    return;
+                }
+                behaviour
+                    .rpc
+                    .send_meta_data_request(RequestId::Internal, peer_id);
+            }
         }
     }
 
     // /////////////////////////////////////////////////////////////////////////////////////////////
     // RPC
     // /////////////////////////////////////////////////////////////////////////////////////////////
-    fn handle_rpc_event(&mut self, event: RpcEvent) {
+    fn handle_rpc_event(&mut self, event: RpcEvent<RequestId<ApplicationRequestId>>) {
         match event {
             RpcEvent::ReceivedRequest(request) => match &request.request {
                 lighthouse_network::rpc::protocol::InboundRequest::Status(message) => {
@@ -223,9 +613,9 @@ where
                             request.peer_id,
                             request.connection_id,
                             request.substream_id,
-                            lighthouse_network::Response::Status(
-                                status_message(&self.lh_beacon_chain),
-                            ),
+                            lighthouse_network::Response::Status(status_message(
+                                &self.lh_beacon_chain,
+                            )),
                         );
                     }
                 }
@@ -234,15 +624,97 @@ where
                     // disconnecting here. The RPC handler will automatically
                     // disconnect for us.
                     // The actual disconnection event will be relayed from `PeerManager` to the application.
-                    debug!("[{}] Peer sent goodbye. reason: {}", request.peer_id, reason);
-                },
-                lighthouse_network::rpc::protocol::InboundRequest::BlocksByRange(blocks_by_range_request) => warn!("[{}] Received `InboundRequest::BlocksByRange` (request: {:?}) but it was not handled.", request.peer_id, blocks_by_range_request),
-                lighthouse_network::rpc::protocol::InboundRequest::BlocksByRoot(blocks_by_root_request) => warn!("[{}] Received `InboundRequest::BlocksByRoot` (request: {:?}) but it was not handled.", request.peer_id, blocks_by_root_request),
+                    debug!(
+                        "[{}] Peer sent goodbye. reason: {}",
+                        request.peer_id, reason
+                    );
+                    self.swarm
+                        .behaviour_mut()
+                        .peer_manager
+                        .goodbye_received(&request.peer_id, reason.clone());
+                }
+                lighthouse_network::rpc::protocol::InboundRequest::BlocksByRange(
+                    blocks_by_range_request,
+                ) => {
+                    // The blocks are sent one by one, each time the previous one has been sent.
+                    if let Some((start_slot, end_slot)) = self.blocks_by_range_slots(
+                        &request.peer_id,
+                        Slot::new(*blocks_by_range_request.start_slot()),
+                        *blocks_by_range_request.count(),
+                    ) {
+                        let key = (request.peer_id, request.connection_id, request.substream_id);
+                        self.blocks_by_range_serving.insert(
+                            key,
+                            BlocksByRangeServing {
+                                next_slot: start_slot,
+                                end_slot,
+                            },
+                        );
+                        self.serve_next_block_by_range(key);
+                    } else {
+                        // Terminate the stream. This is the only frame as we can't serve the range.
+                        self.swarm.behaviour_mut().rpc.send_response(
+                            request.peer_id,
+                            request.connection_id,
+                            request.substream_id,
+                            lighthouse_network::Response::BlocksByRange(None),
+                        );
+                    }
+                }
+                lighthouse_network::rpc::protocol::InboundRequest::BlocksByRoot(
+                    blocks_by_root_request,
+                ) => {
+                    // The blocks are sent once they have all been looked up.
+                    let key = (request.peer_id, request.connection_id, request.substream_id);
+                    let roots = blocks_by_root_request
+                        .block_roots()
+                        .iter()
+                        .copied()
+                        .collect::<Vec<_>>();
+                    let lh_beacon_chain = self.lh_beacon_chain.clone();
+                    let block_cache = self.block_cache.clone();
+                    let sender = self.block_lookup_sender.clone();
+                    tokio::task::spawn_blocking(move || {
+                        // Spec: Clients MUST respond with at least one block, if they have it.
+                        // Blocks we don't have are simply skipped.
+                        // https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#beaconblocksbyroot
+                        let blocks = roots
+                            .iter()
+                            .filter_map(|root| get_block(&lh_beacon_chain, &block_cache, root))
+                            .collect();
+                        // The Network has stopped, i.e. the node is shutting down.
+                        let _ = sender.send(BlockLookup::ByRoot { key, blocks });
+                    });
+                }
                 lighthouse_network::rpc::protocol::InboundRequest::BlobsByRange(_) => todo!(),
                 lighthouse_network::rpc::protocol::InboundRequest::BlobsByRoot(_) => todo!(),
-                lighthouse_network::rpc::protocol::InboundRequest::Ping(ping) => warn!("[{}] Received `InboundRequest::Ping` (ping: {:?}) but it was not handled.", request.peer_id, ping),
-                lighthouse_network::rpc::protocol::InboundRequest::MetaData(_) => warn!("[{}] Received `InboundRequest::MetaData` but it was not handled.", request.peer_id),
-                lighthouse_network::rpc::protocol::InboundRequest::LightClientBootstrap(_) => todo!(),
+                lighthouse_network::rpc::protocol::InboundRequest::Ping(ping) => {
+                    self.swarm.behaviour_mut().rpc.send_pong(
+                        request.peer_id,
+                        request.connection_id,
+                        request.substream_id,
+                        META_DATA_SEQ_NUMBER,
+                    );
+                    self.swarm
+                        .behaviour_mut()
+                        .peer_manager
+                        .ping_request(&request.peer_id, ping.data);
+                }
+                lighthouse_network::rpc::protocol::InboundRequest::MetaData(meta_data_request) => {
+                    let meta_data = local_meta_data(
+                        &self.swarm.behaviour().discovery.local_enr(),
+                        meta_data_request,
+                    );
+                    self.swarm.behaviour_mut().rpc.send_meta_data_response(
+                        request.peer_id,
+                        request.connection_id,
+                        request.substream_id,
+                        meta_data,
+                    );
+                }
+                lighthouse_network::rpc::protocol::InboundRequest::LightClientBootstrap(_) => {
+                    todo!()
+                }
             },
             RpcEvent::ReceivedResponse(response) => match &response.response {
                 lighthouse_network::rpc::methods::RPCResponse::Status(message) => {
@@ -253,14 +725,105 @@ where
                             .statusd_peer(response.peer_id);
                     }
                 }
-                lighthouse_network::rpc::methods::RPCResponse::BlocksByRange(_) => {}
-                lighthouse_network::rpc::methods::RPCResponse::BlocksByRoot(_) => {}
+                lighthouse_network::rpc::methods::RPCResponse::BlocksByRange(block) => {
+                    if let RequestId::Application(ApplicationRequestId::Sync(sync_request_id)) =
+                        response.request_id
+                    {
+                        self.sync_sender
+                            .send(SyncOperation::BlockByRange(
+                                response.peer_id,
+                                sync_request_id,
+                                block.clone(),
+                            ))
+                            .unwrap_or_else(|e| {
+                                error!("Failed to send message to the sync manager: {}", e);
+                            });
+                    }
+                }
+                lighthouse_network::rpc::methods::RPCResponse::BlocksByRoot(block) => {
+                    self.sync_sender
+                        .send(SyncOperation::BlockByRoot(response.peer_id, block.clone()))
+                        .unwrap_or_else(|e| {
+                            error!("Failed to send message to the sync manager: {}", e);
+                        });
+                }
                 lighthouse_network::rpc::methods::RPCResponse::BlobsByRange(_) => todo!(),
                 lighthouse_network::rpc::methods::RPCResponse::BlobsByRoot(_) => todo!(),
-                lighthouse_network::rpc::methods::RPCResponse::Pong(_) => {}
-                lighthouse_network::rpc::methods::RPCResponse::MetaData(_) => {}
+                lighthouse_network::rpc::methods::RPCResponse::Pong(ping) => {
+                    if let Some(sent) = self.pings_sent.remove(&response.peer_id) {
+                        let round_trip_time = sent.elapsed();
+                        info!(
+                            "[{}] Received Pong. round-trip time: {:?}",
+                            response.peer_id, round_trip_time
+                        );
+                        crate::metrics::observe_duration(
+                            &crate::metrics::PING_ROUND_TRIP_TIME,
+                            round_trip_time,
+                        );
+                    }
+                    self.swarm
+                        .behaviour_mut()
+                        .peer_manager
+                        .pong_response(&response.peer_id, ping.data);
+                }
+                lighthouse_network::rpc::methods::RPCResponse::MetaData(meta_data) => {
+                    self.swarm
+                        .behaviour_mut()
+                        .peer_manager
+                        .meta_data_response(&response.peer_id, meta_data.clone());
+                }
                 lighthouse_network::rpc::methods::RPCResponse::LightClientBootstrap(_) => todo!(),
             },
+            RpcEvent::UnsupportedProtocol(peer_id, protocol) => {
+                self.swarm
+                    .behaviour_mut()
+                    .peer_manager
+                    .unsupported_protocol(&peer_id, protocol);
+            }
+            RpcEvent::ResponsesSent(peer_id, connection_id, substream_id) => {
+                self.serve_next_block_by_range((peer_id, connection_id, substream_id));
+            }
+            RpcEvent::StreamTerminated(peer_id, request_id) => match request_id {
+                RequestId::Application(ApplicationRequestId::Sync(sync_request_id)) => {
+                    self.sync_sender
+                        .send(SyncOperation::StreamTerminated(peer_id, sync_request_id))
+                        .unwrap_or_else(|e| {
+                            error!("Failed to send message to the sync manager: {}", e);
+                        });
+                }
+                RequestId::Application(ApplicationRequestId::Router) | RequestId::Internal => {}
+            },
+            RpcEvent::ResponseFailed(peer_id, connection_id, substream_id, rpc_error) => {
+                // Nothing more can be sent on the substream.
+                self.blocks_by_range_serving
+                    .remove(&(peer_id, connection_id, substream_id));
+                self.swarm
+                    .behaviour_mut()
+                    .peer_manager
+                    .response_failed(&peer_id, &rpc_error);
+            }
+            RpcEvent::ConnectionClosed(peer_id) => {
+                self.swarm
+                    .behaviour_mut()
+                    .peer_manager
+                    .rpc_connection_closed(&peer_id);
+            }
+            RpcEvent::ResponseError(peer_id, request_id, code, message) => {
+                warn!(
+                    "[{}] The peer responded with an error. request_id: {:?}, code: {}, message: {}",
+                    peer_id, request_id, code, message
+                );
+                match request_id {
+                    RequestId::Application(ApplicationRequestId::Sync(sync_request_id)) => {
+                        self.sync_sender
+                            .send(SyncOperation::ResponseError(peer_id, sync_request_id, code))
+                            .unwrap_or_else(|e| {
+                                error!("Failed to send message to the sync manager: {}", e);
+                            });
+                    }
+                    RequestId::Application(ApplicationRequestId::Router) | RequestId::Internal => {}
+                }
+            }
         }
     }
 
@@ -282,7 +845,7 @@ where
             true
         } else {
             info!("[{}] the remote chain is not relevant to ours.", peer_id);
-            self.swarm.behaviour_mut().peer_manager.goodbye(
+            self.swarm.behaviour_mut().peer_manager.disconnect_peer(
                 peer_id,
                 lighthouse_network::rpc::GoodbyeReason::IrrelevantNetwork,
             );
@@ -309,12 +872,25 @@ where
             return false;
         }
 
-        if remote_status.head_slot > self.lh_beacon_chain.slot().expect("slot") {
-            info!(
-                "[{}] The node is not relevant to us: Different system clocks or genesis time",
-                peer_id
-            );
-            return false;
+        match tolerated_head_slot(&self.lh_beacon_chain.slot_clock, self.max_clock_disparity) {
+            Some(slot) => {
+                if remote_status.head_slot > slot {
+                    info!(
+                        "[{}] The node is not relevant to us: Different system clocks or genesis time. Ours:{} Theirs:{}",
+                        peer_id,
+                        slot,
+                        remote_status.head_slot
+                    );
+                    return false;
+                }
+            }
+            None => {
+                // The head can't be compared before genesis, so the peer is treated leniently.
+                debug!(
+                    "[{}] Skipped checking the head slot of the peer as the genesis hasn't come yet.",
+                    peer_id
+                );
+            }
         }
 
         // NOTE: We can implement more checks to be production-ready.
@@ -323,15 +899,180 @@ where
         true
     }
 
+    // Returns the slots to serve for a BlocksByRange request, or `None` for a range we lack, e.g.
+    // below the checkpoint anchor.
+    fn blocks_by_range_slots(
+        &self,
+        peer_id: &PeerId,
+        start_slot: Slot,
+        count: u64,
+    ) -> Option<(Slot, Slot)> {
+        let oldest_block_slot = self
+            .lh_beacon_chain
+            .store
+            .get_anchor_info()
+            .map(|anchor| anchor.oldest_block_slot)
+            .unwrap_or_else(|| Slot::new(0));
+        let head_slot = self
+            .lh_beacon_chain
+            .canonical_head
+            .cached_head()
+            .head_slot();
+
+        let slots = servable_slots(start_slot, count, oldest_block_slot, head_slot);
+        if slots.is_none() {
+            debug!(
+                "[{peer_id}] Can't serve the BlocksByRange request. start_slot: {start_slot}, oldest_block_slot: {oldest_block_slot}, head_slot: {head_slot}"
+            );
+        }
+        slots
+    }
+
+    // Looks up the next block of a BlocksByRange request being served, on a blocking thread. Blocks
+    // are looked up one at a time, once the previous one has been sent, so that memory stays
+    // bounded regardless of the range size.
+    fn serve_next_block_by_range(&mut self, key: InboundSubstreamKey) {
+        // The substream isn't serving BlocksByRange (e.g. it's serving BlocksByRoot).
+        let Some(mut serving) = self.blocks_by_range_serving.get(&key).copied() else {
+            return;
+        };
+        let lh_beacon_chain = self.lh_beacon_chain.clone();
+        let block_cache = self.block_cache.clone();
+        let sender = self.block_lookup_sender.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = serving.next_block(|slot| {
+                match lh_beacon_chain.block_root_at_slot(slot, WhenSlotSkipped::None) {
+                    Ok(Some(root)) => Ok(get_block(&lh_beacon_chain, &block_cache, &root)),
+                    Ok(None) => Ok(None),
+                    Err(e) => Err(format!("{e:?}")),
+                }
+            });
+            // The Network has stopped, i.e. the node is shutting down.
+            let _ = sender.send(BlockLookup::ByRange {
+                key,
+                serving,
+                result,
+            });
+        });
+    }
+
+    // Sends the blocks looked up for an inbound request. A BlocksByRange request is sent its next
+    // block, or the stream termination if no blocks are left.
+    fn on_block_lookup(&mut self, lookup: BlockLookup) {
+        let (key, serving, result) = match lookup {
+            BlockLookup::ByRange {
+                key,
+                serving,
+                result,
+            } => (key, serving, result),
+            BlockLookup::ByRoot { key, blocks } => {
+                let (peer_id, connection_id, substream_id) = key;
+                let rpc = &mut self.swarm.behaviour_mut().rpc;
+                for block in blocks {
+                    rpc.send_response(
+                        peer_id,
+                        connection_id,
+                        substream_id,
+                        lighthouse_network::Response::BlocksByRoot(Some(block)),
+                    );
+                }
+                // Terminate the stream.
+                rpc.send_response(
+                    peer_id,
+                    connection_id,
+                    substream_id,
+                    lighthouse_network::Response::BlocksByRoot(None),
+                );
+                return;
+            }
+        };
+
+        // The connection has closed or the substream has failed during the lookup.
+        if self.blocks_by_range_serving.remove(&key).is_none() {
+            return;
+        }
+        let (peer_id, connection_id, substream_id) = key;
+
+        match result {
+            Ok(Some(block)) => {
+                self.blocks_by_range_serving.insert(key, serving);
+                self.swarm.behaviour_mut().rpc.send_response(
+                    peer_id,
+                    connection_id,
+                    substream_id,
+                    lighthouse_network::Response::BlocksByRange(Some(block)),
+                );
+            }
+            // Terminate the stream.
+            Ok(None) => self.swarm.behaviour_mut().rpc.send_response(
+                peer_id,
+                connection_id,
+                substream_id,
+                lighthouse_network::Response::BlocksByRange(None),
+            ),
+            // Terminating the stream would tell the peer that the rest of the slots are empty.
+            Err(e) => {
+                error!("[{peer_id}] {e}");
+                self.swarm.behaviour_mut().rpc.send_error_response(
+                    peer_id,
+                    connection_id,
+                    substream_id,
+                    RPCResponseErrorCode::ServerError,
+                    "Failed to look up the blocks".to_string(),
+                );
+            }
+        }
+    }
+
     /// Handle a message sent to the network service.
     fn on_network_message(&mut self, message: NetworkMessage) {
+        crate::metrics::set_gauge(
+            &crate::metrics::NETWORK_QUEUE_DEPTH,
+            self.network_receiver.len() as i64,
+        );
         match message {
             NetworkMessage::SendRequest {
                 peer_id,
                 request,
                 request_id,
             } => self.send_request(peer_id, request, request_id),
+            NetworkMessage::PingPeer(peer_id) => self.ping_peer(peer_id),
+            NetworkMessage::QueryIdentity(reply) => {
+                // The requester may have gone, which is fine.
+                let _ = reply.send(self.identity());
+            }
+        }
+    }
+
+    fn identity(&self) -> serde_json::Value {
+        serde_json::json!({
+            "peer_id": self.swarm.local_peer_id().to_string(),
+            "enr": self.swarm.behaviour().discovery.local_enr().to_base64(),
+            "p2p_addresses": self
+                .swarm
+                .listeners()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    fn ping_peer(&mut self, peer_id: PeerId) {
+        let behaviour = self.swarm.behaviour_mut();
+        if !behaviour
+            .peer_manager
+            .supports_protocol(&peer_id, &lighthouse_network::rpc::protocol::Protocol::Ping)
+        {
+            warn!(
+                "[{}] Skipped sending a Ping as the peer doesn't support it.",
+                peer_id
+            );
+            return;
         }
+
+        self.pings_sent.insert(peer_id, Instant::now());
+        behaviour
+            .rpc
+            .send_ping(RequestId::Internal, peer_id, META_DATA_SEQ_NUMBER);
     }
 
     fn send_request(
@@ -340,11 +1081,144 @@ where
         request: lighthouse_network::Request,
         request_id: ApplicationRequestId,
     ) {
-        self.swarm.behaviour_mut().rpc.send_request(
-            peer_id,
-            request,
-            RequestId::Application(request_id),
-        );
+        let request: lighthouse_network::rpc::outbound::OutboundRequest<MainnetEthSpec> =
+            request.into();
+        let protocol = request.versioned_protocol().protocol();
+
+        let behaviour = self.swarm.behaviour_mut();
+        if !behaviour
+            .peer_manager
+            .supports_protocol(&peer_id, &protocol)
+        {
+            warn!(
+                "[{}] Skipped sending a request as the peer doesn't support the protocol: {}",
+                peer_id, protocol
+            );
+            // Sync is told the request has failed, so that it doesn't wait for the response
+            // forever. The peer isn't at fault, so the error isn't penalized.
+            if let ApplicationRequestId::Sync(sync_request_id) = request_id {
+                self.sync_sender
+                    .send(SyncOperation::ResponseError(
+                        peer_id,
+                        sync_request_id,
+                        RPCResponseErrorCode::ResourceUnavailable,
+                    ))
+                    .unwrap_or_else(|e| {
+                        error!("Failed to send message to the sync manager: {}", e);
+                    });
+            }
+            return;
+        }
+
+        behaviour
+            .rpc
+            .send_request(peer_id, request, RequestId::Application(request_id));
+    }
+}
+
+// Looks up a block by its root, checking the recent blocks cache before the store. The store is
+// read without holding the lock of the cache, which sync takes to insert the imported blocks, and
+// the block read isn't cached.
+fn get_block<T: BeaconChainTypes>(
+    lh_beacon_chain: &beacon_chain::BeaconChain<T>,
+    block_cache: &Mutex<BlockCache>,
+    root: &Hash256,
+) -> Option<Arc<SignedBeaconBlock<MainnetEthSpec>>> {
+    if let Some(block) = block_cache.lock().get(root) {
+        return Some(block);
+    }
+    match lh_beacon_chain.store.get_full_block(root) {
+        Ok(block) => block.map(Arc::new),
+        Err(e) => {
+            error!("Failed to look up a block in the store. root: {root}, error: {e:?}");
+            None
+        }
+    }
+}
+
+// Returns the slots of a BlocksByRange request that we hold the blocks of, i.e. from the oldest
+// block (the checkpoint anchor) up to the head, or `None` if we hold none of them. At most
+// `MAX_REQUEST_BLOCKS` slots are served.
+fn servable_slots(
+    start_slot: Slot,
+    count: u64,
+    oldest_block_slot: Slot,
+    head_slot: Slot,
+) -> Option<(Slot, Slot)> {
+    if start_slot < oldest_block_slot || start_slot > head_slot {
+        return None;
+    }
+
+    let count = count.min(MAX_REQUEST_BLOCKS);
+    Some((start_slot, std::cmp::min(start_slot + count, head_slot + 1)))
+}
+
+// The latest head slot a peer can have under our slot clock, or `None` before genesis. A small
+// clock disagreement is tolerated, as lighthouse does, so that peers slightly ahead of us near slot
+// boundaries aren't rejected.
+fn tolerated_head_slot<S: SlotClock>(
+    slot_clock: &S,
+    max_clock_disparity: Duration,
+) -> Option<Slot> {
+    slot_clock.now_with_future_tolerance(max_clock_disparity)
+}
+
+// Our MetaData in the version the peer requested, carrying the subnets advertised via our ENR.
+fn local_meta_data(
+    enr: &Enr,
+    request: &lighthouse_network::rpc::methods::MetadataRequest<MainnetEthSpec>,
+) -> lighthouse_network::rpc::methods::MetaData<MainnetEthSpec> {
+    let attnets = enr.attestation_bitfield().unwrap_or_else(|e| {
+        warn!("Failed to read the attnets of the local ENR: {e}");
+        EnrAttestationBitfield::<MainnetEthSpec>::new()
+    });
+
+    match request {
+        lighthouse_network::rpc::methods::MetadataRequest::V1(_) => {
+            lighthouse_network::rpc::methods::MetaData::V1(
+                lighthouse_network::rpc::methods::MetaDataV1 {
+                    seq_number: META_DATA_SEQ_NUMBER,
+                    attnets,
+                },
+            )
+        }
+        lighthouse_network::rpc::methods::MetadataRequest::V2(_) => {
+            let syncnets = enr.sync_committee_bitfield().unwrap_or_else(|e| {
+                warn!("Failed to read the syncnets of the local ENR: {e}");
+                EnrSyncCommitteeBitfield::<MainnetEthSpec>::new()
+            });
+            lighthouse_network::rpc::methods::MetaData::V2(
+                lighthouse_network::rpc::methods::MetaDataV2 {
+                    seq_number: META_DATA_SEQ_NUMBER,
+                    attnets,
+                    syncnets,
+                },
+            )
+        }
+    }
+}
+
+// Builds the TCP multiaddr libp2p listens on.
+fn listen_multiaddr(address: std::net::IpAddr, port: u16) -> Multiaddr {
+    let mut multiaddr = Multiaddr::from(address);
+    multiaddr.push(libp2p::core::multiaddr::Protocol::Tcp(port));
+    multiaddr
+}
+
+// Returns a delay firing at the next scheduled fork, if any.
+fn next_fork_delay<T: BeaconChainTypes>(
+    lh_beacon_chain: &beacon_chain::BeaconChain<T>,
+) -> Option<Pin<Box<Sleep>>> {
+    lh_beacon_chain
+        .duration_to_next_fork()
+        .map(|(_fork_name, duration)| Box::pin(tokio::time::sleep(duration)))
+}
+
+// Waits for the delay, or forever if it is not set.
+async fn wait_for_delay(delay: &mut Option<Pin<Box<Sleep>>>) {
+    match delay {
+        Some(sleep) => sleep.await,
+        None => futures::future::pending().await,
     }
 }
 
@@ -363,4 +1237,531 @@ pub(crate) enum NetworkMessage {
         request: lighthouse_network::Request,
         request_id: ApplicationRequestId,
     },
+    /// Send a Ping to the peer and log the round-trip time, for diagnostics via the HTTP API.
+    PingPeer(PeerId),
+    /// Reply with the local PeerId, ENR and listen addresses, for the HTTP API.
+    QueryIdentity(oneshot::Sender<serde_json::Value>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::test_block;
+
+    #[test]
+    fn behaviours_with_queued_events_are_suspected_once_the_swarm_stalls() {
+        assert!(
+            suspected_stuck_behaviours(SWARM_STALL_TIMEOUT / 2, vec!["peer_manager"]).is_empty()
+        );
+        assert_eq!(
+            suspected_stuck_behaviours(SWARM_STALL_TIMEOUT, vec!["peer_manager", "rpc"]),
+            vec!["peer_manager", "rpc"]
+        );
+        assert!(suspected_stuck_behaviours(SWARM_STALL_TIMEOUT * 2, vec![]).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn re_listens_with_backoff_after_the_listener_closes() {
+        let mut listen_retry = ListenRetry::new();
+        let start = tokio::time::Instant::now();
+
+        // The listener has closed.
+        assert_eq!(listen_retry.schedule(), LISTEN_RETRY_INITIAL_BACKOFF);
+        wait_for_delay(&mut listen_retry.delay).await;
+        assert_eq!(start.elapsed(), LISTEN_RETRY_INITIAL_BACKOFF);
+
+        // Re-listening keeps failing.
+        let backoffs = (0..8).map(|_| listen_retry.schedule()).collect::<Vec<_>>();
+        assert_eq!(backoffs[0], LISTEN_RETRY_INITIAL_BACKOFF * 2);
+        assert_eq!(backoffs.last(), Some(&LISTEN_RETRY_MAX_BACKOFF));
+
+        // The listener is back.
+        listen_retry.reset();
+        assert_eq!(listen_retry.schedule(), LISTEN_RETRY_INITIAL_BACKOFF);
+    }
+
+    async fn swarm(
+        runtime: &Arc<Runtime>,
+        idle_connection_timeout: Duration,
+    ) -> Swarm<libp2p::swarm::dummy::Behaviour> {
+        let key_pair = Keypair::generate_secp256k1();
+        let transport = build_network_transport(key_pair.clone())
+            .await
+            .expect("transport");
+        SwarmBuilder::with_existing_identity(key_pair)
+            .with_tokio()
+            .with_other_transport(|_| transport)
+            .expect("infallible")
+            .with_behaviour(|_| libp2p::swarm::dummy::Behaviour)
+            .expect("infallible")
+            .with_swarm_config(|_| swarm_config(runtime, idle_connection_timeout))
+            .build()
+    }
+
+    async fn rpc_swarm(runtime: &Arc<Runtime>) -> Swarm<crate::rpc::behaviour::Behaviour<u32>> {
+        rpc_swarm_with(runtime, crate::rpc::protocol::supported_protocols()).await
+    }
+
+    // A swarm accepting requests only on the given protocols.
+    async fn rpc_swarm_with(
+        runtime: &Arc<Runtime>,
+        inbound_protocols: Vec<crate::rpc::protocol::ProtocolId>,
+    ) -> Swarm<crate::rpc::behaviour::Behaviour<u32>> {
+        let key_pair = Keypair::generate_secp256k1();
+        let transport = build_network_transport(key_pair.clone())
+            .await
+            .expect("transport");
+        let fork_context = Arc::new(types::ForkContext::new::<MainnetEthSpec>(
+            Slot::new(0),
+            Hash256::zero(),
+            &types::ChainSpec::mainnet(),
+        ));
+        let rpc_config = crate::rpc::RpcConfig {
+            request_timeout: Duration::from_secs(15),
+            read_timeout: Duration::from_secs(5),
+        };
+        SwarmBuilder::with_existing_identity(key_pair)
+            .with_tokio()
+            .with_other_transport(|_| transport)
+            .expect("infallible")
+            .with_behaviour(|_| {
+                crate::rpc::behaviour::Behaviour::new(fork_context, 1024 * 1024, rpc_config)
+                    .with_inbound_protocols(inbound_protocols)
+            })
+            .expect("infallible")
+            .with_swarm_config(|_| swarm_config(runtime, Duration::from_secs(10)))
+            .build()
+    }
+
+    #[test]
+    fn meta_data_is_served_in_the_requested_version() {
+        let enr_fork_id = types::EnrForkId {
+            fork_digest: [1, 2, 3, 4],
+            next_fork_version: [0, 0, 0, 0],
+            next_fork_epoch: types::Epoch::max_value(),
+        };
+        let enr = crate::build_local_enr(&CombinedKey::generate_secp256k1(), &enr_fork_id, true);
+
+        let meta_data = local_meta_data(
+            &enr,
+            &lighthouse_network::rpc::methods::MetadataRequest::new_v1(),
+        );
+        assert!(matches!(
+            meta_data,
+            lighthouse_network::rpc::methods::MetaData::V1(_)
+        ));
+        assert_eq!(*meta_data.seq_number(), META_DATA_SEQ_NUMBER);
+        assert!(meta_data.attnets().iter().all(|subscribed| subscribed));
+
+        let meta_data = local_meta_data(
+            &enr,
+            &lighthouse_network::rpc::methods::MetadataRequest::new_v2(),
+        );
+        let syncnets = meta_data.syncnets().expect("V2 carries the syncnets");
+        assert!(syncnets.iter().all(|subscribed| subscribed));
+    }
+
+    #[test]
+    fn error_response_is_reported_with_its_request_id() {
+        let runtime = Arc::new(Runtime::new().expect("runtime"));
+
+        runtime.block_on(async {
+            let mut listener = rpc_swarm(&runtime).await;
+            let mut dialer = rpc_swarm(&runtime).await;
+            listener
+                .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+                .expect("listen");
+            let address = loop {
+                if let SwarmEvent::NewListenAddr { address, .. } =
+                    listener.select_next_some().await
+                {
+                    break address;
+                }
+            };
+            dialer.dial(address).expect("dial");
+
+            tokio::time::timeout(Duration::from_secs(10), async {
+                loop {
+                    tokio::select! {
+                        event = dialer.select_next_some() => match event {
+                            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                                let request = lighthouse_network::Request::BlocksByRange(
+                                    lighthouse_network::rpc::BlocksByRangeRequest::new(0, 32),
+                                );
+                                dialer.behaviour_mut().send_request(peer_id, request.into(), 2);
+                            }
+                            SwarmEvent::Behaviour(RpcEvent::ResponseError(
+                                _peer_id,
+                                request_id,
+                                code,
+                                _message,
+                            )) => {
+                                assert_eq!(request_id, 2);
+                                assert!(matches!(code, RPCResponseErrorCode::RateLimited));
+                                return;
+                            }
+                            _ => {}
+                        },
+                        event = listener.select_next_some() => {
+                            if let SwarmEvent::Behaviour(RpcEvent::ReceivedRequest(request)) = event {
+                                listener.behaviour_mut().send_error_response(
+                                    request.peer_id,
+                                    request.connection_id,
+                                    request.substream_id,
+                                    RPCResponseErrorCode::RateLimited,
+                                    "Rate limited".to_string(),
+                                );
+                            }
+                        }
+                    }
+                }
+            })
+            .await
+            .expect("the error response should be received");
+        });
+    }
+
+    #[test]
+    fn closed_stream_of_responses_is_reported_as_terminated() {
+        let runtime = Arc::new(Runtime::new().expect("runtime"));
+
+        runtime.block_on(async {
+            let mut listener = rpc_swarm(&runtime).await;
+            let mut dialer = rpc_swarm(&runtime).await;
+            listener
+                .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+                .expect("listen");
+            let address = loop {
+                if let SwarmEvent::NewListenAddr { address, .. } =
+                    listener.select_next_some().await
+                {
+                    break address;
+                }
+            };
+            dialer.dial(address).expect("dial");
+            let block = test_block(0);
+
+            let received_blocks = tokio::time::timeout(Duration::from_secs(10), async {
+                let mut received_blocks = 0;
+                loop {
+                    tokio::select! {
+                        event = dialer.select_next_some() => match event {
+                            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                                let request = lighthouse_network::Request::BlocksByRange(
+                                    lighthouse_network::rpc::BlocksByRangeRequest::new(0, 32),
+                                );
+                                dialer.behaviour_mut().send_request(peer_id, request.into(), 3);
+                            }
+                            SwarmEvent::Behaviour(RpcEvent::ReceivedResponse(response)) => {
+                                assert_eq!(response.request_id, 3);
+                                received_blocks += 1;
+                            }
+                            SwarmEvent::Behaviour(RpcEvent::StreamTerminated(_peer_id, request_id)) => {
+                                assert_eq!(request_id, 3);
+                                return received_blocks;
+                            }
+                            SwarmEvent::Behaviour(RpcEvent::ConnectionClosed(_)) => {
+                                panic!("The termination shouldn't close the connection");
+                            }
+                            _ => {}
+                        },
+                        event = listener.select_next_some() => {
+                            if let SwarmEvent::Behaviour(RpcEvent::ReceivedRequest(request)) = event {
+                                for response in [Some(block.clone()), None] {
+                                    listener.behaviour_mut().send_response(
+                                        request.peer_id,
+                                        request.connection_id,
+                                        request.substream_id,
+                                        lighthouse_network::Response::BlocksByRange(response),
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+            })
+            .await
+            .expect("the stream should be terminated");
+            assert_eq!(received_blocks, 1);
+        });
+    }
+
+    #[test]
+    fn blocks_by_range_is_down_negotiated_with_a_peer_supporting_only_v1() {
+        let runtime = Arc::new(Runtime::new().expect("runtime"));
+
+        runtime.block_on(async {
+            let v1_only = crate::rpc::protocol::supported_protocols()
+                .into_iter()
+                .filter(|protocol_id| {
+                    protocol_id.as_ref()
+                        != "/eth2/beacon_chain/req/beacon_blocks_by_range/2/ssz_snappy"
+                })
+                .collect();
+            let mut listener = rpc_swarm_with(&runtime, v1_only).await;
+            let mut dialer = rpc_swarm(&runtime).await;
+            listener
+                .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+                .expect("listen");
+            let address = loop {
+                if let SwarmEvent::NewListenAddr { address, .. } =
+                    listener.select_next_some().await
+                {
+                    break address;
+                }
+            };
+            dialer.dial(address).expect("dial");
+            let block = test_block(0);
+
+            let received_blocks = tokio::time::timeout(Duration::from_secs(10), async {
+                let mut received_blocks = 0;
+                loop {
+                    tokio::select! {
+                        event = dialer.select_next_some() => match event {
+                            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                                let request = lighthouse_network::Request::BlocksByRange(
+                                    lighthouse_network::rpc::BlocksByRangeRequest::new(0, 32),
+                                );
+                                dialer.behaviour_mut().send_request(peer_id, request.into(), 4);
+                            }
+                            SwarmEvent::Behaviour(RpcEvent::ReceivedResponse(response)) => {
+                                assert_eq!(response.request_id, 4);
+                                received_blocks += 1;
+                            }
+                            SwarmEvent::Behaviour(RpcEvent::StreamTerminated(_peer_id, request_id)) => {
+                                assert_eq!(request_id, 4);
+                                return received_blocks;
+                            }
+                            SwarmEvent::Behaviour(RpcEvent::UnsupportedProtocol(..)) => {
+                                panic!("The request should be sent over V1");
+                            }
+                            _ => {}
+                        },
+                        event = listener.select_next_some() => {
+                            if let SwarmEvent::Behaviour(RpcEvent::ReceivedRequest(request)) = event {
+                                // The request has been received over V1.
+                                assert!(matches!(
+                                    request.request,
+                                    lighthouse_network::rpc::protocol::InboundRequest::BlocksByRange(
+                                        lighthouse_network::rpc::methods::OldBlocksByRangeRequest::V1(_)
+                                    )
+                                ));
+                                for response in [Some(block.clone()), None] {
+                                    listener.behaviour_mut().send_response(
+                                        request.peer_id,
+                                        request.connection_id,
+                                        request.substream_id,
+                                        lighthouse_network::Response::BlocksByRange(response),
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+            })
+            .await
+            .expect("the request should complete over V1");
+            assert_eq!(received_blocks, 1);
+        });
+    }
+
+    #[test]
+    fn ping_round_trips_over_the_loopback_transport() {
+        let runtime = Arc::new(Runtime::new().expect("runtime"));
+
+        runtime.block_on(async {
+            let mut listener = rpc_swarm(&runtime).await;
+            let mut dialer = rpc_swarm(&runtime).await;
+            listener
+                .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+                .expect("listen");
+            let address = loop {
+                if let SwarmEvent::NewListenAddr { address, .. } =
+                    listener.select_next_some().await
+                {
+                    break address;
+                }
+            };
+            dialer.dial(address).expect("dial");
+
+            let round_trip_time = tokio::time::timeout(Duration::from_secs(10), async {
+                let mut sent = None;
+                loop {
+                    tokio::select! {
+                        event = dialer.select_next_some() => match event {
+                            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                                dialer.behaviour_mut().send_ping(1, peer_id, META_DATA_SEQ_NUMBER);
+                                sent = Some(Instant::now());
+                            }
+                            SwarmEvent::Behaviour(RpcEvent::ReceivedResponse(response)) => {
+                                assert_eq!(response.request_id, 1);
+                                assert!(matches!(
+                                    response.response,
+                                    lighthouse_network::rpc::methods::RPCResponse::Pong(ping)
+                                        if ping.data == 7
+                                ));
+                                return sent.expect("sent").elapsed();
+                            }
+                            _ => {}
+                        },
+                        event = listener.select_next_some() => {
+                            if let SwarmEvent::Behaviour(RpcEvent::ReceivedRequest(request)) = event {
+                                assert!(matches!(
+                                    request.request,
+                                    lighthouse_network::rpc::protocol::InboundRequest::Ping(_)
+                                ));
+                                listener.behaviour_mut().send_pong(
+                                    request.peer_id,
+                                    request.connection_id,
+                                    request.substream_id,
+                                    7,
+                                );
+                            }
+                        }
+                    }
+                }
+            })
+            .await
+            .expect("the Pong should be received");
+            assert!(round_trip_time < Duration::from_secs(10));
+        });
+    }
+
+    // The swarms run the dummy behaviour, whose handlers never keep the connection alive, so the
+    // timeout applies right away. With the RPC handler, it applies only once the handler has
+    // released the keep-alive.
+    #[test]
+    fn idle_connections_are_closed() {
+        let runtime = Arc::new(Runtime::new().expect("runtime"));
+        let idle_connection_timeout = Duration::from_millis(500);
+
+        runtime.block_on(async {
+            let mut listener = swarm(&runtime, idle_connection_timeout).await;
+            let mut dialer = swarm(&runtime, idle_connection_timeout).await;
+            listener
+                .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+                .expect("listen");
+            let address = loop {
+                if let SwarmEvent::NewListenAddr { address, .. } =
+                    listener.select_next_some().await
+                {
+                    break address;
+                }
+            };
+            dialer.dial(address).expect("dial");
+
+            // No substream is opened on the connection, so it's closed once idle.
+            let established = tokio::time::timeout(Duration::from_secs(10), async {
+                let mut established = None;
+                loop {
+                    tokio::select! {
+                        event = dialer.select_next_some() => match event {
+                            SwarmEvent::ConnectionEstablished { .. } => established = Some(Instant::now()),
+                            SwarmEvent::ConnectionClosed { .. } => return established,
+                            _ => {}
+                        },
+                        _ = listener.select_next_some() => {}
+                    }
+                }
+            })
+            .await
+            .expect("the idle connection should be closed")
+            .expect("the connection should have been established");
+            assert!(established.elapsed() >= idle_connection_timeout);
+        });
+    }
+
+    #[test]
+    fn pre_anchor_range_is_not_served() {
+        let oldest_block_slot = Slot::new(1000);
+        let head_slot = Slot::new(2000);
+
+        // Below the checkpoint anchor.
+        assert_eq!(
+            servable_slots(Slot::new(10), 64, oldest_block_slot, head_slot),
+            None
+        );
+        // Beyond the head.
+        assert_eq!(
+            servable_slots(Slot::new(2001), 64, oldest_block_slot, head_slot),
+            None
+        );
+        // The range is served up to the head.
+        assert_eq!(
+            servable_slots(Slot::new(1990), 64, oldest_block_slot, head_slot),
+            Some((Slot::new(1990), Slot::new(2001)))
+        );
+    }
+
+    #[test]
+    fn range_count_is_clamped_to_max_request_blocks() {
+        let oldest_block_slot = Slot::new(0);
+        let head_slot = Slot::new(10_000_000);
+
+        assert_eq!(
+            servable_slots(Slot::new(100), u64::MAX, oldest_block_slot, head_slot),
+            Some((Slot::new(100), Slot::new(100 + MAX_REQUEST_BLOCKS)))
+        );
+    }
+
+    #[test]
+    fn head_slot_is_tolerated_up_to_the_clock_disparity() {
+        let slot_duration = Duration::from_secs(12);
+        let max_clock_disparity = Duration::from_millis(500);
+        let slot_clock =
+            slot_clock::ManualSlotClock::new(Slot::new(0), Duration::from_secs(0), slot_duration);
+        let slot_11_start = slot_duration * 11;
+
+        // Just outside the tolerance of the next slot.
+        slot_clock.set_current_time(slot_11_start - max_clock_disparity - Duration::from_millis(1));
+        assert_eq!(
+            tolerated_head_slot(&slot_clock, max_clock_disparity),
+            Some(Slot::new(10))
+        );
+
+        // At the boundary of the tolerance, a peer at the next slot is accepted.
+        slot_clock.set_current_time(slot_11_start - max_clock_disparity);
+        assert_eq!(
+            tolerated_head_slot(&slot_clock, max_clock_disparity),
+            Some(Slot::new(11))
+        );
+
+        // Before genesis.
+        let slot_clock =
+            slot_clock::ManualSlotClock::new(Slot::new(0), Duration::from_secs(60), slot_duration);
+        slot_clock.set_current_time(Duration::from_secs(0));
+        assert_eq!(tolerated_head_slot(&slot_clock, max_clock_disparity), None);
+    }
+
+    #[test]
+    fn large_range_is_bounded_and_served_one_block_at_a_time() {
+        let block = test_block(0);
+        // The peer asks for far more blocks than are served for a request.
+        let (next_slot, end_slot) =
+            servable_slots(Slot::new(0), u64::MAX, Slot::new(0), Slot::new(100_000))
+                .expect("servable");
+        let mut serving = BlocksByRangeServing {
+            next_slot,
+            end_slot,
+        };
+
+        let mut served = 0;
+        loop {
+            // Every other slot has been skipped.
+            let mut lookups = 0;
+            let next = serving
+                .next_block(|slot| {
+                    lookups += 1;
+                    Ok((slot.as_u64() % 2 == 1).then(|| block.clone()))
+                })
+                .expect("lookup");
+            // Only the slots up to the next block are looked up.
+            assert!(lookups <= 2);
+            if next.is_none() {
+                break;
+            }
+            served += 1;
+        }
+        assert_eq!(served, MAX_REQUEST_BLOCKS / 2);
+    }
 }
@@ -1,20 +1,50 @@
+use libp2p::multiaddr::Protocol;
 use libp2p::{Multiaddr, PeerId};
-use std::collections::HashMap;
-use std::time::Instant;
-use tracing::{error, info};
+use lighthouse_network::rpc::methods::MetaData;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use types::MainnetEthSpec;
+
+// The number of recent Goodbyes we sent that are kept for introspection.
+const RECENT_GOODBYES_SENT: usize = 32;
 
 pub(crate) struct PeerDB {
     peers: HashMap<PeerId, PeerInfo>,
+    // The recent Goodbyes we sent, the oldest first.
+    recent_goodbyes_sent: VecDeque<GoodbyeSent>,
+}
+
+/// A Goodbye we sent to a peer.
+#[derive(Debug, Clone)]
+pub(crate) struct GoodbyeSent {
+    pub(crate) peer_id: PeerId,
+    pub(crate) reason: lighthouse_network::rpc::GoodbyeReason,
+    pub(crate) at: Instant,
 }
 
 struct PeerInfo {
-    #[allow(dead_code)]
     listening_address: Multiaddr,
+    // Whether `listening_address` can be dialed, i.e. we dialed the peer on it. The address of an
+    // inbound connection is the ephemeral port the peer dialed us from, so it isn't persisted.
+    is_dialable: bool,
     sync_status: SyncStatus,
     connection_status: ConnectionStatus,
+    // The reputation of the peer. Peers below a threshold are not dialed again.
+    score: f64,
+    // The latest MetaData of the peer, which includes the subnets the peer subscribes to.
+    meta_data: Option<MetaData<MainnetEthSpec>>,
+    // The protocols the peer failed to negotiate, so that requests on them aren't sent again.
+    unsupported_protocols: HashSet<lighthouse_network::rpc::protocol::Protocol>,
+    // The reason of the latest Goodbye the peer sent us, if any.
+    goodbye_reason: Option<lighthouse_network::rpc::GoodbyeReason>,
+    // The last time the peer was confirmed to be alive by a PONG.
+    last_seen: Option<Instant>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum SyncStatus {
     // At the current state as our node or ahead of us.
     Synced,
@@ -28,7 +58,7 @@ pub(crate) enum SyncStatus {
     Unknown,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ConnectionStatus {
     /// The peer is connected.
     Connected,
@@ -41,12 +71,51 @@ pub enum ConnectionStatus {
     },
 }
 
+/// A point-in-time copy of a peer's state, for introspection.
+#[derive(Debug)]
+pub(crate) struct PeerSnapshot {
+    pub(crate) peer_id: PeerId,
+    pub(crate) connection_status: ConnectionStatus,
+    pub(crate) sync_status: SyncStatus,
+    pub(crate) score: f64,
+    pub(crate) goodbye_reason: Option<lighthouse_network::rpc::GoodbyeReason>,
+    pub(crate) last_seen: Option<Instant>,
+}
+
 impl PeerInfo {
-    fn new(listening_address: Multiaddr) -> Self {
+    // The approximate memory used by the peer in the PeerDB, including its key.
+    fn estimated_size(&self) -> usize {
+        std::mem::size_of::<PeerId>()
+            + std::mem::size_of::<PeerInfo>()
+            + self.listening_address.len()
+            + self.unsupported_protocols.len()
+                * std::mem::size_of::<lighthouse_network::rpc::protocol::Protocol>()
+    }
+
+    fn new(listening_address: Multiaddr, is_dialable: bool) -> Self {
         PeerInfo {
             listening_address,
+            is_dialable,
             sync_status: SyncStatus::Unknown,
             connection_status: ConnectionStatus::Connected,
+            score: 0.0,
+            meta_data: None,
+            unsupported_protocols: HashSet::new(),
+            goodbye_reason: None,
+            last_seen: None,
+        }
+    }
+}
+
+impl SyncStatus {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Synced" => Some(SyncStatus::Synced),
+            "Advanced" => Some(SyncStatus::Advanced),
+            "Behind" => Some(SyncStatus::Behind),
+            "IrrelevantPeer" => Some(SyncStatus::IrrelevantPeer),
+            "Unknown" => Some(SyncStatus::Unknown),
+            _ => None,
         }
     }
 }
@@ -55,11 +124,145 @@ impl PeerDB {
     pub(crate) fn new() -> Self {
         PeerDB {
             peers: HashMap::new(),
+            recent_goodbyes_sent: VecDeque::with_capacity(RECENT_GOODBYES_SENT),
+        }
+    }
+
+    // Loads the peers persisted by `save_to_path`, as disconnected peers. A missing file yields an
+    // empty PeerDB, and entries that can't be parsed (e.g. written by an older version) are skipped.
+    pub(crate) fn load_from_path(path: &Path) -> Result<Self, String> {
+        let mut peer_db = PeerDB::new();
+        if !path.exists() {
+            return Ok(peer_db);
+        }
+
+        let bytes =
+            std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let json: serde_json::Value = serde_json::from_slice(&bytes)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        let entries = json
+            .as_array()
+            .ok_or_else(|| format!("Unexpected format of {}", path.display()))?;
+
+        for entry in entries {
+            let parsed = (|| {
+                let peer_id = entry["peer_id"].as_str()?.parse::<PeerId>().ok()?;
+                let address = entry["address"].as_str()?.parse::<Multiaddr>().ok()?;
+                let sync_status = SyncStatus::from_name(entry["sync_status"].as_str()?)?;
+                let score = entry["score"].as_f64()?;
+                Some((peer_id, address, sync_status, score))
+            })();
+            let Some((peer_id, address, sync_status, score)) = parsed else {
+                warn!(
+                    "Skipped an unparseable peer in {}: {}",
+                    path.display(),
+                    entry
+                );
+                continue;
+            };
+
+            let mut info = PeerInfo::new(address, true);
+            info.sync_status = sync_status;
+            info.connection_status = ConnectionStatus::Disconnected {
+                since: Instant::now(),
+            };
+            info.score = score;
+            peer_db.peers.insert(peer_id, info);
+        }
+
+        info!(
+            "Loaded {} known peers from {}",
+            peer_db.peers.len(),
+            path.display()
+        );
+        Ok(peer_db)
+    }
+
+    // Persists the known peers so that they can be reconnected after a restart.
+    pub(crate) fn save_to_path(&self, path: &Path) -> Result<(), String> {
+        PeerDB::write_entries(&self.persisted_entries(), path)
+    }
+
+    // A snapshot of the peers to persist, i.e. the ones with a dialable address, so that it can be
+    // written without holding the lock.
+    pub(crate) fn persisted_entries(&self) -> Vec<serde_json::Value> {
+        self.peers
+            .iter()
+            .filter(|(_peer_id, info)| info.is_dialable)
+            .map(|(peer_id, info)| {
+                serde_json::json!({
+                    "peer_id": peer_id.to_string(),
+                    "address": info.listening_address.to_string(),
+                    "sync_status": format!("{:?}", info.sync_status),
+                    "score": info.score,
+                })
+            })
+            .collect()
+    }
+
+    // Writes the entries to the file. The file is replaced atomically so that a crash while saving
+    // doesn't lose the previous one.
+    pub(crate) fn write_entries(entries: &[serde_json::Value], path: &Path) -> Result<(), String> {
+        let bytes = serde_json::to_vec(entries)
+            .map_err(|e| format!("Failed to serialize the peers: {}", e))?;
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, bytes)
+            .map_err(|e| format!("Failed to write {}: {}", tmp_path.display(), e))?;
+        std::fs::rename(&tmp_path, path)
+            .map_err(|e| format!("Failed to rename to {}: {}", path.display(), e))?;
+        Ok(())
+    }
+
+    // Adds a connected peer. `is_dialable` tells whether the address can be dialed, i.e. we dialed
+    // the peer rather than it dialed us.
+    pub(crate) fn add_peer(&mut self, peer_id: PeerId, address: Multiaddr, is_dialable: bool) {
+        // Keep the history (e.g. score) of a known peer.
+        match self.peers.get_mut(&peer_id) {
+            Some(peer_info) => {
+                // A dialable address isn't replaced with an ephemeral one.
+                if is_dialable || !peer_info.is_dialable {
+                    peer_info.listening_address = address;
+                    peer_info.is_dialable = is_dialable;
+                }
+                peer_info.sync_status = SyncStatus::Unknown;
+                peer_info.connection_status = ConnectionStatus::Connected;
+            }
+            None => {
+                self.peers
+                    .insert(peer_id, PeerInfo::new(address, is_dialable));
+            }
         }
     }
 
-    pub(crate) fn add_peer(&mut self, peer_id: PeerId, address: Multiaddr) {
-        self.peers.insert(peer_id, PeerInfo::new(address));
+    // Returns the score of the peer, if known.
+    pub(crate) fn score(&self, peer_id: &PeerId) -> Option<f64> {
+        self.peers.get(peer_id).map(|peer_info| peer_info.score)
+    }
+
+    pub(crate) fn add_score(&mut self, peer_id: &PeerId, delta: f64) {
+        match self.peers.get_mut(peer_id) {
+            None => error!("[{}] add_score: Peer not found.", peer_id),
+            Some(peer_info) => {
+                info!(
+                    "[{}] Updated score: before: {}, after: {}",
+                    peer_id,
+                    peer_info.score,
+                    peer_info.score + delta
+                );
+                peer_info.score += delta;
+            }
+        }
+    }
+
+    pub(crate) fn sync_status(&self, peer_id: &PeerId) -> Option<&SyncStatus> {
+        self.peers
+            .get(peer_id)
+            .map(|peer_info| &peer_info.sync_status)
     }
 
     pub(crate) fn update_sync_status(&mut self, peer_id: &PeerId, sync_status: SyncStatus) {
@@ -77,6 +280,103 @@ impl PeerDB {
         }
     }
 
+    pub(crate) fn meta_data(&self, peer_id: &PeerId) -> Option<&MetaData<MainnetEthSpec>> {
+        self.peers
+            .get(peer_id)
+            .and_then(|peer_info| peer_info.meta_data.as_ref())
+    }
+
+    pub(crate) fn update_meta_data(
+        &mut self,
+        peer_id: &PeerId,
+        meta_data: MetaData<MainnetEthSpec>,
+    ) {
+        match self.peers.get_mut(peer_id) {
+            None => error!("[{}] update_meta_data: Peer not found.", peer_id),
+            Some(peer_info) => {
+                info!(
+                    "[{}] Updated meta_data: seq_number: {}",
+                    peer_id,
+                    meta_data.seq_number()
+                );
+                peer_info.meta_data = Some(meta_data);
+            }
+        }
+    }
+
+    pub(crate) fn update_last_seen(&mut self, peer_id: &PeerId) {
+        match self.peers.get_mut(peer_id) {
+            None => error!("[{}] update_last_seen: Peer not found.", peer_id),
+            Some(peer_info) => peer_info.last_seen = Some(Instant::now()),
+        }
+    }
+
+    pub(crate) fn add_unsupported_protocol(
+        &mut self,
+        peer_id: &PeerId,
+        protocol: lighthouse_network::rpc::protocol::Protocol,
+    ) {
+        match self.peers.get_mut(peer_id) {
+            None => error!("[{}] add_unsupported_protocol: Peer not found.", peer_id),
+            Some(peer_info) => {
+                info!("[{}] Added an unsupported protocol: {}", peer_id, protocol);
+                peer_info.unsupported_protocols.insert(protocol);
+            }
+        }
+    }
+
+    // The peer may support other protocols on the next connection, e.g. after upgrading its
+    // client, so the protocols it failed to negotiate are only remembered while it's connected.
+    pub(crate) fn clear_unsupported_protocols(&mut self, peer_id: &PeerId) {
+        if let Some(peer_info) = self.peers.get_mut(peer_id) {
+            peer_info.unsupported_protocols.clear();
+        }
+    }
+
+    // Returns false if the peer is known not to support the protocol.
+    pub(crate) fn supports_protocol(
+        &self,
+        peer_id: &PeerId,
+        protocol: &lighthouse_network::rpc::protocol::Protocol,
+    ) -> bool {
+        self.peers.get(peer_id).map_or(true, |peer_info| {
+            !peer_info.unsupported_protocols.contains(protocol)
+        })
+    }
+
+    pub(crate) fn update_goodbye_reason(
+        &mut self,
+        peer_id: &PeerId,
+        reason: lighthouse_network::rpc::GoodbyeReason,
+    ) {
+        match self.peers.get_mut(peer_id) {
+            None => error!("[{}] update_goodbye_reason: Peer not found.", peer_id),
+            Some(peer_info) => {
+                info!("[{}] Updated goodbye_reason: {}", peer_id, reason);
+                peer_info.goodbye_reason = Some(reason);
+            }
+        }
+    }
+
+    pub(crate) fn record_goodbye_sent(
+        &mut self,
+        peer_id: PeerId,
+        reason: lighthouse_network::rpc::GoodbyeReason,
+    ) {
+        if self.recent_goodbyes_sent.len() >= RECENT_GOODBYES_SENT {
+            self.recent_goodbyes_sent.pop_front();
+        }
+        self.recent_goodbyes_sent.push_back(GoodbyeSent {
+            peer_id,
+            reason,
+            at: Instant::now(),
+        });
+    }
+
+    pub(crate) fn recent_goodbyes_sent(&self) -> Vec<GoodbyeSent> {
+        self.recent_goodbyes_sent.iter().cloned().collect()
+    }
+
     pub(crate) fn update_connection_status(
         &mut self,
         peer_id: &PeerId,
@@ -97,6 +397,140 @@ impl PeerDB {
         }
     }
 
+    // Returns the IP addresses of the connected peers, except the given one.
+    pub(crate) fn connected_peer_ips(&self, except: &PeerId) -> Vec<IpAddr> {
+        self.peers
+            .iter()
+            .filter(|(id, info)| {
+                *id != except && matches!(info.connection_status, ConnectionStatus::Connected)
+            })
+            .filter_map(|(_id, info)| ip_address(&info.listening_address))
+            .collect()
+    }
+
+    // Returns a copy of the state of all the known peers.
+    pub(crate) fn snapshot(&self) -> Vec<PeerSnapshot> {
+        self.peers
+            .iter()
+            .map(|(peer_id, info)| PeerSnapshot {
+                peer_id: *peer_id,
+                connection_status: info.connection_status.clone(),
+                sync_status: info.sync_status.clone(),
+                score: info.score,
+                goodbye_reason: info.goodbye_reason.clone(),
+                last_seen: info.last_seen,
+            })
+            .collect()
+    }
+
+    // Returns the last known address of the peer, if it's not connected.
+    pub(crate) fn disconnected_address(&self, peer_id: &PeerId) -> Option<Multiaddr> {
+        self.peers.get(peer_id).and_then(|info| {
+            matches!(
+                info.connection_status,
+                ConnectionStatus::Disconnected { .. }
+            )
+            .then(|| info.listening_address.clone())
+        })
+    }
+
+    // A dial to the peer has failed. A peer being disconnected is marked disconnected, while the
+    // time of an already disconnected peer is kept so that it's still pruned in time.
+    pub(crate) fn dial_failed(&mut self, peer_id: &PeerId) {
+        if let Some(info) = self.peers.get_mut(peer_id) {
+            if matches!(info.connection_status, ConnectionStatus::Disconnecting) {
+                info.connection_status = ConnectionStatus::Disconnected {
+                    since: Instant::now(),
+                };
+            }
+        }
+    }
+
+    pub(crate) fn is_connected(&self, peer_id: &PeerId) -> bool {
+        self.peers.get(peer_id).map_or(false, |info| {
+            matches!(info.connection_status, ConnectionStatus::Connected)
+        })
+    }
+
+    // Whether the peer has a connection, including one being closed.
+    pub(crate) fn is_connected_or_disconnecting(&self, peer_id: &PeerId) -> bool {
+        self.peers.get(peer_id).map_or(false, |info| {
+            matches!(
+                info.connection_status,
+                ConnectionStatus::Connected | ConnectionStatus::Disconnecting
+            )
+        })
+    }
+
+    // Removes the peers disconnected for longer than `max_age`. Returns the number of peers
+    // removed.
+    pub(crate) fn prune_disconnected(&mut self, max_age: Duration) -> usize {
+        let before = self.peers.len();
+        self.peers
+            .retain(|_peer_id, info| match info.connection_status {
+                ConnectionStatus::Disconnected { since } => since.elapsed() <= max_age,
+                ConnectionStatus::Connected | ConnectionStatus::Disconnecting => true,
+            });
+        before - self.peers.len()
+    }
+
+    // Returns the approximate memory used by the known peers.
+    pub(crate) fn estimated_memory(&self) -> usize {
+        self.peers.values().map(PeerInfo::estimated_size).sum()
+    }
+
+    // Removes the disconnected peers, the longest disconnected first, until the estimated memory
+    // is at most `target`. Returns the number of peers removed.
+    pub(crate) fn prune_disconnected_to_size(&mut self, target: usize) -> usize {
+        let mut memory = self.estimated_memory();
+        let mut disconnected = self
+            .peers
+            .iter()
+            .filter_map(|(id, info)| match info.connection_status {
+                ConnectionStatus::Disconnected { since } => Some((*id, since)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        disconnected.sort_by_key(|(_id, since)| *since);
+
+        let mut removed = 0;
+        for (peer_id, _since) in disconnected {
+            if memory <= target {
+                break;
+            }
+            if let Some(info) = self.peers.remove(&peer_id) {
+                memory -= info.estimated_size();
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    // Returns the number of known peers, including the disconnected ones.
+    pub(crate) fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub(crate) fn connected_peer_ids(&self) -> Vec<PeerId> {
+        self.peers
+            .iter()
+            .filter(|(_id, info)| matches!(info.connection_status, ConnectionStatus::Connected))
+            .map(|(id, _info)| *id)
+            .collect()
+    }
+
+    // Returns the connected peers with the sync status, e.g. to pick the peers to sync from.
+    pub(crate) fn peers_by_sync_status(&self, sync_status: &SyncStatus) -> Vec<PeerId> {
+        self.peers
+            .iter()
+            .filter(|(_id, info)| {
+                matches!(info.connection_status, ConnectionStatus::Connected)
+                    && info.sync_status == *sync_status
+            })
+            .map(|(id, _info)| *id)
+            .collect()
+    }
+
     pub(crate) fn active_peer_count(&self) -> usize {
         self.peers
             .iter()
@@ -104,3 +538,134 @@ impl PeerDB {
             .count()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_recent_goodbyes_sent_are_kept() {
+        let mut peer_db = PeerDB::new();
+        let peer_ids = (0..RECENT_GOODBYES_SENT + 1)
+            .map(|_| PeerId::random())
+            .collect::<Vec<_>>();
+        for peer_id in &peer_ids {
+            peer_db.record_goodbye_sent(
+                *peer_id,
+                lighthouse_network::rpc::GoodbyeReason::IrrelevantNetwork,
+            );
+        }
+
+        let recent = peer_db.recent_goodbyes_sent();
+        assert_eq!(recent.len(), RECENT_GOODBYES_SENT);
+        // The oldest has been dropped.
+        assert_eq!(recent[0].peer_id, peer_ids[1]);
+        assert_eq!(recent.last().unwrap().peer_id, *peer_ids.last().unwrap());
+    }
+
+    #[test]
+    fn only_dialable_addresses_are_persisted() {
+        let mut peer_db = PeerDB::new();
+        let (dialed, inbound, known) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let listening_address: Multiaddr = "/ip4/127.0.0.1/tcp/9000".parse().unwrap();
+        let ephemeral_address: Multiaddr = "/ip4/127.0.0.1/tcp/53124".parse().unwrap();
+        peer_db.add_peer(dialed, listening_address.clone(), true);
+        peer_db.add_peer(inbound, ephemeral_address.clone(), false);
+        // A peer we dialed before has now dialed us.
+        peer_db.add_peer(known, listening_address.clone(), true);
+        peer_db.add_peer(known, ephemeral_address, false);
+
+        let entries = peer_db.persisted_entries();
+
+        let persisted = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry["peer_id"].as_str().unwrap().to_string(),
+                    entry["address"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            persisted,
+            HashSet::from([
+                (dialed.to_string(), listening_address.to_string()),
+                (known.to_string(), listening_address.to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn only_peers_disconnected_for_longer_than_the_max_age_are_pruned() {
+        let mut peer_db = PeerDB::new();
+        let max_age = Duration::from_secs(60);
+        let now = Instant::now();
+        let (expired, recent, connected) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/9000".parse().unwrap();
+        for peer_id in [expired, recent, connected] {
+            peer_db.add_peer(peer_id, address.clone(), true);
+        }
+        peer_db.update_connection_status(
+            &expired,
+            ConnectionStatus::Disconnected {
+                since: now - max_age - Duration::from_secs(1),
+            },
+        );
+        peer_db.update_connection_status(
+            &recent,
+            ConnectionStatus::Disconnected {
+                since: now - max_age + Duration::from_secs(1),
+            },
+        );
+        peer_db.update_connection_status(&connected, ConnectionStatus::Connected);
+
+        assert_eq!(peer_db.prune_disconnected(max_age), 1);
+        assert!(!peer_db.peers.contains_key(&expired));
+        assert!(peer_db.peers.contains_key(&recent));
+        assert!(peer_db.peers.contains_key(&connected));
+        assert_eq!(peer_db.active_peer_count(), 1);
+    }
+
+    #[test]
+    fn peers_are_counted_by_state() {
+        let mut peer_db = PeerDB::new();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/9000".parse().unwrap();
+        let (synced, advanced, disconnecting, disconnected) = (
+            PeerId::random(),
+            PeerId::random(),
+            PeerId::random(),
+            PeerId::random(),
+        );
+        for peer_id in [synced, advanced, disconnecting, disconnected] {
+            peer_db.add_peer(peer_id, address.clone(), true);
+            peer_db.update_connection_status(&peer_id, ConnectionStatus::Connected);
+            peer_db.update_sync_status(&peer_id, SyncStatus::Synced);
+        }
+        peer_db.update_sync_status(&advanced, SyncStatus::Advanced);
+        peer_db.update_connection_status(&disconnecting, ConnectionStatus::Disconnecting);
+        peer_db.update_connection_status(
+            &disconnected,
+            ConnectionStatus::Disconnected {
+                since: Instant::now(),
+            },
+        );
+
+        assert_eq!(peer_db.peer_count(), 4);
+        assert_eq!(peer_db.active_peer_count(), 2);
+        let mut connected = peer_db.connected_peer_ids();
+        connected.sort();
+        let mut expected = vec![synced, advanced];
+        expected.sort();
+        assert_eq!(connected, expected);
+        // Only the connected peers are picked by their sync status.
+        assert_eq!(
+            peer_db.peers_by_sync_status(&SyncStatus::Synced),
+            vec![synced]
+        );
+        assert_eq!(
+            peer_db.peers_by_sync_status(&SyncStatus::Advanced),
+            vec![advanced]
+        );
+        assert!(peer_db.peers_by_sync_status(&SyncStatus::Behind).is_empty());
+    }
+}
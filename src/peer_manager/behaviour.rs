@@ -11,8 +11,8 @@ use libp2p::swarm::{
 use libp2p::{Multiaddr, PeerId};
 use std::task::{Context, Poll};
 use std::time::Instant;
-use tracing::info;
 use tracing::log::{error, trace};
+use tracing::{debug, info};
 
 // SEE https://github.com/sigp/lighthouse/blob/eee0260a68696db58e92385ebd11a9a08e4c4665/beacon_node/lighthouse_network/src/peer_manager/network_behaviour.rs#L21
 impl NetworkBehaviour for PeerManager {
@@ -22,20 +22,68 @@ impl NetworkBehaviour for PeerManager {
     fn handle_established_inbound_connection(
         &mut self,
         _connection_id: ConnectionId,
-        _peer: PeerId,
+        peer: PeerId,
         _local_addr: &Multiaddr,
-        _remote_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
     ) -> Result<THandler<Self>, ConnectionDenied> {
+        if let Err(e) = self.peer_filter.check(&peer) {
+            info!("[{}] Denied an inbound connection. {}", peer, e);
+            return Err(ConnectionDenied::new(e));
+        }
+        if let Err(e) = self.check_banned(&peer) {
+            info!("[{}] Denied an inbound connection. {}", peer, e);
+            return Err(ConnectionDenied::new(e));
+        }
+        if let Err(e) = self.check_ip_limits(&peer, remote_addr) {
+            info!("[{}] Denied an inbound connection. {}", peer, e);
+            return Err(ConnectionDenied::new(e));
+        }
+        if let Err(e) = self.check_connection_limits(&peer, Endpoint::Listener) {
+            info!("[{}] Denied an inbound connection. {}", peer, e);
+            return Err(ConnectionDenied::new(e));
+        }
         Ok(DummyConnectionHandler)
     }
 
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        _addresses: &[Multiaddr],
+        _effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        if let Some(peer_id) = maybe_peer {
+            if let Err(e) = self.peer_filter.check(&peer_id) {
+                info!("[{}] Denied an outbound connection. {}", peer_id, e);
+                return Err(ConnectionDenied::new(e));
+            }
+            if let Err(e) = self.check_banned(&peer_id) {
+                info!("[{}] Denied an outbound connection. {}", peer_id, e);
+                return Err(ConnectionDenied::new(e));
+            }
+            // The address known from the previous run, for the peers not in the discovery cache.
+            if let Some(address) = self.peer_db.read().disconnected_address(&peer_id) {
+                return Ok(vec![address]);
+            }
+        }
+        Ok(vec![])
+    }
+
     fn handle_established_outbound_connection(
         &mut self,
         _connection_id: ConnectionId,
-        _peer: PeerId,
-        _addr: &Multiaddr,
+        peer: PeerId,
+        addr: &Multiaddr,
         _role_override: Endpoint,
     ) -> Result<THandler<Self>, ConnectionDenied> {
+        if let Err(e) = self.check_ip_limits(&peer, addr) {
+            info!("[{}] Denied an outbound connection. {}", peer, e);
+            return Err(ConnectionDenied::new(e));
+        }
+        if let Err(e) = self.check_connection_limits(&peer, Endpoint::Dialer) {
+            info!("[{}] Denied an outbound connection. {}", peer, e);
+            return Err(ConnectionDenied::new(e));
+        }
         Ok(DummyConnectionHandler)
     }
 
@@ -47,8 +95,18 @@ impl NetworkBehaviour for PeerManager {
                     connection_established.peer_id,
                     connection_established.endpoint
                 );
-                // TODO: Check the connection limits
-                // https://github.com/sigp/lighthouse/blob/81c667b58e78243df38dc2d7311cb285f7c1d4f4/beacon_node/lighthouse_network/src/peer_manager/network_behaviour.rs#L142
+                self.dialing_peers.remove(&connection_established.peer_id);
+                self.dial_failures.remove(&connection_established.peer_id);
+                self.ping_peers.insert(connection_established.peer_id);
+                // The connection limits have been checked by
+                // `handle_established_{inbound,outbound}_connection`.
+                if connection_established.other_established == 0 {
+                    if connection_established.endpoint.is_dialer() {
+                        self.outbound_peers.insert(connection_established.peer_id);
+                    } else {
+                        self.inbound_peers.insert(connection_established.peer_id);
+                    }
+                }
 
                 let address = match connection_established.endpoint {
                     // We dialed the node
@@ -56,9 +114,11 @@ impl NetworkBehaviour for PeerManager {
                         address,
                         role_override: _,
                     } => {
-                        self.peer_db
-                            .write()
-                            .add_peer(connection_established.peer_id, address.clone());
+                        self.peer_db.write().add_peer(
+                            connection_established.peer_id,
+                            address.clone(),
+                            true,
+                        );
                         self.events.push(PeerManagerEvent::PeerConnectedOutgoing(
                             connection_established.peer_id,
                         ));
@@ -69,9 +129,18 @@ impl NetworkBehaviour for PeerManager {
                         local_addr: _,
                         send_back_addr,
                     } => {
-                        self.peer_db
-                            .write()
-                            .add_peer(connection_established.peer_id, send_back_addr.clone());
+                        self.peer_db.write().add_peer(
+                            connection_established.peer_id,
+                            send_back_addr.clone(),
+                            false,
+                        );
+                        // Spec: The dialing client MUST send a Status request upon connection.
+                        // So we wait for the peer's Status, send ours if none arrives shortly,
+                        // and disconnect if the handshake doesn't complete in time.
+                        self.inbound_status_pending
+                            .insert(connection_established.peer_id);
+                        self.inbound_status_grace
+                            .insert(connection_established.peer_id);
                         self.events.push(PeerManagerEvent::PeerConnectedIncoming(
                             connection_established.peer_id,
                         ));
@@ -86,22 +155,33 @@ impl NetworkBehaviour for PeerManager {
                 }
 
                 self.status_peers.remove(&connection_closed.peer_id);
-                self.peer_db.write().update_connection_status(
+                self.ping_peers.remove(&connection_closed.peer_id);
+                self.inbound_peers.remove(&connection_closed.peer_id);
+                self.outbound_peers.remove(&connection_closed.peer_id);
+                self.inbound_status_pending
+                    .remove(&connection_closed.peer_id);
+                self.inbound_status_grace.remove(&connection_closed.peer_id);
+                let mut peer_db = self.peer_db.write();
+                peer_db.update_connection_status(
                     &connection_closed.peer_id,
                     ConnectionStatus::Disconnected {
                         since: Instant::now(),
                     },
                 );
+                peer_db.clear_unsupported_protocols(&connection_closed.peer_id);
                 info!(
                     "[{}] on_swarm_event ConnectionClosed. endpoint: {:?}",
                     connection_closed.peer_id, connection_closed.endpoint
                 );
             }
-            FromSwarm::DialFailure(_) => {
-                // TODO: https://github.com/sigp/lighthouse/blob/ff9b09d9646b712b2fd9fe26feeed5758daa0aa6/beacon_node/lighthouse_network/src/peer_manager/network_behaviour.rs#L130
+            FromSwarm::DialFailure(dial_failure) => {
+                // Ref: https://github.com/sigp/lighthouse/blob/ff9b09d9646b712b2fd9fe26feeed5758daa0aa6/beacon_node/lighthouse_network/src/peer_manager/network_behaviour.rs#L130
+                if let Some(peer_id) = dial_failure.peer_id {
+                    self.dial_failed(&peer_id);
+                }
             }
             FromSwarm::ExternalAddrConfirmed(_) => {
-                // TODO:https://github.com/sigp/lighthouse/blob/ff9b09d9646b712b2fd9fe26feeed5758daa0aa6/beacon_node/lighthouse_network/src/peer_manager/network_behaviour.rs#L138
+                // The local ENR is updated with the confirmed address by the discovery behaviour.
             }
             FromSwarm::AddressChange(_)
             | FromSwarm::ListenFailure(_)
@@ -135,11 +215,26 @@ impl NetworkBehaviour for PeerManager {
         trace!("poll");
 
         while self.heartbeat.poll_tick(cx).is_ready() {
+            self.trim_peers();
+            self.prune_disconnected_peers();
+            self.save_peer_db();
             if self.need_more_peers() {
                 return Poll::Ready(ToSwarm::GenerateEvent(PeerManagerEvent::NeedMorePeers));
             }
         }
 
+        // STATUS all peers after each epoch boundary so that our view of the peers' heads is
+        // updated as the chain advances.
+        if let Some(epoch_status) = self.epoch_status.as_mut() {
+            while epoch_status.poll_tick(cx).is_ready() {
+                for peer_id in self.status_peers.iter().copied().collect::<Vec<_>>() {
+                    // Reset the timer of the regular re-status to avoid flooding the peer.
+                    self.status_peers.insert(peer_id);
+                    self.events.push(PeerManagerEvent::SendStatus(peer_id));
+                }
+            }
+        }
+
         if !self.events.is_empty() {
             // Emit peer manager event
             return Poll::Ready(ToSwarm::GenerateEvent(self.events.remove(0)));
@@ -162,8 +257,125 @@ impl NetworkBehaviour for PeerManager {
             }
         }
 
-        if let Some(peer_id) = self.peers_to_dial.pop_front() {
+        // PING the peers regularly to learn if their MetaData has changed.
+        loop {
+            match self.ping_peers.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(peer_id))) => {
+                    self.ping_peers.insert(peer_id);
+                    self.events.push(PeerManagerEvent::Ping(peer_id));
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    error!("Failed to check for peers to ping. error: {}", e);
+                }
+                Poll::Ready(None) | Poll::Pending => {
+                    break;
+                }
+            }
+        }
+
+        // Send our Status to the peers that dialed us but haven't sent theirs yet, e.g. as they
+        // wait for us to do so.
+        loop {
+            match self.inbound_status_grace.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(peer_id))) => {
+                    debug!(
+                        "[{}] The peer dialed us but hasn't sent Status yet. Sending ours.",
+                        peer_id
+                    );
+                    self.events.push(PeerManagerEvent::SendStatus(peer_id));
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    error!("Failed to check for peers to send Status. error: {}", e);
+                }
+                Poll::Ready(None) | Poll::Pending => {
+                    break;
+                }
+            }
+        }
+
+        // Disconnect the peers that dialed us but haven't completed the Status handshake in time.
+        loop {
+            match self.inbound_status_pending.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(peer_id))) => {
+                    info!(
+                        "[{}] The peer dialed us but hasn't completed the Status handshake in time.",
+                        peer_id
+                    );
+                    self.disconnect_peer(&peer_id, lighthouse_network::rpc::GoodbyeReason::Fault);
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    error!("Failed to check for peers awaiting Status. error: {}", e);
+                }
+                Poll::Ready(None) | Poll::Pending => {
+                    break;
+                }
+            }
+        }
+
+        // Re-dial the peers whose dial backoff has expired, if we still need peers.
+        loop {
+            match self.dial_backoff.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(peer_id))) => {
+                    let connected = self.peer_db.read().is_connected(&peer_id);
+                    if !connected && self.need_more_peers() {
+                        trace!("[{}] Dial backoff expired. Re-dialing the peer.", peer_id);
+                        self.dial_peer(peer_id);
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    error!("Failed to check for peers to re-dial. error: {}", e);
+                }
+                Poll::Ready(None) | Poll::Pending => {
+                    break;
+                }
+            }
+        }
+
+        // Allow dialing the unreachable peers again once the cooldown has expired. They are
+        // dialed when discovered again.
+        loop {
+            match self.unreachable_peers.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(peer_id))) => {
+                    trace!("[{}] The unreachable peer cooldown has expired.", peer_id);
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    error!(
+                        "Failed to check for unreachable peers to retry. error: {}",
+                        e
+                    );
+                }
+                Poll::Ready(None) | Poll::Pending => {
+                    break;
+                }
+            }
+        }
+
+        // Lift the bans that have expired.
+        loop {
+            match self.banned_peers.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(peer_id))) => {
+                    info!("[{}] The ban of the peer has expired.", peer_id);
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    error!("Failed to check for peers to unban. error: {}", e);
+                }
+                Poll::Ready(None) | Poll::Pending => {
+                    break;
+                }
+            }
+        }
+
+        if !self.events.is_empty() {
+            return Poll::Ready(ToSwarm::GenerateEvent(self.events.remove(0)));
+        }
+
+        if self.dialing_peers.len() >= self.max_concurrent_dials() {
+            return Poll::Pending;
+        }
+
+        if let Some(peer_id) = self.next_peer_to_dial() {
             trace!("[{}] Dialing to the peer.", peer_id);
+            self.dialing_peers.insert(peer_id);
 
             return Poll::Ready(ToSwarm::Dial {
                 opts: DialOpts::peer_id(peer_id)
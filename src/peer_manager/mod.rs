@@ -1,13 +1,21 @@
-use crate::peer_db::{ConnectionStatus, SyncStatus};
+use crate::peer_db::{ip_address, ConnectionStatus, SyncStatus};
+use crate::rpc::error::RPCError;
 use crate::PeerDB;
 use delay_map::HashSetDelay;
-use libp2p::PeerId;
+use libp2p::core::Endpoint;
+use libp2p::{Multiaddr, PeerId};
+use lighthouse_network::types::EnrAttestationBitfield;
 use parking_lot::RwLock;
 use smallvec::{smallvec, SmallVec};
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
-use tracing::{info, trace};
+use std::time::{Duration, Instant};
+use tracing::{error, info, trace, warn};
+use types::{MainnetEthSpec, SubnetId};
 
 pub(crate) mod behaviour;
 
@@ -15,6 +23,55 @@ pub(crate) mod behaviour;
 // requests. This defines the interval in seconds.
 const HEARTBEAT_INTERVAL: u64 = 30;
 
+// The maximum number of dials in progress at the same time.
+const MAX_CONCURRENT_DIALS: usize = 5;
+
+// At cold start, until this number of peers are connected, peers are dialed more aggressively to
+// reduce the time to sync.
+const FAST_START_PEERS: usize = 5;
+// The maximum number of dials in progress at the same time during the cold start.
+const FAST_START_MAX_CONCURRENT_DIALS: usize = 20;
+
+// The interval of sending PING to each peer.
+// https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#ping
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+// The time to wait before re-dialing a peer we failed to dial.
+const DIAL_BACKOFF: Duration = Duration::from_secs(60);
+
+// A peer we failed to dial this many times in a row is considered unreachable, and isn't dialed
+// for `UNREACHABLE_PEER_COOLDOWN`.
+const MAX_CONSECUTIVE_DIAL_FAILURES: usize = 5;
+const UNREACHABLE_PEER_COOLDOWN: Duration = Duration::from_secs(1800);
+
+// The time to wait for the Status from a peer that dialed us, before sending ours.
+const INBOUND_STATUS_GRACE: Duration = Duration::from_secs(5);
+// The time to wait for the Status from a peer that dialed us, before disconnecting it.
+const INBOUND_STATUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A peer whose connection is closed due to RPC errors this many times within `CHURN_WINDOW` is
+// banned for `CHURN_BAN_DURATION`, to stop it from reconnecting and failing in a tight loop.
+const MAX_RPC_CLOSES: usize = 3;
+const CHURN_WINDOW: Duration = Duration::from_secs(600);
+const CHURN_BAN_DURATION: Duration = Duration::from_secs(1800);
+
+// The score deltas of a peer on which we failed to send a response, or to close the substream
+// after the response. A failed send means the peer didn't get what it asked for, so it weighs more.
+const SEND_FAILED_SCORE_DELTA: f64 = -5.0;
+const CLOSE_FAILED_SCORE_DELTA: f64 = -1.0;
+
+// Peers disconnected for longer than this are removed from the PeerDB on the heartbeat, so that
+// it doesn't grow without bound over a long run.
+const DISCONNECTED_PEER_MAX_AGE: Duration = Duration::from_secs(6 * 60 * 60);
+
+// The number of queued events held without a heap allocation. See
+// `benches/smallvec_inline_capacity.rs` for the allocations at each capacity.
+const EVENTS_INLINE_CAPACITY: usize = 10;
+
+// The delay after an epoch boundary before sending STATUS to all peers, so that peers have likely
+// processed the first block of the epoch.
+const EPOCH_STATUS_DELAY: Duration = Duration::from_secs(4);
+
 // ////////////////////////////////////////////////////////
 // Public events sent by PeerManager module
 // ////////////////////////////////////////////////////////
@@ -32,6 +89,152 @@ pub(crate) enum PeerManagerEvent {
     SendStatus(PeerId),
     /// The peer should be disconnected.
     DisconnectPeer(PeerId, lighthouse_network::rpc::GoodbyeReason),
+    /// Request to send a PING to a peer.
+    Ping(PeerId),
+    /// Request to send a METADATA request to a peer.
+    MetaData(PeerId),
+}
+
+/// The timing of epochs, used to STATUS all peers shortly after each epoch boundary.
+pub(crate) struct EpochTiming {
+    /// The duration until the next epoch boundary.
+    pub(crate) duration_to_next_epoch: Duration,
+    /// The duration of an epoch.
+    pub(crate) epoch_duration: Duration,
+}
+
+/// The limits on peers sharing the same network location, to resist eclipse attacks.
+pub(crate) struct IpLimits {
+    /// The maximum number of peers connected from the same IP address.
+    pub(crate) max_peers_per_ip: usize,
+    /// The maximum number of peers connected from the same /24 subnet (IPv4 only).
+    pub(crate) max_peers_per_subnet: usize,
+}
+
+/// The reason a connection is denied by the `IpLimits`.
+#[derive(Debug)]
+pub(crate) enum IpLimitExceeded {
+    PeersPerIp(IpAddr),
+    PeersPerSubnet(IpAddr),
+}
+
+impl Display for IpLimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpLimitExceeded::PeersPerIp(ip) => write!(f, "Too many peers from the IP: {ip}"),
+            IpLimitExceeded::PeersPerSubnet(ip) => {
+                write!(f, "Too many peers from the subnet of the IP: {ip}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for IpLimitExceeded {}
+
+/// The limits on the number of connected peers, so that connections beyond the target are
+/// denied up front rather than accepted and then disconnected.
+pub(crate) struct ConnectionLimits {
+    /// The maximum number of peers connected in total, checked on inbound connections.
+    pub(crate) max_peers: usize,
+    /// The maximum number of peers that dialed us.
+    pub(crate) max_inbound_peers: usize,
+    /// The maximum number of peers we dialed.
+    pub(crate) max_outbound_peers: usize,
+}
+
+/// The reason a connection is denied by the `ConnectionLimits`.
+#[derive(Debug)]
+pub(crate) enum ConnectionLimitExceeded {
+    Peers(usize),
+    InboundPeers(usize),
+    OutboundPeers(usize),
+}
+
+impl Display for ConnectionLimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionLimitExceeded::Peers(limit) => write!(f, "Too many peers. limit: {limit}"),
+            ConnectionLimitExceeded::InboundPeers(limit) => {
+                write!(f, "Too many inbound peers. limit: {limit}")
+            }
+            ConnectionLimitExceeded::OutboundPeers(limit) => {
+                write!(f, "Too many outbound peers. limit: {limit}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnectionLimitExceeded {}
+
+/// The allowlist and denylist of peers, for operational control such as testnets and incident
+/// response.
+#[derive(Default)]
+pub(crate) struct PeerFilter {
+    /// If set, only these peers are connected.
+    pub(crate) allowlist: Option<HashSet<PeerId>>,
+    /// These peers are never connected.
+    pub(crate) denylist: HashSet<PeerId>,
+}
+
+/// The reason a peer is rejected by the `PeerFilter`.
+#[derive(Debug)]
+pub(crate) enum PeerNotAllowed {
+    Denylisted,
+    NotAllowlisted,
+}
+
+impl Display for PeerNotAllowed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerNotAllowed::Denylisted => write!(f, "The peer is in the denylist"),
+            PeerNotAllowed::NotAllowlisted => write!(f, "The peer is not in the allowlist"),
+        }
+    }
+}
+
+impl std::error::Error for PeerNotAllowed {}
+
+/// The peer is temporarily banned as its connections keep being closed due to RPC errors.
+#[derive(Debug)]
+pub(crate) struct PeerBanned;
+
+impl Display for PeerBanned {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The peer is banned for repeatedly failing RPC")
+    }
+}
+
+impl std::error::Error for PeerBanned {}
+
+/// The priority of a peer to dial. Peers with a higher priority are dialed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum DialPriority {
+    /// The peer has a negative score.
+    LowScore,
+    /// Nothing in particular is known about the peer.
+    Unknown,
+    /// The peer was ahead of us when we last knew.
+    Advanced,
+    /// The peer is in the allowlist.
+    Trusted,
+}
+
+impl PeerFilter {
+    fn is_trusted(&self, peer_id: &PeerId) -> bool {
+        self.allowlist
+            .as_ref()
+            .map_or(false, |allowlist| allowlist.contains(peer_id))
+    }
+
+    pub(crate) fn check(&self, peer_id: &PeerId) -> Result<(), PeerNotAllowed> {
+        if self.denylist.contains(peer_id) {
+            return Err(PeerNotAllowed::Denylisted);
+        }
+        match &self.allowlist {
+            Some(allowlist) if !allowlist.contains(peer_id) => Err(PeerNotAllowed::NotAllowlisted),
+            _ => Ok(()),
+        }
+    }
 }
 
 // ////////////////////////////////////////////////////////
@@ -40,32 +243,188 @@ pub(crate) enum PeerManagerEvent {
 
 pub(crate) struct PeerManager {
     peer_db: Arc<RwLock<PeerDB>>,
-    events: SmallVec<[PeerManagerEvent; 10]>,
+    events: SmallVec<[PeerManagerEvent; EVENTS_INLINE_CAPACITY]>,
     /// Target number of peers to connect to.
     target_peers_count: usize,
+    /// Connected peers whose score drops below this are disconnected on the heartbeat. It's the
+    /// score below which discovered peers aren't dialed.
+    min_score_to_keep: f64,
     /// The heartbeat interval to perform routine maintenance.
     heartbeat: tokio::time::Interval,
     /// A collection of peers awaiting to be Status'd.
     status_peers: HashSetDelay<PeerId>,
-    /// Peers queued to be dialed.
-    peers_to_dial: VecDeque<PeerId>,
+    /// A collection of peers awaiting to be pinged.
+    ping_peers: HashSetDelay<PeerId>,
+    /// Peers that dialed us, whose Status we are waiting for.
+    inbound_status_pending: HashSetDelay<PeerId>,
+    /// Peers that dialed us, which are sent our Status if theirs hasn't arrived by the end of the
+    /// grace period.
+    inbound_status_grace: HashSetDelay<PeerId>,
+    /// Peers queued to be dialed, by priority and then in the order they were queued.
+    peers_to_dial: BinaryHeap<(DialPriority, Reverse<u64>, PeerId)>,
+    /// The sequence number of the next peer queued to be dialed.
+    dial_seq: u64,
+    /// Peers being dialed.
+    dialing_peers: HashSet<PeerId>,
+    /// Peers we failed to dial, which are re-dialed once the backoff expires.
+    dial_backoff: HashSetDelay<PeerId>,
+    /// The number of consecutive failed dials to each peer, reset once connected.
+    dial_failures: HashMap<PeerId, usize>,
+    /// Peers we repeatedly failed to dial, which aren't dialed until the cooldown expires.
+    unreachable_peers: HashSetDelay<PeerId>,
+    /// Ticks shortly after each epoch boundary to STATUS all peers, if enabled.
+    epoch_status: Option<tokio::time::Interval>,
+    /// The limits on peers sharing the same IP address or subnet.
+    ip_limits: IpLimits,
+    /// The limits on the number of connected peers.
+    connection_limits: ConnectionLimits,
+    /// The connected peers that dialed us.
+    inbound_peers: HashSet<PeerId>,
+    /// The connected peers we dialed.
+    outbound_peers: HashSet<PeerId>,
+    /// The peers allowed or denied to connect.
+    peer_filter: Arc<PeerFilter>,
+    /// The times the connections to each peer were closed due to RPC errors, within the
+    /// `CHURN_WINDOW`.
+    rpc_closes: HashMap<PeerId, VecDeque<Instant>>,
+    /// Peers banned for repeatedly failing RPC, until the ban expires.
+    banned_peers: HashSetDelay<PeerId>,
+    /// The file the PeerDB is persisted to on each heartbeat, if any.
+    peer_db_path: Option<PathBuf>,
 }
 
 impl PeerManager {
-    pub(crate) fn new(target_peers_count: usize, peer_db: Arc<RwLock<PeerDB>>) -> Self {
+    pub(crate) fn new(
+        target_peers_count: usize,
+        min_score_to_keep: f64,
+        peer_db: Arc<RwLock<PeerDB>>,
+        epoch_timing: Option<EpochTiming>,
+        ip_limits: IpLimits,
+        connection_limits: ConnectionLimits,
+        peer_filter: Arc<PeerFilter>,
+        peer_db_path: Option<PathBuf>,
+    ) -> Self {
         // Set up the peer manager heartbeat interval
         let heartbeat = tokio::time::interval(tokio::time::Duration::from_secs(HEARTBEAT_INTERVAL));
 
         // NOTE: The time in seconds between re-status's peers. Hardcoding this for now.
         let status_interval = Duration::from_secs(300);
 
-        Self {
+        let epoch_status = epoch_timing.map(|timing| {
+            tokio::time::interval_at(
+                tokio::time::Instant::now() + timing.duration_to_next_epoch + EPOCH_STATUS_DELAY,
+                timing.epoch_duration,
+            )
+        });
+
+        // Reconnect to the peers known from the previous run, except the bad ones.
+        let known_peers = peer_db
+            .read()
+            .snapshot()
+            .into_iter()
+            .filter(|peer| {
+                matches!(
+                    peer.connection_status,
+                    ConnectionStatus::Disconnected { .. }
+                ) && peer.score >= 0.0
+            })
+            .map(|peer| peer.peer_id)
+            .collect::<Vec<_>>();
+
+        let mut peer_manager = Self {
             peer_db,
             events: smallvec![],
             target_peers_count,
+            min_score_to_keep,
             heartbeat,
             status_peers: HashSetDelay::new(status_interval),
-            peers_to_dial: VecDeque::new(),
+            ping_peers: HashSetDelay::new(PING_INTERVAL),
+            inbound_status_pending: HashSetDelay::new(INBOUND_STATUS_TIMEOUT),
+            inbound_status_grace: HashSetDelay::new(INBOUND_STATUS_GRACE),
+            peers_to_dial: BinaryHeap::new(),
+            dial_seq: 0,
+            dialing_peers: HashSet::new(),
+            dial_backoff: HashSetDelay::new(DIAL_BACKOFF),
+            dial_failures: HashMap::new(),
+            unreachable_peers: HashSetDelay::new(UNREACHABLE_PEER_COOLDOWN),
+            epoch_status,
+            ip_limits,
+            connection_limits,
+            inbound_peers: HashSet::new(),
+            outbound_peers: HashSet::new(),
+            peer_filter,
+            rpc_closes: HashMap::new(),
+            banned_peers: HashSetDelay::new(CHURN_BAN_DURATION),
+            peer_db_path,
+        };
+        for peer_id in known_peers {
+            peer_manager.dial_peer(peer_id);
+        }
+        peer_manager
+    }
+
+    pub(crate) fn prune_disconnected_peers(&self) {
+        let pruned = self
+            .peer_db
+            .write()
+            .prune_disconnected(DISCONNECTED_PEER_MAX_AGE);
+        if pruned > 0 {
+            info!(
+                "Pruned {} peers disconnected for longer than {:?}.",
+                pruned, DISCONNECTED_PEER_MAX_AGE
+            );
+        }
+    }
+
+    // Saves the PeerDB on a blocking thread, so that the file I/O doesn't stall the swarm.
+    pub(crate) fn save_peer_db(&self) {
+        let Some(path) = self.peer_db_path.clone() else {
+            return;
+        };
+        let entries = self.peer_db.read().persisted_entries();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = PeerDB::write_entries(&entries, &path) {
+                error!("Failed to save the PeerDB. {}", e);
+            }
+        });
+    }
+
+    // Disconnects the connected peers with a bad score, and then the lowest scored peers beyond the
+    // target number of peers. Trusted peers are never disconnected, though they count towards the
+    // target.
+    pub(crate) fn trim_peers(&mut self) {
+        let (connected, mut peers) = {
+            let peer_db = self.peer_db.read();
+            let connected = peer_db.connected_peer_ids();
+            let peers = connected
+                .iter()
+                .filter(|peer_id| !self.peer_filter.is_trusted(peer_id))
+                .map(|peer_id| (*peer_id, peer_db.score(peer_id).unwrap_or_default()))
+                .collect::<Vec<_>>();
+            (connected.len(), peers)
+        };
+        peers.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        let excess = connected.saturating_sub(self.target_peers_count);
+        for (i, (peer_id, score)) in peers.into_iter().enumerate() {
+            if score < self.min_score_to_keep {
+                info!(
+                    "[{}] Disconnecting the peer due to the bad score: {}",
+                    peer_id, score
+                );
+                self.disconnect_peer(&peer_id, lighthouse_network::rpc::GoodbyeReason::BadScore);
+            } else if i < excess {
+                info!(
+                    "[{}] Disconnecting the peer as we have more peers than the target: {}",
+                    peer_id, self.target_peers_count
+                );
+                self.disconnect_peer(
+                    &peer_id,
+                    lighthouse_network::rpc::GoodbyeReason::TooManyPeers,
+                );
+            } else {
+                break;
+            }
         }
     }
 
@@ -75,34 +434,1082 @@ impl PeerManager {
         count < self.target_peers_count
     }
 
+    // The attestation subnets among `subscribed` that no connected peer is subscribed to, by the
+    // MetaData of the peers.
+    pub(crate) fn subnets_without_peers(
+        &self,
+        subscribed: &EnrAttestationBitfield<MainnetEthSpec>,
+    ) -> Vec<SubnetId> {
+        let peer_db = self.peer_db.read();
+        let connected = peer_db.connected_peer_ids();
+        (0..subscribed.len())
+            .filter(|subnet| subscribed.get(*subnet).unwrap_or(false))
+            .filter(|subnet| {
+                !connected.iter().any(|peer_id| {
+                    peer_db.meta_data(peer_id).map_or(false, |meta_data| {
+                        meta_data.attnets().get(*subnet).unwrap_or(false)
+                    })
+                })
+            })
+            .map(|subnet| SubnetId::new(subnet as u64))
+            .collect()
+    }
+
+    // Checks whether a new connection with the peer keeps the peers within the `IpLimits`.
+    pub(crate) fn check_ip_limits(
+        &self,
+        peer_id: &PeerId,
+        address: &Multiaddr,
+    ) -> Result<(), IpLimitExceeded> {
+        let Some(ip) = ip_address(address) else {
+            return Ok(());
+        };
+
+        let connected_ips = self.peer_db.read().connected_peer_ips(peer_id);
+
+        if connected_ips.iter().filter(|i| **i == ip).count() >= self.ip_limits.max_peers_per_ip {
+            return Err(IpLimitExceeded::PeersPerIp(ip));
+        }
+
+        if let IpAddr::V4(ipv4) = ip {
+            let subnet = |ipv4: &std::net::Ipv4Addr| ipv4.octets()[..3].to_vec();
+            let peers_in_subnet = connected_ips
+                .iter()
+                .filter(|i| matches!(i, IpAddr::V4(i) if subnet(i) == subnet(&ipv4)))
+                .count();
+            if peers_in_subnet >= self.ip_limits.max_peers_per_subnet {
+                return Err(IpLimitExceeded::PeersPerSubnet(ip));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Checks whether a new connection with the peer keeps the peers within the
+    // `ConnectionLimits`. Trusted peers and the peers already connected are always accepted.
+    pub(crate) fn check_connection_limits(
+        &self,
+        peer_id: &PeerId,
+        endpoint: Endpoint,
+    ) -> Result<(), ConnectionLimitExceeded> {
+        if self.peer_filter.is_trusted(peer_id)
+            || self.inbound_peers.contains(peer_id)
+            || self.outbound_peers.contains(peer_id)
+        {
+            return Ok(());
+        }
+
+        let limits = &self.connection_limits;
+        match endpoint {
+            Endpoint::Listener => {
+                if self.peer_db.read().active_peer_count() >= limits.max_peers {
+                    return Err(ConnectionLimitExceeded::Peers(limits.max_peers));
+                }
+                if self.inbound_peers.len() >= limits.max_inbound_peers {
+                    return Err(ConnectionLimitExceeded::InboundPeers(
+                        limits.max_inbound_peers,
+                    ));
+                }
+            }
+            Endpoint::Dialer => {
+                if self.outbound_peers.len() >= limits.max_outbound_peers {
+                    return Err(ConnectionLimitExceeded::OutboundPeers(
+                        limits.max_outbound_peers,
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // The maximum number of dials in progress, which is higher until the first peers connect.
+    fn max_concurrent_dials(&self) -> usize {
+        if self.peer_db.read().active_peer_count() < FAST_START_PEERS {
+            FAST_START_MAX_CONCURRENT_DIALS
+        } else {
+            MAX_CONCURRENT_DIALS
+        }
+    }
+
     pub(crate) fn dial_peer(&mut self, peer_id: PeerId) {
-        self.peers_to_dial.push_back(peer_id);
+        if let Err(e) = self.peer_filter.check(&peer_id) {
+            info!("[{}] Skipped dialing the peer. {}", peer_id, e);
+            return;
+        }
+        if self.dial_backoff.contains_key(&peer_id) {
+            trace!("[{}] Skipped dialing the peer as it's in backoff.", peer_id);
+            return;
+        }
+        if self.unreachable_peers.contains_key(&peer_id) {
+            trace!(
+                "[{}] Skipped dialing the peer as it's unreachable.",
+                peer_id
+            );
+            return;
+        }
+        if let Err(e) = self.check_banned(&peer_id) {
+            trace!("[{}] Skipped dialing the peer. {}", peer_id, e);
+            return;
+        }
+        let priority = self.dial_priority(&peer_id);
+        self.peers_to_dial
+            .push((priority, Reverse(self.dial_seq), peer_id));
+        self.dial_seq += 1;
+    }
+
+    // Whether dialing the peer would be redundant, as it has a connection or is already being
+    // dialed or queued for dialing.
+    pub(crate) fn is_connected_or_dialing(&self, peer_id: &PeerId) -> bool {
+        self.peer_db.read().is_connected_or_disconnecting(peer_id)
+            || self.dialing_peers.contains(peer_id)
+            || self
+                .peers_to_dial
+                .iter()
+                .any(|(_priority, _seq, id)| id == peer_id)
+    }
+
+    fn dial_priority(&self, peer_id: &PeerId) -> DialPriority {
+        if self.peer_filter.is_trusted(peer_id) {
+            return DialPriority::Trusted;
+        }
+
+        let peer_db = self.peer_db.read();
+        if peer_db.score(peer_id).map_or(false, |score| score < 0.0) {
+            DialPriority::LowScore
+        } else if matches!(peer_db.sync_status(peer_id), Some(SyncStatus::Advanced)) {
+            DialPriority::Advanced
+        } else {
+            DialPriority::Unknown
+        }
+    }
+
+    fn next_peer_to_dial(&mut self) -> Option<PeerId> {
+        self.peers_to_dial
+            .pop()
+            .map(|(_priority, _seq, peer_id)| peer_id)
     }
 
     // A STATUS message has been received from a peer. This resets the status timer.
     pub(crate) fn statusd_peer(&mut self, peer_id: PeerId) {
+        self.inbound_status_pending.remove(&peer_id);
+        self.inbound_status_grace.remove(&peer_id);
         self.status_peers.insert(peer_id);
     }
 
-    pub(crate) fn goodbye(
+    // A PING has been received from a peer.
+    // The sequence number of the peer's MetaData is carried by the PING.
+    pub(crate) fn ping_request(&mut self, peer_id: &PeerId, seq: u64) {
+        self.check_meta_data_seq(peer_id, seq);
+    }
+
+    // A PONG has been received from a peer.
+    // The sequence number of the peer's MetaData is carried by the PONG.
+    pub(crate) fn pong_response(&mut self, peer_id: &PeerId, seq: u64) {
+        self.peer_db.write().update_last_seen(peer_id);
+        self.check_meta_data_seq(peer_id, seq);
+    }
+
+    // A METADATA response has been received from a peer.
+    pub(crate) fn meta_data_response(
+        &mut self,
+        peer_id: &PeerId,
+        meta_data: lighthouse_network::rpc::methods::MetaData<MainnetEthSpec>,
+    ) {
+        self.peer_db.write().update_meta_data(peer_id, meta_data);
+    }
+
+    // Requests the MetaData of the peer if its sequence number has advanced since we last knew,
+    // which means the peer has changed its subnet subscriptions.
+    // https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#ping
+    fn check_meta_data_seq(&mut self, peer_id: &PeerId, seq: u64) {
+        let known_seq = self
+            .peer_db
+            .read()
+            .meta_data(peer_id)
+            .map(|meta_data| *meta_data.seq_number());
+
+        if known_seq.map_or(true, |known_seq| known_seq < seq) {
+            trace!(
+                "[{}] The MetaData seq number has advanced. known: {:?}, received: {}",
+                peer_id,
+                known_seq,
+                seq
+            );
+            self.events.push(PeerManagerEvent::MetaData(*peer_id));
+        }
+    }
+
+    // A peer has failed to negotiate a protocol.
+    pub(crate) fn unsupported_protocol(
+        &mut self,
+        peer_id: &PeerId,
+        protocol: lighthouse_network::rpc::protocol::Protocol,
+    ) {
+        self.peer_db
+            .write()
+            .add_unsupported_protocol(peer_id, protocol);
+    }
+
+    // Failed to send a response to the peer, or to close the substream after the response.
+    pub(crate) fn response_failed(&mut self, peer_id: &PeerId, rpc_error: &RPCError) {
+        let score_delta = match rpc_error {
+            RPCError::SendFailed(_) => SEND_FAILED_SCORE_DELTA,
+            RPCError::CloseFailed(_) => CLOSE_FAILED_SCORE_DELTA,
+            RPCError::Disconnected | RPCError::IncompleteStream => return,
+        };
+        self.peer_db.write().add_score(peer_id, score_delta);
+    }
+
+    pub(crate) fn supports_protocol(
+        &self,
+        peer_id: &PeerId,
+        protocol: &lighthouse_network::rpc::protocol::Protocol,
+    ) -> bool {
+        self.peer_db.read().supports_protocol(peer_id, protocol)
+    }
+
+    // A GOODBYE has been received from a peer. The handler disconnects the peer.
+    pub(crate) fn goodbye_received(
         &mut self,
         peer_id: &PeerId,
         reason: lighthouse_network::rpc::GoodbyeReason,
     ) {
-        trace!("[{}] sending goodbye to the peer.", peer_id);
+        let mut guard = self.peer_db.write();
+        guard.update_goodbye_reason(peer_id, reason);
+        guard.update_connection_status(peer_id, ConnectionStatus::Disconnecting);
+    }
+
+    // Sends a GOODBYE to the peer with the reason and disconnects it.
+    pub(crate) fn disconnect_peer(
+        &mut self,
+        peer_id: &PeerId,
+        reason: lighthouse_network::rpc::GoodbyeReason,
+    ) {
+        trace!(
+            "[{}] sending goodbye to the peer. reason: {}",
+            peer_id,
+            reason
+        );
 
         let mut guard = self.peer_db.write();
 
-        if matches!(
-            reason,
-            lighthouse_network::rpc::GoodbyeReason::IrrelevantNetwork
-        ) {
-            guard.update_sync_status(peer_id, SyncStatus::IrrelevantPeer);
+        match reason {
+            lighthouse_network::rpc::GoodbyeReason::IrrelevantNetwork => {
+                guard.update_sync_status(peer_id, SyncStatus::IrrelevantPeer);
+            }
+            // The peer has misbehaved, so it's banned rather than reconnected as soon as it's
+            // discovered again.
+            lighthouse_network::rpc::GoodbyeReason::BadScore => {
+                self.banned_peers.insert(*peer_id);
+            }
+            // The peer is fine but we have enough peers. Its sync status is kept as is, so that it
+            // can be reconnected once we need more peers.
+            lighthouse_network::rpc::GoodbyeReason::TooManyPeers => {}
+            _ => {}
         }
 
         guard.update_connection_status(peer_id, ConnectionStatus::Disconnecting);
+        guard.record_goodbye_sent(*peer_id, reason.clone());
+
+        crate::metrics::inc_counter_vec(&crate::metrics::GOODBYES_SENT, &[&reason.to_string()]);
 
         self.events
             .push(PeerManagerEvent::DisconnectPeer(*peer_id, reason));
     }
+
+    // A dial to the peer has failed. The peer is re-dialed after a backoff, unless it has failed
+    // too many times in a row, in which case it's left alone for a while. Discovery marks the peer
+    // disconnected in the DHT as well.
+    pub(crate) fn dial_failed(&mut self, peer_id: &PeerId) {
+        self.dialing_peers.remove(peer_id);
+        self.peer_db.write().dial_failed(peer_id);
+        crate::metrics::inc_counter(&crate::metrics::DIAL_FAILURES);
+
+        let failures = self.dial_failures.entry(*peer_id).or_default();
+        *failures += 1;
+        if *failures < MAX_CONSECUTIVE_DIAL_FAILURES {
+            self.dial_backoff.insert(*peer_id);
+            return;
+        }
+
+        info!(
+            "[{}] Failed to dial the peer {} times in a row. Not dialing it for {:?}.",
+            peer_id, failures, UNREACHABLE_PEER_COOLDOWN
+        );
+        self.dial_failures.remove(peer_id);
+        self.dial_backoff.remove(peer_id);
+        self.unreachable_peers.insert(*peer_id);
+    }
+
+    pub(crate) fn check_banned(&self, peer_id: &PeerId) -> Result<(), PeerBanned> {
+        if self.banned_peers.contains_key(peer_id) {
+            Err(PeerBanned)
+        } else {
+            Ok(())
+        }
+    }
+
+    // The connection to a peer has been closed due to an RPC error. A peer that keeps hitting this
+    // is likely incompatible with us, so it's banned rather than reconnected over and over.
+    pub(crate) fn rpc_connection_closed(&mut self, peer_id: &PeerId) {
+        let now = Instant::now();
+        self.rpc_closes.retain(|_peer_id, closes| {
+            while closes
+                .front()
+                .map_or(false, |t| now.duration_since(*t) >= CHURN_WINDOW)
+            {
+                closes.pop_front();
+            }
+            !closes.is_empty()
+        });
+
+        let closes = self.rpc_closes.entry(*peer_id).or_default();
+        closes.push_back(now);
+        if closes.len() < MAX_RPC_CLOSES {
+            return;
+        }
+
+        warn!(
+            "[{}] The connection has been closed due to RPC errors {} times within {:?}. Banning the peer for {:?}.",
+            peer_id,
+            closes.len(),
+            CHURN_WINDOW,
+            CHURN_BAN_DURATION
+        );
+        self.rpc_closes.remove(peer_id);
+        self.banned_peers.insert(*peer_id);
+    }
+
+    pub(crate) fn has_queued_events(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::core::ConnectedPoint;
+    use libp2p::swarm::behaviour::{ConnectionClosed, ConnectionEstablished};
+    use libp2p::swarm::{ConnectionId, FromSwarm, NetworkBehaviour, ToSwarm};
+    use lighthouse_network::rpc::methods::{MetaData, MetaDataV2};
+    use lighthouse_network::types::{EnrAttestationBitfield, EnrSyncCommitteeBitfield};
+    use std::task::{Context, Poll};
+
+    fn peer_manager() -> PeerManager {
+        peer_manager_with_epoch_timing(None)
+    }
+
+    fn peer_manager_with_epoch_timing(epoch_timing: Option<EpochTiming>) -> PeerManager {
+        peer_manager_with(epoch_timing, PeerFilter::default())
+    }
+
+    fn peer_manager_with(
+        epoch_timing: Option<EpochTiming>,
+        peer_filter: PeerFilter,
+    ) -> PeerManager {
+        PeerManager::new(
+            10,
+            -20.0,
+            Arc::new(RwLock::new(PeerDB::new())),
+            epoch_timing,
+            IpLimits {
+                max_peers_per_ip: 2,
+                max_peers_per_subnet: 5,
+            },
+            ConnectionLimits {
+                max_peers: 11,
+                max_inbound_peers: 8,
+                max_outbound_peers: 8,
+            },
+            Arc::new(peer_filter),
+            None,
+        )
+    }
+
+    fn connect(peer_manager: &PeerManager, peer_id: PeerId) {
+        connect_from(peer_manager, peer_id, "/ip4/127.0.0.1/tcp/9000");
+    }
+
+    fn connect_from(peer_manager: &PeerManager, peer_id: PeerId, address: &str) {
+        peer_manager
+            .peer_db
+            .write()
+            .add_peer(peer_id, address.parse().unwrap(), true);
+    }
+
+    // Polls the peer manager until it has nothing more to do, and returns the number of dials.
+    fn poll_dials(peer_manager: &mut PeerManager) -> usize {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut dials = 0;
+        while let Poll::Ready(event) = peer_manager.poll(&mut cx) {
+            if matches!(event, ToSwarm::Dial { .. }) {
+                dials += 1;
+            }
+        }
+        dials
+    }
+
+    // Notifies the peer manager of a connection to the peer.
+    fn establish(peer_manager: &mut PeerManager, peer_id: PeerId, endpoint: &ConnectedPoint) {
+        peer_manager.on_swarm_event(FromSwarm::ConnectionEstablished(ConnectionEstablished {
+            peer_id,
+            connection_id: ConnectionId::new_unchecked(0),
+            endpoint,
+            failed_addresses: &[],
+            other_established: 0,
+        }));
+    }
+
+    // Polls the peer manager until it has nothing more to do, and returns the events emitted.
+    fn poll_events(peer_manager: &mut PeerManager) -> Vec<PeerManagerEvent> {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut events = vec![];
+        while let Poll::Ready(event) = peer_manager.poll(&mut cx) {
+            if let ToSwarm::GenerateEvent(event) = event {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    fn meta_data(seq_number: u64) -> MetaData<MainnetEthSpec> {
+        MetaData::V2(MetaDataV2 {
+            seq_number,
+            attnets: EnrAttestationBitfield::<MainnetEthSpec>::new(),
+            syncnets: EnrSyncCommitteeBitfield::<MainnetEthSpec>::new(),
+        })
+    }
+
+    fn meta_data_requested(peer_manager: &PeerManager, peer_id: &PeerId) -> bool {
+        peer_manager
+            .events
+            .iter()
+            .any(|event| matches!(event, PeerManagerEvent::MetaData(id) if id == peer_id))
+    }
+
+    #[tokio::test]
+    async fn meta_data_is_requested_on_seq_bump() {
+        let mut peer_manager = peer_manager();
+        let peer_id = PeerId::random();
+        connect(&peer_manager, peer_id);
+        peer_manager.meta_data_response(&peer_id, meta_data(1));
+
+        peer_manager.ping_request(&peer_id, 1);
+        assert!(!meta_data_requested(&peer_manager, &peer_id));
+
+        peer_manager.pong_response(&peer_id, 2);
+        assert!(meta_data_requested(&peer_manager, &peer_id));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn epoch_status_is_aligned_to_epoch_boundaries() {
+        let epoch_duration = Duration::from_secs(384);
+        let mut peer_manager = peer_manager_with_epoch_timing(Some(EpochTiming {
+            duration_to_next_epoch: Duration::from_secs(10),
+            epoch_duration,
+        }));
+        let start = tokio::time::Instant::now();
+        let epoch_status = peer_manager.epoch_status.as_mut().unwrap();
+
+        epoch_status.tick().await;
+        let first = Duration::from_secs(10) + EPOCH_STATUS_DELAY;
+        assert_eq!(start.elapsed(), first);
+
+        epoch_status.tick().await;
+        assert_eq!(start.elapsed(), first + epoch_duration);
+    }
+
+    #[tokio::test]
+    async fn queued_events_are_reported_for_the_liveness_check() {
+        let mut peer_manager = peer_manager();
+        let peer_id = PeerId::random();
+        connect(&peer_manager, peer_id);
+        assert!(!peer_manager.has_queued_events());
+
+        // A MetaData request is queued as the peer has no MetaData yet.
+        peer_manager.pong_response(&peer_id, 1);
+        assert!(peer_manager.has_queued_events());
+    }
+
+    #[tokio::test]
+    async fn peers_per_ip_are_limited() {
+        let peer_manager = peer_manager();
+        let address: Multiaddr = "/ip4/10.0.0.1/tcp/9000".parse().unwrap();
+        for _ in 0..2 {
+            let peer_id = PeerId::random();
+            assert!(peer_manager.check_ip_limits(&peer_id, &address).is_ok());
+            connect_from(&peer_manager, peer_id, "/ip4/10.0.0.1/tcp/9000");
+        }
+
+        assert!(matches!(
+            peer_manager.check_ip_limits(&PeerId::random(), &address),
+            Err(IpLimitExceeded::PeersPerIp(_))
+        ));
+        // Another IP address in the subnet is still accepted.
+        assert!(peer_manager
+            .check_ip_limits(
+                &PeerId::random(),
+                &"/ip4/10.0.0.2/tcp/9000".parse().unwrap()
+            )
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn peers_per_subnet_are_limited() {
+        let peer_manager = peer_manager();
+        for i in 1..=5 {
+            connect_from(
+                &peer_manager,
+                PeerId::random(),
+                &format!("/ip4/10.0.0.{i}/tcp/9000"),
+            );
+        }
+
+        assert!(matches!(
+            peer_manager.check_ip_limits(
+                &PeerId::random(),
+                &"/ip4/10.0.0.6/tcp/9000".parse().unwrap()
+            ),
+            Err(IpLimitExceeded::PeersPerSubnet(_))
+        ));
+        assert!(peer_manager
+            .check_ip_limits(
+                &PeerId::random(),
+                &"/ip4/10.0.1.1/tcp/9000".parse().unwrap()
+            )
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn goodbye_reason_is_stored() {
+        let mut peer_manager = peer_manager();
+        let peer_id = PeerId::random();
+        connect(&peer_manager, peer_id);
+
+        peer_manager.goodbye_received(
+            &peer_id,
+            lighthouse_network::rpc::GoodbyeReason::ClientShutdown,
+        );
+
+        let snapshot = peer_manager.peer_db.read().snapshot();
+        let peer = snapshot
+            .iter()
+            .find(|peer| peer.peer_id == peer_id)
+            .expect("peer");
+        assert!(matches!(
+            peer.goodbye_reason,
+            Some(lighthouse_network::rpc::GoodbyeReason::ClientShutdown)
+        ));
+        assert!(matches!(
+            peer.connection_status,
+            ConnectionStatus::Disconnecting
+        ));
+    }
+
+    #[tokio::test]
+    async fn more_dials_are_issued_until_the_first_peers_connect() {
+        let mut starting = peer_manager();
+        for _ in 0..30 {
+            starting.dial_peer(PeerId::random());
+        }
+        assert_eq!(poll_dials(&mut starting), FAST_START_MAX_CONCURRENT_DIALS);
+
+        let mut started = peer_manager();
+        for _ in 0..FAST_START_PEERS {
+            connect(&started, PeerId::random());
+        }
+        for _ in 0..30 {
+            started.dial_peer(PeerId::random());
+        }
+        assert_eq!(poll_dials(&mut started), MAX_CONCURRENT_DIALS);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_is_sent_to_the_peer_we_dialed() {
+        let mut peer_manager = peer_manager();
+        let peer_id = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/9000".parse().unwrap(),
+            role_override: Endpoint::Dialer,
+        };
+        establish(&mut peer_manager, peer_id, &endpoint);
+
+        let events = poll_events(&mut peer_manager);
+        assert!(events.iter().any(
+            |event| matches!(event, PeerManagerEvent::PeerConnectedOutgoing(id) if *id == peer_id)
+        ));
+
+        // The peer isn't expected to send Status first, so it's kept.
+        tokio::time::advance(INBOUND_STATUS_TIMEOUT).await;
+        let events = poll_events(&mut peer_manager);
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, PeerManagerEvent::DisconnectPeer(..))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_that_dialed_us_is_disconnected_without_status() {
+        let mut peer_manager = peer_manager();
+        let peer_id = PeerId::random();
+        let endpoint = ConnectedPoint::Listener {
+            local_addr: "/ip4/127.0.0.1/tcp/9000".parse().unwrap(),
+            send_back_addr: "/ip4/127.0.0.1/tcp/50000".parse().unwrap(),
+        };
+        establish(&mut peer_manager, peer_id, &endpoint);
+
+        // We wait for the peer's Status.
+        let events = poll_events(&mut peer_manager);
+        assert!(events.iter().any(
+            |event| matches!(event, PeerManagerEvent::PeerConnectedIncoming(id) if *id == peer_id)
+        ));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, PeerManagerEvent::SendStatus(_))));
+
+        tokio::time::advance(INBOUND_STATUS_TIMEOUT).await;
+        let events = poll_events(&mut peer_manager);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            PeerManagerEvent::DisconnectPeer(id, lighthouse_network::rpc::GoodbyeReason::Fault) if *id == peer_id
+        )));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_that_dialed_us_is_kept_once_it_sends_status() {
+        let mut peer_manager = peer_manager();
+        let peer_id = PeerId::random();
+        let endpoint = ConnectedPoint::Listener {
+            local_addr: "/ip4/127.0.0.1/tcp/9000".parse().unwrap(),
+            send_back_addr: "/ip4/127.0.0.1/tcp/50000".parse().unwrap(),
+        };
+        establish(&mut peer_manager, peer_id, &endpoint);
+        poll_events(&mut peer_manager);
+
+        // We respond to the peer's Status.
+        peer_manager.statusd_peer(peer_id);
+
+        tokio::time::advance(INBOUND_STATUS_TIMEOUT).await;
+        let events = poll_events(&mut peer_manager);
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, PeerManagerEvent::DisconnectPeer(..))));
+    }
+
+    #[tokio::test]
+    async fn denylisted_peers_are_denied() {
+        let denylisted = PeerId::random();
+        let mut peer_manager = peer_manager_with(
+            None,
+            PeerFilter {
+                allowlist: None,
+                denylist: HashSet::from([denylisted]),
+            },
+        );
+        let local_addr: Multiaddr = "/ip4/127.0.0.1/tcp/9000".parse().unwrap();
+        let remote_addr: Multiaddr = "/ip4/10.0.0.1/tcp/9000".parse().unwrap();
+
+        assert!(peer_manager
+            .handle_established_inbound_connection(
+                ConnectionId::new_unchecked(0),
+                denylisted,
+                &local_addr,
+                &remote_addr,
+            )
+            .is_err());
+        assert!(peer_manager
+            .handle_pending_outbound_connection(
+                ConnectionId::new_unchecked(1),
+                Some(denylisted),
+                &[],
+                Endpoint::Dialer,
+            )
+            .is_err());
+        // Other peers are accepted.
+        assert!(peer_manager
+            .handle_established_inbound_connection(
+                ConnectionId::new_unchecked(2),
+                PeerId::random(),
+                &local_addr,
+                &remote_addr,
+            )
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn only_allowlisted_peers_are_connected() {
+        let allowlisted = PeerId::random();
+        let mut peer_manager = peer_manager_with(
+            None,
+            PeerFilter {
+                allowlist: Some(HashSet::from([allowlisted])),
+                denylist: HashSet::new(),
+            },
+        );
+
+        assert!(peer_manager
+            .handle_pending_outbound_connection(
+                ConnectionId::new_unchecked(0),
+                Some(allowlisted),
+                &[],
+                Endpoint::Dialer,
+            )
+            .is_ok());
+        assert!(peer_manager
+            .handle_pending_outbound_connection(
+                ConnectionId::new_unchecked(1),
+                Some(PeerId::random()),
+                &[],
+                Endpoint::Dialer,
+            )
+            .is_err());
+        assert!(peer_manager
+            .handle_established_inbound_connection(
+                ConnectionId::new_unchecked(2),
+                PeerId::random(),
+                &"/ip4/127.0.0.1/tcp/9000".parse().unwrap(),
+                &"/ip4/10.0.0.1/tcp/9000".parse().unwrap(),
+            )
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn meta_data_response_updates_the_stored_subnets() {
+        let mut peer_manager = peer_manager();
+        let peer_id = PeerId::random();
+        connect(&peer_manager, peer_id);
+        peer_manager.meta_data_response(&peer_id, meta_data(1));
+
+        let mut attnets = EnrAttestationBitfield::<MainnetEthSpec>::new();
+        attnets.set(3, true).unwrap();
+        peer_manager.meta_data_response(
+            &peer_id,
+            MetaData::V2(MetaDataV2 {
+                seq_number: 2,
+                attnets,
+                syncnets: EnrSyncCommitteeBitfield::<MainnetEthSpec>::new(),
+            }),
+        );
+
+        let peer_db = peer_manager.peer_db.read();
+        let stored = peer_db.meta_data(&peer_id).expect("meta data");
+        assert_eq!(*stored.seq_number(), 2);
+        assert!(stored.attnets().get(3).unwrap());
+        assert!(!stored.attnets().get(4).unwrap());
+    }
+
+    #[tokio::test]
+    async fn pong_updates_the_last_seen() {
+        let mut peer_manager = peer_manager();
+        let peer_id = PeerId::random();
+        connect(&peer_manager, peer_id);
+        let last_seen = |peer_manager: &PeerManager| {
+            peer_manager
+                .peer_db
+                .read()
+                .snapshot()
+                .into_iter()
+                .find(|peer| peer.peer_id == peer_id)
+                .and_then(|peer| peer.last_seen)
+        };
+        assert!(last_seen(&peer_manager).is_none());
+
+        let before = Instant::now();
+        peer_manager.pong_response(&peer_id, 1);
+        assert!(last_seen(&peer_manager).expect("last seen") >= before);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_is_re_dialed_once_its_backoff_expires() {
+        let mut peer_manager = peer_manager();
+        let peer_id = PeerId::random();
+        peer_manager.dial_peer(peer_id);
+        assert_eq!(poll_dials(&mut peer_manager), 1);
+
+        peer_manager.dial_failed(&peer_id);
+        // The peer isn't dialed while backing off, even if discovered again.
+        peer_manager.dial_peer(peer_id);
+        assert_eq!(poll_dials(&mut peer_manager), 0);
+
+        tokio::time::advance(DIAL_BACKOFF).await;
+        assert_eq!(poll_dials(&mut peer_manager), 1);
+    }
+
+    #[tokio::test]
+    async fn peers_are_dialed_by_priority() {
+        let mut peer_manager = peer_manager();
+        let (low_score, unknown, advanced) = (PeerId::random(), PeerId::random(), PeerId::random());
+        connect(&peer_manager, low_score);
+        connect(&peer_manager, advanced);
+        {
+            let mut peer_db = peer_manager.peer_db.write();
+            peer_db.add_score(&low_score, -5.0);
+            peer_db.update_sync_status(&advanced, SyncStatus::Advanced);
+        }
+
+        for peer_id in [low_score, unknown, advanced] {
+            peer_manager.dial_peer(peer_id);
+        }
+
+        assert_eq!(peer_manager.next_peer_to_dial(), Some(advanced));
+        assert_eq!(peer_manager.next_peer_to_dial(), Some(unknown));
+        assert_eq!(peer_manager.next_peer_to_dial(), Some(low_score));
+        assert_eq!(peer_manager.next_peer_to_dial(), None);
+    }
+
+    #[tokio::test]
+    async fn trusted_peers_are_dialed_first() {
+        let trusted = PeerId::random();
+        let peer_manager = peer_manager_with(
+            None,
+            PeerFilter {
+                allowlist: Some(HashSet::from([trusted])),
+                denylist: HashSet::new(),
+            },
+        );
+        connect(&peer_manager, trusted);
+        peer_manager
+            .peer_db
+            .write()
+            .update_sync_status(&trusted, SyncStatus::Advanced);
+
+        assert_eq!(peer_manager.dial_priority(&trusted), DialPriority::Trusted);
+        assert!(DialPriority::Trusted > DialPriority::Advanced);
+        assert!(DialPriority::Advanced > DialPriority::Unknown);
+        assert!(DialPriority::Unknown > DialPriority::LowScore);
+    }
+
+    #[tokio::test]
+    async fn goodbyes_sent_are_recorded() {
+        let mut peer_manager = peer_manager();
+        let reason = lighthouse_network::rpc::GoodbyeReason::TooManyPeers;
+        let goodbyes_sent = || {
+            crate::metrics::get_int_counter(&crate::metrics::GOODBYES_SENT, &[&reason.to_string()])
+                .map_or(0, |counter| counter.get())
+        };
+        let before = goodbyes_sent();
+        let peer_id = PeerId::random();
+        connect(&peer_manager, peer_id);
+
+        peer_manager.disconnect_peer(&peer_id, reason.clone());
+
+        let recent = peer_manager.peer_db.read().recent_goodbyes_sent();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].peer_id, peer_id);
+        assert!(matches!(
+            recent[0].reason,
+            lighthouse_network::rpc::GoodbyeReason::TooManyPeers
+        ));
+        // Other tests may send Goodbyes concurrently.
+        assert!(goodbyes_sent() > before);
+    }
+
+    #[tokio::test]
+    async fn peer_is_banned_after_repeated_rpc_closes() {
+        let mut peer_manager = peer_manager();
+        let (peer_id, other_peer_id) = (PeerId::random(), PeerId::random());
+
+        for _ in 0..MAX_RPC_CLOSES - 1 {
+            peer_manager.rpc_connection_closed(&peer_id);
+        }
+        peer_manager.rpc_connection_closed(&other_peer_id);
+        assert!(peer_manager.check_banned(&peer_id).is_ok());
+
+        // The connection has been closed once too often within the window.
+        peer_manager.rpc_connection_closed(&peer_id);
+        assert!(peer_manager.check_banned(&peer_id).is_err());
+        assert!(peer_manager.check_banned(&other_peer_id).is_ok());
+    }
+
+    #[tokio::test]
+    async fn excess_inbound_connections_are_denied() {
+        let mut peer_manager = peer_manager();
+        let local_addr: Multiaddr = "/ip4/127.0.0.1/tcp/9000".parse().unwrap();
+        let inbound_peers = (0..8)
+            .map(|i| {
+                let peer_id = PeerId::random();
+                let endpoint = ConnectedPoint::Listener {
+                    local_addr: local_addr.clone(),
+                    send_back_addr: format!("/ip4/10.0.{i}.1/tcp/9000").parse().unwrap(),
+                };
+                establish(&mut peer_manager, peer_id, &endpoint);
+                (peer_id, endpoint)
+            })
+            .collect::<Vec<_>>();
+
+        let remote_addr: Multiaddr = "/ip4/10.1.0.1/tcp/9000".parse().unwrap();
+        let accept = |peer_manager: &mut PeerManager| {
+            peer_manager
+                .handle_established_inbound_connection(
+                    ConnectionId::new_unchecked(1),
+                    PeerId::random(),
+                    &local_addr,
+                    &remote_addr,
+                )
+                .is_ok()
+        };
+        assert!(!accept(&mut peer_manager));
+
+        // The peer still counts while it has another connection open.
+        let (peer_id, endpoint) = &inbound_peers[0];
+        let close = |remaining_established| {
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id: *peer_id,
+                connection_id: ConnectionId::new_unchecked(0),
+                endpoint,
+                remaining_established,
+            })
+        };
+        peer_manager.on_swarm_event(close(1));
+        assert!(!accept(&mut peer_manager));
+
+        peer_manager.on_swarm_event(close(0));
+        assert!(accept(&mut peer_manager));
+    }
+
+    #[tokio::test]
+    async fn failed_sends_are_penalized_more_than_failed_closes() {
+        let mut peer_manager = peer_manager();
+        let (send_failed, close_failed) = (PeerId::random(), PeerId::random());
+        connect(&peer_manager, send_failed);
+        connect(&peer_manager, close_failed);
+
+        peer_manager.response_failed(
+            &send_failed,
+            &RPCError::SendFailed(lighthouse_network::rpc::RPCError::StreamTimeout),
+        );
+        peer_manager.response_failed(
+            &close_failed,
+            &RPCError::CloseFailed(lighthouse_network::rpc::RPCError::StreamTimeout),
+        );
+
+        let peer_db = peer_manager.peer_db.read();
+        let close_failed_score = peer_db.score(&close_failed).unwrap();
+        assert!(close_failed_score < 0.0);
+        assert!(peer_db.score(&send_failed).unwrap() < close_failed_score);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_is_skipped_after_repeated_dial_failures() {
+        let mut peer_manager = peer_manager();
+        let peer_id = PeerId::random();
+        peer_manager.dial_peer(peer_id);
+        assert_eq!(poll_dials(&mut peer_manager), 1);
+
+        // The peer is re-dialed after each backoff until it has failed too many times in a row.
+        for _ in 1..MAX_CONSECUTIVE_DIAL_FAILURES {
+            peer_manager.dial_failed(&peer_id);
+            tokio::time::advance(DIAL_BACKOFF).await;
+            assert_eq!(poll_dials(&mut peer_manager), 1);
+        }
+        peer_manager.dial_failed(&peer_id);
+
+        // The unreachable peer isn't dialed, even if discovered again.
+        peer_manager.dial_peer(peer_id);
+        tokio::time::advance(DIAL_BACKOFF).await;
+        assert_eq!(poll_dials(&mut peer_manager), 0);
+        assert!(!peer_manager.is_connected_or_dialing(&peer_id));
+
+        tokio::time::advance(UNREACHABLE_PEER_COOLDOWN).await;
+        poll_dials(&mut peer_manager);
+        peer_manager.dial_peer(peer_id);
+        assert_eq!(poll_dials(&mut peer_manager), 1);
+    }
+
+    #[tokio::test]
+    async fn connected_and_dialing_peers_are_not_redialed() {
+        let mut peer_manager = peer_manager();
+        let (connected, disconnecting, dialing, queued, unknown) = (
+            PeerId::random(),
+            PeerId::random(),
+            PeerId::random(),
+            PeerId::random(),
+            PeerId::random(),
+        );
+        connect(&peer_manager, connected);
+        connect(&peer_manager, disconnecting);
+        peer_manager
+            .peer_db
+            .write()
+            .update_connection_status(&disconnecting, ConnectionStatus::Disconnecting);
+        peer_manager.dial_peer(dialing);
+        assert_eq!(poll_dials(&mut peer_manager), 1);
+        peer_manager.dial_peer(queued);
+
+        for peer_id in [connected, disconnecting, dialing, queued] {
+            assert!(peer_manager.is_connected_or_dialing(&peer_id));
+        }
+        assert!(!peer_manager.is_connected_or_dialing(&unknown));
+    }
+
+    #[tokio::test]
+    async fn trusted_peers_are_not_trimmed() {
+        let trusted = PeerId::random();
+        let mut peer_manager = peer_manager_with(
+            None,
+            PeerFilter {
+                allowlist: Some(HashSet::from([trusted])),
+                denylist: HashSet::new(),
+            },
+        );
+        connect(&peer_manager, trusted);
+        peer_manager.peer_db.write().add_score(&trusted, -30.0);
+
+        peer_manager.trim_peers();
+
+        assert!(!peer_manager
+            .events
+            .iter()
+            .any(|event| matches!(event, PeerManagerEvent::DisconnectPeer(..))));
+    }
+
+    #[tokio::test]
+    async fn peers_below_the_min_score_are_trimmed() {
+        let mut peer_manager = peer_manager();
+        let (bad, good) = (PeerId::random(), PeerId::random());
+        connect(&peer_manager, bad);
+        connect(&peer_manager, good);
+        peer_manager.peer_db.write().add_score(&bad, -30.0);
+
+        peer_manager.trim_peers();
+
+        let disconnected = peer_manager
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                PeerManagerEvent::DisconnectPeer(peer_id, reason) => Some((peer_id, reason)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(disconnected.len(), 1);
+        assert!(matches!(
+            disconnected[0],
+            (peer_id, lighthouse_network::rpc::GoodbyeReason::BadScore) if *peer_id == bad
+        ));
+    }
+
+    #[tokio::test]
+    async fn subnets_without_connected_peers_are_found() {
+        let peer_manager = peer_manager();
+        let peer_id = PeerId::random();
+        connect(&peer_manager, peer_id);
+        let mut attnets = EnrAttestationBitfield::<MainnetEthSpec>::new();
+        attnets.set(3, true).unwrap();
+        peer_manager.peer_db.write().update_meta_data(
+            &peer_id,
+            MetaData::V2(MetaDataV2 {
+                seq_number: 1,
+                attnets,
+                syncnets: EnrSyncCommitteeBitfield::<MainnetEthSpec>::new(),
+            }),
+        );
+
+        let mut subscribed = EnrAttestationBitfield::<MainnetEthSpec>::new();
+        subscribed.set(3, true).unwrap();
+        subscribed.set(4, true).unwrap();
+        assert_eq!(
+            peer_manager.subnets_without_peers(&subscribed),
+            vec![SubnetId::new(4)]
+        );
+    }
 }
@@ -1,6 +1,8 @@
 use crate::network::ReqId;
+use crate::rpc::error::RPCError;
 use crate::rpc::handler::{Handler, SubstreamId, ToBehaviour};
-use crate::rpc::{ReceivedRequest, ReceivedResponse, RpcEvent};
+use crate::rpc::protocol::{supported_protocols, ProtocolId};
+use crate::rpc::{ReceivedRequest, ReceivedResponse, RpcConfig, RpcEvent};
 use libp2p::core::Endpoint;
 use libp2p::swarm::{
     CloseConnection, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, NotifyHandler,
@@ -21,6 +23,7 @@ use types::{ForkContext, MainnetEthSpec};
 pub(crate) enum InstructionToHandler<Id> {
     Status(Id, lighthouse_network::rpc::StatusMessage, PeerId),
     Goodbye(Id, lighthouse_network::rpc::GoodbyeReason, PeerId),
+    Ping(Id, lighthouse_network::rpc::methods::Ping, PeerId),
     Request(
         Id,
         lighthouse_network::rpc::outbound::OutboundRequest<MainnetEthSpec>,
@@ -28,7 +31,7 @@ pub(crate) enum InstructionToHandler<Id> {
     ),
     Response(
         SubstreamId,
-        lighthouse_network::Response<MainnetEthSpec>,
+        lighthouse_network::rpc::methods::RPCCodedResponse<MainnetEthSpec>,
         PeerId,
     ),
 }
@@ -38,18 +41,42 @@ pub(crate) enum InstructionToHandler<Id> {
 // ////////////////////////////////////////////////////////
 
 pub(crate) struct Behaviour<Id: ReqId> {
-    events: Vec<ToSwarm<RpcEvent, InstructionToHandler<Id>>>,
+    events: Vec<ToSwarm<RpcEvent<Id>, InstructionToHandler<Id>>>,
     fork_context: Arc<ForkContext>,
+    // The maximum bytes of responses each handler buffers.
+    max_buffered_response_bytes: usize,
+    rpc_config: RpcConfig,
+    // The protocols the handlers accept requests on.
+    inbound_protocols: Vec<ProtocolId>,
 }
 
 impl<Id: ReqId> Behaviour<Id> {
-    pub(crate) fn new(fork_context: Arc<ForkContext>) -> Self {
+    pub(crate) fn new(
+        fork_context: Arc<ForkContext>,
+        max_buffered_response_bytes: usize,
+        rpc_config: RpcConfig,
+    ) -> Self {
         Behaviour {
             events: vec![],
             fork_context,
+            max_buffered_response_bytes,
+            rpc_config,
+            inbound_protocols: supported_protocols(),
         }
     }
 
+    // Restricts the protocols we accept requests on, so that a test can play a peer supporting
+    // only some of them.
+    #[cfg(test)]
+    pub(crate) fn with_inbound_protocols(mut self, inbound_protocols: Vec<ProtocolId>) -> Self {
+        self.inbound_protocols = inbound_protocols;
+        self
+    }
+
+    pub(crate) fn has_queued_events(&self) -> bool {
+        !self.events.is_empty()
+    }
+
     // Status
     // https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#status
     pub(crate) fn send_status(
@@ -82,16 +109,48 @@ impl<Id: ReqId> Behaviour<Id> {
         })
     }
 
+    // GetMetaData
+    // https://github.com/ethereum/consensus-specs/blob/dev/specs/altair/p2p-interface.md#getmetadata-v2
+    pub(crate) fn send_meta_data_request(&mut self, request_id: Id, peer_id: PeerId) {
+        trace!("[{}] Sending MetaData request to the peer.", peer_id);
+        self.events.push(ToSwarm::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::Any,
+            event: InstructionToHandler::Request(
+                request_id,
+                lighthouse_network::rpc::outbound::OutboundRequest::MetaData(
+                    lighthouse_network::rpc::methods::MetadataRequest::new_v2(),
+                ),
+                peer_id,
+            ),
+        })
+    }
+
+    // Ping
+    // https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#ping
+    pub(crate) fn send_ping(&mut self, request_id: Id, peer_id: PeerId, seq_number: u64) {
+        trace!("[{}] Sending Ping to the peer.", peer_id);
+        self.events.push(ToSwarm::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::Any,
+            event: InstructionToHandler::Ping(
+                request_id,
+                lighthouse_network::rpc::methods::Ping { data: seq_number },
+                peer_id,
+            ),
+        })
+    }
+
     pub(crate) fn send_request(
         &mut self,
         peer_id: PeerId,
-        request: lighthouse_network::service::api_types::Request,
+        request: lighthouse_network::rpc::outbound::OutboundRequest<MainnetEthSpec>,
         request_id: Id,
     ) {
         self.events.push(ToSwarm::NotifyHandler {
             peer_id,
             handler: NotifyHandler::Any,
-            event: InstructionToHandler::Request(request_id, request.into(), peer_id),
+            event: InstructionToHandler::Request(request_id, request, peer_id),
         })
     }
 
@@ -105,7 +164,71 @@ impl<Id: ReqId> Behaviour<Id> {
         self.events.push(ToSwarm::NotifyHandler {
             peer_id,
             handler: NotifyHandler::One(connection_id),
-            event: InstructionToHandler::Response(substream_id, response, peer_id),
+            event: InstructionToHandler::Response(substream_id, response.into(), peer_id),
+        })
+    }
+
+    // Ends the stream of responses with an error.
+    pub(crate) fn send_error_response(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        substream_id: SubstreamId,
+        code: lighthouse_network::rpc::methods::RPCResponseErrorCode,
+        reason: String,
+    ) {
+        self.events.push(ToSwarm::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::One(connection_id),
+            event: InstructionToHandler::Response(
+                substream_id,
+                lighthouse_network::rpc::methods::RPCCodedResponse::Error(code, reason.into()),
+                peer_id,
+            ),
+        })
+    }
+
+    // Responds to a Ping with our MetaData sequence number.
+    pub(crate) fn send_pong(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        substream_id: SubstreamId,
+        seq_number: u64,
+    ) {
+        self.events.push(ToSwarm::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::One(connection_id),
+            event: InstructionToHandler::Response(
+                substream_id,
+                lighthouse_network::rpc::methods::RPCCodedResponse::Success(
+                    lighthouse_network::rpc::methods::RPCResponse::Pong(
+                        lighthouse_network::rpc::methods::Ping { data: seq_number },
+                    ),
+                ),
+                peer_id,
+            ),
+        })
+    }
+
+    // Responds to a GetMetaData request with our MetaData.
+    pub(crate) fn send_meta_data_response(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        substream_id: SubstreamId,
+        meta_data: lighthouse_network::rpc::methods::MetaData<MainnetEthSpec>,
+    ) {
+        self.events.push(ToSwarm::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::One(connection_id),
+            event: InstructionToHandler::Response(
+                substream_id,
+                lighthouse_network::rpc::methods::RPCCodedResponse::Success(
+                    lighthouse_network::rpc::methods::RPCResponse::MetaData(meta_data),
+                ),
+                peer_id,
+            ),
         })
     }
 }
@@ -114,7 +237,7 @@ impl<Id: ReqId> Behaviour<Id> {
 // SEE https://docs.rs/libp2p/0.39.1/libp2p/tutorial/index.html#network-behaviour
 impl<Id: ReqId> NetworkBehaviour for Behaviour<Id> {
     type ConnectionHandler = Handler<Id>;
-    type ToSwarm = RpcEvent;
+    type ToSwarm = RpcEvent<Id>;
 
     fn handle_established_inbound_connection(
         &mut self,
@@ -123,7 +246,13 @@ impl<Id: ReqId> NetworkBehaviour for Behaviour<Id> {
         _local_addr: &Multiaddr,
         _remote_addr: &Multiaddr,
     ) -> Result<THandler<Self>, ConnectionDenied> {
-        Ok(Handler::new(peer_id, self.fork_context.clone()))
+        Ok(Handler::new(
+            peer_id,
+            self.fork_context.clone(),
+            self.max_buffered_response_bytes,
+            self.rpc_config.clone(),
+            self.inbound_protocols.clone(),
+        ))
     }
 
     fn handle_established_outbound_connection(
@@ -133,7 +262,13 @@ impl<Id: ReqId> NetworkBehaviour for Behaviour<Id> {
         _addr: &Multiaddr,
         _role_override: Endpoint,
     ) -> Result<THandler<Self>, ConnectionDenied> {
-        Ok(Handler::new(peer_id, self.fork_context.clone()))
+        Ok(Handler::new(
+            peer_id,
+            self.fork_context.clone(),
+            self.max_buffered_response_bytes,
+            self.rpc_config.clone(),
+            self.inbound_protocols.clone(),
+        ))
     }
 
     fn on_swarm_event(&mut self, event: FromSwarm) {
@@ -180,14 +315,68 @@ impl<Id: ReqId> NetworkBehaviour for Behaviour<Id> {
                         },
                     )));
             }
-            ToBehaviour::ResponseReceived(response) => {
+            ToBehaviour::ResponseReceived(request_id, response) => {
                 info!(
                     "[{}] [on_connection_handler_event] Received response: {:?}",
                     peer_id, response
                 );
                 self.events
                     .push(ToSwarm::GenerateEvent(RpcEvent::ReceivedResponse(
-                        ReceivedResponse { peer_id, response },
+                        ReceivedResponse {
+                            peer_id,
+                            request_id,
+                            response,
+                        },
+                    )));
+            }
+            ToBehaviour::UnsupportedProtocol(protocol) => {
+                info!(
+                    "[{}] [on_connection_handler_event] Unsupported protocol: {}",
+                    peer_id, protocol
+                );
+                self.events
+                    .push(ToSwarm::GenerateEvent(RpcEvent::UnsupportedProtocol(
+                        peer_id, protocol,
+                    )));
+            }
+            ToBehaviour::ResponsesSent(substream_id) => {
+                self.events
+                    .push(ToSwarm::GenerateEvent(RpcEvent::ResponsesSent(
+                        peer_id,
+                        connection_id,
+                        substream_id,
+                    )));
+            }
+            ToBehaviour::ResponseError {
+                substream_id: _,
+                request_id,
+                code,
+                message,
+            } => {
+                self.events
+                    .push(ToSwarm::GenerateEvent(RpcEvent::ResponseError(
+                        peer_id, request_id, code, message,
+                    )));
+            }
+            ToBehaviour::StreamTerminated {
+                substream_id: _,
+                request_id,
+            } => {
+                self.events
+                    .push(ToSwarm::GenerateEvent(RpcEvent::StreamTerminated(
+                        peer_id, request_id,
+                    )));
+            }
+            ToBehaviour::ResponseFailed {
+                substream_id,
+                error,
+            } => {
+                self.events
+                    .push(ToSwarm::GenerateEvent(RpcEvent::ResponseFailed(
+                        peer_id,
+                        connection_id,
+                        substream_id,
+                        error,
                     )));
             }
             ToBehaviour::CloseConnection(rpc_error) => {
@@ -199,6 +388,12 @@ impl<Id: ReqId> NetworkBehaviour for Behaviour<Id> {
                     peer_id,
                     connection: CloseConnection::All,
                 });
+                // The handler shutting down (e.g. after a goodbye) closes the connection
+                // intentionally, which doesn't count against the peer.
+                if !matches!(rpc_error, RPCError::Disconnected) {
+                    self.events
+                        .push(ToSwarm::GenerateEvent(RpcEvent::ConnectionClosed(peer_id)));
+                }
             }
         }
     }
@@ -214,3 +409,56 @@ impl<Id: ReqId> NetworkBehaviour for Behaviour<Id> {
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use types::{ChainSpec, Hash256, Slot};
+
+    fn rpc_behaviour() -> Behaviour<u32> {
+        let fork_context = Arc::new(ForkContext::new::<MainnetEthSpec>(
+            Slot::new(0),
+            Hash256::zero(),
+            &ChainSpec::mainnet(),
+        ));
+        Behaviour::new(
+            fork_context,
+            1024 * 1024,
+            RpcConfig {
+                request_timeout: Duration::from_secs(15),
+                read_timeout: Duration::from_secs(5),
+            },
+        )
+    }
+
+    fn connection_closed_reported(behaviour: &Behaviour<u32>) -> bool {
+        behaviour
+            .events
+            .iter()
+            .any(|event| matches!(event, ToSwarm::GenerateEvent(RpcEvent::ConnectionClosed(_))))
+    }
+
+    #[test]
+    fn only_closes_on_rpc_errors_are_reported() {
+        let peer_id = PeerId::random();
+
+        // The handler has shut down.
+        let mut behaviour = rpc_behaviour();
+        behaviour.on_connection_handler_event(
+            peer_id,
+            ConnectionId::new_unchecked(0),
+            ToBehaviour::CloseConnection(RPCError::Disconnected),
+        );
+        assert!(!connection_closed_reported(&behaviour));
+
+        // The peer closed the substream without responding.
+        let mut behaviour = rpc_behaviour();
+        behaviour.on_connection_handler_event(
+            peer_id,
+            ConnectionId::new_unchecked(0),
+            ToBehaviour::CloseConnection(RPCError::IncompleteStream),
+        );
+        assert!(connection_closed_reported(&behaviour));
+    }
+}
@@ -4,11 +4,22 @@ use std::fmt::{Debug, Display, Formatter};
 pub enum RPCError {
     /// We have intentionally disconnected.
     Disconnected,
+    /// Failed to send a response on an inbound substream.
+    SendFailed(lighthouse_network::rpc::RPCError),
+    /// Failed to close an inbound substream.
+    CloseFailed(lighthouse_network::rpc::RPCError),
+    /// The peer closed the substream without responding to our request.
+    IncompleteStream,
 }
 
 impl Display for RPCError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str("RPC Error")
+        match self {
+            RPCError::Disconnected => f.write_str("RPC Error"),
+            RPCError::SendFailed(e) => write!(f, "Failed to send a response. error: {}", e),
+            RPCError::CloseFailed(e) => write!(f, "Failed to close substream. error: {}", e),
+            RPCError::IncompleteStream => f.write_str("The stream ended without a response"),
+        }
     }
 }
 
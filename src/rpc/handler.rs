@@ -2,14 +2,20 @@ use crate::network::ReqId;
 use crate::rpc::behaviour::InstructionToHandler;
 use crate::rpc::error::RPCError;
 use crate::rpc::protocol::{
-    InboundFramed, OutboundFramed, OutboundRequest, RpcProtocol, RpcRequestProtocol,
+    InboundFramed, OutboundFramed, OutboundRequest, ProtocolId, RpcProtocol, RpcRequestProtocol,
 };
+use crate::rpc::RpcConfig;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
 use futures::{FutureExt, SinkExt, StreamExt};
 use libp2p::swarm::handler::{ConnectionEvent, FullyNegotiatedInbound, FullyNegotiatedOutbound};
-use libp2p::swarm::{ConnectionHandler, ConnectionHandlerEvent, SubstreamProtocol};
+use libp2p::swarm::{
+    ConnectionHandler, ConnectionHandlerEvent, StreamUpgradeError, SubstreamProtocol,
+};
 use libp2p::{PeerId, Stream};
-use lighthouse_network::rpc::methods::RPCCodedResponse;
+use lighthouse_network::rpc::methods::{RPCCodedResponse, RPCResponse, RPCResponseErrorCode};
 use smallvec::SmallVec;
+use ssz::Encode;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, VecDeque};
 use std::future::Future;
@@ -32,10 +38,10 @@ impl SubstreamIdGenerator {
         SubstreamIdGenerator { current_id: 0 }
     }
 
-    // Returns a sequential ID for substreams.
+    // Returns a sequential ID for substreams. The IDs are unique unless `usize` wraps around.
     fn next(&mut self) -> SubstreamId {
         let id = SubstreamId(self.current_id);
-        self.current_id += 1;
+        self.current_id = self.current_id.wrapping_add(1);
         id
     }
 }
@@ -48,16 +54,30 @@ enum InboundSubstreamState {
     // The underlying substream is not being used.
     Idle(InboundFramed<Stream>),
     // The underlying substream is processing responses.
-    Busy(Pin<Box<dyn Future<Output = Result<InboundFramed<Stream>, String>> + Send>>),
+    // The future resolves to the substream if it remains open for further responses.
+    Busy(Pin<Box<dyn Future<Output = Result<Option<InboundFramed<Stream>>, RPCError>> + Send>>),
     // Temporary state during processing
     Poisoned,
 }
 
+struct OutboundSubstreamInfo<Id> {
+    // The underlying substream.
+    substream: OutboundFramed,
+    // The id of the request sent on the substream.
+    request_id: Id,
+    // Whether the request is responded with a stream of responses (e.g. BlocksByRange), which
+    // the peer ends by closing the substream, rather than a single response.
+    expects_multiple_responses: bool,
+}
+
 struct InboundSubstreamInfo {
     // State of the substream.
     state: InboundSubstreamState,
     // Responses queued for sending.
-    responses_to_send: VecDeque<lighthouse_network::Response<MainnetEthSpec>>,
+    responses_to_send: VecDeque<RPCCodedResponse<MainnetEthSpec>>,
+    // Whether the stream has been ended with an error as the buffered responses exceeded the
+    // limit. The further responses on the substream are discarded.
+    ended_with_error: bool,
 }
 
 // ////////////////////////////////////////////////////////
@@ -66,12 +86,37 @@ struct InboundSubstreamInfo {
 
 // RPC internal message sent from handler to the behaviour
 #[derive(Debug)]
-pub(crate) enum ToBehaviour {
+pub(crate) enum ToBehaviour<Id> {
     // A request received from the outside.
     RequestReceived(InboundRequest),
-    // A response received from the outside.
-    ResponseReceived(lighthouse_network::rpc::methods::RPCResponse<MainnetEthSpec>),
+    // A response received from the outside, with the id of our request.
+    ResponseReceived(
+        Id,
+        lighthouse_network::rpc::methods::RPCResponse<MainnetEthSpec>,
+    ),
     CloseConnection(RPCError),
+    // The peer supports none of the protocol versions we offered.
+    UnsupportedProtocol(lighthouse_network::rpc::protocol::Protocol),
+    // All the queued responses on the substream have been sent and the substream remains open,
+    // so that the next chunk of a streamed response can be sent.
+    ResponsesSent(SubstreamId),
+    // The peer responded to our request with an error.
+    ResponseError {
+        substream_id: SubstreamId,
+        request_id: Id,
+        code: RPCResponseErrorCode,
+        message: String,
+    },
+    // The peer has terminated the stream of responses to our request.
+    StreamTerminated {
+        substream_id: SubstreamId,
+        request_id: Id,
+    },
+    // Failed to send a response on, or to close, an inbound substream.
+    ResponseFailed {
+        substream_id: SubstreamId,
+        error: RPCError,
+    },
 }
 
 // A request received from the outside.
@@ -86,6 +131,12 @@ pub struct InboundRequest {
 // ////////////////////////////////////////////////////////
 /// Maximum time given to the handler to perform shutdown operations.
 const SHUTDOWN_TIMEOUT_SECS: u64 = 15;
+/// Maximum time given to close an inbound substream once the handler is deactivated.
+const SUBSTREAM_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The numbers of queued outbound substreams and events held without a heap allocation. See
+/// `benches/smallvec_inline_capacity.rs` for the allocations at each capacity.
+const DIAL_QUEUE_INLINE_CAPACITY: usize = 4;
+const OUT_EVENTS_INLINE_CAPACITY: usize = 4;
 
 #[derive(Debug)]
 enum HandlerState {
@@ -93,8 +144,9 @@ enum HandlerState {
     Active,
     /// The handler is shutting_down.
     ///
-    /// While in this state the handler rejects new requests but tries to finish existing ones.
-    /// Once the timer expires, all messages are killed.
+    /// While in this state the handler rejects new requests but tries to finish existing ones,
+    /// including the queued ones and the goodbye. Once they have finished or the timer expires,
+    /// the queued responses are flushed and the inbound substreams are closed.
     ShuttingDown(Pin<Box<Sleep>>),
     /// The handler is deactivated. A goodbye has been sent and no more messages are sent or
     /// received.
@@ -105,38 +157,62 @@ pub(crate) struct Handler<Id> {
     /// State of the handler.
     state: HandlerState,
     // Queue of outbound substreams to open.
-    dial_queue: SmallVec<[(Id, OutboundRequest); 4]>,
+    dial_queue: SmallVec<[(Id, OutboundRequest); DIAL_QUEUE_INLINE_CAPACITY]>,
+    // Number of outbound substreams requested but not negotiated yet.
+    pending_dials: usize,
     fork_context: Arc<ForkContext>,
     max_rpc_size: usize,
     // Queue of events to produce in `poll()`.
-    out_events: SmallVec<[ToBehaviour; 4]>,
+    out_events: SmallVec<[ToBehaviour<Id>; OUT_EVENTS_INLINE_CAPACITY]>,
     // Current inbound substreams awaiting processing.
     inbound_substreams: HashMap<SubstreamId, InboundSubstreamInfo>,
+    // Inbound substreams being closed as the handler has been deactivated.
+    closing_inbound_substreams: FuturesUnordered<Pin<Box<dyn Future<Output = ()> + Send>>>,
     // Sequential ID generator for inbound substreams.
     inbound_substream_id: SubstreamIdGenerator,
     // Map of outbound substreams that need to be driven to completion.
-    outbound_substreams: HashMap<SubstreamId, OutboundFramed>,
+    outbound_substreams: HashMap<SubstreamId, OutboundSubstreamInfo<Id>>,
     // Sequential ID generator for outbound substreams.
     outbound_substream_id: SubstreamIdGenerator,
     // The PeerId this handler communicate to. Note this is just for debugging.
     peer_id: PeerId,
+    // Total bytes of the responses queued across all inbound substreams.
+    buffered_response_bytes: usize,
+    // The maximum of `buffered_response_bytes`.
+    max_buffered_response_bytes: usize,
+    // The timeouts of both the inbound and the outbound substreams.
+    rpc_config: RpcConfig,
+    // The protocols we accept requests on.
+    inbound_protocols: Vec<ProtocolId>,
 }
 
 impl<Id> Handler<Id> {
-    pub(crate) fn new(peer_id: PeerId, fork_context: Arc<ForkContext>) -> Self {
+    pub(crate) fn new(
+        peer_id: PeerId,
+        fork_context: Arc<ForkContext>,
+        max_buffered_response_bytes: usize,
+        rpc_config: RpcConfig,
+        inbound_protocols: Vec<ProtocolId>,
+    ) -> Self {
         // SEE: https://github.com/sigp/lighthouse/blob/fff4dd6311695c1d772a9d6991463915edf223d5/beacon_node/lighthouse_network/src/rpc/protocol.rs#L114
         let max_rpc_size = 10 * 1_048_576; // 10M
         Handler {
             state: HandlerState::Active,
             dial_queue: SmallVec::new(),
+            pending_dials: 0,
             fork_context,
             max_rpc_size,
             out_events: SmallVec::new(),
             inbound_substreams: HashMap::new(),
+            closing_inbound_substreams: FuturesUnordered::new(),
             inbound_substream_id: SubstreamIdGenerator::new(),
             outbound_substreams: HashMap::new(),
             outbound_substream_id: SubstreamIdGenerator::new(),
             peer_id,
+            buffered_response_bytes: 0,
+            max_buffered_response_bytes,
+            rpc_config,
+            inbound_protocols,
         }
     }
 
@@ -157,6 +233,23 @@ impl<Id> Handler<Id> {
         ));
     }
 
+    // Ping
+    // https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#ping
+    fn send_ping(
+        &mut self,
+        request_id: Id,
+        peer_id: PeerId,
+        ping: lighthouse_network::rpc::methods::Ping,
+    ) {
+        self.dial_queue.push((
+            request_id,
+            OutboundRequest {
+                peer_id,
+                request: lighthouse_network::rpc::outbound::OutboundRequest::Ping(ping),
+            },
+        ));
+    }
+
     // Goodbye
     // https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#goodbye
     fn shutdown(&mut self, reason: Option<(Id, PeerId, lighthouse_network::rpc::GoodbyeReason)>) {
@@ -168,7 +261,8 @@ impl<Id> Handler<Id> {
             return;
         }
 
-        // TODO: Drive self.dial_queue to complete communications
+        // The queued requests are still dialed while shutting down, and the goodbye is queued after
+        // them so that it's the last message sent. See `poll()`.
         // Ref: https://github.com/sigp/lighthouse/blob/9976d3bbbcff07ebb2503e48baba1a04cae59390/beacon_node/lighthouse_network/src/rpc/handler.rs#L244-L251
 
         // Queue our goodbye message.
@@ -193,6 +287,12 @@ impl<Id> Handler<Id> {
         )));
     }
 
+    // Whether all the outbound requests have been sent and their responses received, so that the
+    // shutdown can complete without waiting for the timer.
+    fn is_drained(&self) -> bool {
+        self.dial_queue.is_empty() && self.pending_dials == 0 && self.outbound_substreams.is_empty()
+    }
+
     fn send_request(
         &mut self,
         request_id: Id,
@@ -215,7 +315,7 @@ impl<Id> Handler<Id> {
         &mut self,
         _peer_id: PeerId,
         substream_id: SubstreamId,
-        response: lighthouse_network::Response<MainnetEthSpec>,
+        response: RPCCodedResponse<MainnetEthSpec>,
     ) {
         match self.inbound_substreams.get_mut(&substream_id) {
             None => {
@@ -225,14 +325,96 @@ impl<Id> Handler<Id> {
                 )
             }
             Some(inbound_substream_info) => {
+                if inbound_substream_info.ended_with_error {
+                    trace!(
+                        "[{}] Discarded a response as the stream has been ended with an error. substream_id: {}",
+                        self.peer_id, substream_id.0
+                    );
+                    return;
+                }
+
+                let size = response_size(&response);
+                if self.buffered_response_bytes + size > self.max_buffered_response_bytes {
+                    // Dropping the chunk would leave a gap in the stream the peer can't detect, so
+                    // the stream is ended with an error instead. The error isn't a streamed
+                    // response, so the substream is closed once it has been sent.
+                    warn!(
+                        "[{}] Ending the stream with an error as the buffered responses exceed the limit. buffered: {}, response: {}, limit: {}",
+                        self.peer_id, self.buffered_response_bytes, size, self.max_buffered_response_bytes
+                    );
+                    inbound_substream_info.ended_with_error = true;
+                    inbound_substream_info
+                        .responses_to_send
+                        .push_back(RPCCodedResponse::Error(
+                            RPCResponseErrorCode::ResourceUnavailable,
+                            "Too many responses buffered".into(),
+                        ));
+                    return;
+                }
+
+                self.buffered_response_bytes += size;
                 inbound_substream_info.responses_to_send.push_back(response);
             }
         }
     }
 
+    // Closes the inbound substreams gracefully, once the in-progress and queued responses are
+    // sent, so that the peer sees clean stream closes rather than resets.
+    fn close_inbound_substreams(&mut self) {
+        for (substream_id, inbound_substream_info) in self.inbound_substreams.drain() {
+            let responses = inbound_substream_info.responses_to_send;
+            let close = match inbound_substream_info.state {
+                InboundSubstreamState::Idle(substream) => {
+                    flush_and_close(substream, responses).boxed()
+                }
+                InboundSubstreamState::Busy(future) => async move {
+                    match future.await? {
+                        Some(substream) => flush_and_close(substream, responses).await,
+                        // The substream has been closed after the response.
+                        None => Ok(()),
+                    }
+                }
+                .boxed(),
+                InboundSubstreamState::Poisoned => continue,
+            };
+
+            self.push_closing_inbound_substream(substream_id, close);
+        }
+        self.buffered_response_bytes = 0;
+    }
+
+    // Drives closing the inbound substream, giving up after `SUBSTREAM_CLOSE_TIMEOUT`.
+    fn push_closing_inbound_substream(
+        &mut self,
+        substream_id: SubstreamId,
+        close: BoxFuture<'static, Result<(), RPCError>>,
+    ) {
+        let peer_id = self.peer_id;
+        self.closing_inbound_substreams.push(
+            async move {
+                match tokio::time::timeout(SUBSTREAM_CLOSE_TIMEOUT, close).await {
+                    Ok(Ok(())) => trace!(
+                        "[{}] Closed an inbound substream. substream_id: {}",
+                        peer_id,
+                        substream_id.0
+                    ),
+                    Ok(Err(rpc_error)) => warn!(
+                        "[{}] {} substream_id: {}",
+                        peer_id, rpc_error, substream_id.0
+                    ),
+                    Err(_) => warn!(
+                        "[{}] Timed out closing an inbound substream. substream_id: {}",
+                        peer_id, substream_id.0
+                    ),
+                }
+            }
+            .boxed(),
+        );
+    }
+
     // Injects the output of a successful upgrade on a new inbound substream.
     fn on_fully_negotiated_inbound(&mut self, inbound: FullyNegotiatedInbound<RpcProtocol, ()>) {
-        let (request, substream) = inbound.protocol;
+        let (request, mut substream) = inbound.protocol;
         info!(
             "[{}] on_fully_negotiated_inbound. request: {request:?}",
             self.peer_id
@@ -240,12 +422,27 @@ impl<Id> Handler<Id> {
 
         let inbound_substream_id = self.inbound_substream_id.next();
 
+        // While shutting down, the requests in flight are completed but new ones are rejected by
+        // closing their substreams.
+        if !matches!(self.state, HandlerState::Active) {
+            info!(
+                "[{}] Rejected an inbound request as the handler is shutting down. request: {request:?}",
+                self.peer_id
+            );
+            self.push_closing_inbound_substream(
+                inbound_substream_id,
+                async move { substream.close().await.map_err(RPCError::CloseFailed) }.boxed(),
+            );
+            return;
+        }
+
         // Store the inbound substream
         if let Some(_old_substream) = self.inbound_substreams.insert(
             inbound_substream_id,
             InboundSubstreamInfo {
                 state: InboundSubstreamState::Idle(substream),
                 responses_to_send: VecDeque::new(),
+                ended_with_error: false,
             },
         ) {
             error!(
@@ -277,41 +474,106 @@ impl<Id> Handler<Id> {
         &mut self,
         outbound: FullyNegotiatedOutbound<
             RpcRequestProtocol,
-            lighthouse_network::rpc::outbound::OutboundRequest<MainnetEthSpec>,
+            (
+                Id,
+                lighthouse_network::rpc::outbound::OutboundRequest<MainnetEthSpec>,
+            ),
         >,
     ) {
         info!("[{}] on_fully_negotiated_outbound", self.peer_id,);
-        let request = outbound.info;
+        self.pending_dials = self.pending_dials.saturating_sub(1);
+        let (request_id, request) = outbound.info;
         let outbound_substream_id = self.outbound_substream_id.next();
 
-        if request.expected_responses() > 0
-            && self
-                .outbound_substreams
-                .insert(outbound_substream_id, outbound.protocol)
-                .is_some()
-        {
+        // The request (e.g. a goodbye) has been sent and the substream closed in the upgrade.
+        if request.expected_responses() == 0 {
+            return;
+        }
+
+        // The ids are sequential so this never happens, unless the id generator wraps around.
+        if let Some(displaced) = self.outbound_substreams.insert(
+            outbound_substream_id,
+            OutboundSubstreamInfo {
+                substream: outbound.protocol,
+                request_id,
+                expects_multiple_responses: request.expected_responses() > 1,
+            },
+        ) {
             error!(
-                "Duplicate outbound substream id: {:?}",
-                outbound_substream_id
+                "[{}] Duplicate outbound substream id: {:?}. Closing the displaced substream.",
+                self.peer_id, outbound_substream_id
             );
+            // Dropping the substream resets it, so that the peer doesn't wait on it.
+            let OutboundSubstreamInfo { request_id, .. } = displaced;
+            self.fail_displaced_request(outbound_substream_id, request_id);
+        }
+    }
+
+    // Reports the request of a displaced outbound substream as failed, so that the behaviour
+    // doesn't wait for its responses. It isn't the peer's fault, so the error isn't penalized.
+    fn fail_displaced_request(&mut self, substream_id: SubstreamId, request_id: Id) {
+        self.out_events.push(ToBehaviour::ResponseError {
+            substream_id,
+            request_id,
+            code: RPCResponseErrorCode::ResourceUnavailable,
+            message: "The outbound substream was displaced".into(),
+        });
+    }
+}
+
+// Sends the queued responses and closes the substream.
+async fn flush_and_close(
+    mut substream: InboundFramed<Stream>,
+    responses: VecDeque<RPCCodedResponse<MainnetEthSpec>>,
+) -> Result<(), RPCError> {
+    for response in responses {
+        // A stream termination isn't encoded. Closing the substream terminates the stream.
+        if matches!(response, RPCCodedResponse::StreamTermination(_)) {
+            break;
         }
+        substream
+            .send(response)
+            .await
+            .map_err(RPCError::SendFailed)?;
+    }
+    substream.close().await.map_err(RPCError::CloseFailed)
+}
+
+// The bytes a queued response takes up. Only blocks are counted as the other responses are small,
+// so that stream terminations are never dropped.
+fn response_size(response: &RPCCodedResponse<MainnetEthSpec>) -> usize {
+    match response {
+        RPCCodedResponse::Success(RPCResponse::BlocksByRange(block))
+        | RPCCodedResponse::Success(RPCResponse::BlocksByRoot(block)) => block.ssz_bytes_len(),
+        _ => 0,
     }
 }
 
 // SEE https://github.com/sigp/lighthouse/blob/4af6fcfafd2c29bca82474ee378cda9ac254783a/beacon_node/eth2_libp2p/src/rpc/handler.rs#L311
 impl<Id: ReqId> ConnectionHandler for Handler<Id> {
     type FromBehaviour = InstructionToHandler<Id>;
-    type ToBehaviour = ToBehaviour;
+    type ToBehaviour = ToBehaviour<Id>;
     type InboundProtocol = RpcProtocol;
     type OutboundProtocol = RpcRequestProtocol;
     type InboundOpenInfo = ();
-    type OutboundOpenInfo = lighthouse_network::rpc::outbound::OutboundRequest<MainnetEthSpec>;
+    // The request id is carried through the substream negotiation, so that the responses on the
+    // substream can be correlated with the request.
+    type OutboundOpenInfo = (
+        Id,
+        lighthouse_network::rpc::outbound::OutboundRequest<MainnetEthSpec>,
+    );
 
     fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
         info!("[{}] [ConnectionHandler::listen_protocol]", self.peer_id);
 
         SubstreamProtocol::new(
-            RpcProtocol::new(self.fork_context.clone(), self.max_rpc_size, self.peer_id),
+            RpcProtocol::new(
+                self.fork_context.clone(),
+                self.max_rpc_size,
+                self.peer_id,
+                self.rpc_config.clone(),
+                self.inbound_protocols.clone(),
+            ),
             (),
         )
     }
@@ -336,15 +598,23 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
         // Check if we are shutting down, and if the timer ran out
         // /////////////////////////////////////////////////////////////////////////////////////////////////
         if let HandlerState::ShuttingDown(delay) = &mut self.state {
-            match delay.as_mut().poll(cx) {
-                Poll::Ready(_) => {
-                    self.state = HandlerState::Deactivated;
-                    info!("poll: Updated the handler state to Deactivated");
-                    return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
-                        ToBehaviour::CloseConnection(RPCError::Disconnected),
-                    ));
+            let timed_out = delay.as_mut().poll(cx).is_ready();
+            if timed_out || self.is_drained() {
+                if timed_out {
+                    warn!(
+                        "[{}] Shutdown timed out. queued: {}, negotiating: {}, awaiting responses: {}",
+                        self.peer_id,
+                        self.dial_queue.len(),
+                        self.pending_dials,
+                        self.outbound_substreams.len()
+                    );
                 }
-                Poll::Pending => {}
+                self.state = HandlerState::Deactivated;
+                info!("poll: Updated the handler state to Deactivated");
+                self.close_inbound_substreams();
+                return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                    ToBehaviour::CloseConnection(RPCError::Disconnected),
+                ));
             }
         }
 
@@ -352,7 +622,8 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
         // Establish outbound substreams
         // /////////////////////////////////////////////////////////////////////////////////////////////////
         if !self.dial_queue.is_empty() {
-            let (_id, request) = self.dial_queue.remove(0);
+            let (id, request) = self.dial_queue.remove(0);
+            self.pending_dials += 1;
             info!(
                 "[{}] ConnectionHandlerEvent::OutboundSubstreamRequest. request: {:?}",
                 request.peer_id, request.request,
@@ -363,8 +634,9 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
                         request: request.clone(),
                         max_rpc_size: self.max_rpc_size,
                         fork_context: self.fork_context.clone(),
+                        rpc_config: self.rpc_config.clone(),
                     },
-                    request.request,
+                    (id, request.request),
                 ),
             });
         }
@@ -382,6 +654,8 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
         // /////////////////////////////////////////////////////////////////////////////////////////////////
         // Drive inbound streams that need to be processed
         // /////////////////////////////////////////////////////////////////////////////////////////////////
+        while let Poll::Ready(Some(())) = self.closing_inbound_substreams.poll_next_unpin(cx) {}
+
         let mut inbound_substreams_to_remove = vec![];
         for (substream_id, inbound_substream_info) in self.inbound_substreams.iter_mut() {
             loop {
@@ -393,35 +667,49 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
                         if let Some(response_to_send) =
                             inbound_substream_info.responses_to_send.pop_front()
                         {
-                            let boxed_future = async move {
-                                let rpc_coded_response: RPCCodedResponse<MainnetEthSpec> =
-                                    response_to_send.into();
-
-                                match substream.send(rpc_coded_response).await {
-                                    Ok(_) => match substream.close().await {
-                                        Ok(_) => Ok(substream),
-                                        Err(rpc_error) => Err(format!(
-                                            "Failed to close substream. error: {}",
-                                            rpc_error
-                                        )),
-                                    },
-                                    Err(rpc_error) => {
-                                        let mut error_message = format!(
-                                            "Failed to send response. rpc_error: {}",
-                                            rpc_error
-                                        );
-                                        if let Err(e) = substream.close().await {
-                                            error_message = format!(
-                                                "Failed to close substream. error: {}, {}",
-                                                e, error_message
-                                            );
-                                        }
+                            self.buffered_response_bytes = self
+                                .buffered_response_bytes
+                                .saturating_sub(response_size(&response_to_send));
+
+                            // Streamed responses (e.g. `BlocksByRoot`) keep the substream open
+                            // until the stream termination is sent.
+                            let close_after = !matches!(
+                                response_to_send,
+                                RPCCodedResponse::Success(RPCResponse::BlocksByRange(_))
+                                    | RPCCodedResponse::Success(RPCResponse::BlocksByRoot(_))
+                            );
 
-                                        Err(error_message)
+                            let boxed_future = if matches!(
+                                response_to_send,
+                                RPCCodedResponse::StreamTermination(_)
+                            ) {
+                                // A stream termination isn't encoded. Closing the substream
+                                // terminates the stream.
+                                async move {
+                                    match substream.close().await {
+                                        Ok(_) => Ok(None),
+                                        Err(rpc_error) => Err(RPCError::CloseFailed(rpc_error)),
                                     }
                                 }
-                            }
-                            .boxed();
+                                .boxed()
+                            } else {
+                                async move {
+                                    match substream.send(response_to_send).await {
+                                        Ok(_) if !close_after => Ok(Some(substream)),
+                                        Ok(_) => match substream.close().await {
+                                            Ok(_) => Ok(None),
+                                            Err(rpc_error) => Err(RPCError::CloseFailed(rpc_error)),
+                                        },
+                                        Err(rpc_error) => {
+                                            // The send failure is the cause, so a failure to close
+                                            // the broken substream is not reported.
+                                            let _ = substream.close().await;
+                                            Err(RPCError::SendFailed(rpc_error))
+                                        }
+                                    }
+                                }
+                                .boxed()
+                            };
 
                             inbound_substream_info.state =
                                 InboundSubstreamState::Busy(Box::pin(boxed_future));
@@ -434,9 +722,20 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
                     }
                     InboundSubstreamState::Busy(mut future) => {
                         match future.poll_unpin(cx) {
+                            // The response has been sent and the stream remains open. Move on to
+                            // the next queued response.
+                            Poll::Ready(Ok(Some(substream))) => {
+                                inbound_substream_info.state =
+                                    InboundSubstreamState::Idle(substream);
+                                if inbound_substream_info.responses_to_send.is_empty() {
+                                    self.out_events
+                                        .push(ToBehaviour::ResponsesSent(*substream_id));
+                                    cx.waker().wake_by_ref();
+                                }
+                            }
                             // The pending messages have been sent successfully and the stream has
                             // terminated
-                            Poll::Ready(Ok(_stream)) => {
+                            Poll::Ready(Ok(None)) => {
                                 trace!("[{}] Sent a response successfully.", self.peer_id,);
                                 inbound_substreams_to_remove.push(*substream_id);
                                 // There is nothing more to process on this substream as it has
@@ -444,12 +743,12 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
                                 break;
                             }
                             // An error occurred when trying to send a response.
-                            Poll::Ready(Err(error_message)) => {
-                                // TODO: Report the error that occurred during the send process
-                                error!(
-                                    "[{}] Failed to send a response. error: {}",
-                                    self.peer_id, error_message,
-                                );
+                            Poll::Ready(Err(rpc_error)) => {
+                                error!("[{}] {}", self.peer_id, rpc_error);
+                                self.out_events.push(ToBehaviour::ResponseFailed {
+                                    substream_id: *substream_id,
+                                    error: rpc_error,
+                                });
                                 inbound_substreams_to_remove.push(*substream_id);
                                 break;
                             }
@@ -467,7 +766,14 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
         }
         // Remove closed substreams
         for id in inbound_substreams_to_remove {
-            self.inbound_substreams.remove(&id);
+            if let Some(inbound_substream_info) = self.inbound_substreams.remove(&id) {
+                // Release the responses that will never be sent.
+                for response in inbound_substream_info.responses_to_send {
+                    self.buffered_response_bytes = self
+                        .buffered_response_bytes
+                        .saturating_sub(response_size(&response));
+                }
+            }
         }
 
         // /////////////////////////////////////////////////////////////////////////////////////////////////
@@ -479,19 +785,51 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
                 Entry::Vacant(_) => unreachable!(),
             };
 
-            match entry.get_mut().poll_next_unpin(cx) {
+            match entry.get_mut().substream.poll_next_unpin(cx) {
                 Poll::Ready(Some(Ok(rpc_coded_response))) => match rpc_coded_response {
                     RPCCodedResponse::Success(response) => {
                         info!("[{}] received a response: {response:?}", self.peer_id);
+                        let request_id = entry.get().request_id;
+                        // The only response has been received, so the request has completed.
+                        if !entry.get().expects_multiple_responses {
+                            entry.remove_entry();
+                        }
                         return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
-                            ToBehaviour::ResponseReceived(response),
+                            ToBehaviour::ResponseReceived(request_id, response),
                         ));
                     }
-                    RPCCodedResponse::Error(_, _) => {
-                        todo!()
+                    RPCCodedResponse::Error(code, message) => {
+                        let message = message.to_string();
+                        warn!(
+                            "[{}] received an error response. code: {code}, message: {message}",
+                            self.peer_id
+                        );
+                        // The error response terminates the stream.
+                        let (_, info) = entry.remove_entry();
+                        return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                            ToBehaviour::ResponseError {
+                                substream_id: outbound_substream_id,
+                                request_id: info.request_id,
+                                code,
+                                message,
+                            },
+                        ));
                     }
+                    // The codec doesn't decode a termination frame, as the peer terminates the
+                    // stream by closing the substream, which is handled below. Handled the same
+                    // way for completeness.
                     RPCCodedResponse::StreamTermination(_) => {
-                        todo!()
+                        trace!(
+                            "[{}] The stream of responses has been terminated. outbound_substream_id: {:?}",
+                            self.peer_id, outbound_substream_id
+                        );
+                        let (_, info) = entry.remove_entry();
+                        return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                            ToBehaviour::StreamTerminated {
+                                substream_id: outbound_substream_id,
+                                request_id: info.request_id,
+                            },
+                        ));
                     }
                 },
                 Poll::Ready(Some(Err(e))) => {
@@ -508,13 +846,23 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
                         "[{}] Stream closed by remote. outbound_substream_id: {:?}",
                         self.peer_id, outbound_substream_id
                     );
-                    // drop the stream
-                    entry.remove_entry();
+                    let (_, info) = entry.remove_entry();
 
-                    // TODO: Return an error
                     // ref: https://github.com/sigp/lighthouse/blob/3dd50bda11cefb3c17d851cbb8811610385c20aa/beacon_node/lighthouse_network/src/rpc/handler.rs#L884-L898
+                    if info.expects_multiple_responses {
+                        // The peer has terminated the stream of responses, so the request has
+                        // completed, which isn't a disconnect.
+                        return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                            ToBehaviour::StreamTerminated {
+                                substream_id: outbound_substream_id,
+                                request_id: info.request_id,
+                            },
+                        ));
+                    }
+                    // The substream of a single response is removed once the response has been
+                    // received, so the peer has closed it without responding.
                     return Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
-                        ToBehaviour::CloseConnection(RPCError::Disconnected),
+                        ToBehaviour::CloseConnection(RPCError::IncompleteStream),
                     ));
                 }
                 Poll::Pending => {}
@@ -524,6 +872,19 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
         Poll::Pending
     }
 
+    // Called while the connection is being closed. The connection is closed once the inbound
+    // substreams are.
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Option<Self::ToBehaviour>> {
+        self.close_inbound_substreams();
+        loop {
+            match self.closing_inbound_substreams.poll_next_unpin(cx) {
+                Poll::Ready(Some(())) => {}
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
     fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
         info!("[{}] on_behaviour_event. event: {:?}", self.peer_id, event);
 
@@ -534,6 +895,9 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
             InstructionToHandler::Goodbye(request_id, reason, peer_id) => {
                 self.shutdown(Some((request_id, peer_id, reason)));
             }
+            InstructionToHandler::Ping(request_id, ping, peer_id) => {
+                self.send_ping(request_id, peer_id, ping);
+            }
             InstructionToHandler::Request(request_id, request, peer_id) => {
                 self.send_request(request_id, peer_id, request);
             }
@@ -566,8 +930,18 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
             ConnectionEvent::DialUpgradeError(dial_upgrade_error) => {
                 warn!(
                     "[{}] dial_upgrade_error. info: {}, error: {}",
-                    self.peer_id, dial_upgrade_error.info, dial_upgrade_error.error,
+                    self.peer_id, dial_upgrade_error.info.1, dial_upgrade_error.error,
                 );
+                self.pending_dials = self.pending_dials.saturating_sub(1);
+
+                if matches!(
+                    dial_upgrade_error.error,
+                    StreamUpgradeError::NegotiationFailed
+                ) {
+                    self.out_events.push(ToBehaviour::UnsupportedProtocol(
+                        dial_upgrade_error.info.1.versioned_protocol().protocol(),
+                    ));
+                }
 
                 // TODO
                 // ref: https://github.com/sigp/lighthouse/blob/3dd50bda11cefb3c17d851cbb8811610385c20aa/beacon_node/lighthouse_network/src/rpc/handler.rs#L453
@@ -585,3 +959,275 @@ impl<Id: ReqId> ConnectionHandler for Handler<Id> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::test_block;
+    use types::{ChainSpec, Hash256, Slot};
+
+    fn handler(max_buffered_response_bytes: usize) -> Handler<usize> {
+        let fork_context = Arc::new(ForkContext::new::<MainnetEthSpec>(
+            Slot::new(0),
+            Hash256::zero(),
+            &ChainSpec::mainnet(),
+        ));
+        Handler::new(
+            PeerId::random(),
+            fork_context,
+            max_buffered_response_bytes,
+            RpcConfig {
+                request_timeout: Duration::from_secs(15),
+                read_timeout: Duration::from_secs(5),
+            },
+            crate::rpc::protocol::supported_protocols(),
+        )
+    }
+
+    fn block_response() -> RPCCodedResponse<MainnetEthSpec> {
+        RPCCodedResponse::Success(RPCResponse::BlocksByRange(test_block(0)))
+    }
+
+    // Registers an inbound substream without the underlying stream, which is enough to queue
+    // responses on it.
+    fn add_inbound_substream(handler: &mut Handler<usize>) -> SubstreamId {
+        let substream_id = handler.inbound_substream_id.next();
+        handler.inbound_substreams.insert(
+            substream_id,
+            InboundSubstreamInfo {
+                state: InboundSubstreamState::Poisoned,
+                responses_to_send: VecDeque::new(),
+                ended_with_error: false,
+            },
+        );
+        substream_id
+    }
+
+    #[test]
+    fn stream_is_ended_with_an_error_when_the_buffered_responses_exceed_the_limit() {
+        let size = response_size(&block_response());
+        let mut handler = handler(size * 2);
+        let peer_id = handler.peer_id;
+        let substream_id = add_inbound_substream(&mut handler);
+
+        for _ in 0..3 {
+            handler.send_response(peer_id, substream_id, block_response());
+        }
+        // The stream termination after the error is discarded.
+        handler.send_response(
+            peer_id,
+            substream_id,
+            RPCCodedResponse::StreamTermination(
+                lighthouse_network::rpc::methods::ResponseTermination::BlocksByRange,
+            ),
+        );
+
+        let responses = &handler.inbound_substreams[&substream_id].responses_to_send;
+        assert_eq!(responses.len(), 3);
+        assert!(responses
+            .iter()
+            .take(2)
+            .all(|response| matches!(response, RPCCodedResponse::Success(_))));
+        assert!(matches!(
+            responses.back(),
+            Some(RPCCodedResponse::Error(
+                RPCResponseErrorCode::ResourceUnavailable,
+                _
+            ))
+        ));
+        assert_eq!(handler.buffered_response_bytes, size * 2);
+    }
+
+    #[test]
+    fn other_streams_are_unaffected_by_an_ended_stream() {
+        let size = response_size(&block_response());
+        let mut handler = handler(size);
+        let peer_id = handler.peer_id;
+        let ended = add_inbound_substream(&mut handler);
+        let other = add_inbound_substream(&mut handler);
+
+        handler.send_response(peer_id, ended, block_response());
+        handler.send_response(peer_id, ended, block_response());
+        assert!(handler.inbound_substreams[&ended].ended_with_error);
+
+        // Non-block responses aren't counted toward the limit.
+        handler.send_response(
+            peer_id,
+            other,
+            RPCCodedResponse::StreamTermination(
+                lighthouse_network::rpc::methods::ResponseTermination::BlocksByRoot,
+            ),
+        );
+        assert!(!handler.inbound_substreams[&other].ended_with_error);
+        assert_eq!(
+            handler.inbound_substreams[&other].responses_to_send.len(),
+            1
+        );
+    }
+
+    #[test]
+    fn substream_ids_are_unique() {
+        let mut generator = SubstreamIdGenerator::new();
+        let ids = (0..10_000)
+            .map(|_| generator.next())
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(ids.len(), 10_000);
+    }
+
+    #[tokio::test]
+    async fn inbound_substreams_are_closed_on_shutdown() {
+        let mut handler = handler(1024 * 1024);
+        let peer_id = handler.peer_id;
+        let substream_id = add_inbound_substream(&mut handler);
+        // A response is being sent on the substream, which closes it afterwards.
+        handler
+            .inbound_substreams
+            .get_mut(&substream_id)
+            .unwrap()
+            .state = InboundSubstreamState::Busy(Box::pin(async {
+            Ok::<_, RPCError>(None::<InboundFramed<Stream>>)
+        }));
+        handler.send_response(peer_id, substream_id, block_response());
+
+        handler.close_inbound_substreams();
+
+        assert!(handler.inbound_substreams.is_empty());
+        assert_eq!(handler.closing_inbound_substreams.len(), 1);
+        assert_eq!(handler.buffered_response_bytes, 0);
+        // The connection is closed once the substream is.
+        assert!(std::future::poll_fn(|cx| handler.poll_close(cx))
+            .await
+            .is_none());
+        assert!(handler.closing_inbound_substreams.is_empty());
+    }
+
+    #[test]
+    fn failure_to_close_a_substream_is_reported() {
+        let mut handler = handler(1024 * 1024);
+        let substream_id = add_inbound_substream(&mut handler);
+        handler
+            .inbound_substreams
+            .get_mut(&substream_id)
+            .unwrap()
+            .state = InboundSubstreamState::Busy(Box::pin(async {
+            Err::<Option<InboundFramed<Stream>>, _>(RPCError::CloseFailed(
+                lighthouse_network::rpc::RPCError::StreamTimeout,
+            ))
+        }));
+
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut events = vec![];
+        while let Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(event)) =
+            handler.poll(&mut cx)
+        {
+            events.push(event);
+        }
+        assert!(!handler.inbound_substreams.contains_key(&substream_id));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ToBehaviour::ResponseFailed {
+                substream_id: id,
+                error: RPCError::CloseFailed(_),
+            } if *id == substream_id
+        )));
+    }
+
+    #[tokio::test]
+    async fn response_in_flight_at_shutdown_is_completed() {
+        let mut handler = handler(1024 * 1024);
+        let substream_id = add_inbound_substream(&mut handler);
+        // A response is being sent when the handler shuts down.
+        let (sent, response_sent) = tokio::sync::oneshot::channel::<()>();
+        handler
+            .inbound_substreams
+            .get_mut(&substream_id)
+            .unwrap()
+            .state = InboundSubstreamState::Busy(Box::pin(async move {
+            let _ = response_sent.await;
+            Ok::<_, RPCError>(None::<InboundFramed<Stream>>)
+        }));
+
+        handler.shutdown(None);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        assert!(matches!(
+            handler.poll(&mut cx),
+            Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(
+                ToBehaviour::CloseConnection(RPCError::Disconnected)
+            ))
+        ));
+
+        // The response in flight isn't aborted.
+        assert_eq!(handler.closing_inbound_substreams.len(), 1);
+        assert!(handler.poll_close(&mut cx).is_pending());
+
+        sent.send(()).unwrap();
+        assert!(std::future::poll_fn(|cx| handler.poll_close(cx))
+            .await
+            .is_none());
+        assert!(handler.closing_inbound_substreams.is_empty());
+    }
+
+    #[tokio::test]
+    async fn queued_request_and_goodbye_are_dispatched_during_shutdown() {
+        let mut handler = handler(1024 * 1024);
+        let peer_id = handler.peer_id;
+        handler.send_request(
+            1,
+            peer_id,
+            lighthouse_network::rpc::outbound::OutboundRequest::Ping(
+                lighthouse_network::rpc::methods::Ping { data: 0 },
+            ),
+        );
+        handler.shutdown(Some((
+            2,
+            peer_id,
+            lighthouse_network::rpc::GoodbyeReason::ClientShutdown,
+        )));
+
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut dispatched = vec![];
+        while let Poll::Ready(event) = handler.poll(&mut cx) {
+            match event {
+                ConnectionHandlerEvent::OutboundSubstreamRequest { protocol } => {
+                    let (id, request) = protocol.info();
+                    dispatched.push((*id, request.clone()));
+                }
+                ConnectionHandlerEvent::NotifyBehaviour(ToBehaviour::CloseConnection(_)) => {
+                    panic!("the handler is deactivated before the requests complete")
+                }
+                _ => {}
+            }
+        }
+
+        assert_eq!(dispatched.len(), 2);
+        assert_eq!(dispatched[0].0, 1);
+        // The goodbye is the last message sent.
+        assert!(matches!(
+            dispatched[1],
+            (
+                2,
+                lighthouse_network::rpc::outbound::OutboundRequest::Goodbye(_)
+            )
+        ));
+        assert!(matches!(handler.state, HandlerState::ShuttingDown(_)));
+    }
+
+    #[test]
+    fn request_of_a_displaced_outbound_substream_is_reported_as_failed() {
+        let mut handler = handler(1024 * 1024);
+        let substream_id = handler.outbound_substream_id.next();
+
+        handler.fail_displaced_request(substream_id, 7);
+
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        assert!(matches!(
+            handler.poll(&mut cx),
+            Poll::Ready(ConnectionHandlerEvent::NotifyBehaviour(ToBehaviour::ResponseError {
+                substream_id: id,
+                request_id: 7,
+                code: RPCResponseErrorCode::ResourceUnavailable,
+                ..
+            })) if id == substream_id
+        ));
+    }
+}
@@ -1,15 +1,31 @@
+use crate::rpc::error::RPCError;
 use crate::rpc::handler::SubstreamId;
 use libp2p::swarm::ConnectionId;
 use libp2p::PeerId;
+use std::time::Duration;
 use types::MainnetEthSpec;
 
 pub(crate) mod behaviour;
-mod error;
+pub(crate) mod error;
 pub(crate) mod handler;
 mod message;
-mod protocol;
+pub(crate) mod protocol;
 pub(crate) mod status;
 
+// The sequence number of our MetaData. We don't update our MetaData (e.g. subnet subscriptions)
+// while running, so it stays at the initial value.
+pub(crate) const META_DATA_SEQ_NUMBER: u64 = 0;
+
+// The timeouts of the RPC substreams, honored by both the inbound and the outbound ones.
+#[derive(Clone, Debug)]
+pub(crate) struct RpcConfig {
+    // The time to wait for a whole request once an inbound substream has been established, and
+    // to send ours on an outbound one.
+    pub(crate) request_timeout: Duration,
+    // The time to wait for the next bytes while reading a request.
+    pub(crate) read_timeout: Duration,
+}
+
 // ////////////////////////////////////////////////////////
 // Public events sent by RPC module
 // ////////////////////////////////////////////////////////
@@ -17,9 +33,26 @@ pub(crate) mod status;
 // RPC events sent from RPC behaviour to the behaviour composer
 #[derive(Debug)]
 #[allow(dead_code)]
-pub(crate) enum RpcEvent {
+pub(crate) enum RpcEvent<Id> {
     ReceivedRequest(ReceivedRequest),
-    ReceivedResponse(ReceivedResponse),
+    ReceivedResponse(ReceivedResponse<Id>),
+    // The peer failed to negotiate the protocol.
+    UnsupportedProtocol(PeerId, lighthouse_network::rpc::protocol::Protocol),
+    // The queued responses on an inbound substream have been sent.
+    ResponsesSent(PeerId, ConnectionId, SubstreamId),
+    // The peer responded to our request with an error code and message.
+    ResponseError(
+        PeerId,
+        Id,
+        lighthouse_network::rpc::methods::RPCResponseErrorCode,
+        String,
+    ),
+    // The peer has terminated the stream of responses to our request.
+    StreamTerminated(PeerId, Id),
+    // Failed to send a response on, or to close, an inbound substream.
+    ResponseFailed(PeerId, ConnectionId, SubstreamId, RPCError),
+    // The connections to the peer are being closed due to an RPC error.
+    ConnectionClosed(PeerId),
 }
 
 #[derive(Debug)]
@@ -32,7 +65,9 @@ pub(crate) struct ReceivedRequest {
 }
 
 #[derive(Debug)]
-pub(crate) struct ReceivedResponse {
+pub(crate) struct ReceivedResponse<Id> {
     pub(crate) peer_id: PeerId,
+    // The id of the request this responds to.
+    pub(crate) request_id: Id,
     pub(crate) response: lighthouse_network::rpc::methods::RPCResponse<MainnetEthSpec>,
 }
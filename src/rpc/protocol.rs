@@ -1,3 +1,4 @@
+use crate::rpc::RpcConfig;
 use ::types::fork_context::ForkContext;
 use futures::future::BoxFuture;
 use futures::prelude::*;
@@ -5,7 +6,6 @@ use libp2p::core::UpgradeInfo;
 use libp2p::{InboundUpgrade, OutboundUpgrade, PeerId, Stream};
 use std::fmt::{Display, Formatter};
 use std::sync::Arc;
-use std::time::Duration;
 use tokio_io_timeout::TimeoutStream;
 use tokio_util::codec::Framed;
 use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
@@ -17,28 +17,51 @@ use types::MainnetEthSpec;
 // ProtocolPrefix
 const PROTOCOL_PREFIX: &str = "/eth2/beacon_chain/req";
 
-// The number of seconds to wait for the first bytes of a request once a protocol has been
-// established before the stream is terminated.
-const REQUEST_TIMEOUT: u64 = 15;
-
 #[derive(Clone, Debug)]
 enum Protocol {
     // https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#status
     Status,
     Goodbye,
     BlocksByRange,
+    // https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#beaconblocksbyroot
+    BlocksByRoot,
+    // https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#ping
+    Ping,
+    // https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#getmetadata
+    MetaData,
 }
 
 impl Protocol {
+    // The codec schema has to match the negotiated version, e.g. the BlocksByRange V2 response
+    // chunks carry the fork-context bytes while V1 ones don't.
     fn to_lighthouse_supported_protocol(
         &self,
+        schema_version: &SchemaVersion,
     ) -> lighthouse_network::rpc::protocol::SupportedProtocol {
-        match self {
-            Protocol::Status => lighthouse_network::rpc::protocol::SupportedProtocol::StatusV1,
-            Protocol::Goodbye => lighthouse_network::rpc::protocol::SupportedProtocol::GoodbyeV1,
-            Protocol::BlocksByRange => {
+        match (self, schema_version) {
+            (Protocol::Status, _) => lighthouse_network::rpc::protocol::SupportedProtocol::StatusV1,
+            (Protocol::Goodbye, _) => {
+                lighthouse_network::rpc::protocol::SupportedProtocol::GoodbyeV1
+            }
+            (Protocol::BlocksByRange, SchemaVersion::V1) => {
+                lighthouse_network::rpc::protocol::SupportedProtocol::BlocksByRangeV1
+            }
+            (Protocol::BlocksByRange, SchemaVersion::V2) => {
                 lighthouse_network::rpc::protocol::SupportedProtocol::BlocksByRangeV2
             }
+            (Protocol::BlocksByRoot, SchemaVersion::V1) => {
+                lighthouse_network::rpc::protocol::SupportedProtocol::BlocksByRootV1
+            }
+            (Protocol::BlocksByRoot, SchemaVersion::V2) => {
+                lighthouse_network::rpc::protocol::SupportedProtocol::BlocksByRootV2
+            }
+            (Protocol::Ping, _) => lighthouse_network::rpc::protocol::SupportedProtocol::PingV1,
+            (Protocol::MetaData, SchemaVersion::V1) => {
+                lighthouse_network::rpc::protocol::SupportedProtocol::MetaDataV1
+            }
+            (Protocol::MetaData, SchemaVersion::V2) => {
+                lighthouse_network::rpc::protocol::SupportedProtocol::MetaDataV2
+            }
         }
     }
 }
@@ -49,6 +72,9 @@ impl Display for Protocol {
             Protocol::Status => "status",
             Protocol::Goodbye => "goodbye",
             Protocol::BlocksByRange => "beacon_blocks_by_range",
+            Protocol::BlocksByRoot => "beacon_blocks_by_root",
+            Protocol::Ping => "ping",
+            Protocol::MetaData => "metadata",
         };
         f.write_str(protocol_name)
     }
@@ -93,7 +119,6 @@ impl Display for Encoding {
 pub(crate) struct ProtocolId {
     #[allow(dead_code)]
     protocol: Protocol,
-    #[allow(dead_code)]
     schema_version: SchemaVersion,
     #[allow(dead_code)]
     encoding: Encoding,
@@ -119,7 +144,8 @@ impl ProtocolId {
 
     fn lighthouse_protocol_id(&self) -> lighthouse_network::rpc::protocol::ProtocolId {
         lighthouse_network::rpc::protocol::ProtocolId::new(
-            self.protocol.to_lighthouse_supported_protocol(),
+            self.protocol
+                .to_lighthouse_supported_protocol(&self.schema_version),
             lighthouse_network::rpc::protocol::Encoding::SSZSnappy,
         )
     }
@@ -131,6 +157,39 @@ impl AsRef<str> for ProtocolId {
     }
 }
 
+// The RPC protocols we support, in order of preference. Both the inbound and the outbound
+// `protocol_info` are derived from this, so that we only accept requests we can also make, and
+// vice versa.
+pub(crate) fn supported_protocols() -> Vec<ProtocolId> {
+    vec![
+        ProtocolId::new(Protocol::Status, SchemaVersion::V1, Encoding::SSZSnappy),
+        ProtocolId::new(Protocol::Goodbye, SchemaVersion::V1, Encoding::SSZSnappy),
+        ProtocolId::new(
+            Protocol::BlocksByRange,
+            SchemaVersion::V2,
+            Encoding::SSZSnappy,
+        ),
+        ProtocolId::new(
+            Protocol::BlocksByRange,
+            SchemaVersion::V1,
+            Encoding::SSZSnappy,
+        ),
+        ProtocolId::new(
+            Protocol::BlocksByRoot,
+            SchemaVersion::V2,
+            Encoding::SSZSnappy,
+        ),
+        ProtocolId::new(
+            Protocol::BlocksByRoot,
+            SchemaVersion::V1,
+            Encoding::SSZSnappy,
+        ),
+        ProtocolId::new(Protocol::Ping, SchemaVersion::V1, Encoding::SSZSnappy),
+        ProtocolId::new(Protocol::MetaData, SchemaVersion::V2, Encoding::SSZSnappy),
+        ProtocolId::new(Protocol::MetaData, SchemaVersion::V1, Encoding::SSZSnappy),
+    ]
+}
+
 // /////////////////////////////////////////////////////////////////////////////////////////////////
 // Request
 // * implements `UpgradeInfo` and `OutboundUpgrade`
@@ -147,19 +206,46 @@ pub(crate) struct RpcRequestProtocol {
     pub(super) request: OutboundRequest,
     pub(super) max_rpc_size: usize,
     pub(super) fork_context: Arc<ForkContext>,
+    pub(super) rpc_config: RpcConfig,
 }
 
 impl UpgradeInfo for RpcRequestProtocol {
     type Info = ProtocolId;
     type InfoIter = Vec<Self::Info>;
 
-    // The list of supported RPC protocols
+    // The supported versions of the protocol of the request
     fn protocol_info(&self) -> Self::InfoIter {
-        vec![ProtocolId::new(
-            Protocol::Status,
-            SchemaVersion::V1,
-            Encoding::SSZSnappy,
-        )]
+        let protocol = self.request.request.versioned_protocol().protocol();
+        supported_protocols()
+            .into_iter()
+            .filter(|protocol_id| {
+                protocol_id
+                    .protocol
+                    .to_lighthouse_supported_protocol(&protocol_id.schema_version)
+                    .protocol()
+                    == protocol
+            })
+            .collect()
+    }
+}
+
+// The codec for the responses to our request, built for the negotiated protocol version.
+fn outbound_codec(
+    protocol_id: &ProtocolId,
+    max_rpc_size: usize,
+    fork_context: Arc<ForkContext>,
+) -> lighthouse_network::rpc::codec::OutboundCodec<MainnetEthSpec> {
+    match protocol_id.encoding {
+        Encoding::SSZSnappy => {
+            let ssz_snappy_codec = lighthouse_network::rpc::codec::base::BaseOutboundCodec::new(
+                lighthouse_network::rpc::codec::ssz_snappy::SSZSnappyOutboundCodec::new(
+                    protocol_id.lighthouse_protocol_id(),
+                    max_rpc_size,
+                    fork_context,
+                ),
+            );
+            lighthouse_network::rpc::codec::OutboundCodec::SSZSnappy(ssz_snappy_codec)
+        }
     }
 }
 
@@ -178,30 +264,29 @@ impl OutboundUpgrade<Stream> for RpcRequestProtocol {
         );
         // convert to a tokio compatible socket
         let socket = socket.compat();
-        let codec = match protocol_id.encoding {
-            Encoding::SSZSnappy => {
-                let ssz_snappy_codec = lighthouse_network::rpc::codec::base::BaseOutboundCodec::new(
-                    lighthouse_network::rpc::codec::ssz_snappy::SSZSnappyOutboundCodec::new(
-                        protocol_id.lighthouse_protocol_id(),
-                        self.max_rpc_size,
-                        self.fork_context.clone(),
-                    ),
-                );
-                lighthouse_network::rpc::codec::OutboundCodec::SSZSnappy(ssz_snappy_codec)
-            }
-        };
+        let codec = outbound_codec(&protocol_id, self.max_rpc_size, self.fork_context.clone());
 
         let mut socket = Framed::new(socket, codec);
 
         async move {
-            match socket.send(self.request.request.clone()).await {
-                Ok(_) => {
+            match tokio::time::timeout(
+                self.rpc_config.request_timeout,
+                socket.send(self.request.request.clone()),
+            )
+            .await
+            {
+                Ok(Ok(_)) => {
                     info!("[{}] [RpcRequestProtocol::upgrade_outbound] sent outbound rpc: {:?}", self.request.peer_id, self.request.request);
                 }
-                Err(rpc_error) => {
+                Ok(Err(rpc_error)) => {
                     error!("[{}] [RpcRequestProtocol::upgrade_outbound] RPCError: {rpc_error}, request: {:?}", self.request.peer_id, self.request.request);
                     return Err(rpc_error);
                 }
+                // The peer didn't read our request in time.
+                Err(_elapsed) => {
+                    error!("[{}] [RpcRequestProtocol::upgrade_outbound] Timed out sending the request: {:?}", self.request.peer_id, self.request.request);
+                    return Err(lighthouse_network::rpc::RPCError::StreamTimeout);
+                }
             }
             socket.close().await?;
             Ok(socket)
@@ -221,6 +306,9 @@ pub(crate) struct RpcProtocol {
     pub(crate) max_rpc_size: usize,
     // The PeerId this communicate to. Note this is just for debugging.
     peer_id: PeerId,
+    rpc_config: RpcConfig,
+    // The protocols we accept requests on, `supported_protocols()` unless restricted by a test.
+    protocols: Vec<ProtocolId>,
 }
 
 impl RpcProtocol {
@@ -228,11 +316,15 @@ impl RpcProtocol {
         fork_context: Arc<ForkContext>,
         max_rpc_size: usize,
         peer_id: PeerId,
+        rpc_config: RpcConfig,
+        protocols: Vec<ProtocolId>,
     ) -> RpcProtocol {
         RpcProtocol {
             fork_context,
             max_rpc_size,
             peer_id,
+            rpc_config,
+            protocols,
         }
     }
 }
@@ -243,20 +335,7 @@ impl UpgradeInfo for RpcProtocol {
 
     // The list of supported RPC protocols
     fn protocol_info(&self) -> Self::InfoIter {
-        vec![
-            ProtocolId::new(Protocol::Status, SchemaVersion::V1, Encoding::SSZSnappy),
-            ProtocolId::new(Protocol::Goodbye, SchemaVersion::V1, Encoding::SSZSnappy),
-            ProtocolId::new(
-                Protocol::BlocksByRange,
-                SchemaVersion::V2,
-                Encoding::SSZSnappy,
-            ),
-            ProtocolId::new(
-                Protocol::BlocksByRange,
-                SchemaVersion::V1,
-                Encoding::SSZSnappy,
-            ),
-        ]
+        self.protocols.clone()
     }
 }
 
@@ -269,6 +348,26 @@ pub type InboundFramed<TSocket> = Framed<
     lighthouse_network::rpc::codec::InboundCodec<MainnetEthSpec>,
 >;
 
+// The codec for an inbound request and our responses, built for the negotiated protocol version.
+fn inbound_codec(
+    protocol_id: &ProtocolId,
+    max_rpc_size: usize,
+    fork_context: Arc<ForkContext>,
+) -> lighthouse_network::rpc::codec::InboundCodec<MainnetEthSpec> {
+    match protocol_id.encoding {
+        Encoding::SSZSnappy => {
+            let ssz_snappy_codec = lighthouse_network::rpc::codec::base::BaseInboundCodec::new(
+                lighthouse_network::rpc::codec::ssz_snappy::SSZSnappyInboundCodec::new(
+                    protocol_id.lighthouse_protocol_id(),
+                    max_rpc_size,
+                    fork_context,
+                ),
+            );
+            lighthouse_network::rpc::codec::InboundCodec::SSZSnappy(ssz_snappy_codec)
+        }
+    }
+}
+
 impl<TSocket> InboundUpgrade<TSocket> for RpcProtocol
 where
     TSocket: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -284,31 +383,26 @@ where
         );
 
         async move {
-            let codec: lighthouse_network::rpc::codec::InboundCodec<MainnetEthSpec> =
-                match protocol_id.encoding {
-                    Encoding::SSZSnappy => {
-                        let ssz_snappy_codec =
-                        lighthouse_network::rpc::codec::base::BaseInboundCodec::new(
-                            lighthouse_network::rpc::codec::ssz_snappy::SSZSnappyInboundCodec::new(
-                                protocol_id.lighthouse_protocol_id(),
-                                self.max_rpc_size,
-                                self.fork_context.clone(),
-                            ),
-                        );
-                        lighthouse_network::rpc::codec::InboundCodec::SSZSnappy(ssz_snappy_codec)
-                    }
-                };
+            let codec =
+                inbound_codec(&protocol_id, self.max_rpc_size, self.fork_context.clone());
 
             // convert the socket to tokio compatible socket
             let socket = socket.compat();
             let mut timed_socket = TimeoutStream::new(socket);
-            timed_socket.set_read_timeout(Some(Duration::from_secs(5)));
+            timed_socket.set_read_timeout(Some(self.rpc_config.read_timeout));
             let socket = Framed::new(Box::pin(timed_socket), codec);
 
-            match tokio::time::timeout(Duration::from_secs(REQUEST_TIMEOUT), socket.into_future())
+            match tokio::time::timeout(self.rpc_config.request_timeout, socket.into_future())
                 .await
             {
-                Err(_e) => todo!(),
+                // The peer opened the substream but didn't send a whole request in time.
+                Err(_elapsed) => {
+                    error!(
+                        "[{}] [RpcProtocol::upgrade_inbound] Timed out waiting for a request. protocol_id: {protocol_id:?}",
+                        self.peer_id
+                    );
+                    Err(lighthouse_network::rpc::RPCError::StreamTimeout)
+                }
                 Ok((Some(Ok(request)), stream)) => {
                     info!("[{}] [RpcProtocol::upgrade_inbound] received inbound message: {:?}", self.peer_id, request);
                     Ok((request, stream))
@@ -320,9 +414,322 @@ where
                     );
                     Err(rpc_error)
                 }
-                Ok((None, _)) => todo!(),
+                // The stream has ended before a whole request arrived.
+                Ok((None, _)) => {
+                    error!(
+                        "[{}] [RpcProtocol::upgrade_inbound] The stream ended without a request. protocol_id: {protocol_id:?}",
+                        self.peer_id
+                    );
+                    Err(lighthouse_network::rpc::RPCError::IncompleteStream)
+                }
             }
         }
         .boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::test_block;
+    use bytes::BytesMut;
+    use lighthouse_network::rpc::methods::{RPCCodedResponse, RPCResponse};
+    use rand::{Rng, SeedableRng};
+    use std::time::Duration;
+    use tokio_util::codec::{Decoder, Encoder};
+    use types::{ChainSpec, Hash256, SignedBeaconBlock, Slot};
+
+    const MAX_RPC_SIZE: usize = 10 * 1_048_576;
+
+    fn fork_context() -> Arc<ForkContext> {
+        Arc::new(ForkContext::new::<MainnetEthSpec>(
+            Slot::new(0),
+            Hash256::zero(),
+            &ChainSpec::mainnet(),
+        ))
+    }
+
+    fn rpc_config() -> RpcConfig {
+        RpcConfig {
+            request_timeout: Duration::from_secs(15),
+            read_timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn blocks_by_range(schema_version: SchemaVersion) -> ProtocolId {
+        ProtocolId::new(Protocol::BlocksByRange, schema_version, Encoding::SSZSnappy)
+    }
+
+    // Encodes the response as the responding peer does, over the given protocol version.
+    fn encode_response(
+        protocol_id: &ProtocolId,
+        block: Arc<SignedBeaconBlock<MainnetEthSpec>>,
+    ) -> BytesMut {
+        let mut buf = BytesMut::new();
+        inbound_codec(protocol_id, MAX_RPC_SIZE, fork_context())
+            .encode(
+                RPCCodedResponse::Success(RPCResponse::BlocksByRange(block)),
+                &mut buf,
+            )
+            .expect("encode");
+        buf
+    }
+
+    #[test]
+    fn decodes_blocks_by_range_responses_of_each_version() {
+        for schema_version in [SchemaVersion::V1, SchemaVersion::V2] {
+            let protocol_id = blocks_by_range(schema_version);
+            let block = test_block(0);
+            let mut buf = encode_response(&protocol_id, block.clone());
+
+            let decoded = outbound_codec(&protocol_id, MAX_RPC_SIZE, fork_context())
+                .decode(&mut buf)
+                .expect("decode");
+            assert!(
+                matches!(decoded, Some(RPCCodedResponse::Success(RPCResponse::BlocksByRange(decoded))) if decoded == block),
+                "protocol_id: {}",
+                protocol_id.as_ref()
+            );
+        }
+    }
+
+    #[test]
+    fn v2_response_is_not_decoded_as_v1() {
+        let block = test_block(0);
+        // The V2 chunk is prefixed with the fork-context bytes.
+        let mut buf = encode_response(&blocks_by_range(SchemaVersion::V2), block.clone());
+
+        let decoded = outbound_codec(
+            &blocks_by_range(SchemaVersion::V1),
+            MAX_RPC_SIZE,
+            fork_context(),
+        )
+        .decode(&mut buf);
+        assert!(!matches!(
+            decoded,
+            Ok(Some(RPCCodedResponse::Success(RPCResponse::BlocksByRange(decoded)))) if decoded == block
+        ));
+    }
+
+    // The SSZ length of a request over the protocol, which the length prefix has to be within the
+    // bounds of. A MetaData request has no body.
+    fn request_length(protocol_id: &ProtocolId) -> Option<u8> {
+        match (&protocol_id.protocol, &protocol_id.schema_version) {
+            (Protocol::Status, _) => Some(84),
+            (Protocol::Goodbye, _) | (Protocol::Ping, _) => Some(8),
+            (Protocol::BlocksByRange, SchemaVersion::V1) => Some(24),
+            (Protocol::BlocksByRange, SchemaVersion::V2) => Some(16),
+            (Protocol::BlocksByRoot, _) => Some(32),
+            (Protocol::MetaData, _) => None,
+        }
+    }
+
+    // Returns true if the upgrade fails on the bytes, rather than hangs or receives a request. The
+    // stream ends after the bytes, so no timeout is involved.
+    async fn upgrade_inbound_fails(protocol_id: &ProtocolId, bytes: Vec<u8>) -> bool {
+        let upgrade = RpcProtocol::new(
+            fork_context(),
+            MAX_RPC_SIZE,
+            PeerId::random(),
+            rpc_config(),
+            supported_protocols(),
+        );
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            upgrade.upgrade_inbound(futures::io::Cursor::new(bytes), protocol_id.clone()),
+        )
+        .await;
+        matches!(result, Ok(Err(_)))
+    }
+
+    #[tokio::test]
+    async fn upgrade_inbound_fails_on_arbitrary_input() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for protocol_id in supported_protocols() {
+            // A MetaData request is received whatever follows, as it has no body.
+            let Some(length) = request_length(&protocol_id) else {
+                continue;
+            };
+            for _ in 0..256 {
+                let mut bytes = vec![0u8; rng.gen_range(0..1024)];
+                rng.fill(&mut bytes[..]);
+                assert!(
+                    upgrade_inbound_fails(&protocol_id, bytes.clone()).await,
+                    "protocol_id: {}",
+                    protocol_id.as_ref()
+                );
+
+                // The length prefix is valid, so the bytes reach the snappy decoder.
+                let mut prefixed = vec![length];
+                prefixed.extend(bytes);
+                assert!(
+                    upgrade_inbound_fails(&protocol_id, prefixed).await,
+                    "protocol_id: {}",
+                    protocol_id.as_ref()
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrade_inbound_fails_on_invalid_ssz() {
+        use std::io::Write;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for schema_version in [SchemaVersion::V1, SchemaVersion::V2] {
+            let protocol_id =
+                ProtocolId::new(Protocol::BlocksByRoot, schema_version, Encoding::SSZSnappy);
+            for _ in 0..256 {
+                // The block roots are 32 bytes each, so 33 bytes are valid snappy but not SSZ.
+                let mut ssz = [0u8; 33];
+                rng.fill(&mut ssz[..]);
+                let mut bytes = vec![ssz.len() as u8];
+                let mut encoder = snap::write::FrameEncoder::new(&mut bytes);
+                encoder.write_all(&ssz).expect("compress");
+                encoder.into_inner().expect("compress");
+
+                assert!(
+                    upgrade_inbound_fails(&protocol_id, bytes).await,
+                    "protocol_id: {}",
+                    protocol_id.as_ref()
+                );
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn upgrade_inbound_times_out_without_a_request() {
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        // The peer opens the substream but sends nothing, and keeps it open.
+        let (socket, _peer) = tokio::io::duplex(1024);
+        let upgrade = RpcProtocol::new(
+            fork_context(),
+            MAX_RPC_SIZE,
+            PeerId::random(),
+            RpcConfig {
+                request_timeout: Duration::from_secs(1),
+                read_timeout: Duration::from_secs(5),
+            },
+            supported_protocols(),
+        );
+
+        let result = upgrade
+            .upgrade_inbound(
+                socket.compat(),
+                ProtocolId::new(Protocol::Status, SchemaVersion::V1, Encoding::SSZSnappy),
+            )
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(lighthouse_network::rpc::RPCError::StreamTimeout)
+        ));
+    }
+
+    #[test]
+    fn ping_and_metadata_are_supported() {
+        let protocol_ids = supported_protocols()
+            .into_iter()
+            .map(|protocol_id| protocol_id.protocol_id)
+            .collect::<Vec<_>>();
+
+        for expected in [
+            "/eth2/beacon_chain/req/ping/1/ssz_snappy",
+            "/eth2/beacon_chain/req/metadata/2/ssz_snappy",
+            "/eth2/beacon_chain/req/metadata/1/ssz_snappy",
+        ] {
+            assert!(
+                protocol_ids.iter().any(|id| id == expected),
+                "{expected} is not supported"
+            );
+        }
+    }
+
+    #[test]
+    fn inbound_and_outbound_protocols_are_symmetric() {
+        use lighthouse_network::rpc::methods::{
+            BlocksByRootRequest, GoodbyeReason, MetadataRequest, OldBlocksByRangeRequest, Ping,
+            StatusMessage,
+        };
+        use lighthouse_network::rpc::outbound::OutboundRequest as Request;
+
+        let peer_id = PeerId::random();
+        let requests: Vec<Request<MainnetEthSpec>> = vec![
+            Request::Status(StatusMessage {
+                fork_digest: [0; 4],
+                finalized_root: Hash256::zero(),
+                finalized_epoch: types::Epoch::new(0),
+                head_root: Hash256::zero(),
+                head_slot: Slot::new(0),
+            }),
+            Request::Goodbye(GoodbyeReason::ClientShutdown),
+            Request::BlocksByRange(OldBlocksByRangeRequest::new(0, 32, 1)),
+            Request::BlocksByRoot(BlocksByRootRequest::new(
+                vec![Hash256::zero()],
+                &ChainSpec::mainnet(),
+            )),
+            Request::Ping(Ping { data: 0 }),
+            Request::MetaData(MetadataRequest::new_v2()),
+        ];
+        let mut outbound = requests
+            .into_iter()
+            .flat_map(|request| {
+                RpcRequestProtocol {
+                    request: OutboundRequest { peer_id, request },
+                    max_rpc_size: MAX_RPC_SIZE,
+                    fork_context: fork_context(),
+                    rpc_config: rpc_config(),
+                }
+                .protocol_info()
+            })
+            .map(|protocol_id| protocol_id.protocol_id)
+            .collect::<Vec<_>>();
+        let mut inbound = RpcProtocol::new(
+            fork_context(),
+            MAX_RPC_SIZE,
+            peer_id,
+            rpc_config(),
+            supported_protocols(),
+        )
+        .protocol_info()
+        .into_iter()
+        .map(|protocol_id| protocol_id.protocol_id)
+        .collect::<Vec<_>>();
+        outbound.sort();
+        inbound.sort();
+
+        // We accept exactly the requests we can make.
+        assert_eq!(outbound, inbound);
+    }
+
+    #[test]
+    fn blocks_by_range_request_advertises_v2_then_v1() {
+        use lighthouse_network::rpc::methods::OldBlocksByRangeRequest;
+        use lighthouse_network::rpc::outbound::OutboundRequest as Request;
+
+        let request = RpcRequestProtocol {
+            request: OutboundRequest {
+                peer_id: PeerId::random(),
+                request: Request::BlocksByRange(OldBlocksByRangeRequest::new(0, 32, 1)),
+            },
+            max_rpc_size: MAX_RPC_SIZE,
+            fork_context: fork_context(),
+            rpc_config: rpc_config(),
+        };
+
+        let protocol_ids = request
+            .protocol_info()
+            .into_iter()
+            .map(|protocol_id| protocol_id.protocol_id)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            protocol_ids,
+            vec![
+                "/eth2/beacon_chain/req/beacon_blocks_by_range/2/ssz_snappy",
+                "/eth2/beacon_chain/req/beacon_blocks_by_range/1/ssz_snappy",
+            ]
+        );
+    }
+}
@@ -1,20 +1,23 @@
 use beacon_chain::{BeaconChain, BeaconChainTypes};
 use lighthouse_network::rpc::StatusMessage;
-use types::{EthSpec, Hash256, MainnetEthSpec};
+use slot_clock::SlotClock;
+use types::{ChainSpec, Checkpoint, EthSpec, Hash256, MainnetEthSpec};
 
 // refs: https://github.com/sigp/lighthouse/blob/be4e261e7433e02983648f7d7d8f21f74d3fa9d8/beacon_node/network/src/status.rs#L20
 pub(crate) fn status_message<T: BeaconChainTypes>(chain: &BeaconChain<T>) -> StatusMessage {
-    let fork_digest = chain.enr_fork_id().fork_digest;
-    let cached_head = chain.canonical_head.cached_head();
-    let mut finalized_checkpoint = cached_head.finalized_checkpoint();
-
-    // Alias the genesis checkpoint root to `0x00`.
     let spec = &chain.spec;
-    let genesis_epoch = spec.genesis_slot.epoch(MainnetEthSpec::slots_per_epoch());
-    if finalized_checkpoint.epoch == genesis_epoch {
-        finalized_checkpoint.root = Hash256::zero();
+
+    // Before genesis the slot clock can't tell the current slot, so the genesis fork digest and a
+    // zero head are used so that we can join a network before it starts.
+    if is_pre_genesis(&chain.slot_clock) {
+        return pre_genesis_status_message(spec, chain.genesis_validators_root);
     }
 
+    let fork_digest = chain.enr_fork_id().fork_digest;
+    let cached_head = chain.canonical_head.cached_head();
+    let finalized_checkpoint =
+        advertised_finalized_checkpoint(spec, cached_head.finalized_checkpoint());
+
     StatusMessage {
         fork_digest,
         finalized_root: finalized_checkpoint.root,
@@ -23,3 +26,92 @@ pub(crate) fn status_message<T: BeaconChainTypes>(chain: &BeaconChain<T>) -> Sta
         head_slot: cached_head.head_slot(),
     }
 }
+
+// Alias the genesis checkpoint root to `0x00`.
+// Spec: `finalized_root` defaults to `Root()` (i.e. `0x00`) in the genesis epoch.
+// https://github.com/ethereum/consensus-specs/blob/dev/specs/phase0/p2p-interface.md#status
+// The aliasing is by epoch, so a checkpoint-synced node advertises the real root of its anchor,
+// which is finalized at a later epoch, rather than the zero root. Peers look up the anchor root
+// to check that we are on the same chain, so it must not be aliased.
+fn advertised_finalized_checkpoint(
+    spec: &ChainSpec,
+    mut finalized_checkpoint: Checkpoint,
+) -> Checkpoint {
+    let genesis_epoch = spec.genesis_slot.epoch(MainnetEthSpec::slots_per_epoch());
+    if finalized_checkpoint.epoch == genesis_epoch {
+        finalized_checkpoint.root = Hash256::zero();
+    }
+    finalized_checkpoint
+}
+
+/// Whether the genesis hasn't come yet, i.e. the slot clock can't tell the current slot.
+fn is_pre_genesis<S: SlotClock>(slot_clock: &S) -> bool {
+    slot_clock.now().is_none()
+}
+
+fn pre_genesis_status_message(spec: &ChainSpec, genesis_validators_root: Hash256) -> StatusMessage {
+    StatusMessage {
+        fork_digest: spec.fork_digest::<MainnetEthSpec>(spec.genesis_slot, genesis_validators_root),
+        finalized_root: Hash256::zero(),
+        finalized_epoch: spec.genesis_slot.epoch(MainnetEthSpec::slots_per_epoch()),
+        head_root: Hash256::zero(),
+        head_slot: spec.genesis_slot,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slot_clock::ManualSlotClock;
+    use std::time::Duration;
+    use types::{Epoch, Slot};
+
+    #[test]
+    fn status_before_genesis_has_the_genesis_fork_digest_and_a_zero_head() {
+        let spec = ChainSpec::mainnet();
+        // The genesis is a minute ahead of the clock.
+        let slot_clock = ManualSlotClock::new(
+            spec.genesis_slot,
+            Duration::from_secs(60),
+            Duration::from_secs(spec.seconds_per_slot),
+        );
+        slot_clock.set_current_time(Duration::from_secs(0));
+        assert!(is_pre_genesis(&slot_clock));
+
+        let genesis_validators_root = Hash256::repeat_byte(1);
+        let status = pre_genesis_status_message(&spec, genesis_validators_root);
+        assert_eq!(
+            status.fork_digest,
+            spec.fork_digest::<MainnetEthSpec>(spec.genesis_slot, genesis_validators_root)
+        );
+        assert_eq!(status.head_root, Hash256::zero());
+        assert_eq!(status.head_slot, Slot::new(0));
+        assert_eq!(status.finalized_root, Hash256::zero());
+
+        // The genesis has come.
+        slot_clock.set_current_time(Duration::from_secs(60));
+        assert!(!is_pre_genesis(&slot_clock));
+    }
+
+    #[test]
+    fn anchor_root_of_a_checkpoint_synced_node_is_advertised() {
+        let spec = ChainSpec::mainnet();
+        // The anchor the node has been checkpoint-synced from.
+        let anchor = Checkpoint {
+            epoch: Epoch::new(200_000),
+            root: Hash256::repeat_byte(7),
+        };
+
+        assert_eq!(advertised_finalized_checkpoint(&spec, anchor), anchor);
+
+        // Only the genesis checkpoint is aliased.
+        let genesis = Checkpoint {
+            epoch: Epoch::new(0),
+            root: Hash256::repeat_byte(7),
+        };
+        assert_eq!(
+            advertised_finalized_checkpoint(&spec, genesis).root,
+            Hash256::zero()
+        );
+    }
+}
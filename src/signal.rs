@@ -1,10 +1,13 @@
+use crate::sync::{SyncOperation, SyncSender};
+use crate::PeerDB;
+use parking_lot::RwLock;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::runtime::Runtime;
 use tokio::signal::unix::{signal, Signal, SignalKind};
-use tracing::error;
+use tracing::{error, info};
 
 pub(crate) fn block_until_shutdown_requested(
     runtime: Arc<Runtime>,
@@ -32,6 +35,75 @@ pub(crate) fn block_until_shutdown_requested(
     })
 }
 
+// Dumps the known peers to the log on each SIGUSR1, for quick introspection of a running node.
+#[cfg(target_family = "unix")]
+pub(crate) fn dump_peers_on_sigusr1(runtime: Arc<Runtime>, peer_db: Arc<RwLock<PeerDB>>) {
+    let mut user_defined1 = match runtime.block_on(async { signal(SignalKind::user_defined1()) }) {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Could not register SIGUSR1 handler: {}", e);
+            return;
+        }
+    };
+
+    runtime.spawn(async move {
+        while user_defined1.recv().await.is_some() {
+            let peers = peer_db.read().snapshot();
+            info!("Received SIGUSR1. Dumping {} known peers.", peers.len());
+            for peer in peers {
+                info!(
+                    "[{}] connection_status: {:?}, sync_status: {:?}, score: {}, goodbye_reason: {:?}, last_seen: {:?}",
+                    peer.peer_id,
+                    peer.connection_status,
+                    peer.sync_status,
+                    peer.score,
+                    peer.goodbye_reason,
+                    peer.last_seen.map(|last_seen| last_seen.elapsed())
+                );
+            }
+
+            let goodbyes = peer_db.read().recent_goodbyes_sent();
+            info!("Dumping {} recent Goodbyes we sent.", goodbyes.len());
+            for goodbye in goodbyes {
+                info!(
+                    "[{}] reason: {}, {:?} ago",
+                    goodbye.peer_id,
+                    goodbye.reason,
+                    goodbye.at.elapsed()
+                );
+            }
+        }
+    });
+}
+
+#[cfg(not(target_family = "unix"))]
+pub(crate) fn dump_peers_on_sigusr1(_runtime: Arc<Runtime>, _peer_db: Arc<RwLock<PeerDB>>) {}
+
+// Requests the sync manager to dump the state of sync and peers as JSON on each SIGUSR2, to
+// diagnose a stuck sync.
+#[cfg(target_family = "unix")]
+pub(crate) fn dump_sync_state_on_sigusr2(runtime: Arc<Runtime>, sync_sender: SyncSender) {
+    let mut user_defined2 = match runtime.block_on(async { signal(SignalKind::user_defined2()) }) {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Could not register SIGUSR2 handler: {}", e);
+            return;
+        }
+    };
+
+    runtime.spawn(async move {
+        while user_defined2.recv().await.is_some() {
+            info!("Received SIGUSR2. Dumping the sync state.");
+            if let Err(e) = sync_sender.send(SyncOperation::DumpState) {
+                error!("Failed to send message to the sync manager: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(not(target_family = "unix"))]
+pub(crate) fn dump_sync_state_on_sigusr2(_runtime: Arc<Runtime>, _sync_sender: SyncSender) {}
+
 // SEE: https://github.com/sigp/lighthouse/blob/d9910f96c5f71881b88eec15253b31890bcd28d2/lighthouse/environment/src/lib.rs#L492
 #[cfg(target_family = "unix")]
 pub(crate) struct SignalFuture {
@@ -0,0 +1,302 @@
+use crate::sync::syncing_chain::EPOCHS_PER_BATCH;
+use libp2p::PeerId;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::ops::Sub;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use types::{Epoch, EthSpec, MainnetEthSpec, SignedBeaconBlock, Slot};
+
+/// The number of times a batch can fail to be downloaded or validated before it's considered
+/// failed for good.
+const MAX_BATCH_ATTEMPTS: usize = 5;
+
+/// The delay before downloading a failed batch again, doubled on each failed attempt.
+pub(crate) const BATCH_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The state of a batch.
+// refs: https://github.com/sigp/lighthouse/blob/f4ffa9e0b4acbe3cc3b50f9eeeb6b3d87e58a1a5/beacon_node/network/src/sync/range_sync/batch.rs#L166
+#[derive(Debug)]
+pub(crate) enum BatchState {
+    /// The batch is waiting for a peer to download it.
+    AwaitingDownload,
+    /// The batch is being downloaded from the peer, by the request.
+    Downloading(PeerId, u32),
+    /// The batch has been downloaded from the peer and is waiting to be validated.
+    AwaitingValidation(PeerId),
+    /// The batch has been validated.
+    Valid,
+    /// The batch has failed too many times, with the number of attempts.
+    Failed(usize),
+}
+
+/// An invalid transition of the batch state.
+#[derive(Debug)]
+pub(crate) struct WrongState(String);
+
+impl Display for WrongState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A segment of a chain.
+pub(crate) struct BatchInfo {
+    /// Start slot of the batch.
+    pub(crate) start_slot: Slot,
+    /// End slot of the batch.
+    pub(crate) end_slot: Slot,
+    /// The number of times the batch has failed to be downloaded or validated.
+    failed_attempts: usize,
+    /// The state of the batch.
+    state: BatchState,
+    /// The blocks received for the batch.
+    blocks: Vec<Arc<SignedBeaconBlock<MainnetEthSpec>>>,
+    /// The peers that failed to serve the batch, which are avoided when downloading it again.
+    failed_peers: HashSet<PeerId>,
+    /// The batch isn't downloaded again until this time, after a failed attempt.
+    retry_at: Option<Instant>,
+}
+
+impl BatchInfo {
+    pub(crate) fn new(epoch: Epoch) -> Self {
+        // refs: https://github.com/sigp/lighthouse/blob/f4ffa9e0b4acbe3cc3b50f9eeeb6b3d87e58a1a5/beacon_node/network/src/sync/range_sync/batch.rs#L134-L141
+        let start_slot = epoch.start_slot(MainnetEthSpec::slots_per_epoch()) + 1;
+        let end_slot = start_slot + EPOCHS_PER_BATCH * MainnetEthSpec::slots_per_epoch();
+        BatchInfo {
+            start_slot,
+            end_slot,
+            failed_attempts: 0,
+            state: BatchState::AwaitingDownload,
+            blocks: vec![],
+            failed_peers: HashSet::new(),
+            retry_at: None,
+        }
+    }
+
+    pub(crate) fn state(&self) -> &BatchState {
+        &self.state
+    }
+
+    pub(crate) fn blocks(&self) -> &[Arc<SignedBeaconBlock<MainnetEthSpec>>] {
+        &self.blocks
+    }
+
+    pub(crate) fn failed_peers(&self) -> &HashSet<PeerId> {
+        &self.failed_peers
+    }
+
+    /// Returns true if the batch is waiting to be downloaded and its backoff, if any, has expired.
+    pub(crate) fn is_ready_to_download(&self) -> bool {
+        matches!(self.state, BatchState::AwaitingDownload)
+            && self.retry_at.map_or(true, |at| at <= Instant::now())
+    }
+
+    /// Returns true if the batch is being downloaded from the peer by the request.
+    pub(crate) fn is_downloading(&self, peer_id: &PeerId, request_id: u32) -> bool {
+        matches!(self.state, BatchState::Downloading(p, id) if p == *peer_id && id == request_id)
+    }
+
+    /// Adds a block received from the peer that is downloading the batch.
+    pub(crate) fn add_block(
+        &mut self,
+        block: Arc<SignedBeaconBlock<MainnetEthSpec>>,
+    ) -> Result<(), WrongState> {
+        match self.state {
+            BatchState::Downloading(_peer_id, _request_id) => {
+                self.blocks.push(block);
+                Ok(())
+            }
+            _ => Err(self.wrong_state("add_block")),
+        }
+    }
+
+    /// Returns a BlocksByRange request associated with the batch.
+    pub(crate) fn to_blocks_by_range_request(
+        &self,
+    ) -> lighthouse_network::rpc::BlocksByRangeRequest {
+        lighthouse_network::rpc::BlocksByRangeRequest::new(
+            self.start_slot.into(),
+            self.end_slot.sub(self.start_slot).into(),
+        )
+    }
+
+    /// The batch has been requested from the peer.
+    pub(crate) fn start_downloading(
+        &mut self,
+        peer_id: PeerId,
+        request_id: u32,
+    ) -> Result<(), WrongState> {
+        match self.state {
+            BatchState::AwaitingDownload => {
+                self.state = BatchState::Downloading(peer_id, request_id);
+                Ok(())
+            }
+            _ => Err(self.wrong_state("start_downloading")),
+        }
+    }
+
+    /// The batch has been downloaded. Returns the peer that served it.
+    pub(crate) fn download_completed(&mut self) -> Result<PeerId, WrongState> {
+        match self.state {
+            BatchState::Downloading(peer_id, _request_id) => {
+                self.state = BatchState::AwaitingValidation(peer_id);
+                Ok(peer_id)
+            }
+            _ => Err(self.wrong_state("download_completed")),
+        }
+    }
+
+    /// The download has failed (e.g. the peer responded with an error or disconnected), so the
+    /// batch is downloaded again unless it has failed too many times.
+    pub(crate) fn download_failed(&mut self) -> Result<&BatchState, WrongState> {
+        match self.state {
+            BatchState::Downloading(peer_id, _request_id) => {
+                self.fail(peer_id);
+                Ok(&self.state)
+            }
+            _ => Err(self.wrong_state("download_failed")),
+        }
+    }
+
+    /// The blocks of the batch have been validated.
+    pub(crate) fn validation_succeeded(&mut self) -> Result<(), WrongState> {
+        match self.state {
+            BatchState::AwaitingValidation(_peer_id) => {
+                self.state = BatchState::Valid;
+                Ok(())
+            }
+            _ => Err(self.wrong_state("validation_succeeded")),
+        }
+    }
+
+    /// The blocks of the batch are invalid, so the batch is downloaded again unless it has failed
+    /// too many times.
+    pub(crate) fn validation_failed(&mut self) -> Result<&BatchState, WrongState> {
+        match self.state {
+            BatchState::AwaitingValidation(peer_id) => {
+                self.fail(peer_id);
+                Ok(&self.state)
+            }
+            _ => Err(self.wrong_state("validation_failed")),
+        }
+    }
+
+    fn fail(&mut self, peer_id: PeerId) {
+        // The blocks are downloaded again, preferably from another peer.
+        self.blocks.clear();
+        self.failed_peers.insert(peer_id);
+        self.failed_attempts += 1;
+        self.state = if self.failed_attempts >= MAX_BATCH_ATTEMPTS {
+            BatchState::Failed(self.failed_attempts)
+        } else {
+            let backoff = BATCH_RETRY_BACKOFF * 2u32.pow(self.failed_attempts as u32 - 1);
+            self.retry_at = Some(Instant::now() + backoff);
+            BatchState::AwaitingDownload
+        };
+    }
+
+    fn wrong_state(&self, transition: &str) -> WrongState {
+        WrongState(format!(
+            "Invalid batch state for `{transition}`. state: {:?}",
+            self.state
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::test_block;
+
+    #[test]
+    fn batch_is_downloaded_and_validated() {
+        let peer_id = PeerId::random();
+        let mut batch = BatchInfo::new(Epoch::new(0));
+        assert!(batch.is_ready_to_download());
+
+        batch
+            .start_downloading(peer_id, 1)
+            .expect("start_downloading");
+        assert!(batch.is_downloading(&peer_id, 1));
+        assert!(!batch.is_downloading(&peer_id, 2));
+        batch.add_block(test_block(0)).expect("add_block");
+
+        assert_eq!(
+            batch.download_completed().expect("download_completed"),
+            peer_id
+        );
+        assert!(matches!(batch.state(), BatchState::AwaitingValidation(p) if *p == peer_id));
+        assert_eq!(batch.blocks().len(), 1);
+
+        batch.validation_succeeded().expect("validation_succeeded");
+        assert!(matches!(batch.state(), BatchState::Valid));
+    }
+
+    #[test]
+    fn invalid_transitions_are_rejected() {
+        let peer_id = PeerId::random();
+        let mut batch = BatchInfo::new(Epoch::new(0));
+        assert!(batch.add_block(test_block(0)).is_err());
+        assert!(batch.download_completed().is_err());
+        assert!(batch.download_failed().is_err());
+        assert!(batch.validation_succeeded().is_err());
+        assert!(batch.validation_failed().is_err());
+
+        batch
+            .start_downloading(peer_id, 1)
+            .expect("start_downloading");
+        assert!(batch.start_downloading(peer_id, 2).is_err());
+        assert!(batch.validation_succeeded().is_err());
+
+        batch.download_completed().expect("download_completed");
+        assert!(batch.add_block(test_block(0)).is_err());
+        assert!(batch.download_completed().is_err());
+
+        // The state is unchanged by the rejected transitions.
+        assert!(matches!(batch.state(), BatchState::AwaitingValidation(p) if *p == peer_id));
+    }
+
+    #[test]
+    fn failed_batch_is_downloaded_again_after_a_backoff() {
+        let peer_id = PeerId::random();
+        let mut batch = BatchInfo::new(Epoch::new(0));
+        batch
+            .start_downloading(peer_id, 1)
+            .expect("start_downloading");
+        batch.add_block(test_block(0)).expect("add_block");
+
+        assert!(matches!(
+            batch.download_failed().expect("download_failed"),
+            BatchState::AwaitingDownload
+        ));
+        assert!(batch.blocks().is_empty());
+        assert!(batch.failed_peers().contains(&peer_id));
+        assert!(!batch.is_ready_to_download());
+    }
+
+    #[test]
+    fn batch_fails_for_good_after_too_many_attempts() {
+        let mut batch = BatchInfo::new(Epoch::new(0));
+        for attempt in 1..=MAX_BATCH_ATTEMPTS {
+            let peer_id = PeerId::random();
+            batch
+                .start_downloading(peer_id, attempt as u32)
+                .expect("start_downloading");
+            batch.download_completed().expect("download_completed");
+            let state = batch.validation_failed().expect("validation_failed");
+            if attempt < MAX_BATCH_ATTEMPTS {
+                assert!(matches!(state, BatchState::AwaitingDownload));
+                // Skip the backoff.
+                batch.retry_at = None;
+            } else {
+                assert!(
+                    matches!(state, BatchState::Failed(attempts) if *attempts == MAX_BATCH_ATTEMPTS)
+                );
+            }
+        }
+        assert!(!batch.is_ready_to_download());
+        assert_eq!(batch.failed_peers().len(), MAX_BATCH_ATTEMPTS);
+    }
+}
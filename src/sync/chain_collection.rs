@@ -1,16 +1,35 @@
 use crate::sync::network_context::SyncNetworkContext;
-use crate::sync::syncing_chain::{ChainId, SyncingChain};
+use crate::sync::range_sync::RangeSyncType;
+use crate::sync::syncing_chain::{BatchId, ChainId, SyncingChain};
 use libp2p::PeerId;
+use std::cmp::Reverse;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::sync::Arc;
 use tracing::{info, warn};
-use types::{Epoch, Hash256, Slot};
+use types::{Epoch, Hash256, MainnetEthSpec, SignedBeaconBlock, Slot};
+
+// The number of peers a finalized chain needs to have more than the syncing chain to switch to it.
+// This prevents thrashing between chains with nearly-equal peer counts.
+const CHAIN_SWITCH_PEER_MARGIN: usize = 2;
+
+// The maximum number of head chains synced in parallel.
+const PARALLEL_HEAD_CHAINS: usize = 2;
 
 pub(crate) struct ChainCollection {
     /// The current sync state of the process.
     state: RangeSyncState,
     /// The set of finalized chains being synced.
     finalized_chains: HashMap<ChainId, SyncingChain>,
+    /// The set of head chains being synced. Their peers agree on our finalized checkpoint but are
+    /// ahead of us at the head.
+    head_chains: HashMap<ChainId, SyncingChain>,
+    /// The maximum number of finalized chains tracked.
+    max_finalized_chains: usize,
+    /// The epoch to stop syncing at, if set.
+    target_epoch: Option<Epoch>,
+    /// Our own peer id, which must never be added to a chain.
+    local_peer_id: PeerId,
 }
 
 enum RangeSyncState {
@@ -18,13 +37,23 @@ enum RangeSyncState {
     Idle,
     /// A finalized chain is being synced.
     Syncing(ChainId),
+    /// There are no finalized chains, so the head chains are being synced.
+    Head,
 }
 
 impl ChainCollection {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(
+        max_finalized_chains: usize,
+        target_epoch: Option<Epoch>,
+        local_peer_id: PeerId,
+    ) -> Self {
         ChainCollection {
             state: RangeSyncState::Idle,
             finalized_chains: HashMap::new(),
+            head_chains: HashMap::new(),
+            max_finalized_chains,
+            target_epoch,
+            local_peer_id,
         }
     }
 
@@ -35,28 +64,318 @@ impl ChainCollection {
         start_epoch: Epoch,
         target_head_root: Hash256,
         target_head_slot: Slot,
+        sync_type: RangeSyncType,
     ) {
+        if peer_id == self.local_peer_id {
+            warn!("[{peer_id}] Refused to add ourselves as a sync peer.");
+            return;
+        }
+
         let chain_id = crate::sync::syncing_chain::id(&target_head_root, &target_head_slot);
+        let (chains, chain_type) = match sync_type {
+            RangeSyncType::Finalized => (&mut self.finalized_chains, "finalized"),
+            RangeSyncType::Head => (&mut self.head_chains, "head"),
+        };
 
-        match self.finalized_chains.entry(chain_id) {
+        match chains.entry(chain_id) {
             Entry::Occupied(mut entry) => {
-                info!(chain_id = %chain_id, "[{peer_id}] Adding peer to known chain.");
+                info!(chain_id = %chain_id, "[{peer_id}] Adding peer to known {chain_type} chain.");
                 let chain = entry.get_mut();
                 assert_eq!(chain.target_head_root, target_head_root);
                 assert_eq!(chain.target_head_slot, target_head_slot);
                 chain.add_peer(network_context, peer_id);
             }
             Entry::Vacant(entry) => {
-                info!("[{peer_id}] A new finalized chain is added to sync. chain_id: {chain_id}");
+                info!(
+                    "[{peer_id}] A new {chain_type} chain is added to sync. chain_id: {chain_id}"
+                );
 
                 entry.insert(SyncingChain::new(
                     start_epoch,
                     target_head_slot,
                     target_head_root,
                     peer_id,
+                    self.target_epoch,
                 ));
+
+                if self.finalized_chains.len() > self.max_finalized_chains {
+                    self.evict_least_useful_chain();
+                }
+            }
+        }
+    }
+
+    /// Returns all the chains, finalized and head.
+    fn chains_mut(&mut self) -> impl Iterator<Item = &mut SyncingChain> {
+        self.finalized_chains
+            .values_mut()
+            .chain(self.head_chains.values_mut())
+    }
+
+    fn get_chain_mut(&mut self, chain_id: &ChainId) -> Option<&mut SyncingChain> {
+        self.finalized_chains
+            .get_mut(chain_id)
+            .or_else(|| self.head_chains.get_mut(chain_id))
+    }
+
+    /// Goes back to idle if the chains being synced have been removed.
+    fn reset_state_if_removed(&mut self) {
+        let removed = match self.state {
+            RangeSyncState::Idle => false,
+            RangeSyncState::Syncing(id) => !self.finalized_chains.contains_key(&id),
+            RangeSyncState::Head => self.head_chains.is_empty(),
+        };
+        if removed {
+            self.state = RangeSyncState::Idle;
+            self.update_metrics();
+        }
+    }
+
+    /// The chain being synced, the farthest one while syncing head chains.
+    fn syncing_chain(&self) -> Option<&SyncingChain> {
+        match self.state {
+            RangeSyncState::Idle => None,
+            RangeSyncState::Syncing(id) => self.finalized_chains.get(&id),
+            RangeSyncState::Head => self
+                .head_chains
+                .values()
+                .filter(|chain| chain.is_syncing())
+                .max_by_key(|chain| chain.target_head_slot),
+        }
+    }
+
+    fn update_metrics(&self) {
+        crate::metrics::set_gauge(
+            &crate::metrics::SYNCING_CHAIN_TARGET_SLOT,
+            self.syncing_chain()
+                .map_or(0, |chain| chain.target_head_slot.as_u64() as i64),
+        );
+    }
+
+    /// The kind and the target of the chain being synced, for the HTTP API.
+    pub(crate) fn syncing_status(&self) -> serde_json::Value {
+        let state = match self.state {
+            RangeSyncState::Idle => "idle",
+            RangeSyncState::Syncing(_) => "finalized",
+            RangeSyncState::Head => "head",
+        };
+        let chain = self.syncing_chain();
+
+        serde_json::json!({
+            "state": state,
+            "target_head_slot": chain.map(|chain| chain.target_head_slot.as_u64()),
+            "target_head_root": chain.map(|chain| format!("{:?}", chain.target_head_root)),
+        })
+    }
+
+    /// Removes the chain with the fewest peers, and the farthest behind among them, so that the
+    /// chains tracked are bounded. The syncing chain is never evicted.
+    fn evict_least_useful_chain(&mut self) {
+        let syncing_chain_id = match self.state {
+            RangeSyncState::Idle | RangeSyncState::Head => None,
+            RangeSyncState::Syncing(id) => Some(id),
+        };
+
+        let least_useful = self
+            .finalized_chains
+            .iter()
+            .filter(|(id, _chain)| Some(**id) != syncing_chain_id)
+            .min_by_key(|(_id, chain)| (chain.available_peers(), chain.target_head_slot))
+            .map(|(id, _chain)| *id);
+
+        if let Some(chain_id) = least_useful {
+            info!(
+                "Evicted a finalized chain as the number of chains exceeded the limit: {}. chain_id: {chain_id}",
+                self.max_finalized_chains
+            );
+            self.finalized_chains.remove(&chain_id);
+        }
+    }
+
+    /// Removes a peer from all the chains. Chains left without peers are removed.
+    pub(crate) fn remove_peer(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+    ) {
+        for chains in [&mut self.finalized_chains, &mut self.head_chains] {
+            chains.retain(|chain_id, chain| {
+                chain.remove_peer(network_context, peer_id);
+
+                if chain.available_peers() > 0 {
+                    return true;
+                }
+
+                info!("[{peer_id}] Removed a chain as no peers are left. chain_id: {chain_id}");
+                false
+            });
+        }
+        self.remove_failed_chains();
+    }
+
+    /// Removes the chains that can't be synced as a batch has failed too many times.
+    fn remove_failed_chains(&mut self) {
+        for chains in [&mut self.finalized_chains, &mut self.head_chains] {
+            chains.retain(|chain_id, chain| {
+                if !chain.has_failed_batch() {
+                    return true;
+                }
+
+                warn!("Removed a chain as a batch has failed. chain_id: {chain_id}");
+                false
+            });
+        }
+        self.reset_state_if_removed();
+    }
+
+    /// Returns the state of the range sync and its chains, for debugging.
+    pub(crate) fn snapshot(&self) -> serde_json::Value {
+        let state = match self.state {
+            RangeSyncState::Idle => "Idle".to_string(),
+            RangeSyncState::Syncing(id) => format!("Syncing({id})"),
+            RangeSyncState::Head => "Head".to_string(),
+        };
+        let finalized_chains = self
+            .finalized_chains
+            .values()
+            .map(|chain| chain.snapshot())
+            .collect::<Vec<_>>();
+        let head_chains = self
+            .head_chains
+            .values()
+            .map(|chain| chain.snapshot())
+            .collect::<Vec<_>>();
+
+        serde_json::json!({
+            "state": state,
+            "finalized_chains": finalized_chains,
+            "head_chains": head_chains,
+        })
+    }
+
+    /// Resumes requesting batches of the syncing chains.
+    pub(crate) fn resume(&mut self, network_context: &mut SyncNetworkContext) {
+        match self.state {
+            RangeSyncState::Idle => {}
+            RangeSyncState::Syncing(id) => {
+                if let Some(chain) = self.finalized_chains.get_mut(&id) {
+                    chain.resume(network_context);
+                }
+            }
+            RangeSyncState::Head => {
+                for chain in self.head_chains.values_mut() {
+                    chain.resume(network_context);
+                }
+            }
+        }
+    }
+
+    /// A block of the batch requested by the request has been received from the peer.
+    pub(crate) fn add_block(
+        &mut self,
+        peer_id: &PeerId,
+        request_id: u32,
+        block: Arc<SignedBeaconBlock<MainnetEthSpec>>,
+    ) {
+        let Some(chain) = self
+            .chains_mut()
+            .find(|chain| chain.is_downloading(peer_id, request_id))
+        else {
+            warn!("[{peer_id}] Received a block for an unknown request. request_id: {request_id}");
+            return;
+        };
+        chain.add_block(peer_id, request_id, block);
+    }
+
+    /// The peer has finished serving the batch requested by the request.
+    pub(crate) fn download_completed(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+        request_id: u32,
+    ) {
+        for chain in self.chains_mut() {
+            if chain.download_completed(network_context, peer_id, request_id) {
+                return;
+            }
+        }
+    }
+
+    /// The peer failed to serve the batch requested by the request.
+    pub(crate) fn download_failed(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+        request_id: u32,
+    ) {
+        for chain in self.chains_mut() {
+            if chain.download_failed(network_context, peer_id, request_id) {
+                break;
             }
         }
+        self.remove_failed_chains();
+    }
+
+    /// Returns the next batch of the syncing chains to import.
+    pub(crate) fn next_batch_to_process(
+        &mut self,
+    ) -> Option<(
+        ChainId,
+        BatchId,
+        PeerId,
+        Vec<Arc<SignedBeaconBlock<MainnetEthSpec>>>,
+    )> {
+        match self.state {
+            RangeSyncState::Idle => None,
+            RangeSyncState::Syncing(id) => {
+                let (batch_id, peer_id, blocks) = self
+                    .finalized_chains
+                    .get_mut(&id)?
+                    .next_batch_to_process()?;
+                Some((id, batch_id, peer_id, blocks))
+            }
+            RangeSyncState::Head => self.head_chains.iter_mut().find_map(|(id, chain)| {
+                let (batch_id, peer_id, blocks) = chain.next_batch_to_process()?;
+                Some((*id, batch_id, peer_id, blocks))
+            }),
+        }
+    }
+
+    /// A batch of the chain has been processed.
+    pub(crate) fn on_batch_processed(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        chain_id: ChainId,
+        batch_id: BatchId,
+        success: bool,
+        local_finalized_epoch: Epoch,
+    ) {
+        let Some(chain) = self.get_chain_mut(&chain_id) else {
+            // The chain has been removed while the batch was being processed.
+            return;
+        };
+        chain.on_batch_processed(network_context, batch_id, success, local_finalized_epoch);
+
+        if chain.is_completed() {
+            info!("Chain has been synced. chain_id: {chain_id}");
+            self.finalized_chains.remove(&chain_id);
+            self.head_chains.remove(&chain_id);
+            self.reset_state_if_removed();
+            self.update(network_context, local_finalized_epoch);
+        }
+        self.remove_failed_chains();
+    }
+
+    /// Requests the batches assigned to the peer again, in all the chains.
+    pub(crate) fn retry_batches(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+    ) {
+        for chain in self.chains_mut() {
+            chain.retry_batches(network_context, peer_id);
+        }
+        self.remove_failed_chains();
     }
 
     pub(crate) fn update(
@@ -66,7 +385,45 @@ impl ChainCollection {
     ) {
         // TODO: purge outdated chains.
 
-        self.update_finalized_chains(network_context, local_finalized_epoch);
+        // Finalized chains are preferred, as the head chains are synced on top of the finalized
+        // checkpoint anyway.
+        if !self.finalized_chains.is_empty() {
+            if matches!(self.state, RangeSyncState::Head) {
+                info!("Stopping the head chains to sync a finalized chain.");
+                for chain in self.head_chains.values_mut() {
+                    chain.stop_syncing();
+                }
+                self.state = RangeSyncState::Idle;
+            }
+            self.update_finalized_chains(network_context, local_finalized_epoch);
+        } else {
+            self.update_head_chains(network_context, local_finalized_epoch);
+        }
+        self.update_metrics();
+    }
+
+    /// Syncs the head chains with the most peers, in parallel.
+    fn update_head_chains(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        local_finalized_epoch: Epoch,
+    ) {
+        if self.head_chains.is_empty() {
+            return;
+        }
+
+        let mut chains = self.head_chains.iter_mut().collect::<Vec<_>>();
+        chains.sort_by_key(|(_id, chain)| Reverse(chain.available_peers()));
+        for (chain_id, chain) in chains.into_iter().take(PARALLEL_HEAD_CHAINS) {
+            if !chain.is_syncing() {
+                info!(
+                    "Syncing new head chain. chain_id: {chain_id}, peers: {}",
+                    chain.available_peers()
+                );
+                chain.start_syncing(network_context, local_finalized_epoch);
+            }
+        }
+        self.state = RangeSyncState::Head;
     }
 
     fn update_finalized_chains(
@@ -74,38 +431,193 @@ impl ChainCollection {
         network_context: &mut SyncNetworkContext,
         local_finalized_epoch: Epoch,
     ) {
-        let new_syncing_chain_id = {
-            let chain_id = self
+        let (mut new_syncing_chain_id, new_peers) = {
+            let chain = self
                 .finalized_chains
                 .iter()
                 .max_by_key(|(_id, chain)| chain.available_peers())
-                .map(|(id, _chain)| *id);
+                .map(|(id, chain)| (*id, chain.available_peers()));
 
-            if let Some(id) = chain_id {
-                id
+            if let Some(chain) = chain {
+                chain
             } else {
                 warn!("No finalized chains found.");
                 return;
             }
         };
 
-        let chain = self
-            .finalized_chains
-            .get_mut(&new_syncing_chain_id)
-            .expect("Syncing chain exists.");
-
-        match self.state {
-            RangeSyncState::Idle => {
-                info!("Syncing new finalized chain. chain_id: {new_syncing_chain_id}");
-            }
-            RangeSyncState::Syncing(id) => {
+        if let RangeSyncState::Syncing(id) = self.state {
+            if let Some(current_chain) = self.finalized_chains.get(&id) {
+                let current_peers = current_chain.available_peers();
                 if new_syncing_chain_id != id {
-                    info!("Switching finalized chains from {id} to {new_syncing_chain_id}");
+                    if new_peers >= current_peers + CHAIN_SWITCH_PEER_MARGIN {
+                        info!(
+                            "Switching finalized chains from {id} (peers: {current_peers}) to {new_syncing_chain_id} (peers: {new_peers})"
+                        );
+                    } else {
+                        // Keep syncing the current chain as the other one isn't clearly better.
+                        new_syncing_chain_id = id;
+                    }
                 }
             }
+        } else {
+            info!(
+                "Syncing new finalized chain. chain_id: {new_syncing_chain_id}, peers: {new_peers}"
+            );
         }
 
+        let chain = self
+            .finalized_chains
+            .get_mut(&new_syncing_chain_id)
+            .expect("Syncing chain exists.");
+
         self.state = RangeSyncState::Syncing(new_syncing_chain_id);
         chain.start_syncing(network_context, local_finalized_epoch);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NetworkMessage;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn chain_is_removed_with_its_last_peer() {
+        let (network_send, _network_recv) = mpsc::channel::<NetworkMessage>(64);
+        let mut network_context = SyncNetworkContext::new(network_send, 64, 64);
+        let mut chains = ChainCollection::new(4, None, PeerId::random());
+        let (silent_peer, other_peer) = (PeerId::random(), PeerId::random());
+        for (peer_id, head_slot) in [(silent_peer, 320), (other_peer, 640)] {
+            chains.add_peer_or_create_chain(
+                &mut network_context,
+                peer_id,
+                Epoch::new(0),
+                Hash256::repeat_byte(head_slot as u8),
+                Slot::new(head_slot),
+                RangeSyncType::Head,
+            );
+        }
+        chains.update(&mut network_context, Epoch::new(0));
+        assert_eq!(chains.head_chains.len(), 2);
+
+        // The peer has stopped exchanging Status.
+        chains.remove_peer(&mut network_context, &silent_peer);
+
+        assert_eq!(chains.head_chains.len(), 1);
+        assert!(chains
+            .head_chains
+            .values()
+            .all(|chain| chain.target_head_slot == Slot::new(640)));
+    }
+
+    fn add_finalized_peer(
+        chains: &mut ChainCollection,
+        network_context: &mut SyncNetworkContext,
+        head_slot: u64,
+    ) {
+        chains.add_peer_or_create_chain(
+            network_context,
+            PeerId::random(),
+            Epoch::new(0),
+            Hash256::repeat_byte(head_slot as u8),
+            Slot::new(head_slot),
+            RangeSyncType::Finalized,
+        );
+    }
+
+    #[test]
+    fn near_equal_chains_do_not_cause_switching() {
+        let (network_send, _network_recv) = mpsc::channel::<NetworkMessage>(1024);
+        let mut network_context = SyncNetworkContext::new(network_send, 64, 64);
+        let mut chains = ChainCollection::new(4, None, PeerId::random());
+        let syncing = crate::sync::syncing_chain::id(&Hash256::repeat_byte(64), &Slot::new(64));
+        let other = crate::sync::syncing_chain::id(&Hash256::repeat_byte(96), &Slot::new(96));
+        let syncing_chain_id = |chains: &ChainCollection| match chains.state {
+            RangeSyncState::Syncing(id) => Some(id),
+            _ => None,
+        };
+
+        add_finalized_peer(&mut chains, &mut network_context, 64);
+        add_finalized_peer(&mut chains, &mut network_context, 64);
+        chains.update(&mut network_context, Epoch::new(0));
+        assert_eq!(syncing_chain_id(&chains), Some(syncing));
+
+        // The other chain has one more peer, which isn't enough to switch.
+        for _ in 0..CHAIN_SWITCH_PEER_MARGIN + 1 {
+            add_finalized_peer(&mut chains, &mut network_context, 96);
+        }
+        chains.update(&mut network_context, Epoch::new(0));
+        assert_eq!(syncing_chain_id(&chains), Some(syncing));
+        chains.update(&mut network_context, Epoch::new(0));
+        assert_eq!(syncing_chain_id(&chains), Some(syncing));
+
+        // The other chain is clearly better now.
+        add_finalized_peer(&mut chains, &mut network_context, 96);
+        chains.update(&mut network_context, Epoch::new(0));
+        assert_eq!(syncing_chain_id(&chains), Some(other));
+    }
+
+    #[test]
+    fn snapshot_serializes() {
+        let (network_send, _network_recv) = mpsc::channel::<NetworkMessage>(1024);
+        let mut network_context = SyncNetworkContext::new(network_send, 64, 64);
+        let mut chains = ChainCollection::new(4, None, PeerId::random());
+        assert!(serde_json::to_string(&chains.snapshot()).is_ok());
+
+        add_finalized_peer(&mut chains, &mut network_context, 640);
+        chains.update(&mut network_context, Epoch::new(0));
+
+        let snapshot = chains.snapshot();
+        assert!(serde_json::to_string(&snapshot).is_ok());
+        assert!(snapshot["state"].as_str().unwrap().starts_with("Syncing"));
+        let chain = &snapshot["finalized_chains"][0];
+        assert_eq!(chain["target_head_slot"], 640);
+        assert!(!chain["batches"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn least_useful_chain_is_evicted_beyond_the_cap() {
+        let (network_send, _network_recv) = mpsc::channel::<NetworkMessage>(1024);
+        let mut network_context = SyncNetworkContext::new(network_send, 64, 64);
+        let mut chains = ChainCollection::new(2, None, PeerId::random());
+        let chain_id = |head_slot: u64| {
+            crate::sync::syncing_chain::id(
+                &Hash256::repeat_byte(head_slot as u8),
+                &Slot::new(head_slot),
+            )
+        };
+
+        // The syncing chain has the fewest peers and is the farthest behind.
+        add_finalized_peer(&mut chains, &mut network_context, 64);
+        chains.update(&mut network_context, Epoch::new(0));
+        add_finalized_peer(&mut chains, &mut network_context, 96);
+        add_finalized_peer(&mut chains, &mut network_context, 96);
+        add_finalized_peer(&mut chains, &mut network_context, 128);
+        add_finalized_peer(&mut chains, &mut network_context, 160);
+
+        assert_eq!(chains.finalized_chains.len(), 2);
+        assert!(chains.finalized_chains.contains_key(&chain_id(64)));
+        assert!(chains.finalized_chains.contains_key(&chain_id(96)));
+    }
+
+    #[test]
+    fn local_peer_is_not_added_to_a_chain() {
+        let (network_send, _network_recv) = mpsc::channel::<NetworkMessage>(64);
+        let mut network_context = SyncNetworkContext::new(network_send, 64, 64);
+        let local_peer_id = PeerId::random();
+        let mut chains = ChainCollection::new(4, None, local_peer_id);
+
+        chains.add_peer_or_create_chain(
+            &mut network_context,
+            local_peer_id,
+            Epoch::new(0),
+            Hash256::repeat_byte(1),
+            Slot::new(320),
+            RangeSyncType::Finalized,
+        );
+
+        assert!(chains.finalized_chains.is_empty());
+        assert!(chains.head_chains.is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+use libp2p::PeerId;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{debug, warn};
+use types::{MainnetEthSpec, SignedBeaconBlock, Slot};
+
+/// Blocks up to this number of slots ahead of our slot clock are queued rather than rejected.
+const MAX_FUTURE_SLOTS: u64 = 2;
+
+/// The maximum number of blocks held in the queue.
+const MAX_QUEUED_BLOCKS: usize = 64;
+
+/// What to do with a block, relative to our slot clock.
+pub(crate) enum FutureBlockCheck {
+    /// The block's slot has arrived, so it can be imported now.
+    Ready(Arc<SignedBeaconBlock<MainnetEthSpec>>),
+    /// The block is slightly ahead of our slot clock and has been queued.
+    Queued,
+    /// The block is too far in the future or the queue is full. The sender should be penalized
+    /// for the former.
+    Rejected { too_far: bool },
+}
+
+/// Holds the blocks from the near future until their slots arrive.
+pub(crate) struct FutureBlockQueue {
+    blocks: BTreeMap<Slot, Vec<(PeerId, Arc<SignedBeaconBlock<MainnetEthSpec>>)>>,
+    len: usize,
+}
+
+impl FutureBlockQueue {
+    pub(crate) fn new() -> Self {
+        FutureBlockQueue {
+            blocks: BTreeMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Checks the slot of a block received from a peer against the current slot, and queues it
+    /// if it is from the near future.
+    pub(crate) fn check(
+        &mut self,
+        peer_id: PeerId,
+        block: Arc<SignedBeaconBlock<MainnetEthSpec>>,
+        current_slot: Slot,
+    ) -> FutureBlockCheck {
+        let slot = block.slot();
+        if slot <= current_slot {
+            return FutureBlockCheck::Ready(block);
+        }
+
+        if slot > current_slot + MAX_FUTURE_SLOTS {
+            warn!("[{peer_id}] Rejected a block too far in the future. slot: {slot}, current_slot: {current_slot}");
+            return FutureBlockCheck::Rejected { too_far: true };
+        }
+
+        if self.len >= MAX_QUEUED_BLOCKS {
+            warn!("[{peer_id}] Rejected a future block as the queue is full. slot: {slot}");
+            return FutureBlockCheck::Rejected { too_far: false };
+        }
+
+        debug!("[{peer_id}] Queued a future block. slot: {slot}, current_slot: {current_slot}");
+        self.blocks.entry(slot).or_default().push((peer_id, block));
+        self.len += 1;
+        FutureBlockCheck::Queued
+    }
+
+    /// Takes the queued blocks whose slots have arrived, in slot order.
+    pub(crate) fn pop_ready(
+        &mut self,
+        current_slot: Slot,
+    ) -> Vec<(PeerId, Arc<SignedBeaconBlock<MainnetEthSpec>>)> {
+        let future_blocks = self.blocks.split_off(&(current_slot + 1));
+        let ready_blocks = std::mem::replace(&mut self.blocks, future_blocks)
+            .into_values()
+            .flatten()
+            .collect::<Vec<_>>();
+        self.len -= ready_blocks.len();
+        ready_blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::test_block;
+
+    #[test]
+    fn near_future_block_is_queued_until_its_slot() {
+        let mut queue = FutureBlockQueue::new();
+        let peer_id = PeerId::random();
+
+        let check = queue.check(peer_id, test_block(11), Slot::new(10));
+        assert!(matches!(check, FutureBlockCheck::Queued));
+        assert!(queue.pop_ready(Slot::new(10)).is_empty());
+
+        let ready = queue.pop_ready(Slot::new(11));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].0, peer_id);
+        assert_eq!(ready[0].1.slot(), Slot::new(11));
+        assert!(queue.pop_ready(Slot::new(12)).is_empty());
+    }
+
+    #[test]
+    fn far_future_block_is_rejected() {
+        let mut queue = FutureBlockQueue::new();
+
+        let check = queue.check(
+            PeerId::random(),
+            test_block(10 + MAX_FUTURE_SLOTS + 1),
+            Slot::new(10),
+        );
+        assert!(matches!(
+            check,
+            FutureBlockCheck::Rejected { too_far: true }
+        ));
+        assert!(queue.pop_ready(Slot::new(100)).is_empty());
+    }
+
+    #[test]
+    fn current_block_is_ready() {
+        let mut queue = FutureBlockQueue::new();
+        let check = queue.check(PeerId::random(), test_block(10), Slot::new(10));
+        assert!(matches!(check, FutureBlockCheck::Ready(_)));
+    }
+
+    #[test]
+    fn blocks_are_rejected_once_the_queue_is_full() {
+        let mut queue = FutureBlockQueue::new();
+        for _ in 0..MAX_QUEUED_BLOCKS {
+            let check = queue.check(PeerId::random(), test_block(11), Slot::new(10));
+            assert!(matches!(check, FutureBlockCheck::Queued));
+        }
+
+        let check = queue.check(PeerId::random(), test_block(11), Slot::new(10));
+        assert!(matches!(
+            check,
+            FutureBlockCheck::Rejected { too_far: false }
+        ));
+        assert_eq!(queue.pop_ready(Slot::new(11)).len(), MAX_QUEUED_BLOCKS);
+    }
+}
@@ -1,28 +1,163 @@
+mod batch;
 mod chain_collection;
+mod future_blocks;
 mod network_context;
 mod range_sync;
 mod syncing_chain;
 
+use crate::block_cache::BlockCache;
+use crate::config::RuntimeConfig;
 use crate::network::NetworkMessage;
 use crate::peer_db::SyncStatus;
 use crate::rpc::status::status_message;
+use crate::sync::future_blocks::{FutureBlockCheck, FutureBlockQueue};
 use crate::sync::network_context::SyncNetworkContext;
-use crate::sync::range_sync::RangeSync;
+use crate::sync::range_sync::{process_batch, RangeSync};
+use crate::sync::syncing_chain::{BatchId, ChainId};
 use crate::PeerDB;
 use beacon_chain::BeaconChainTypes;
 use libp2p::PeerId;
-use parking_lot::RwLock;
+use lighthouse_network::rpc::methods::RPCResponseErrorCode;
+use parking_lot::{Mutex, RwLock};
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use types::{Epoch, Hash256, Slot};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, trace, warn};
+use types::{ChainSpec, Epoch, Hash256, MainnetEthSpec, SignedBeaconBlock, Slot};
+
+// Sync peers that haven't exchanged Status for this long are considered dead for sync, even before
+// the connection is dropped. This is longer than the re-status interval of the peer manager.
+const SYNC_PEER_STATUS_TIMEOUT: Duration = Duration::from_secs(900);
+
+// The interval of checking for the stale sync peers.
+const STALE_PEER_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// The change of the peer's score when it responds to our request with an error.
+const RESPONSE_ERROR_SCORE_DELTA: f64 = -5.0;
+
+// The change of the peer's score when it sends a block whose fork doesn't match its slot.
+const WRONG_FORK_SCORE_DELTA: f64 = -10.0;
+
+// The change of the peer's score when it sends a block too far ahead of our slot clock.
+const FUTURE_BLOCK_SCORE_DELTA: f64 = -10.0;
+
+// The interval of resuming the batch requests, which stop while the peers are throttled.
+const RESUME_REQUESTS_INTERVAL: Duration = Duration::from_secs(1);
 
 /// A message that can be sent to the sync manager thread.
 #[derive(Debug)]
 pub(crate) enum SyncOperation {
     /// A useful peer has been discovered.
+    ///
+    /// The network sends this once a peer's Status has been validated. The sync manager then
+    /// determines the relevance of the peer against our local `SyncInfo`, and if the peer is
+    /// `Advanced`, `RangeSync` adds it to a (new or known) chain in `ChainCollection`.
+    ///
+    /// Since this only needs a `PeerId` and a `SyncInfo`, a peer can also be injected directly
+    /// via the sender returned by `spawn` to exercise sync without the network stack.
     AddPeer(PeerId, SyncInfo),
+    /// A batch downloaded from a peer has been processed.
+    ///
+    /// The result adjusts the score of the peer, so that good download sources are preferred.
+    BatchProcessed {
+        chain_id: ChainId,
+        batch_id: BatchId,
+        peer_id: PeerId,
+        result: BatchProcessingResult,
+    },
+    /// A peer responded to our request with an error.
+    ///
+    /// The peer is penalized unless it's merely busy, and the batch of the request is requested
+    /// again.
+    ResponseError(PeerId, SyncRequestId, RPCResponseErrorCode),
+    /// A block has been received from a peer in response to our `BlocksByRange` request.
+    BlockByRange(
+        PeerId,
+        SyncRequestId,
+        Arc<SignedBeaconBlock<MainnetEthSpec>>,
+    ),
+    /// A peer has terminated the stream of responses to our request, which means the request has
+    /// completed.
+    StreamTerminated(PeerId, SyncRequestId),
+    /// A block has been received from a peer in response to a `BlocksByRoot` request.
+    BlockByRoot(PeerId, Arc<SignedBeaconBlock<MainnetEthSpec>>),
+    /// All the connections to a peer have been closed.
+    ///
+    /// The requests to the peer won't complete, so its batches are requested from other peers.
+    PeerDisconnected(PeerId),
+    /// Dump the state of sync and peers as JSON to the log, for debugging a stuck sync.
+    DumpState,
+    /// Reply with the target of the chain being synced, for the HTTP API.
+    QuerySyncing(oneshot::Sender<serde_json::Value>),
+}
+
+impl SyncOperation {
+    fn name(&self) -> &'static str {
+        match self {
+            SyncOperation::AddPeer(..) => "add_peer",
+            SyncOperation::BatchProcessed { .. } => "batch_processed",
+            SyncOperation::ResponseError(..) => "response_error",
+            SyncOperation::BlockByRange(..) => "block_by_range",
+            SyncOperation::StreamTerminated(..) => "stream_terminated",
+            SyncOperation::BlockByRoot(..) => "block_by_root",
+            SyncOperation::PeerDisconnected(_) => "peer_disconnected",
+            SyncOperation::DumpState => "dump_state",
+            SyncOperation::QuerySyncing(_) => "query_syncing",
+        }
+    }
+
+    /// Whether the operation can be dropped without stalling sync. `AddPeer` is sent again on the
+    /// next Status exchanged with the peer.
+    fn is_low_priority(&self) -> bool {
+        matches!(
+            self,
+            SyncOperation::AddPeer(..) | SyncOperation::DumpState | SyncOperation::QuerySyncing(_)
+        )
+    }
+}
+
+/// The result of processing a batch downloaded from a peer.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub(crate) enum BatchProcessingResult {
+    /// The blocks have been imported.
+    Success,
+    /// The batch contained invalid blocks.
+    Invalid,
+    /// The batch couldn't be processed for a reason the peer isn't at fault for, e.g. our
+    /// beacon chain failed.
+    Failed,
+    /// The peer returned no blocks for the batch.
+    Empty,
+    /// The peer didn't respond in time.
+    TimedOut,
+}
+
+impl BatchProcessingResult {
+    // The change of the peer's score.
+    fn score_delta(&self) -> f64 {
+        match self {
+            BatchProcessingResult::Success => 1.0,
+            BatchProcessingResult::Invalid => -10.0,
+            BatchProcessingResult::Failed => 0.0,
+            BatchProcessingResult::Empty => -2.0,
+            BatchProcessingResult::TimedOut => -5.0,
+        }
+    }
+
+    // Scores the peer that served the batch. Returns whether the chain moves on past the batch.
+    fn score_peer(&self, peer_db: &RwLock<PeerDB>, peer_id: &PeerId) -> bool {
+        peer_db.write().add_score(peer_id, self.score_delta());
+        matches!(
+            self,
+            BatchProcessingResult::Success | BatchProcessingResult::Empty
+        )
+    }
 }
 
 /// Id of rpc requests sent by sync to the network.
@@ -68,14 +203,30 @@ impl From<SyncRelevance> for SyncStatus {
     }
 }
 
+impl SyncInfo {
+    pub(crate) fn new(
+        finalized_root: Hash256,
+        finalized_epoch: Epoch,
+        head_root: Hash256,
+        head_slot: Slot,
+    ) -> Self {
+        SyncInfo {
+            finalized_root,
+            finalized_epoch,
+            head_root,
+            head_slot,
+        }
+    }
+}
+
 impl From<lighthouse_network::rpc::StatusMessage> for SyncInfo {
     fn from(status: lighthouse_network::rpc::StatusMessage) -> Self {
-        Self {
-            finalized_root: status.finalized_root,
-            finalized_epoch: status.finalized_epoch,
-            head_root: status.head_root,
-            head_slot: status.head_slot,
-        }
+        SyncInfo::new(
+            status.finalized_root,
+            status.finalized_epoch,
+            status.head_root,
+            status.head_slot,
+        )
     }
 }
 
@@ -83,29 +234,114 @@ pub(crate) struct SyncManager<T: BeaconChainTypes> {
     peer_db: Arc<RwLock<PeerDB>>,
     lh_beacon_chain: Arc<beacon_chain::BeaconChain<T>>,
     network_context: SyncNetworkContext,
-    receiver: UnboundedReceiver<SyncOperation>,
+    /// The low-priority operations, which are dropped once the channel is full.
+    receiver: Receiver<SyncOperation>,
+    /// The operations sync relies on, which are never dropped.
+    high_priority_receiver: UnboundedReceiver<SyncOperation>,
+    /// Sends the results of the batch processing back to the sync manager.
+    sync_sender: SyncSender,
     range_sync: RangeSync<T>,
+    /// The threads verifying the signatures of the batches being processed.
+    verification_pool: Arc<rayon::ThreadPool>,
+    /// The recent blocks served by the network, which the imported blocks are inserted into.
+    block_cache: Arc<Mutex<BlockCache>>,
+    /// The last time a Status was exchanged with each peer.
+    peers_last_status: HashMap<PeerId, Instant>,
+    /// The blocks received slightly ahead of our slot clock, waiting for their slots.
+    future_blocks: FutureBlockQueue,
 }
 
 impl<T> SyncManager<T>
 where
-    T: BeaconChainTypes,
+    T: BeaconChainTypes<EthSpec = MainnetEthSpec>,
 {
     async fn main(&mut self) {
+        let mut stale_peer_check = tokio::time::interval(STALE_PEER_CHECK_INTERVAL);
+        let mut resume_requests = tokio::time::interval(RESUME_REQUESTS_INTERVAL);
+        let mut import_future_blocks = tokio::time::interval(Duration::from_secs(
+            self.lh_beacon_chain.spec.seconds_per_slot,
+        ));
+
         loop {
-            // Process inbound messages
-            if let Some(operation) = self.receiver.recv().await {
-                match operation {
-                    SyncOperation::AddPeer(peer_id, sync_info) => {
-                        self.add_peer(peer_id, sync_info);
+            tokio::select! {
+                // The branches are polled in order, so the operations sync relies on are handled
+                // ahead of the low-priority ones.
+                biased;
+
+                // Process inbound messages
+                operation = self.high_priority_receiver.recv() => match operation {
+                    Some(operation) => self.on_operation(operation),
+                    None => {
+                        info!("The sync channel has been closed. Stopping the sync manager.");
+                        return;
                     }
-                }
+                },
+                operation = self.receiver.recv() => match operation {
+                    Some(operation) => self.on_operation(operation),
+                    None => {
+                        info!("The sync channel has been closed. Stopping the sync manager.");
+                        return;
+                    }
+                },
+                _ = stale_peer_check.tick() => self.remove_stale_peers(),
+                _ = resume_requests.tick() => self.range_sync.resume(&mut self.network_context),
+                _ = import_future_blocks.tick() => self.import_future_blocks(),
+            }
+            crate::metrics::set_gauge(
+                &crate::metrics::SYNC_QUEUE_DEPTH,
+                (self.receiver.len() + self.high_priority_receiver.len()) as i64,
+            );
+
+            // Nothing can be synced without the Network service, which means the node is
+            // shutting down.
+            if self.network_context.is_network_closed() {
+                info!("The network has been closed. Stopping the sync manager.");
+                return;
+            }
+        }
+    }
+
+    fn on_operation(&mut self, operation: SyncOperation) {
+        match operation {
+            SyncOperation::AddPeer(peer_id, sync_info) => {
+                self.add_peer(peer_id, sync_info);
+            }
+            SyncOperation::BatchProcessed {
+                chain_id,
+                batch_id,
+                peer_id,
+                result,
+            } => {
+                self.on_batch_processed(chain_id, batch_id, peer_id, result);
+            }
+            SyncOperation::ResponseError(peer_id, request_id, code) => {
+                self.on_response_error(peer_id, request_id, code);
+            }
+            SyncOperation::BlockByRange(peer_id, request_id, block) => {
+                self.on_block_by_range(peer_id, request_id, block);
+            }
+            SyncOperation::StreamTerminated(peer_id, request_id) => {
+                self.on_stream_terminated(peer_id, request_id);
+            }
+            SyncOperation::BlockByRoot(peer_id, block) => {
+                self.on_block_by_root(peer_id, block);
+            }
+            SyncOperation::PeerDisconnected(peer_id) => {
+                self.on_peer_disconnected(peer_id);
+            }
+            SyncOperation::DumpState => self.dump_state(),
+            SyncOperation::QuerySyncing(reply) => {
+                // The requester may have gone, which is fine.
+                let _ = reply.send(self.range_sync.syncing_status());
             }
         }
     }
 
     /// A peer has connected which has blocks that are unknown to us.
     fn add_peer(&mut self, peer_id: PeerId, remote_sync_info: SyncInfo) {
+        // `AddPeer` is sent on every Status exchanged with the peer.
+        self.peers_last_status.insert(peer_id, Instant::now());
+
         let local_sync_info: SyncInfo = status_message(&self.lh_beacon_chain).into();
         let sync_relevance = self.determine_sync_relevance(&local_sync_info, &remote_sync_info);
 
@@ -124,6 +360,248 @@ where
         }
     }
 
+    /// Removes the peers that stopped responding to Status from the syncing chains, so that a
+    /// silent peer doesn't stall a chain.
+    fn remove_stale_peers(&mut self) {
+        let stale_peers = stale_peers(&self.peers_last_status);
+        if stale_peers.is_empty() {
+            return;
+        }
+
+        let local_sync_info: SyncInfo = status_message(&self.lh_beacon_chain).into();
+        for peer_id in stale_peers {
+            info!(
+                "[{peer_id}] The peer hasn't exchanged Status for a while. Removing it from sync."
+            );
+            self.peers_last_status.remove(&peer_id);
+            self.network_context.requests_failed(&peer_id);
+            self.range_sync
+                .remove_peer(&mut self.network_context, &peer_id, &local_sync_info);
+        }
+    }
+
+    /// The peer has disconnected, so the requests to it won't complete and its batches are
+    /// downloaded from the other peers.
+    fn on_peer_disconnected(&mut self, peer_id: PeerId) {
+        info!("[{peer_id}] The peer has disconnected. Removing it from sync.");
+        self.peers_last_status.remove(&peer_id);
+        self.network_context.requests_failed(&peer_id);
+        let local_sync_info: SyncInfo = status_message(&self.lh_beacon_chain).into();
+        self.range_sync
+            .remove_peer(&mut self.network_context, &peer_id, &local_sync_info);
+    }
+
+    fn on_batch_processed(
+        &mut self,
+        chain_id: ChainId,
+        batch_id: BatchId,
+        peer_id: PeerId,
+        result: BatchProcessingResult,
+    ) {
+        trace!("[{peer_id}] A batch has been processed. chain_id: {chain_id}, batch_id: {batch_id}, result: {result:?}");
+        let success = result.score_peer(&self.peer_db, &peer_id);
+        let local_sync_info: SyncInfo = status_message(&self.lh_beacon_chain).into();
+        self.range_sync.on_batch_processed(
+            &mut self.network_context,
+            chain_id,
+            batch_id,
+            success,
+            &local_sync_info,
+        );
+        self.process_batches();
+    }
+
+    /// Imports the downloaded batches in the background. The results are sent back as
+    /// `SyncOperation::BatchProcessed`.
+    fn process_batches(&mut self) {
+        while let Some((chain_id, batch_id, peer_id, blocks)) =
+            self.range_sync.next_batch_to_process()
+        {
+            debug!("[{peer_id}] Processing a batch. chain_id: {chain_id}, batch_id: {batch_id}, blocks: {}", blocks.len());
+            let lh_beacon_chain = self.lh_beacon_chain.clone();
+            let verification_pool = self.verification_pool.clone();
+            let block_cache = self.block_cache.clone();
+            let sync_sender = self.sync_sender.clone();
+            tokio::spawn(async move {
+                let result =
+                    process_batch(lh_beacon_chain, verification_pool, block_cache, blocks).await;
+                sync_sender
+                    .send(SyncOperation::BatchProcessed {
+                        chain_id,
+                        batch_id,
+                        peer_id,
+                        result,
+                    })
+                    .unwrap_or_else(|e| {
+                        error!("Failed to send the result of a batch processing: {}", e);
+                    });
+            });
+        }
+    }
+
+    fn on_response_error(
+        &mut self,
+        peer_id: PeerId,
+        request_id: SyncRequestId,
+        code: RPCResponseErrorCode,
+    ) {
+        trace!("[{peer_id}] The peer responded with an error. request_id: {request_id:?}, code: {code}");
+
+        match code {
+            // The peer is asking us to slow down, so it isn't sent any request for a while.
+            RPCResponseErrorCode::RateLimited => self.network_context.rate_limited(&peer_id),
+            // Unavailable resources aren't the peer's fault.
+            RPCResponseErrorCode::ResourceUnavailable => {}
+            _ => {
+                self.peer_db
+                    .write()
+                    .add_score(&peer_id, RESPONSE_ERROR_SCORE_DELTA);
+            }
+        }
+
+        match request_id {
+            SyncRequestId::RangeSync { id } => {
+                self.network_context.request_completed(id);
+                self.range_sync
+                    .download_failed(&mut self.network_context, &peer_id, id);
+            }
+        }
+    }
+
+    fn on_block_by_range(
+        &mut self,
+        peer_id: PeerId,
+        request_id: SyncRequestId,
+        block: Arc<SignedBeaconBlock<MainnetEthSpec>>,
+    ) {
+        // The codec decodes the block by the fork of the response context bytes, which the peer
+        // chooses, so a block of another fork than the one at its slot is rejected here.
+        if let Err(e) = check_block_fork(&block, &self.lh_beacon_chain.spec) {
+            warn!("[{peer_id}] {e}");
+            self.peer_db
+                .write()
+                .add_score(&peer_id, WRONG_FORK_SCORE_DELTA);
+            // The rest of the response is ignored, as the batches of the peer are requested again.
+            self.network_context.requests_failed(&peer_id);
+            self.range_sync
+                .retry_batches(&mut self.network_context, &peer_id);
+            return;
+        }
+
+        match request_id {
+            SyncRequestId::RangeSync { id } => self.range_sync.add_block(&peer_id, id, block),
+        }
+    }
+
+    fn on_stream_terminated(&mut self, peer_id: PeerId, request_id: SyncRequestId) {
+        trace!(
+            "[{peer_id}] The stream of responses has been terminated. request_id: {request_id:?}"
+        );
+        match request_id {
+            SyncRequestId::RangeSync { id } => {
+                self.network_context.request_completed(id);
+                self.range_sync
+                    .download_completed(&mut self.network_context, &peer_id, id);
+                self.process_batches();
+            }
+        }
+    }
+
+    fn on_block_by_root(&mut self, peer_id: PeerId, block: Arc<SignedBeaconBlock<MainnetEthSpec>>) {
+        info!(
+            "[{peer_id}] Received a block by root. slot: {}, root: {}",
+            block.slot(),
+            block.canonical_root()
+        );
+
+        let current_slot = match self.lh_beacon_chain.slot() {
+            Ok(slot) => slot,
+            Err(e) => {
+                error!("Failed to read the current slot: {e:?}");
+                return;
+            }
+        };
+
+        match self.future_blocks.check(peer_id, block, current_slot) {
+            FutureBlockCheck::Ready(block) => self.import_block(peer_id, block),
+            FutureBlockCheck::Queued => {}
+            FutureBlockCheck::Rejected { too_far: true } => {
+                self.peer_db
+                    .write()
+                    .add_score(&peer_id, FUTURE_BLOCK_SCORE_DELTA);
+            }
+            FutureBlockCheck::Rejected { too_far: false } => {}
+        }
+    }
+
+    /// Imports the queued blocks whose slots have arrived.
+    fn import_future_blocks(&mut self) {
+        let current_slot = match self.lh_beacon_chain.slot() {
+            Ok(slot) => slot,
+            Err(e) => {
+                error!("Failed to read the current slot: {e:?}");
+                return;
+            }
+        };
+
+        for (peer_id, block) in self.future_blocks.pop_ready(current_slot) {
+            self.import_block(peer_id, block);
+        }
+    }
+
+    /// Imports a single block in the background, and scores the peer that sent it by the result.
+    fn import_block(&mut self, peer_id: PeerId, block: Arc<SignedBeaconBlock<MainnetEthSpec>>) {
+        debug!("[{peer_id}] Importing a block. slot: {}", block.slot());
+        let lh_beacon_chain = self.lh_beacon_chain.clone();
+        let verification_pool = self.verification_pool.clone();
+        let block_cache = self.block_cache.clone();
+        let peer_db = self.peer_db.clone();
+        tokio::spawn(async move {
+            let result =
+                process_batch(lh_beacon_chain, verification_pool, block_cache, vec![block]).await;
+            peer_db.write().add_score(&peer_id, result.score_delta());
+        });
+    }
+
+    fn dump_state(&self) {
+        // Copy the peers out so that the lock is held only briefly.
+        let (peers, peer_counts) = {
+            let peer_db = self.peer_db.read();
+            let peer_counts = serde_json::json!({
+                "known": peer_db.peer_count(),
+                "connected": peer_db.connected_peer_ids().len(),
+                "synced": peer_db.peers_by_sync_status(&SyncStatus::Synced).len(),
+                "advanced": peer_db.peers_by_sync_status(&SyncStatus::Advanced).len(),
+            });
+            (peer_db.snapshot(), peer_counts)
+        };
+        let peers = peers
+            .iter()
+            .map(|peer| {
+                serde_json::json!({
+                    "peer_id": peer.peer_id.to_string(),
+                    "connection_status": format!("{:?}", peer.connection_status),
+                    "sync_status": format!("{:?}", peer.sync_status),
+                    "score": peer.score,
+                    "goodbye_reason": peer.goodbye_reason.as_ref().map(|reason| reason.to_string()),
+                    "last_seen_secs_ago": peer.last_seen.map(|last_seen| last_seen.elapsed().as_secs()),
+                    "last_status_secs_ago": self
+                        .peers_last_status
+                        .get(&peer.peer_id)
+                        .map(|last_status| last_status.elapsed().as_secs()),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let snapshot = serde_json::json!({
+            "range_sync": self.range_sync.snapshot(),
+            "requests_in_flight": self.network_context.requests_in_flight(),
+            "peer_counts": peer_counts,
+            "peers": peers,
+        });
+        info!("Sync state: {}", snapshot);
+    }
+
     fn determine_sync_relevance(
         &self,
         local_sync_info: &SyncInfo,
@@ -136,26 +614,154 @@ where
             .cmp(&local_sync_info.finalized_epoch)
         {
             Ordering::Less => SyncRelevance::Behind,
+            // The peer agrees on the finalized epoch but may be ahead at the head. Blocks are
+            // imported only via range sync, so any peer ahead of us is worth syncing from.
+            Ordering::Equal if remote_sync_info.head_slot > local_sync_info.head_slot => {
+                SyncRelevance::Advanced
+            }
             Ordering::Equal => SyncRelevance::FullySynced,
             Ordering::Greater => SyncRelevance::Advanced,
         }
     }
 }
 
-pub(crate) fn spawn<T: BeaconChainTypes>(
+/// Sends operations to the sync manager.
+///
+/// The low-priority operations go over a bounded channel, so that a burst of them, e.g. `AddPeer`s
+/// under heavy discovery, can't grow the memory without limit. The trade-off is that they're
+/// dropped when the channel is full, as the network can't wait for the sync manager. The
+/// operations sync relies on (the blocks and the results) go over an unbounded channel so that
+/// they're never dropped. Backpressure isn't an option for them either, as waiting on the channel
+/// would stall the swarm, which sends them. They're bounded without it: the blocks by the
+/// requests in flight, each of a batch of blocks, and the processing results by the batches
+/// being processed.
+#[derive(Clone)]
+pub(crate) struct SyncSender {
+    sender: Sender<SyncOperation>,
+    high_priority_sender: UnboundedSender<SyncOperation>,
+}
+
+impl SyncSender {
+    pub(crate) fn send(&self, operation: SyncOperation) -> Result<(), String> {
+        let name = operation.name();
+
+        if !operation.is_low_priority() {
+            return self
+                .high_priority_sender
+                .send(operation)
+                .map_err(|_| format!("Failed to send {name} as the sync channel is closed"));
+        }
+
+        match self.sender.try_send(operation) {
+            Ok(()) => {
+                crate::metrics::set_gauge(
+                    &crate::metrics::SYNC_QUEUE_DEPTH,
+                    (self.sender.max_capacity() - self.sender.capacity()) as i64,
+                );
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => {
+                crate::metrics::inc_counter_vec(&crate::metrics::SYNC_OPERATIONS_DROPPED, &[name]);
+                Err(format!("Dropped {name} as the sync channel is full"))
+            }
+            Err(TrySendError::Closed(_)) => Err(format!(
+                "Failed to send {name} as the sync channel is closed"
+            )),
+        }
+    }
+}
+
+// Creates the channels to the sync manager. `capacity` bounds the low-priority operations.
+fn channel(
+    capacity: usize,
+) -> (
+    SyncSender,
+    Receiver<SyncOperation>,
+    UnboundedReceiver<SyncOperation>,
+) {
+    let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
+    let (high_priority_sender, high_priority_receiver) = tokio::sync::mpsc::unbounded_channel();
+    (
+        SyncSender {
+            sender,
+            high_priority_sender,
+        },
+        receiver,
+        high_priority_receiver,
+    )
+}
+
+// Checks that the block is of the fork scheduled at its slot.
+fn check_block_fork(
+    block: &SignedBeaconBlock<MainnetEthSpec>,
+    spec: &ChainSpec,
+) -> Result<(), String> {
+    block.fork_name(spec).map(|_fork_name| ()).map_err(|e| {
+        format!(
+            "The block is of a fork inconsistent with its slot. slot: {}, error: {e:?}",
+            block.slot()
+        )
+    })
+}
+
+// The peers that haven't exchanged Status for `SYNC_PEER_STATUS_TIMEOUT`.
+fn stale_peers(peers_last_status: &HashMap<PeerId, Instant>) -> Vec<PeerId> {
+    peers_last_status
+        .iter()
+        .filter(|(_peer_id, last_status)| last_status.elapsed() >= SYNC_PEER_STATUS_TIMEOUT)
+        .map(|(peer_id, _last_status)| *peer_id)
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn spawn<T: BeaconChainTypes<EthSpec = MainnetEthSpec>>(
     runtime: Arc<Runtime>,
     peer_db: Arc<RwLock<PeerDB>>,
     lh_beacon_chain: Arc<beacon_chain::BeaconChain<T>>,
-    network_sender: UnboundedSender<NetworkMessage>,
-) -> UnboundedSender<SyncOperation> {
-    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    network_sender: Sender<NetworkMessage>,
+    block_cache: Arc<Mutex<BlockCache>>,
+    local_peer_id: PeerId,
+    target_epoch: Option<Epoch>,
+    runtime_config: &RuntimeConfig,
+) -> SyncSender {
+    let (sender, receiver, high_priority_receiver) = channel(runtime_config.sync_channel_capacity);
+
+    let verification_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(runtime_config.signature_verification_threads)
+        .thread_name(|i| format!("ray-signature-verification-{i}"))
+        .build()
+        .expect("should build the signature verification thread pool");
+
+    // The local SyncInfo is derived from the (possibly persisted) head of the beacon chain, so
+    // the chains start from the stored finalized epoch after a restart.
+    let local_sync_info: SyncInfo = status_message(&lh_beacon_chain).into();
+    info!(
+        finalized_epoch = %local_sync_info.finalized_epoch,
+        head_slot = %local_sync_info.head_slot,
+        "Starting the sync manager from the local head."
+    );
 
     let mut sync_manager = SyncManager {
-        network_context: SyncNetworkContext::new(network_sender),
+        network_context: SyncNetworkContext::new(
+            network_sender,
+            runtime_config.max_outbound_requests_per_peer,
+            runtime_config.max_outbound_requests_in_flight,
+        ),
         receiver,
+        high_priority_receiver,
+        sync_sender: sender.clone(),
         peer_db,
         lh_beacon_chain: lh_beacon_chain.clone(),
-        range_sync: RangeSync::new(lh_beacon_chain),
+        range_sync: RangeSync::new(
+            lh_beacon_chain,
+            runtime_config.max_finalized_chains,
+            local_peer_id,
+            target_epoch,
+        ),
+        verification_pool: Arc::new(verification_pool),
+        block_cache,
+        peers_last_status: HashMap::new(),
+        future_blocks: FutureBlockQueue::new(),
     };
 
     runtime.spawn(async move {
@@ -164,3 +770,71 @@ pub(crate) fn spawn<T: BeaconChainTypes>(
 
     sender
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::test_block;
+
+    #[test]
+    fn silent_peers_are_stale() {
+        let now = Instant::now();
+        let silent_peer = PeerId::random();
+        let active_peer = PeerId::random();
+        let peers_last_status = HashMap::from([
+            (
+                silent_peer,
+                now.checked_sub(SYNC_PEER_STATUS_TIMEOUT).expect("instant"),
+            ),
+            (active_peer, now),
+        ]);
+
+        assert_eq!(stale_peers(&peers_last_status), vec![silent_peer]);
+    }
+
+    #[test]
+    fn block_of_the_wrong_fork_for_its_slot_is_rejected() {
+        use types::EthSpec;
+
+        let spec = ChainSpec::mainnet();
+        let altair_slot = spec
+            .altair_fork_epoch
+            .expect("altair is scheduled")
+            .start_slot(MainnetEthSpec::slots_per_epoch());
+
+        assert!(check_block_fork(&test_block(altair_slot.as_u64() - 1), &spec).is_ok());
+        assert!(check_block_fork(&test_block(altair_slot.as_u64()), &spec).is_err());
+    }
+
+    #[test]
+    fn only_low_priority_operations_are_dropped_when_the_channel_is_full() {
+        const CAPACITY: usize = 8;
+        let (sender, mut receiver, mut high_priority_receiver) = channel(CAPACITY);
+        let (peer_id, block) = (PeerId::random(), test_block(0));
+        let request_id = SyncRequestId::RangeSync { id: 0 };
+
+        let mut dropped = 0;
+        for _ in 0..CAPACITY * 10 {
+            if sender.send(SyncOperation::DumpState).is_err() {
+                dropped += 1;
+            }
+            sender
+                .send(SyncOperation::BlockByRange(
+                    peer_id,
+                    request_id,
+                    block.clone(),
+                ))
+                .expect("high-priority operations are never dropped");
+        }
+
+        assert_eq!(dropped, CAPACITY * 9);
+        assert_eq!(receiver.len(), CAPACITY);
+        assert_eq!(high_priority_receiver.len(), CAPACITY * 10);
+        while let Ok(operation) = high_priority_receiver.try_recv() {
+            assert!(matches!(operation, SyncOperation::BlockByRange(..)));
+        }
+        while let Ok(operation) = receiver.try_recv() {
+            assert!(matches!(operation, SyncOperation::DumpState));
+        }
+    }
+}
@@ -1,24 +1,108 @@
 use crate::network::{ApplicationRequestId, NetworkMessage};
 use crate::sync::SyncRequestId::RangeSync;
 use libp2p::PeerId;
-use tokio::sync::mpsc::UnboundedSender;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
 use tracing::trace;
 
+/// The window in which the outbound requests to a peer are limited.
+const OUTBOUND_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+
+/// How long no requests are sent to a peer that responded that we are rate-limited.
+const RATE_LIMITED_BACKOFF: Duration = Duration::from_secs(5);
+
 /// Wraps a Network channel to employ various RPC related network functionality for the Sync manager.
 /// This includes management of a global RPC request Id.
 pub(crate) struct SyncNetworkContext {
     /// A sequential ID for all RPC requests.
     request_id: u32,
     /// The network channel to relay messages to the Network service.
-    network_send: UnboundedSender<NetworkMessage>,
+    network_send: Sender<NetworkMessage>,
+    /// The maximum number of requests sent to a peer per `OUTBOUND_RATE_LIMIT_WINDOW`, so that we
+    /// are a polite peer and aren't rate-limited by others.
+    max_requests_per_peer: usize,
+    /// The times of the requests sent to each peer within the window.
+    requests_sent: HashMap<PeerId, VecDeque<Instant>>,
+    /// The peers that rate-limited us, until when no requests are sent to them.
+    rate_limited: HashMap<PeerId, Instant>,
+    /// The maximum number of requests awaiting responses across all the peers, which bounds the
+    /// outstanding network work and the memory of the responses.
+    max_requests_in_flight: usize,
+    /// The peers of the requests awaiting responses, by request ID.
+    requests_in_flight: HashMap<u32, PeerId>,
 }
 
 impl SyncNetworkContext {
-    pub(crate) fn new(network_send: UnboundedSender<NetworkMessage>) -> SyncNetworkContext {
+    pub(crate) fn new(
+        network_send: Sender<NetworkMessage>,
+        max_requests_per_peer: usize,
+        max_requests_in_flight: usize,
+    ) -> SyncNetworkContext {
         SyncNetworkContext {
             request_id: 0,
             network_send,
+            max_requests_per_peer,
+            requests_sent: HashMap::new(),
+            rate_limited: HashMap::new(),
+            max_requests_in_flight,
+            requests_in_flight: HashMap::new(),
+        }
+    }
+
+    /// Returns true if no more requests can be sent to any peer until some of the in-flight ones
+    /// complete.
+    pub(crate) fn is_at_capacity(&self) -> bool {
+        self.requests_in_flight.len() >= self.max_requests_in_flight
+    }
+
+    /// The number of requests awaiting responses.
+    pub(crate) fn requests_in_flight(&self) -> usize {
+        self.requests_in_flight.len()
+    }
+
+    /// The request has completed, so it no longer counts against the in-flight limit.
+    pub(crate) fn request_completed(&mut self, request_id: u32) {
+        self.requests_in_flight.remove(&request_id);
+    }
+
+    /// The requests to the peer won't complete, e.g. the peer responded with an error or has been
+    /// removed, so they no longer count against the in-flight limit.
+    pub(crate) fn requests_failed(&mut self, peer_id: &PeerId) {
+        self.requests_in_flight.retain(|_id, peer| peer != peer_id);
+    }
+
+    /// The peer responded that we are rate-limited, so no requests are sent to it for a while.
+    pub(crate) fn rate_limited(&mut self, peer_id: &PeerId) {
+        self.rate_limited
+            .insert(*peer_id, Instant::now() + RATE_LIMITED_BACKOFF);
+    }
+
+    /// Returns true if no more requests can be sent to the peer for now.
+    pub(crate) fn is_throttled(&mut self, peer_id: &PeerId) -> bool {
+        let now = Instant::now();
+        self.rate_limited.retain(|_peer_id, until| *until > now);
+        if self.rate_limited.contains_key(peer_id) {
+            return true;
         }
+
+        self.requests_sent.retain(|_peer_id, sent| {
+            while sent.front().map_or(false, |t| {
+                now.duration_since(*t) >= OUTBOUND_RATE_LIMIT_WINDOW
+            }) {
+                sent.pop_front();
+            }
+            !sent.is_empty()
+        });
+
+        self.requests_sent
+            .get(peer_id)
+            .map_or(false, |sent| sent.len() >= self.max_requests_per_peer)
+    }
+
+    /// Returns true if the Network service has gone, i.e. the node is shutting down.
+    pub(crate) fn is_network_closed(&self) -> bool {
+        self.network_send.is_closed()
     }
 
     pub(crate) fn blocks_by_range_request(
@@ -28,18 +112,43 @@ impl SyncNetworkContext {
     ) -> Result<u32, String> {
         trace!("[{peer_id}] [SyncNetworkContext::blocks_by_range_request] Sending `BlocksByRange` request to the network component. request: {request:?}");
 
+        if self.is_throttled(peer_id) {
+            return Err(format!(
+                "Too many requests to the peer. limit: {} per {:?}",
+                self.max_requests_per_peer, OUTBOUND_RATE_LIMIT_WINDOW
+            ));
+        }
+
+        if self.is_at_capacity() {
+            return Err(format!(
+                "Too many requests in flight. limit: {}",
+                self.max_requests_in_flight
+            ));
+        }
+
         let request = lighthouse_network::service::api_types::Request::BlocksByRange(request);
         let id = self.next_id();
         let request_id = ApplicationRequestId::Sync(RangeSync { id });
         // network::service::RequestId::Sync(network::sync::manager::RequestId::RangeSync { id });
 
+        // The requests are bounded by the requests in flight, so the channel isn't expected to be
+        // full unless the Network service is stuck.
         self.network_send
-            .send(NetworkMessage::SendRequest {
+            .try_send(NetworkMessage::SendRequest {
                 peer_id: *peer_id,
                 request,
                 request_id,
             })
             .map_err(|e| format!("Failed to send NetworkMessage: {e}"))?;
+        crate::metrics::set_gauge(
+            &crate::metrics::NETWORK_QUEUE_DEPTH,
+            (self.network_send.max_capacity() - self.network_send.capacity()) as i64,
+        );
+        self.requests_sent
+            .entry(*peer_id)
+            .or_default()
+            .push_back(Instant::now());
+        self.requests_in_flight.insert(id, *peer_id);
 
         Ok(id)
     }
@@ -50,3 +159,101 @@ impl SyncNetworkContext {
         id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lighthouse_network::rpc::BlocksByRangeRequest;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn stops_requesting_once_the_network_has_gone() {
+        let (network_send, network_recv) = mpsc::channel(8);
+        let mut network_context = SyncNetworkContext::new(network_send, 4, 4);
+        let peer_id = PeerId::random();
+        assert!(!network_context.is_network_closed());
+
+        // The Network service has stopped, i.e. the node is shutting down.
+        drop(network_recv);
+        assert!(network_context.is_network_closed());
+
+        assert!(network_context
+            .blocks_by_range_request(&peer_id, BlocksByRangeRequest::new(0, 32))
+            .is_err());
+        assert_eq!(network_context.requests_in_flight(), 0);
+    }
+
+    #[test]
+    fn requests_to_a_peer_are_throttled_to_the_configured_rate() {
+        let (network_send, _network_recv) = mpsc::channel(64);
+        let mut network_context = SyncNetworkContext::new(network_send, 2, 64);
+        let (peer_id, other_peer_id) = (PeerId::random(), PeerId::random());
+
+        for _ in 0..2 {
+            assert!(network_context
+                .blocks_by_range_request(&peer_id, BlocksByRangeRequest::new(0, 32))
+                .is_ok());
+        }
+        assert!(network_context.is_throttled(&peer_id));
+        assert!(network_context
+            .blocks_by_range_request(&peer_id, BlocksByRangeRequest::new(0, 32))
+            .is_err());
+
+        // The limit is per peer.
+        assert!(network_context
+            .blocks_by_range_request(&other_peer_id, BlocksByRangeRequest::new(0, 32))
+            .is_ok());
+
+        // The window has passed.
+        std::thread::sleep(OUTBOUND_RATE_LIMIT_WINDOW);
+        assert!(!network_context.is_throttled(&peer_id));
+        assert!(network_context
+            .blocks_by_range_request(&peer_id, BlocksByRangeRequest::new(0, 32))
+            .is_ok());
+        assert_eq!(network_context.requests_in_flight(), 4);
+    }
+
+    #[test]
+    fn no_requests_are_sent_to_a_peer_that_rate_limited_us() {
+        let (network_send, _network_recv) = mpsc::channel(64);
+        let mut network_context = SyncNetworkContext::new(network_send, 64, 64);
+        let (peer_id, other_peer_id) = (PeerId::random(), PeerId::random());
+
+        network_context.rate_limited(&peer_id);
+
+        assert!(network_context.is_throttled(&peer_id));
+        assert!(network_context
+            .blocks_by_range_request(&peer_id, BlocksByRangeRequest::new(0, 32))
+            .is_err());
+        assert!(!network_context.is_throttled(&other_peer_id));
+    }
+
+    #[test]
+    fn requests_in_flight_are_capped_across_peers() {
+        let (network_send, _network_recv) = mpsc::channel(64);
+        let mut network_context = SyncNetworkContext::new(network_send, 4, 2);
+        let (peer_id, other_peer_id) = (PeerId::random(), PeerId::random());
+
+        let id = network_context
+            .blocks_by_range_request(&peer_id, BlocksByRangeRequest::new(0, 32))
+            .expect("first request");
+        assert!(network_context
+            .blocks_by_range_request(&other_peer_id, BlocksByRangeRequest::new(32, 32))
+            .is_ok());
+        assert!(network_context.is_at_capacity());
+        // The cap applies to a peer with no requests in flight too.
+        assert!(network_context
+            .blocks_by_range_request(&PeerId::random(), BlocksByRangeRequest::new(64, 32))
+            .is_err());
+
+        network_context.request_completed(id);
+        assert!(!network_context.is_at_capacity());
+        assert!(network_context
+            .blocks_by_range_request(&peer_id, BlocksByRangeRequest::new(64, 32))
+            .is_ok());
+
+        // The requests to a failed peer are released.
+        network_context.requests_failed(&other_peer_id);
+        assert_eq!(network_context.requests_in_flight(), 1);
+    }
+}
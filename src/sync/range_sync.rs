@@ -1,10 +1,21 @@
+use crate::block_cache::BlockCache;
 use crate::sync::chain_collection::ChainCollection;
 use crate::sync::network_context::SyncNetworkContext;
-use crate::sync::SyncInfo;
-use beacon_chain::BeaconChainTypes;
+use crate::sync::syncing_chain::{BatchId, ChainId};
+use crate::sync::{BatchProcessingResult, SyncInfo};
+use beacon_chain::block_verification_types::RpcBlock;
+use beacon_chain::{BeaconChainTypes, BlockError, ChainSegmentResult, NotifyExecutionLayer};
 use libp2p::PeerId;
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
-use tracing::{trace, warn};
+use tracing::{debug, error, trace, warn};
+use types::{Epoch, EthSpec, Hash256, MainnetEthSpec, SignedBeaconBlock};
+
+/// The number of block roots found in the store that are cached, so that the store isn't looked up
+/// for the same finalized root on every peer added.
+const KNOWN_BLOCKS_CACHE_SIZE: usize = 256;
 
 pub(crate) struct RangeSync<T: BeaconChainTypes> {
     /// The beacon chain for processing.
@@ -12,19 +23,41 @@ pub(crate) struct RangeSync<T: BeaconChainTypes> {
     /// A collection of chains that need to be downloaded. This stores any head or finalized chains
     /// that need to be downloaded.
     chains: ChainCollection,
+    /// The epoch to stop syncing at, rather than following the head of the peers.
+    target_epoch: Option<Epoch>,
+    /// The block roots recently found in the store. Only the known roots are cached, as an
+    /// unknown block may be imported at any time.
+    known_blocks: LruCache<Hash256, ()>,
 }
 
 impl<T> RangeSync<T>
 where
     T: BeaconChainTypes,
 {
-    pub(crate) fn new(lh_beacon_chain: Arc<beacon_chain::BeaconChain<T>>) -> Self {
+    pub(crate) fn new(
+        lh_beacon_chain: Arc<beacon_chain::BeaconChain<T>>,
+        max_finalized_chains: usize,
+        local_peer_id: PeerId,
+        target_epoch: Option<Epoch>,
+    ) -> Self {
         RangeSync {
             lh_beacon_chain,
-            chains: ChainCollection::new(),
+            chains: ChainCollection::new(max_finalized_chains, target_epoch, local_peer_id),
+            target_epoch,
+            known_blocks: LruCache::new(
+                NonZeroUsize::new(KNOWN_BLOCKS_CACHE_SIZE).expect("non zero usize"),
+            ),
         }
     }
 
+    /// Returns true if the block is in our store.
+    fn is_block_known(&mut self, block_root: &Hash256) -> bool {
+        let store = &self.lh_beacon_chain.store;
+        is_block_known(&mut self.known_blocks, block_root, |block_root| {
+            store.block_exists(block_root).map_err(|e| format!("{e:?}"))
+        })
+    }
+
     pub(crate) fn add_peer(
         &mut self,
         network_context: &mut SyncNetworkContext,
@@ -34,30 +67,252 @@ where
     ) {
         trace!("add_peer: {peer_id}");
 
-        let is_block_known = false; // TODO
-
-        // determine which kind of sync to perform and set up the chains
-        match RangeSyncType::new(local_sync_info, remote_sync_info, is_block_known) {
-            RangeSyncType::Finalized => {
-                self.chains.add_peer_or_create_chain(
-                    network_context,
-                    peer_id,
-                    local_sync_info.finalized_epoch,
-                    remote_sync_info.finalized_root,
-                    remote_sync_info.head_slot,
-                );
-            }
-            RangeSyncType::Head => {
-                warn!("[{peer_id}] RangeSyncType::Head is not supported.");
+        if let Some(target_epoch) = self.target_epoch {
+            if local_sync_info
+                .head_slot
+                .epoch(MainnetEthSpec::slots_per_epoch())
+                >= target_epoch
+            {
+                debug!("[{peer_id}] Not syncing from the peer as the target epoch {target_epoch} has been reached.");
+                return;
             }
         }
 
+        // A remote finalized root we already have means we agree on the finalized chain, so the
+        // head of the peer is synced rather than restarting a finalized chain.
+        let is_block_known = self.is_block_known(&remote_sync_info.finalized_root);
+
+        // determine which kind of sync to perform and set up the chains
+        let sync_type = RangeSyncType::new(local_sync_info, remote_sync_info, is_block_known);
+        let target_head_root = match sync_type {
+            RangeSyncType::Finalized => remote_sync_info.finalized_root,
+            // The peer agrees on our finalized checkpoint, so the chain is synced from there up to
+            // the head of the peer.
+            RangeSyncType::Head => remote_sync_info.head_root,
+        };
+        self.chains.add_peer_or_create_chain(
+            network_context,
+            peer_id,
+            local_sync_info.finalized_epoch,
+            target_head_root,
+            remote_sync_info.head_slot,
+            sync_type,
+        );
+
+        self.chains
+            .update(network_context, local_sync_info.finalized_epoch);
+    }
+
+    /// Returns the state of the range sync, for debugging.
+    pub(crate) fn snapshot(&self) -> serde_json::Value {
+        self.chains.snapshot()
+    }
+
+    /// Returns the target of the chain being synced, for the HTTP API.
+    pub(crate) fn syncing_status(&self) -> serde_json::Value {
+        self.chains.syncing_status()
+    }
+
+    /// Resumes requesting batches, which may have stopped as the peers were throttled.
+    pub(crate) fn resume(&mut self, network_context: &mut SyncNetworkContext) {
+        self.chains.resume(network_context);
+    }
+
+    /// Returns the next batch to import.
+    pub(crate) fn next_batch_to_process(
+        &mut self,
+    ) -> Option<(
+        ChainId,
+        BatchId,
+        PeerId,
+        Vec<Arc<SignedBeaconBlock<MainnetEthSpec>>>,
+    )> {
+        self.chains.next_batch_to_process()
+    }
+
+    /// A batch of the chain has been processed.
+    pub(crate) fn on_batch_processed(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        chain_id: ChainId,
+        batch_id: BatchId,
+        success: bool,
+        local_sync_info: &SyncInfo,
+    ) {
+        self.chains.on_batch_processed(
+            network_context,
+            chain_id,
+            batch_id,
+            success,
+            local_sync_info.finalized_epoch,
+        );
+    }
+
+    /// A block of the batch requested by the request has been received from the peer.
+    pub(crate) fn add_block(
+        &mut self,
+        peer_id: &PeerId,
+        request_id: u32,
+        block: Arc<SignedBeaconBlock<MainnetEthSpec>>,
+    ) {
+        self.chains.add_block(peer_id, request_id, block);
+    }
+
+    /// The peer has finished serving the batch requested by the request.
+    pub(crate) fn download_completed(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+        request_id: u32,
+    ) {
+        self.chains
+            .download_completed(network_context, peer_id, request_id);
+    }
+
+    /// The peer failed to serve the batch requested by the request, so the batch is downloaded
+    /// again.
+    pub(crate) fn download_failed(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+        request_id: u32,
+    ) {
+        self.chains
+            .download_failed(network_context, peer_id, request_id);
+    }
+
+    /// Requests the batches assigned to the peer again.
+    pub(crate) fn retry_batches(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+    ) {
+        self.chains.retry_batches(network_context, peer_id);
+    }
+
+    /// A peer is no longer useful for sync (e.g. it stopped responding).
+    pub(crate) fn remove_peer(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+        local_sync_info: &SyncInfo,
+    ) {
+        trace!("remove_peer: {peer_id}");
+
+        self.chains.remove_peer(network_context, peer_id);
         self.chains
             .update(network_context, local_sync_info.finalized_epoch);
     }
 }
 
+/// Imports the blocks of a batch into the beacon chain.
+///
+/// The signatures of the whole batch are verified up front, in parallel on the rayon threads of
+/// `verification_pool`, and then the blocks are imported in order. The imported blocks are inserted
+/// into `block_cache`, as peers mostly ask for the recent blocks.
+// ref: https://github.com/sigp/lighthouse/blob/8c69d57c2ce0d5f1a3cd44c215b2d52844043150/beacon_node/network/src/network_beacon_processor/sync_methods.rs
+pub(crate) async fn process_batch<T>(
+    lh_beacon_chain: Arc<beacon_chain::BeaconChain<T>>,
+    verification_pool: Arc<rayon::ThreadPool>,
+    block_cache: Arc<Mutex<BlockCache>>,
+    blocks: Vec<Arc<SignedBeaconBlock<MainnetEthSpec>>>,
+) -> BatchProcessingResult
+where
+    T: BeaconChainTypes<EthSpec = MainnetEthSpec>,
+{
+    if blocks.is_empty() {
+        // The slots of the batch may all have been skipped.
+        return BatchProcessingResult::Empty;
+    }
+
+    let rpc_blocks = blocks
+        .iter()
+        .map(|block| RpcBlock::new_without_blobs(None, block.clone()))
+        .collect::<Vec<_>>();
+
+    // NOTE: `process_chain_segment` verifies the signatures with rayon, which uses the pool the
+    // processing is installed on. So the processing runs on a blocking thread, inside the pool.
+    let handle = tokio::runtime::Handle::current();
+    let result = tokio::task::spawn_blocking(move || {
+        verification_pool.install(|| {
+            handle.block_on(
+                lh_beacon_chain.process_chain_segment(rpc_blocks, NotifyExecutionLayer::Yes),
+            )
+        })
+    })
+    .await;
+
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            error!("The batch processing has panicked. error: {e}");
+            return BatchProcessingResult::Failed;
+        }
+    };
+
+    match result {
+        ChainSegmentResult::Successful { imported_blocks } => {
+            debug!("Imported a batch. imported_blocks: {imported_blocks}");
+            let mut block_cache = block_cache.lock();
+            for block in blocks {
+                block_cache.insert(block.canonical_root(), block);
+            }
+            BatchProcessingResult::Success
+        }
+        ChainSegmentResult::Failed {
+            imported_blocks,
+            error,
+        } => {
+            warn!("Failed to import a batch. imported_blocks: {imported_blocks}, error: {error:?}");
+            import_failure_result(&error)
+        }
+    }
+}
+
+/// Returns whether the batch failed to be imported as the peer served invalid blocks, or for a
+/// reason the peer isn't at fault for.
+// ref: https://github.com/sigp/lighthouse/blob/8c69d57c2ce0d5f1a3cd44c215b2d52844043150/beacon_node/network/src/network_beacon_processor/sync_methods.rs
+fn import_failure_result(error: &BlockError<MainnetEthSpec>) -> BatchProcessingResult {
+    match error {
+        // Our beacon chain failed to process the blocks.
+        BlockError::BeaconChainError(_) => BatchProcessingResult::Failed,
+        // Our execution layer failed, e.g. it's offline or syncing.
+        BlockError::ExecutionPayloadError(e) if !e.penalize_peer() => BatchProcessingResult::Failed,
+        // The batch doesn't connect to our chain, e.g. as the peer is on another fork or we
+        // have pruned the parent.
+        BlockError::ParentUnknown(_) => BatchProcessingResult::Failed,
+        _ => BatchProcessingResult::Invalid,
+    }
+}
+
+/// Returns true if the block is in the cache of known blocks, or else in the store, which is looked
+/// up with `block_exists`.
+fn is_block_known(
+    known_blocks: &mut LruCache<Hash256, ()>,
+    block_root: &Hash256,
+    block_exists: impl FnOnce(&Hash256) -> Result<bool, String>,
+) -> bool {
+    if known_blocks.get(block_root).is_some() {
+        return true;
+    }
+
+    match block_exists(block_root) {
+        Ok(true) => {
+            known_blocks.put(*block_root, ());
+            true
+        }
+        Ok(false) => false,
+        Err(e) => {
+            error!(
+                "Failed to look up the block in the store. block_root: {block_root}, error: {e}"
+            );
+            false
+        }
+    }
+}
+
 /// The type of Range sync that should be done relative to our current state.
+#[derive(Debug, PartialEq)]
 pub(crate) enum RangeSyncType {
     /// A finalized chain sync should be started with this peer.
     Finalized,
@@ -77,10 +332,66 @@ impl RangeSyncType {
         // The condition is:
         // -  The remotes finalized epoch is greater than our current finalized epoch and we have
         //    not seen the finalized hash before.
-        if remote_sync_info.finalized_root > local_sync_info.finalized_root && !is_block_known {
+        if remote_sync_info.finalized_epoch > local_sync_info.finalized_epoch && !is_block_known {
             RangeSyncType::Finalized
         } else {
             RangeSyncType::Head
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::test_block;
+    use types::Slot;
+
+    fn sync_info(finalized_root: Hash256, finalized_epoch: u64) -> SyncInfo {
+        SyncInfo::new(
+            finalized_root,
+            Epoch::new(finalized_epoch),
+            Hash256::zero(),
+            Slot::new(0),
+        )
+    }
+
+    #[test]
+    fn head_sync_is_chosen_for_a_known_finalized_root() {
+        let (known_root, unknown_root) = (Hash256::repeat_byte(1), Hash256::repeat_byte(2));
+        let store =
+            |block_root: &Hash256| -> Result<bool, String> { Ok(*block_root == known_root) };
+        let mut known_blocks = LruCache::new(NonZeroUsize::new(8).unwrap());
+        let local_sync_info = sync_info(Hash256::zero(), 1);
+
+        for (root, expected) in [
+            (known_root, RangeSyncType::Head),
+            (unknown_root, RangeSyncType::Finalized),
+        ] {
+            let is_known = is_block_known(&mut known_blocks, &root, store);
+            let sync_type = RangeSyncType::new(&local_sync_info, &sync_info(root, 2), is_known);
+            assert_eq!(sync_type, expected);
+        }
+
+        // Only the known root is cached, so the store isn't looked up for it again.
+        assert!(known_blocks.contains(&known_root));
+        assert!(!known_blocks.contains(&unknown_root));
+        assert!(is_block_known(&mut known_blocks, &known_root, |_| {
+            panic!("the store is looked up")
+        }));
+    }
+
+    #[test]
+    fn only_invalid_blocks_fail_the_batch_as_invalid() {
+        assert!(matches!(
+            import_failure_result(&BlockError::GenesisBlock),
+            BatchProcessingResult::Invalid
+        ));
+        assert!(matches!(
+            import_failure_result(&BlockError::ParentUnknown(RpcBlock::new_without_blobs(
+                None,
+                test_block(0)
+            ))),
+            BatchProcessingResult::Failed
+        ));
+    }
+}
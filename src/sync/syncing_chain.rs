@@ -1,11 +1,12 @@
+use crate::sync::batch::{BatchInfo, BatchState};
 use crate::sync::network_context::SyncNetworkContext;
 use libp2p::PeerId;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-use std::ops::Sub;
+use std::sync::Arc;
 use tracing::{debug, error, info, trace, warn};
-use types::{Epoch, EthSpec, Hash256, MainnetEthSpec, Slot};
+use types::{Epoch, EthSpec, Hash256, MainnetEthSpec, SignedBeaconBlock, Slot};
 
 /// A chain identifier
 pub type ChainId = u64;
@@ -15,6 +16,17 @@ pub type BatchId = Epoch;
 /// blocks per batch are requested.
 pub const EPOCHS_PER_BATCH: u64 = 2;
 
+/// The number of batches a peer can be downloading at the same time (pipeline depth).
+const BATCHES_PER_PEER: usize = 2;
+
+/// The maximum number of batches being downloaded at the same time across all the peers.
+const MAX_BATCHES_IN_FLIGHT: usize = 10;
+
+/// The number of batches ahead of the processing target that can be downloaded, which bounds the
+/// blocks held in memory while waiting for the earlier batches to be processed.
+// ref: https://github.com/sigp/lighthouse/blob/8c69d57c2ce0d5f1a3cd44c215b2d52844043150/beacon_node/network/src/sync/range_sync/chain.rs#L1037
+const BATCH_BUFFER_SIZE: u64 = 10;
+
 pub(crate) fn id(target_root: &Hash256, target_slot: &Slot) -> u64 {
     let mut hasher = std::collections::hash_map::DefaultHasher::new();
     (target_root, target_slot).hash(&mut hasher);
@@ -39,36 +51,15 @@ pub(crate) struct SyncingChain {
     peers: HashMap<PeerId, HashSet<BatchId>>,
     /// Starting epoch of the next batch that needs to be downloaded.
     to_be_downloaded: Epoch,
+    /// Starting epoch of the next batch that needs to be processed. The batches are imported in
+    /// order as each one builds on the previous one.
+    processing_target: Epoch,
+    /// The batch being processed, if any.
+    processing: Option<BatchId>,
     /// Map of batches undergoing some kind of processing.
     batches: HashMap<Epoch, BatchInfo>,
-}
-
-/// A segment of a chain.
-struct BatchInfo {
-    /// Start slot of the batch.
-    start_slot: Slot,
-    /// End slot of the batch.
-    end_slot: Slot,
-}
-
-impl BatchInfo {
-    fn new(epoch: Epoch) -> Self {
-        // refs: https://github.com/sigp/lighthouse/blob/f4ffa9e0b4acbe3cc3b50f9eeeb6b3d87e58a1a5/beacon_node/network/src/sync/range_sync/batch.rs#L134-L141
-        let start_slot = epoch.start_slot(MainnetEthSpec::slots_per_epoch()) + 1;
-        let end_slot = start_slot + EPOCHS_PER_BATCH * MainnetEthSpec::slots_per_epoch();
-        BatchInfo {
-            start_slot,
-            end_slot,
-        }
-    }
-
-    /// Returns a BlocksByRange request associated with the batch.
-    fn to_blocks_by_range_request(&self) -> lighthouse_network::rpc::BlocksByRangeRequest {
-        lighthouse_network::rpc::BlocksByRangeRequest::new(
-            self.start_slot.into(),
-            self.end_slot.sub(self.start_slot).into(),
-        )
-    }
+    /// The epoch to stop syncing at, if set, in addition to the target head slot.
+    target_epoch: Option<Epoch>,
 }
 
 #[derive(Debug)]
@@ -77,6 +68,8 @@ enum SyncingState {
     Stopped,
     /// The chain is undergoing syncing.
     Syncing,
+    /// The chain has been synced up to the target epoch.
+    ReachedTarget,
 }
 
 impl SyncingChain {
@@ -85,6 +78,7 @@ impl SyncingChain {
         target_head_slot: Slot,
         target_head_root: Hash256,
         peer_id: PeerId,
+        target_epoch: Option<Epoch>,
     ) -> Self {
         let id = id(&target_head_root, &target_head_slot);
         let mut peers = HashMap::new();
@@ -98,7 +92,10 @@ impl SyncingChain {
             target_head_root,
             peers,
             to_be_downloaded: start_epoch,
+            processing_target: start_epoch,
+            processing: None,
             batches: HashMap::new(),
+            target_epoch,
         }
     }
 
@@ -106,6 +103,67 @@ impl SyncingChain {
         self.peers.len()
     }
 
+    pub(crate) fn is_syncing(&self) -> bool {
+        matches!(self.state, SyncingState::Syncing)
+    }
+
+    /// Returns true if all the batches up to the target head, or the target epoch, have been
+    /// imported.
+    pub(crate) fn is_completed(&self) -> bool {
+        matches!(self.state, SyncingState::ReachedTarget)
+            || self
+                .processing_target
+                .start_slot(MainnetEthSpec::slots_per_epoch())
+                >= self.target_head_slot
+    }
+
+    /// Returns true if a batch has failed too many times, so that the chain can't be synced.
+    pub(crate) fn has_failed_batch(&self) -> bool {
+        self.batches
+            .values()
+            .any(|batch| matches!(batch.state(), BatchState::Failed(_)))
+    }
+
+    /// Returns the state of the chain, for debugging.
+    pub(crate) fn snapshot(&self) -> serde_json::Value {
+        let peers = self
+            .peers
+            .iter()
+            .map(|(peer_id, batches)| {
+                let batches = batches
+                    .iter()
+                    .map(|epoch| epoch.as_u64())
+                    .collect::<Vec<_>>();
+                (peer_id.to_string(), serde_json::json!(batches))
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let batches = self
+            .batches
+            .iter()
+            .map(|(epoch, batch)| {
+                serde_json::json!({
+                    "epoch": epoch.as_u64(),
+                    "start_slot": batch.start_slot.as_u64(),
+                    "end_slot": batch.end_slot.as_u64(),
+                    "state": format!("{:?}", batch.state()),
+                    "blocks": batch.blocks().len(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::json!({
+            "id": self.id,
+            "state": format!("{:?}", self.state),
+            "start_epoch": self.start_epoch.as_u64(),
+            "to_be_downloaded": self.to_be_downloaded.as_u64(),
+            "processing_target": self.processing_target.as_u64(),
+            "target_head_slot": self.target_head_slot.as_u64(),
+            "target_head_root": format!("{:?}", self.target_head_root),
+            "peers": peers,
+            "batches": batches,
+        })
+    }
+
     /// Add a peer to the chain.
     ///
     /// If the chain is active, this starts requesting batches from this peer.
@@ -115,6 +173,240 @@ impl SyncingChain {
         }
     }
 
+    /// Removes a peer from the chain, re-assigning its batches to the remaining peers.
+    pub(crate) fn remove_peer(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+    ) {
+        let Some(batches) = self.peers.remove(peer_id) else {
+            return;
+        };
+
+        debug!(
+            "[{peer_id}] Removed a peer from the chain. chain_id: {}, batches: {batches:?}",
+            self.id
+        );
+
+        self.forget_batches(batches);
+
+        if matches!(self.state, SyncingState::Syncing) {
+            self.request_batches(network_context);
+        }
+    }
+
+    /// Requests the batches assigned to the peer again, e.g. as the peer responded with an error.
+    pub(crate) fn retry_batches(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+    ) {
+        let Some(batches) = self.peers.get_mut(peer_id).map(std::mem::take) else {
+            return;
+        };
+
+        debug!(
+            "[{peer_id}] Retrying the batches of the peer. chain_id: {}, batches: {batches:?}",
+            self.id
+        );
+
+        self.forget_batches(batches);
+
+        if matches!(self.state, SyncingState::Syncing) {
+            self.request_batches(network_context);
+        }
+    }
+
+    /// Returns true if a batch of this chain is being downloaded from the peer by the request.
+    pub(crate) fn is_downloading(&self, peer_id: &PeerId, request_id: u32) -> bool {
+        self.batches
+            .values()
+            .any(|batch| batch.is_downloading(peer_id, request_id))
+    }
+
+    /// Adds a block received from the peer to the batch requested by the request.
+    pub(crate) fn add_block(
+        &mut self,
+        peer_id: &PeerId,
+        request_id: u32,
+        block: Arc<SignedBeaconBlock<MainnetEthSpec>>,
+    ) {
+        let Some((epoch, batch)) = self
+            .batches
+            .iter_mut()
+            .find(|(_epoch, batch)| batch.is_downloading(peer_id, request_id))
+        else {
+            return;
+        };
+
+        if let Err(e) = batch.add_block(block) {
+            error!("[{peer_id}] chain_id: {}, epoch: {epoch}, {e}", self.id);
+        }
+    }
+
+    /// The peer has finished serving the batch requested by the request, which frees the peer
+    /// up for the next batch. Returns false if the request isn't for this chain.
+    pub(crate) fn download_completed(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+        request_id: u32,
+    ) -> bool {
+        let Some((epoch, batch)) = self
+            .batches
+            .iter_mut()
+            .find(|(_epoch, batch)| batch.is_downloading(peer_id, request_id))
+        else {
+            return false;
+        };
+        let epoch = *epoch;
+
+        if let Err(e) = batch.download_completed() {
+            error!("[{peer_id}] chain_id: {}, epoch: {epoch}, {e}", self.id);
+        }
+        debug!(
+            "[{peer_id}] Downloaded a batch. chain_id: {}, epoch: {epoch}",
+            self.id
+        );
+        if let Some(batches) = self.peers.get_mut(peer_id) {
+            batches.remove(&epoch);
+        }
+
+        if matches!(self.state, SyncingState::Syncing) {
+            self.request_batches(network_context);
+        }
+        true
+    }
+
+    /// The peer failed to serve the batch requested by the request (e.g. it responded with an
+    /// error), so only that batch is downloaded again, preferably from another peer. Returns false
+    /// if the request isn't for this chain.
+    pub(crate) fn download_failed(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        peer_id: &PeerId,
+        request_id: u32,
+    ) -> bool {
+        let Some(epoch) = self
+            .batches
+            .iter()
+            .find(|(_epoch, batch)| batch.is_downloading(peer_id, request_id))
+            .map(|(epoch, _batch)| *epoch)
+        else {
+            return false;
+        };
+
+        if let Some(batches) = self.peers.get_mut(peer_id) {
+            batches.remove(&epoch);
+        }
+        self.forget_batches(HashSet::from([epoch]));
+
+        if matches!(self.state, SyncingState::Syncing) {
+            self.request_batches(network_context);
+        }
+        true
+    }
+
+    /// Returns the next batch to import, with the peer that served it, unless a batch is being
+    /// processed already.
+    pub(crate) fn next_batch_to_process(
+        &mut self,
+    ) -> Option<(BatchId, PeerId, Vec<Arc<SignedBeaconBlock<MainnetEthSpec>>>)> {
+        if self.processing.is_some() {
+            return None;
+        }
+
+        let epoch = self.processing_target;
+        let batch = self.batches.get(&epoch)?;
+        let BatchState::AwaitingValidation(peer_id) = batch.state() else {
+            return None;
+        };
+
+        self.processing = Some(epoch);
+        Some((epoch, *peer_id, batch.blocks().to_vec()))
+    }
+
+    /// The batch has been processed. On success the chain moves on to the next batch, otherwise
+    /// the batch is downloaded again, preferably from another peer.
+    pub(crate) fn on_batch_processed(
+        &mut self,
+        network_context: &mut SyncNetworkContext,
+        batch_id: BatchId,
+        success: bool,
+        local_finalized_epoch: Epoch,
+    ) {
+        if self.processing != Some(batch_id) {
+            warn!(
+                "Unexpected batch processed. chain_id: {}, batch_id: {batch_id}, processing: {:?}",
+                self.id, self.processing
+            );
+            return;
+        }
+        self.processing = None;
+
+        let Some(batch) = self.batches.get_mut(&batch_id) else {
+            return;
+        };
+
+        if success {
+            if let Err(e) = batch.validation_succeeded() {
+                error!("chain_id: {}, epoch: {batch_id}, {e}", self.id);
+            }
+            // The blocks have been imported, so the batch is no longer needed.
+            self.batches.remove(&batch_id);
+            self.processing_target += EPOCHS_PER_BATCH;
+            self.advance_chain(local_finalized_epoch);
+
+            if self
+                .target_epoch
+                .map_or(false, |target_epoch| self.processing_target >= target_epoch)
+            {
+                info!(
+                    "Reached the target epoch. chain_id: {}, processing_target: {}",
+                    self.id, self.processing_target
+                );
+                self.state = SyncingState::ReachedTarget;
+            }
+        } else {
+            match batch.validation_failed() {
+                Ok(BatchState::Failed(attempts)) => error!(
+                    "Batch failed too many times. chain_id: {}, epoch: {batch_id}, attempts: {attempts}",
+                    self.id
+                ),
+                Ok(_) => {}
+                Err(e) => error!("chain_id: {}, epoch: {batch_id}, {e}", self.id),
+            }
+        }
+
+        if matches!(self.state, SyncingState::Syncing) {
+            self.request_batches(network_context);
+        }
+    }
+
+    /// Marks the downloads of the batches as failed so that they are downloaded again.
+    fn forget_batches(&mut self, batches: HashSet<BatchId>) {
+        for epoch in batches {
+            let Some(batch) = self.batches.get_mut(&epoch) else {
+                continue;
+            };
+            match batch.download_failed() {
+                Ok(BatchState::Failed(attempts)) => error!(
+                    "Batch failed too many times. chain_id: {}, epoch: {epoch}, attempts: {attempts}",
+                    self.id
+                ),
+                Ok(_) => {}
+                Err(e) => error!("chain_id: {}, epoch: {epoch}, {e}", self.id),
+            }
+        }
+    }
+
+    /// Requests more batches, e.g. once the peers are no longer throttled.
+    pub(crate) fn resume(&mut self, network_context: &mut SyncNetworkContext) {
+        if matches!(self.state, SyncingState::Syncing) {
+            self.request_batches(network_context);
+        }
+    }
+
     pub(crate) fn start_syncing(
         &mut self,
         network_context: &mut SyncNetworkContext,
@@ -131,6 +423,12 @@ impl SyncingChain {
         self.request_batches(network_context);
     }
 
+    /// Stops requesting batches. The requests in flight are still completed.
+    pub(crate) fn stop_syncing(&mut self) {
+        debug!("stop_syncing: chain_id: {}", self.id);
+        self.state = SyncingState::Stopped;
+    }
+
     fn advance_chain(&mut self, local_finalized_epoch: Epoch) {
         // make sure this epoch produces an advancement
         if local_finalized_epoch <= self.start_epoch {
@@ -138,9 +436,6 @@ impl SyncingChain {
             return;
         }
 
-        // TODO: some batch processing should be implemented
-        // https://github.com/sigp/lighthouse/blob/8c69d57c2ce0d5f1a3cd44c215b2d52844043150/beacon_node/network/src/sync/range_sync/chain.rs#L624
-
         let old_start_epoch = self.start_epoch;
         self.start_epoch = local_finalized_epoch;
         info!(
@@ -158,19 +453,82 @@ impl SyncingChain {
         // NOTE: The peer pool should be shuffled before sending request for load balancing.
         // https://github.com/sigp/lighthouse/blob/8c69d57c2ce0d5f1a3cd44c215b2d52844043150/beacon_node/network/src/sync/range_sync/chain.rs#L985
 
-        for (peer_id, _batches) in self.peers.clone().iter() {
-            if let Some(epoch) = self.next_batch() {
-                self.send_batch(network_context, peer_id, epoch);
-            } else {
+        loop {
+            // The node is shutting down, so no more requests can be sent.
+            if network_context.is_network_closed() {
+                debug!("[SyncingChain::request_batches] The network has been closed. Stopped requesting batches.");
+                return;
+            }
+
+            if self.batches_in_flight() >= MAX_BATCHES_IN_FLIGHT {
+                return;
+            }
+
+            // The requests are resumed once some of the in-flight ones across all the chains
+            // complete.
+            if network_context.is_at_capacity() {
+                return;
+            }
+
+            // The peers that failed to serve the batch to be downloaded again are avoided.
+            let failed_peers = self
+                .batches
+                .iter()
+                .filter(|(_epoch, batch)| batch.is_ready_to_download())
+                .min_by_key(|(epoch, _batch)| **epoch)
+                .map(|(_epoch, batch)| batch.failed_peers().clone())
+                .unwrap_or_default();
+
+            // Pick the least busy peer so that the batches are spread over the peers.
+            let Some(peer_id) = self
+                .peers
+                .iter()
+                .filter(|(_peer_id, batches)| batches.len() < BATCHES_PER_PEER)
+                .filter(|(peer_id, _batches)| !network_context.is_throttled(peer_id))
+                .min_by_key(|(peer_id, batches)| (failed_peers.contains(*peer_id), batches.len()))
+                .map(|(peer_id, _batches)| *peer_id)
+            else {
+                // All the peers are busy.
+                return;
+            };
+
+            let Some(epoch) = self.next_batch() else {
                 // No more batches, simply stop
                 return;
+            };
+            // The request couldn't be sent (e.g. the peer is throttled), so the batch is requested
+            // again on the next resume.
+            if !self.send_batch(network_context, &peer_id, epoch) {
+                return;
             }
         }
     }
 
+    /// The number of batches being downloaded from the peers.
+    fn batches_in_flight(&self) -> usize {
+        self.peers.values().map(|batches| batches.len()).sum()
+    }
+
+    /// Returns the next batch to download, preferring the batches to be downloaded again over
+    /// creating a new one. The batches backing off after a failure are skipped. If there are no
+    /// more batches required, `None` is returned.
+    fn next_batch(&mut self) -> Option<Epoch> {
+        let awaiting_download = self
+            .batches
+            .iter()
+            .filter(|(_epoch, batch)| batch.is_ready_to_download())
+            .map(|(epoch, _batch)| *epoch)
+            .min();
+        if awaiting_download.is_some() {
+            return awaiting_download;
+        }
+
+        self.new_batch()
+    }
+
     /// Creates the next required batch from the chain. If there are no more batches required,
     /// `None` is returned.
-    fn next_batch(&mut self) -> Option<Epoch> {
+    fn new_batch(&mut self) -> Option<Epoch> {
         // don't request batches beyond the target head slot
         if self
             .to_be_downloaded
@@ -180,15 +538,25 @@ impl SyncingChain {
             return None;
         }
 
-        // NOTE: making buffer size limit would be better.
-        // https://github.com/sigp/lighthouse/blob/8c69d57c2ce0d5f1a3cd44c215b2d52844043150/beacon_node/network/src/sync/range_sync/chain.rs#L1037
+        // nor beyond the target epoch
+        if self
+            .target_epoch
+            .map_or(false, |target_epoch| self.to_be_downloaded >= target_epoch)
+        {
+            return None;
+        }
+
+        // nor beyond the buffer ahead of the batch to be processed
+        if self.to_be_downloaded >= self.processing_target + BATCH_BUFFER_SIZE * EPOCHS_PER_BATCH {
+            return None;
+        }
 
         let epoch = self.to_be_downloaded;
         match self.batches.entry(epoch) {
             Entry::Occupied(_) => {
                 // this batch doesn't need downloading, let this same function decide the next batch
                 self.to_be_downloaded += EPOCHS_PER_BATCH;
-                self.next_batch()
+                self.new_batch()
             }
             Entry::Vacant(entry) => {
                 entry.insert(BatchInfo::new(epoch));
@@ -198,33 +566,284 @@ impl SyncingChain {
         }
     }
 
-    /// Requests the batch assigned to the given epoch (batch id) from a given peer.
+    /// Requests the batch assigned to the given epoch (batch id) from a given peer. Returns false
+    /// if the request couldn't be sent.
     fn send_batch(
         &mut self,
         network_context: &mut SyncNetworkContext,
         peer_id: &PeerId,
         epoch: Epoch,
-    ) {
+    ) -> bool {
         trace!("[{peer_id}] [SyncingChain::send_batch] epoch(batch_id):{epoch}");
 
         let batch_info = match self.batches.get_mut(&epoch) {
             Some(batch_info) => batch_info,
             None => {
                 warn!("[{peer_id}] [SyncingChain::send_batch] BatchInfo not found. epoch:{epoch}");
-                return;
+                return false;
             }
         };
 
         let request = batch_info.to_blocks_by_range_request();
         match network_context.blocks_by_range_request(peer_id, request) {
-            Ok(_request_id) => {
-                // TODO: store the request_id in self.peers
-                // https://github.com/ackintosh/lighthouse/blob/8c69d57c2ce0d5f1a3cd44c215b2d52844043150/beacon_node/network/src/sync/range_sync/chain.rs#L902
+            Ok(request_id) => {
+                if let Err(e) = batch_info.start_downloading(*peer_id, request_id) {
+                    error!("[{peer_id}] [SyncingChain::send_batch] epoch:{epoch}, {e}");
+                }
+                if let Some(batches) = self.peers.get_mut(peer_id) {
+                    batches.insert(epoch);
+                }
+                true
             }
             Err(e) => {
-                error!("[{peer_id}] [SyncingChain::send_batch] Failed to send `BlocksByRange` request. error:{e}")
-                // TODO: error handling
-                // https://github.com/ackintosh/lighthouse/blob/8c69d57c2ce0d5f1a3cd44c215b2d52844043150/beacon_node/network/src/sync/range_sync/chain.rs#L929
+                // The request wasn't sent for a local reason (e.g. the peer is throttled or too
+                // many requests are in flight), so neither the batch nor the peer is at fault. The
+                // batch is left awaiting download.
+                // ref: https://github.com/ackintosh/lighthouse/blob/8c69d57c2ce0d5f1a3cd44c215b2d52844043150/beacon_node/network/src/sync/range_sync/chain.rs#L929
+                debug!("[{peer_id}] [SyncingChain::send_batch] Failed to send `BlocksByRange` request. epoch:{epoch}, error:{e}");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::test_block;
+    use crate::network::NetworkMessage;
+    use crate::peer_db::PeerDB;
+    use crate::sync::BatchProcessingResult;
+    use parking_lot::RwLock;
+    use tokio::sync::mpsc;
+
+    fn network_context() -> (SyncNetworkContext, mpsc::Receiver<NetworkMessage>) {
+        let (network_send, network_recv) = mpsc::channel(64);
+        (SyncNetworkContext::new(network_send, 64, 64), network_recv)
+    }
+
+    // The peers and the start slots of the BlocksByRange requests sent to the network.
+    fn sent_requests(network_recv: &mut mpsc::Receiver<NetworkMessage>) -> Vec<(PeerId, u64)> {
+        let mut sent = vec![];
+        while let Ok(message) = network_recv.try_recv() {
+            if let NetworkMessage::SendRequest {
+                peer_id,
+                request: lighthouse_network::Request::BlocksByRange(request),
+                ..
+            } = message
+            {
+                sent.push((peer_id, *request.start_slot()));
+            }
+        }
+        sent
+    }
+
+    fn start_slot(epoch: Epoch) -> u64 {
+        BatchInfo::new(epoch).start_slot.as_u64()
+    }
+
+    #[test]
+    fn sync_starts_at_the_stored_finalized_epoch() {
+        let (mut network_context, mut network_recv) = network_context();
+        let peer_id = PeerId::random();
+        // The finalized epoch of the head persisted by a previous run.
+        let finalized_epoch = Epoch::new(100);
+        let mut chain = SyncingChain::new(
+            finalized_epoch,
+            Epoch::new(200).start_slot(MainnetEthSpec::slots_per_epoch()),
+            Hash256::zero(),
+            peer_id,
+            None,
+        );
+
+        chain.start_syncing(&mut network_context, finalized_epoch);
+
+        let sent = sent_requests(&mut network_recv);
+        assert_eq!(sent.first(), Some(&(peer_id, start_slot(finalized_epoch))));
+    }
+
+    #[test]
+    fn a_peer_downloads_several_batches_concurrently() {
+        let (mut network_context, mut network_recv) = network_context();
+        let peer_id = PeerId::random();
+        let mut chain = SyncingChain::new(
+            Epoch::new(0),
+            Epoch::new(100).start_slot(MainnetEthSpec::slots_per_epoch()),
+            Hash256::zero(),
+            peer_id,
+            None,
+        );
+
+        chain.start_syncing(&mut network_context, Epoch::new(0));
+
+        let sent = sent_requests(&mut network_recv);
+        assert_eq!(sent.len(), BATCHES_PER_PEER);
+        assert!(sent.iter().all(|(id, _start_slot)| *id == peer_id));
+        assert_eq!(chain.peers[&peer_id].len(), BATCHES_PER_PEER);
+    }
+
+    #[test]
+    fn only_the_batch_of_the_failed_request_is_downloaded_again() {
+        let (mut network_context, _network_recv) = network_context();
+        let peer_id = PeerId::random();
+        let mut chain = SyncingChain::new(
+            Epoch::new(0),
+            Epoch::new(100).start_slot(MainnetEthSpec::slots_per_epoch()),
+            Hash256::zero(),
+            peer_id,
+            None,
+        );
+        chain.start_syncing(&mut network_context, Epoch::new(0));
+        let request_id =
+            |chain: &SyncingChain, epoch: u64| match chain.batches[&Epoch::new(epoch)].state() {
+                BatchState::Downloading(_peer_id, request_id) => Some(*request_id),
+                _ => None,
+            };
+        let failed_request_id = request_id(&chain, 0).expect("downloading");
+        let other_request_id = request_id(&chain, EPOCHS_PER_BATCH).expect("downloading");
+
+        assert!(chain.download_failed(&mut network_context, &peer_id, failed_request_id));
+
+        let failed_batch = &chain.batches[&Epoch::new(0)];
+        assert!(matches!(failed_batch.state(), BatchState::AwaitingDownload));
+        assert!(failed_batch.failed_peers().contains(&peer_id));
+        assert_eq!(request_id(&chain, EPOCHS_PER_BATCH), Some(other_request_id));
+        // The request is no longer known.
+        assert!(!chain.download_failed(&mut network_context, &peer_id, failed_request_id));
+    }
+
+    #[test]
+    fn batches_are_downloaded_up_to_the_buffer_ahead_of_processing() {
+        let (mut network_context, _network_recv) = network_context();
+        let peer_id = PeerId::random();
+        let mut chain = SyncingChain::new(
+            Epoch::new(0),
+            Epoch::new(1000).start_slot(MainnetEthSpec::slots_per_epoch()),
+            Hash256::zero(),
+            peer_id,
+            None,
+        );
+        chain.start_syncing(&mut network_context, Epoch::new(0));
+
+        // The peer serves every batch, but none is processed.
+        loop {
+            let downloading = chain
+                .batches
+                .values()
+                .filter_map(|batch| match batch.state() {
+                    BatchState::Downloading(_peer_id, request_id) => Some(*request_id),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            if downloading.is_empty() {
+                break;
+            }
+            for request_id in downloading {
+                assert!(chain.download_completed(&mut network_context, &peer_id, request_id));
+            }
+        }
+
+        assert_eq!(chain.batches.len() as u64, BATCH_BUFFER_SIZE);
+        assert_eq!(
+            chain.to_be_downloaded,
+            Epoch::new(BATCH_BUFFER_SIZE * EPOCHS_PER_BATCH)
+        );
+
+        // The first batch has been processed, which makes room for the next one.
+        let (batch_id, _peer_id, _blocks) = chain.next_batch_to_process().expect("batch");
+        chain.on_batch_processed(&mut network_context, batch_id, true, Epoch::new(0));
+        assert!(matches!(
+            chain.batches[&Epoch::new(BATCH_BUFFER_SIZE * EPOCHS_PER_BATCH)].state(),
+            BatchState::Downloading(..)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batch_of_a_failing_peer_is_retried_on_another_peer() {
+        let (mut network_context, mut network_recv) = network_context();
+        let (failing, other) = (PeerId::random(), PeerId::random());
+        let mut chain = SyncingChain::new(
+            Epoch::new(0),
+            Epoch::new(100).start_slot(MainnetEthSpec::slots_per_epoch()),
+            Hash256::zero(),
+            failing,
+            None,
+        );
+        chain.start_syncing(&mut network_context, Epoch::new(0));
+        sent_requests(&mut network_recv);
+        let BatchState::Downloading(_peer_id, request_id) = *chain.batches[&Epoch::new(0)].state()
+        else {
+            panic!("The first batch is not being downloaded");
+        };
+
+        assert!(chain.download_failed(&mut network_context, &failing, request_id));
+        chain.add_peer(&mut network_context, other);
+        // The later batches are still downloaded while the failed one is backing off.
+        let sent = sent_requests(&mut network_recv);
+        assert!(sent.iter().any(|(peer_id, _start_slot)| *peer_id == other));
+        assert!(sent
+            .iter()
+            .all(|(_peer_id, slot)| *slot != start_slot(Epoch::new(0))));
+
+        tokio::time::advance(crate::sync::batch::BATCH_RETRY_BACKOFF).await;
+        // The other peer serves a batch, which makes room for the failed one.
+        let epoch = *chain.peers[&other].iter().next().expect("batch");
+        let BatchState::Downloading(_peer_id, request_id) = *chain.batches[&epoch].state() else {
+            panic!("The batch is not being downloaded");
+        };
+        assert!(chain.download_completed(&mut network_context, &other, request_id));
+
+        let sent = sent_requests(&mut network_recv);
+        assert_eq!(sent.first(), Some(&(other, start_slot(Epoch::new(0)))));
+    }
+
+    #[test]
+    fn processed_batch_scores_the_peer_and_moves_the_batch_on() {
+        for (result, score) in [
+            (BatchProcessingResult::Success, 1.0),
+            (BatchProcessingResult::Invalid, -10.0),
+        ] {
+            let (mut network_context, mut network_recv) = network_context();
+            let peer_id = PeerId::random();
+            let peer_db = RwLock::new(PeerDB::new());
+            peer_db
+                .write()
+                .add_peer(peer_id, "/ip4/127.0.0.1/tcp/9000".parse().unwrap(), true);
+            let mut chain = SyncingChain::new(
+                Epoch::new(0),
+                Epoch::new(100).start_slot(MainnetEthSpec::slots_per_epoch()),
+                Hash256::zero(),
+                peer_id,
+                None,
+            );
+            chain.start_syncing(&mut network_context, Epoch::new(0));
+            sent_requests(&mut network_recv);
+            let batch_id = Epoch::new(0);
+            let BatchState::Downloading(_peer_id, request_id) = *chain.batches[&batch_id].state()
+            else {
+                panic!("The first batch is not being downloaded");
+            };
+            chain.add_block(&peer_id, request_id, test_block(0));
+            assert!(chain.download_completed(&mut network_context, &peer_id, request_id));
+            assert_eq!(
+                chain
+                    .next_batch_to_process()
+                    .map(|(batch_id, peer_id, _blocks)| (batch_id, peer_id)),
+                Some((batch_id, peer_id))
+            );
+
+            let success = result.score_peer(&peer_db, &peer_id);
+            chain.on_batch_processed(&mut network_context, batch_id, success, Epoch::new(0));
+
+            assert_eq!(peer_db.read().score(&peer_id), Some(score), "{result:?}");
+            if success {
+                // The blocks have been imported, so the chain has moved on to the next batch.
+                assert!(!chain.batches.contains_key(&batch_id));
+            } else {
+                // The batch is downloaded again, preferably from another peer.
+                let batch = &chain.batches[&batch_id];
+                assert!(matches!(batch.state(), BatchState::AwaitingDownload));
+                assert!(batch.failed_peers().contains(&peer_id));
             }
         }
     }